@@ -0,0 +1,229 @@
+//! Client access rules.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::message::{self, DomainName};
+
+/// An IP network in CIDR notation, e.g. `192.168.0.0/16`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        fn masked(bytes: &[u8], prefix: u8) -> Vec<u8> {
+            bytes
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| {
+                    let bits = (prefix as usize).saturating_sub(i * 8).min(8);
+                    if bits == 0 {
+                        0
+                    } else {
+                        b & (0xff << (8 - bits))
+                    }
+                })
+                .collect()
+        }
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(&net.octets(), self.prefix) == masked(&ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(&net.octets(), self.prefix) == masked(&ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("Error parsing network {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or(format!("Error parsing prefix length of {}", s))?,
+            None => max,
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+/// What to do with a query.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Action {
+    Allow,
+    /// Log the query, then answer it normally.
+    Log,
+    /// Answer with REFUSED.
+    Refuse,
+    /// Don't answer at all.
+    Drop,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "log" => Ok(Action::Log),
+            "refuse" => Ok(Action::Refuse),
+            "drop" => Ok(Action::Drop),
+            _ => Err(format!("Unknown action {}", s)),
+        }
+    }
+}
+
+/// A policy for queries of one type, optionally only from some clients.
+///
+/// Written as `TYPE=ACTION[@NETWORK]`, e.g. `ANY=refuse@192.168.0.0/16` or
+/// `TYPE65535=drop`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QtypeRule {
+    /// The number of the type.
+    pub qtype: u16,
+    pub action: Action,
+    pub clients: Option<Network>,
+}
+
+impl QtypeRule {
+    pub fn matches(&self, qtype: u16, client: &IpAddr) -> bool {
+        self.qtype == qtype && self.clients.map_or(true, |net| net.contains(client))
+    }
+}
+
+impl FromStr for QtypeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QtypeRule, String> {
+        let mut parts = s.splitn(2, '=');
+        let qtype = message::type_code(parts.next().unwrap())?;
+        let rest = parts
+            .next()
+            .ok_or(format!("Missing action in qtype policy {}", s))?;
+        let mut parts = rest.splitn(2, '@');
+        let action = parts.next().unwrap().parse()?;
+        let clients = match parts.next() {
+            Some(net) => Some(net.parse()?),
+            None => None,
+        };
+        Ok(QtypeRule {
+            qtype,
+            action,
+            clients,
+        })
+    }
+}
+
+/// A zone open to updates or transfers by clients in a network and holding
+/// a TSIG key.  Without a network, any client holding the key has access,
+/// and without a key, clients in the network.  Without both, only the local
+/// host does.
+///
+/// Written as `ZONE[@NETWORK][,KEY]`, e.g. `lan@192.168.0.0/16,dhcp`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneAccess {
+    pub zone: DomainName,
+    pub clients: Option<Network>,
+    pub key: Option<DomainName>,
+}
+
+impl ZoneAccess {
+    /// Whether `client`, which signed its request with `key`, has access.
+    pub fn allows(&self, client: &IpAddr, key: Option<&DomainName>) -> bool {
+        match (&self.key, key, self.clients) {
+            (Some(ref wanted), Some(key), _) if wanted != key => false,
+            (Some(_), None, _) => false,
+            (_, _, Some(net)) => net.contains(client),
+            (Some(_), Some(_), None) => true,
+            (None, _, None) => client.is_loopback(),
+        }
+    }
+}
+
+impl FromStr for ZoneAccess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ZoneAccess, String> {
+        let mut parts = s.splitn(2, ',');
+        let zone = parts.next().unwrap();
+        let key = match parts.next() {
+            Some(key) => Some(DomainName::from_idn(key)?),
+            None => None,
+        };
+        let mut parts = zone.splitn(2, '@');
+        let zone = DomainName::from_idn(parts.next().unwrap())?;
+        let clients = match parts.next() {
+            Some(net) => Some(net.parse()?),
+            None => None,
+        };
+        Ok(ZoneAccess { zone, clients, key })
+    }
+}
+
+/// The strictest action of the first matching rule for each question type,
+/// by number.
+pub fn qtype_action<'a, I>(rules: &[QtypeRule], qtypes: I, client: &IpAddr) -> Action
+where
+    I: IntoIterator<Item = &'a u16>,
+{
+    qtypes
+        .into_iter()
+        .filter_map(|&qtype| rules.iter().find(|rule| rule.matches(qtype, client)))
+        .map(|rule| rule.action)
+        .fold(Action::Allow, |a, b| if b > a { b } else { a })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::DnsType;
+
+    #[test]
+    fn test_network() {
+        let lan: Network = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains(&"192.168.1.2".parse().unwrap()));
+        assert!(!lan.contains(&"192.169.1.2".parse().unwrap()));
+        assert!(!lan.contains(&"::1".parse().unwrap()));
+        let odd: Network = "10.0.0.0/9".parse().unwrap();
+        assert!(odd.contains(&"10.127.0.1".parse().unwrap()));
+        assert!(!odd.contains(&"10.128.0.1".parse().unwrap()));
+        let v6: Network = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&"fd12::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_qtype_action() {
+        let rules: Vec<QtypeRule> = vec![
+            "ANY=refuse@192.168.0.0/16".parse().unwrap(),
+            "PTR=log".parse().unwrap(),
+            "type65535=drop".parse().unwrap(),
+        ];
+        let (any, ptr, a) = (DnsType::Any as u16, DnsType::PTR as u16, DnsType::A as u16);
+        let lan = "192.168.1.2".parse().unwrap();
+        let wan = "8.8.8.8".parse().unwrap();
+        assert_eq!(qtype_action(&rules, &[any], &lan), Action::Refuse);
+        assert_eq!(qtype_action(&rules, &[any], &wan), Action::Allow);
+        assert_eq!(qtype_action(&rules, &[ptr, a], &wan), Action::Log);
+        assert_eq!(qtype_action(&rules, &[a, 65535], &wan), Action::Drop);
+        assert_eq!(qtype_action(&rules, &[65534], &wan), Action::Allow);
+        assert!("UNKNOWN=drop".parse::<QtypeRule>().is_err());
+        assert!("TYPE65536=drop".parse::<QtypeRule>().is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! The admin API: JSON over HTTP on a loopback address, to look into and
+//! change a running server without restarting it.
+//!
+//! ```text
+//! GET    /stats                          query counters, memory and cache
+//! GET    /upstreams                      upstream servers and their health
+//! POST   /cache/flush[?name=NAME]        drop cached answers (with `cache`)
+//! GET    /local                          local records
+//! POST   /local?name=NAME&address=IP     add a local entry
+//! DELETE /local?name=NAME[&address=IP]   remove local entries
+//! GET    /blocklist                      blocked names
+//! POST   /blocklist?name=NAME            block a name
+//! DELETE /blocklist?name=NAME            unblock it
+//! ```
+//!
+//! The blocklist routes need the `blocklists` feature.
+//!
+//! Requests must name the listen address as their `Host`, against DNS
+//! rebinding, and not come from web pages, which send an `Origin`.  With a
+//! token, they must also carry it as `Authorization: Bearer TOKEN`.
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN, WWW_AUTHENTICATE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+
+use crate::http::{json_string, param, percent_decode, status_response};
+use crate::message::{DnsType, DomainName};
+use crate::server::Managed;
+use crate::stats::{Variant, VariantStats};
+
+const PATHS: &[&str] = &[
+    "/stats",
+    "/upstreams",
+    "/cache/flush",
+    "/local",
+    "/blocklist",
+];
+
+/// Read the token clients must send from `path`, without surrounding
+/// whitespace.
+pub(crate) fn read_token(path: &Path) -> io::Result<String> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The admin token file {} is empty", path.display()),
+        ));
+    }
+    Ok(token)
+}
+
+/// Serve the admin API on `listener`, bound to `addr`, over plain HTTP.
+pub(crate) fn serve(
+    listener: TcpListener,
+    addr: SocketAddr,
+    token: Option<String>,
+    managed: Managed,
+) -> impl Future<Item = (), Error = ()> {
+    let managed = Arc::new(managed);
+    let token = Arc::new(token);
+    listener
+        .incoming()
+        .map_err(|e| error!("error in admin listener: {}", e))
+        .for_each(move |stream| {
+            let (managed, token) = (managed.clone(), token.clone());
+            let service = service_fn_ok(move |request| {
+                match refuse(addr, token.as_ref().as_ref(), &request) {
+                    Some(response) => response,
+                    None => respond(&managed, &request),
+                }
+            });
+            tokio::spawn(
+                Http::new()
+                    .serve_connection(stream, service)
+                    .map_err(|e| debug!("Admin client: {}", e)),
+            );
+            Ok(())
+        })
+}
+
+/// The response refusing `request` to a server on `addr`, unless it names
+/// `addr` as its host, comes from no web page and carries `token`.
+fn refuse(
+    addr: SocketAddr,
+    token: Option<&String>,
+    request: &Request<Body>,
+) -> Option<Response<Body>> {
+    let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+    let hosts = [addr.to_string(), format!("localhost:{}", addr.port())];
+    let host_ok = header(HOST).map_or(false, |host| {
+        hosts.iter().any(|h| h.eq_ignore_ascii_case(host))
+    });
+    if !host_ok || request.headers().contains_key(ORIGIN) {
+        warn!("Admin: refused a request for {:?}", header(HOST));
+        return Some(status_response(StatusCode::FORBIDDEN));
+    }
+    let token = token?;
+    let sent = header(AUTHORIZATION)
+        .filter(|auth| auth.starts_with("Bearer "))
+        .map(|auth| &auth[7..]);
+    if sent.map_or(false, |sent| same(sent.as_bytes(), token.as_bytes())) {
+        return None;
+    }
+    let mut response = status_response(StatusCode::UNAUTHORIZED);
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    Some(response)
+}
+
+/// Compare secrets in a time independent of where they differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn respond(managed: &Managed, request: &Request<Body>) -> Response<Body> {
+    let uri = request.uri();
+    let name = match param(uri, "name") {
+        Some(name) => {
+            match percent_decode(name).and_then(|name| DomainName::from_idn(&name).ok()) {
+                Some(name) => Some(name),
+                None => return status_response(StatusCode::BAD_REQUEST),
+            }
+        }
+        None => None,
+    };
+    let address = match param(uri, "address") {
+        Some(addr) => match percent_decode(addr).and_then(|addr| addr.parse::<IpAddr>().ok()) {
+            Some(addr) => Some(addr),
+            None => return status_response(StatusCode::BAD_REQUEST),
+        },
+        None => None,
+    };
+    match (request.method(), uri.path(), name) {
+        (&Method::GET, "/stats", _) => json_response(stats(managed)),
+        (&Method::GET, "/upstreams", _) => json_response(upstreams(managed)),
+        #[cfg(not(feature = "cache"))]
+        (_, "/cache/flush", _) => status_response(StatusCode::NOT_FOUND),
+        #[cfg(not(feature = "blocklists"))]
+        (_, "/blocklist", _) => status_response(StatusCode::NOT_FOUND),
+        #[cfg(feature = "cache")]
+        (&Method::POST, "/cache/flush", name) => {
+            let flushed = managed.cache.flush(name.as_ref());
+            info!("Admin: flushed {} cached answers", flushed);
+            json_response(format!(r#"{{"flushed":{}}}"#, flushed))
+        }
+        (&Method::GET, "/local", _) => json_response(local(managed)),
+        (&Method::POST, "/local", Some(name)) => match address {
+            Some(addr) => {
+                info!("Admin: added {} {}", name, addr);
+                managed.add_local(name, addr);
+                json_response(String::from("{}"))
+            }
+            None => status_response(StatusCode::BAD_REQUEST),
+        },
+        (&Method::DELETE, "/local", Some(name)) => {
+            let removed = managed.remove_local(&name, address);
+            info!("Admin: removed {} records of {}", removed, name);
+            json_response(format!(r#"{{"removed":{}}}"#, removed))
+        }
+        #[cfg(feature = "blocklists")]
+        (&Method::GET, "/blocklist", _) => {
+            let names: Vec<_> = managed.blocked().iter().map(|n| json_string(n)).collect();
+            json_response(format!("[{}]", names.join(",")))
+        }
+        #[cfg(feature = "blocklists")]
+        (&Method::POST, "/blocklist", Some(name)) | (&Method::DELETE, "/blocklist", Some(name)) => {
+            let blocked = *request.method() == Method::POST;
+            let changed = managed.set_blocked(&name, blocked);
+            info!(
+                "Admin: {} {}",
+                if blocked { "blocked" } else { "unblocked" },
+                name
+            );
+            json_response(format!(r#"{{"changed":{}}}"#, changed))
+        }
+        (&Method::POST, "/local", None) | (&Method::DELETE, "/local", None) => {
+            status_response(StatusCode::BAD_REQUEST)
+        }
+        #[cfg(feature = "blocklists")]
+        (&Method::POST, "/blocklist", None) | (&Method::DELETE, "/blocklist", None) => {
+            status_response(StatusCode::BAD_REQUEST)
+        }
+        (_, path, _) if PATHS.contains(&path) => status_response(StatusCode::METHOD_NOT_ALLOWED),
+        _ => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn json_response(json: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(json));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn stats(managed: &Managed) -> String {
+    let stats = &managed.stats;
+    let counters = |counters: &VariantStats| {
+        format!(
+            r#"{{"queries":{},"local":{},"forwarded":{},"responses":{}}}"#,
+            counters.queries.load(Ordering::Relaxed),
+            counters.local.load(Ordering::Relaxed),
+            counters.forwarded.load(Ordering::Relaxed),
+            counters.responses.load(Ordering::Relaxed),
+        )
+    };
+    let views: Vec<_> = stats
+        .views()
+        .map(|(name, stats)| format!("{}:{}", json_string(name), counters(stats)))
+        .collect();
+    let qtypes: Vec<_> = stats
+        .qtypes()
+        .into_iter()
+        .map(|(qtype, count)| {
+            let qtype = match DnsType::try_from(qtype) {
+                Some(qtype) => qtype.to_string(),
+                None => format!("TYPE{}", qtype),
+            };
+            format!("{}:{}", json_string(&qtype), count)
+        })
+        .collect();
+    #[cfg(feature = "cache")]
+    let cache = format!(
+        r#","cache":{{"entries":{},"bytes":{},"hits":{},"misses":{},"evictions":{}}}"#,
+        managed.cache.len(),
+        managed.cache.bytes(),
+        managed.cache.hits(),
+        managed.cache.misses(),
+        managed.cache.evictions(),
+    );
+    #[cfg(not(feature = "cache"))]
+    let cache = String::new();
+    format!(
+        concat!(
+            r#"{{"stable":{},"canary":{},"views":{{{}}},"qtypes":{{{}}},"#,
+            r#""memory":{{"used":{},"local":{},"pending":{},"limit":{}}}{}}}"#
+        ),
+        counters(stats.variant(Variant::Stable)),
+        counters(stats.variant(Variant::Canary)),
+        views.join(","),
+        qtypes.join(","),
+        stats.memory.used(),
+        stats.memory.local(),
+        stats.memory.pending(),
+        stats.memory.limit(),
+        cache,
+    )
+}
+
+fn upstreams(managed: &Managed) -> String {
+    let upstreams: Vec<_> = managed
+        .upstreams
+        .status()
+        .into_iter()
+        .map(|(addr, rtt, up)| {
+            let rtt = match rtt {
+                Some(rtt) => format!(
+                    "{:.3}",
+                    rtt.as_secs() as f64 * 1e3 + f64::from(rtt.subsec_nanos()) / 1e6
+                ),
+                None => String::from("null"),
+            };
+            let url = match managed.upstreams.url(addr) {
+                Some(url) => json_string(url),
+                None => String::from("null"),
+            };
+            format!(
+                r#"{{"address":{},"url":{},"up":{},"rtt_ms":{}}}"#,
+                json_string(&addr.to_string()),
+                url,
+                up,
+                rtt
+            )
+        })
+        .collect();
+    format!("[{}]", upstreams.join(","))
+}
+
+fn local(managed: &Managed) -> String {
+    let records: Vec<_> = managed
+        .local_records()
+        .iter()
+        .map(|rr| {
+            format!(
+                r#"{{"name":{},"type":{},"ttl":{},"data":{}}}"#,
+                json_string(&rr.name.to_string()),
+                json_string(&rr.rtype.to_string()),
+                rr.ttl,
+                json_string(&rr.data.to_string())
+            )
+        })
+        .collect();
+    format!("[{}]", records.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority;
+    #[cfg(feature = "cache")]
+    use crate::cache::ResponseCache;
+    use crate::message::DnsMessage;
+    use crate::server::{EntryTable, ServerConfig};
+    use crate::stats::Stats;
+    use crate::upstream::{Strategy, Upstreams};
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_respond() {
+        let managed = Managed {
+            config: Arc::new(ServerConfig::default()),
+            local: Arc::new(RwLock::new(EntryTable::new())),
+            stats: Arc::new(Stats::default()),
+            #[cfg(feature = "cache")]
+            cache: Arc::new(ResponseCache::new(10, 0)),
+            upstreams: Arc::new(Upstreams::new(
+                vec!["192.0.2.53:53"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into()],
+                Strategy::First,
+            )),
+        };
+        let request = |method: &str, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let response = respond(&managed, &request);
+            let status = response.status();
+            let body = response.into_body().concat2().wait().unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let (status, _) = request("POST", "/local?name=A.example&address=192.0.2.1");
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = request("GET", "/local");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["name"], "A.example");
+        assert_eq!(json[0]["type"], "A");
+        assert_eq!(json[0]["data"], "192.0.2.1");
+        assert_eq!(
+            request("DELETE", "/local?name=a.example"),
+            (StatusCode::OK, String::from(r#"{"removed":1}"#))
+        );
+        assert_eq!(request("GET", "/local").1, "[]");
+        assert_eq!(
+            request("POST", "/local?name=a.example").0,
+            StatusCode::BAD_REQUEST
+        );
+
+        if cfg!(feature = "blocklists") {
+            assert_eq!(
+                request("POST", "/blocklist?name=ads.example"),
+                (StatusCode::OK, String::from(r#"{"changed":true}"#))
+            );
+            assert_eq!(request("GET", "/blocklist").1, r#"["ads.example"]"#);
+            assert_eq!(
+                request("DELETE", "/blocklist?name=ads.example").1,
+                r#"{"changed":true}"#
+            );
+        } else {
+            assert_eq!(request("GET", "/blocklist").0, StatusCode::NOT_FOUND);
+        }
+
+        let (_, body) = request("GET", "/stats");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["stable"]["queries"], 0);
+        assert_eq!(json["views"], serde_json::json!({}));
+        if cfg!(feature = "cache") {
+            assert_eq!(request("POST", "/cache/flush").1, r#"{"flushed":0}"#);
+            assert_eq!(json["cache"]["entries"], 0);
+        } else {
+            assert_eq!(request("POST", "/cache/flush").0, StatusCode::NOT_FOUND);
+        }
+        let (_, body) = request("GET", "/upstreams");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["address"], "192.0.2.53:53");
+        assert_eq!(json[0]["up"], true);
+
+        assert_eq!(request("PUT", "/stats").0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(request("GET", "/nothing").0, StatusCode::NOT_FOUND);
+
+        // A zone apex keeps its SOA record
+        let zone: DomainName = "lan".into();
+        managed
+            .local
+            .write()
+            .unwrap()
+            .insert(zone.clone(), vec![authority::soa(&zone)]);
+        request("POST", "/local?name=lan&address=10.0.0.1");
+        assert_eq!(
+            request("DELETE", "/local?name=lan"),
+            (StatusCode::OK, String::from(r#"{"removed":1}"#))
+        );
+        let query = DnsMessage::query(zone.clone(), DnsType::SOA);
+        let table = managed.local.read().unwrap();
+        let response = authority::answer(&table, &[zone], &query).unwrap();
+        assert_eq!(response.answer[0].rtype, DnsType::SOA);
+    }
+
+    #[test]
+    fn test_refuse() {
+        let addr = "127.0.0.1:8053".parse().unwrap();
+        let token = String::from("s3cret");
+        let status = |headers: &[(&str, &str)], token| {
+            let mut request = Request::builder();
+            for (name, value) in headers {
+                request.header(*name, *value);
+            }
+            let request = request.uri("/stats").body(Body::empty()).unwrap();
+            refuse(addr, token, &request).map(|response| response.status())
+        };
+        assert_eq!(status(&[("Host", "127.0.0.1:8053")], None), None);
+        assert_eq!(status(&[("Host", "LocalHost:8053")], None), None);
+        for headers in &[
+            &[][..],
+            &[("Host", "rebound.example:8053")],
+            &[
+                ("Host", "127.0.0.1:8053"),
+                ("Origin", "http://127.0.0.1:8053"),
+            ],
+        ] {
+            assert_eq!(status(headers, None), Some(StatusCode::FORBIDDEN));
+        }
+
+        let host = ("Host", "127.0.0.1:8053");
+        assert_eq!(
+            status(&[host, ("Authorization", "Bearer s3cret")], Some(&token)),
+            None
+        );
+        for auth in &["Bearer s3cre", "Bearer s3cret2", "Basic s3cret"] {
+            assert_eq!(
+                status(&[host, ("Authorization", auth)], Some(&token)),
+                Some(StatusCode::UNAUTHORIZED)
+            );
+        }
+        assert_eq!(
+            status(&[host], Some(&token)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
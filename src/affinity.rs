@@ -0,0 +1,104 @@
+//! Pinning of worker threads and sockets to CPUs.  Only supported on Linux;
+//! elsewhere the settings are ignored with a warning.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Pin the calling thread to `cpu`.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Hint the kernel to process packets of `socket` on `cpu` (SO_INCOMING_CPU).
+#[cfg(target_os = "linux")]
+pub fn set_incoming_cpu<S: std::os::unix::io::AsRawFd>(socket: &S, cpu: usize) -> io::Result<()> {
+    // Not exported by libc for every architecture, but the same everywhere
+    // uind runs.
+    const SO_INCOMING_CPU: libc::c_int = 49;
+    let cpu = cpu as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_INCOMING_CPU,
+            &cpu as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_incoming_cpu<S>(_socket: &S, _cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "socket CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Hands out CPUs to worker threads as they start, round-robin.
+#[derive(Debug)]
+pub struct CpuSet {
+    cpus: Vec<usize>,
+    next: AtomicUsize,
+}
+
+impl CpuSet {
+    pub fn new(cpus: Vec<usize>) -> CpuSet {
+        CpuSet {
+            cpus,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pin the calling worker thread to the next CPU of the set.
+    pub fn pin_next(&self) {
+        if self.cpus.is_empty() {
+            return;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.cpus.len();
+        let cpu = self.cpus[i];
+        match pin_current_thread(cpu) {
+            Ok(()) => debug!("Worker pinned to CPU {}", cpu),
+            Err(e) => warn!("Can't pin worker to CPU {}: {}", cpu, e),
+        }
+    }
+}
+
+/// Parse a CPU list like `0,2-3`.
+pub fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in s.split(',') {
+        let mut range = part.splitn(2, '-');
+        let first: usize = range.next()?.trim().parse().ok()?;
+        let last = match range.next() {
+            Some(last) => last.trim().parse().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
@@ -0,0 +1,154 @@
+//! Zones of local entries which uind is authoritative for, like `home.arpa`:
+//! questions under them are answered from the local table alone, and never
+//! go upstream.
+
+use std::iter;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::message::*;
+use crate::server::EntryTable;
+
+/// Answer a query for a name in one of `zones`, which have SOA records in
+/// the table.  Names without entries don't exist, unless there are names
+/// under them.
+pub fn answer(
+    table: &EntryTable,
+    zones: &[DomainName],
+    message: &DnsMessage,
+) -> Option<DnsMessage> {
+    let question = message.question.first()?;
+    if message.header.opcode != DnsOpcode::Query
+        || message.question.len() != 1
+        || question.qclass != DnsClass::Internet
+        || question.qtype == DnsType::AXFR
+    {
+        return None;
+    }
+    let zone = zones
+        .iter()
+        .filter(|zone| question.qname.ends_with(zone))
+        .max_by_key(|zone| zone.len())?;
+
+    let mut response = DnsMessage::response_to(message)
+        .with_authoritative(true)
+        .with_recur_available(true);
+    match table.get(&question.qname) {
+        Some(rrs) => {
+            let wanted = |rr: &DnsResourceRecord| {
+                question.qtype == DnsType::Any || question.code() == rr.code()
+            };
+            response.answer = rrs.iter().filter(|rr| wanted(rr)).cloned().collect();
+            // An alias stands in for every other type
+            if response.answer.is_empty() {
+                response.answer = rrs
+                    .iter()
+                    .filter(|rr| rr.rtype == DnsType::CNAME)
+                    .cloned()
+                    .collect();
+            }
+        }
+        None if table.keys().any(|name| name.ends_with(&question.qname)) => {}
+        None => response.header.rcode = DnsRcode::NameError,
+    }
+    if response.answer.is_empty() {
+        let soa = table
+            .get(zone)
+            .and_then(|rrs| rrs.iter().find(|rr| rr.rtype == DnsType::SOA));
+        response.authority.extend(soa.cloned());
+    }
+    Some(response)
+}
+
+/// The SOA record of a zone made of local entries.  The serial starts at
+/// the current time, so that it still grows across restarts.
+pub fn soa(zone: &DomainName) -> DnsResourceRecord {
+    let rname: DomainName = iter::once("hostmaster".to_string())
+        .chain(zone.iter().cloned())
+        .collect();
+    DnsResourceRecord {
+        name: zone.clone(),
+        rtype: DnsType::SOA,
+        rclass: DnsClass::Internet,
+        ttl: 10,
+        data: DnsRRData::SOA(
+            zone.clone(),
+            rname,
+            unix_time() as u32,
+            3600,
+            600,
+            86400,
+            10,
+        ),
+    }
+}
+
+/// Give the zone's SOA record, if it has one, a new serial after a change.
+pub fn bump_serial(table: &mut EntryTable, zone: &DomainName) {
+    let rrs = table.get_mut(zone).into_iter().flatten();
+    for rr in rrs {
+        if let DnsRRData::SOA(_, _, ref mut serial, _, _, _, _) = rr.data {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_answer() {
+        let zone: DomainName = "home.arpa".into();
+        let mut table = EntryTable::new();
+        table.insert(zone.clone(), vec![soa(&zone)]);
+        table.insert(
+            "nas.lab.home.arpa".into(),
+            vec![DnsResourceRecord {
+                name: "nas.lab.home.arpa".into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(192, 168, 1, 2)),
+            }],
+        );
+        let zones = vec![zone];
+        let ask = |name: &str, qtype| {
+            answer(&table, &zones, &DnsMessage::query(name.into(), qtype)).map(|response| {
+                let header = &response.header;
+                assert!(header.authoritative);
+                (
+                    header.rcode,
+                    response.answer.len(),
+                    response.authority.len(),
+                )
+            })
+        };
+
+        assert_eq!(
+            ask("NAS.lab.home.arpa", DnsType::A),
+            Some((DnsRcode::NoErrorCondition, 1, 0))
+        );
+        assert_eq!(
+            ask("nas.lab.home.arpa", DnsType::AAAA),
+            Some((DnsRcode::NoErrorCondition, 0, 1))
+        );
+        // An empty non-terminal exists
+        assert_eq!(
+            ask("lab.home.arpa", DnsType::A),
+            Some((DnsRcode::NoErrorCondition, 0, 1))
+        );
+        assert_eq!(
+            ask("tv.home.arpa", DnsType::A),
+            Some((DnsRcode::NameError, 0, 1))
+        );
+        assert_eq!(ask("example.com", DnsType::A), None);
+    }
+}
@@ -0,0 +1,235 @@
+//! Blocklists: names which are refused, along with the names under them.
+//!
+//! Lists come in the hosts(5) format (`0.0.0.0 ads.example`) or as one name
+//! per line, and run to a million entries.  A name is looked up by each of
+//! its suffixes in a hash set, so a lookup costs a few hash probes however
+//! long the lists are.
+//!
+//! An allowlist of names (`host.example`) and wildcards (`*.example`, for
+//! the names under `example`) overrides the lists, so that names they catch
+//! by mistake can be unblocked without editing them.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::BufRead;
+use std::mem::size_of;
+use std::net::IpAddr;
+
+use crate::message::DomainName;
+
+/// Names hosts files map to loopback addresses, which must not be blocked.
+const HOSTS_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+#[derive(Clone, Default)]
+pub struct Blocklist {
+    /// Lowercase, in dot notation without the trailing dot.
+    names: HashSet<Box<str>>,
+    /// Allowed names, and the names under which all are allowed.
+    allowed: HashSet<Box<str>>,
+    allowed_under: HashSet<Box<str>>,
+    bytes: usize,
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist::default()
+    }
+
+    /// Block `name` and the names under it.
+    pub fn insert(&mut self, name: &str) {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if name.is_empty() {
+            return;
+        }
+        let size = size_of::<Box<str>>() + name.len();
+        if self.names.insert(name.into_boxed_str()) {
+            self.bytes += size;
+        }
+    }
+
+    /// Stop blocking `name`, returning whether it was blocked.  Names under
+    /// it stay blocked if they are themselves.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let removed = self.names.remove(name.as_str());
+        if removed {
+            self.bytes -= size_of::<Box<str>>() + name.len();
+        }
+        removed
+    }
+
+    /// The blocked names, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| &**name)
+    }
+
+    /// Allow `pattern`, a name or `*.` and a name to allow the names under
+    /// it, even if it is blocked.
+    pub fn allow(&mut self, pattern: &str) {
+        let (set, name) = if pattern.starts_with("*.") {
+            (&mut self.allowed_under, &pattern[2..])
+        } else {
+            (&mut self.allowed, pattern)
+        };
+        let name = name.trim_end_matches('.').to_lowercase();
+        if name.is_empty() {
+            return;
+        }
+        let size = size_of::<Box<str>>() + name.len();
+        if set.insert(name.into_boxed_str()) {
+            self.bytes += size;
+        }
+    }
+
+    /// Add the patterns of an allowlist, one per line with `#` comments,
+    /// returning how many there were.
+    pub fn read_allowed<R: BufRead>(&mut self, reader: R) -> Result<usize, String> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line.map_err(|e| format!("Error reading line {}", e))?;
+            let line = line.split('#').next().unwrap().trim();
+            if !line.is_empty() {
+                self.allow(line);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Add the names of a list, returning how many lines named one.  Lines
+    /// are either `ADDRESS NAME [NAME...]` or `NAME`, with `#` comments;
+    /// others are skipped with a warning.
+    pub fn read<R: BufRead>(&mut self, reader: R) -> Result<usize, String> {
+        let mut count = 0;
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("Error reading line {}", e))?;
+            let line = line.split('#').next().unwrap();
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let names = match parts.first() {
+                None => continue,
+                Some(addr) if addr.parse::<IpAddr>().is_ok() => &parts[1..],
+                Some(_) if parts.len() == 1 => &parts[..],
+                Some(_) => {
+                    warn!("Blocklist line {} is malformed, ignoring", lineno + 1);
+                    continue;
+                }
+            };
+            for name in names {
+                if !HOSTS_NAMES.contains(&name.to_lowercase().as_str()) {
+                    self.insert(name);
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Whether `name` or a name it is under is blocked, and `name` is not
+    /// allowed.
+    pub fn contains(&self, name: &DomainName) -> bool {
+        if self.names.is_empty() || name.is_empty() {
+            return false;
+        }
+        let name = name.to_string().to_lowercase();
+        let name = name.trim_end_matches('.');
+        let blocked = suffixes(name).any(|suffix| self.names.contains(suffix));
+        blocked
+            && !self.allowed.contains(name)
+            && !suffixes(name)
+                .skip(1)
+                .any(|suffix| self.allowed_under.contains(suffix))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The approximate memory the names take.
+    pub fn size(&self) -> usize {
+        let capacity =
+            self.names.capacity() + self.allowed.capacity() + self.allowed_under.capacity();
+        self.bytes + capacity * size_of::<u64>()
+    }
+}
+
+/// `name`, then the names it is under, e.g. `a.example` and `example`.
+fn suffixes(name: &str) -> impl Iterator<Item = &str> {
+    let dots = name.match_indices('.').map(|(dot, _)| dot + 1);
+    Some(0)
+        .into_iter()
+        .chain(dots)
+        .map(move |start| &name[start..])
+}
+
+// Lists are too long to print
+impl fmt::Debug for Blocklist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Blocklist({} names)", self.names.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist() {
+        let list = "\
+# A hosts file
+127.0.0.1 localhost
+0.0.0.0 doubleclick.net ads.example  # trailing comment
+:: v6.example
+
+Tracker.example.
+not a line
+";
+        let mut blocklist = Blocklist::new();
+        assert_eq!(blocklist.read(list.as_bytes()), Ok(4));
+        assert_eq!(blocklist.len(), 4);
+
+        let blocked = |name: &str| blocklist.contains(&name.into());
+        assert!(blocked("doubleclick.net"));
+        assert!(blocked("stats.g.DoubleClick.net."));
+        assert!(blocked("tracker.example"));
+        assert!(blocked("v6.example"));
+        assert!(!blocked("localhost"));
+        assert!(!blocked("net"));
+        assert!(!blocked("notdoubleclick.net"));
+        assert!(!blocked("example"));
+        assert!(!blocked("."));
+
+        let allowlist = "\
+stats.g.doubleclick.net
+*.ads.example  # but not ads.example itself
+";
+        assert_eq!(blocklist.read_allowed(allowlist.as_bytes()), Ok(2));
+        let blocked = |name: &str| blocklist.contains(&name.into());
+        assert!(!blocked("Stats.G.doubleclick.net"));
+        assert!(blocked("x.stats.g.doubleclick.net"));
+        assert!(blocked("doubleclick.net"));
+        assert!(!blocked("cdn.ads.example"));
+        assert!(!blocked("a.b.ads.example"));
+        assert!(blocked("ads.example"));
+
+        assert!(blocklist.remove("DoubleClick.net."));
+        assert!(!blocklist.remove("doubleclick.net"));
+        assert!(!blocklist.contains(&"doubleclick.net".into()));
+        assert_eq!(blocklist.names().count(), 3);
+    }
+}
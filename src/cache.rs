@@ -0,0 +1,471 @@
+//! Caching upstream answers, so that a question asked again is answered
+//! without going upstream.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use linked_hash_map::LinkedHashMap;
+
+use crate::memory;
+use crate::message::*;
+use crate::server::decay;
+use crate::stats::Variant;
+
+/// The configuration whose upstream answered, and the name, type and class
+/// of the question.
+type QuestionKey = (Variant, DomainName, u16, u16);
+
+/// The subnet an answer tailored by upstream is for: the address, cleared
+/// past the scope prefix upstream returned, and that prefix.
+type Scope = (IpAddr, u8);
+
+/// The question, and the scope of a tailored answer.  Answers with no
+/// Client Subnet option, or a scope of 0, are for everyone.
+type CacheKey = (QuestionKey, Option<Scope>);
+
+/// How many tailored answers are kept for one question.  Past this, the
+/// oldest is dropped, so that a name answered differently for each subnet
+/// can't take over the cache.
+pub const MAX_SCOPES: usize = 16;
+
+struct Entry {
+    records: Vec<DnsResourceRecord>,
+    stored: Instant,
+    expires: Instant,
+    size: usize,
+}
+
+/// The entries, least recently used first, their approximate size, and the
+/// scopes of the tailored ones of each question, oldest first.
+#[derive(Default)]
+struct Entries {
+    map: LinkedHashMap<CacheKey, Entry>,
+    bytes: usize,
+    scopes: HashMap<QuestionKey, Vec<Scope>>,
+}
+
+impl Entries {
+    fn insert(&mut self, key: CacheKey, entry: Entry) {
+        if let Some(scope) = key.1 {
+            self.scopes.entry(key.0.clone()).or_default().push(scope);
+        }
+        self.bytes += entry.size;
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> bool {
+        match self.map.remove(key) {
+            Some(entry) => {
+                self.forget(key, entry.size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict the least recently used entry.
+    fn pop_front(&mut self) -> bool {
+        match self.map.pop_front() {
+            Some((key, entry)) => {
+                self.forget(&key, entry.size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forget(&mut self, key: &CacheKey, size: usize) {
+        self.bytes -= size;
+        if let Some(scope) = key.1 {
+            let empty = match self.scopes.get_mut(&key.0) {
+                Some(scopes) => {
+                    scopes.retain(|s| *s != scope);
+                    scopes.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.scopes.remove(&key.0);
+            }
+        }
+    }
+
+    /// The narrowest cached scope which `subnet` falls in.
+    fn scope(&self, question: &QuestionKey, subnet: &ClientSubnet) -> Option<Scope> {
+        let scopes = self.scopes.get(question)?;
+        scopes
+            .iter()
+            .filter(|&&(addr, prefix)| {
+                prefix <= subnet.source_prefix
+                    && ClientSubnet::new(subnet.addr, prefix).addr == addr
+            })
+            .max_by_key(|&&(_, prefix)| prefix)
+            .cloned()
+    }
+
+    /// The records of an entry which hasn't expired, with the time they
+    /// spent in the cache taken off their TTLs.
+    fn records(&mut self, key: &CacheKey) -> Option<Vec<DnsResourceRecord>> {
+        let expired = self.map.get_refresh(key)?.expires <= Instant::now();
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.map.get(key).map(|entry| {
+            let mut records = entry.records.clone();
+            decay(&mut records, entry.stored.elapsed());
+            records
+        })
+    }
+}
+
+/// Positive answers from upstream, each kept for the smallest TTL among its
+/// records.  Answers tailored to a Client Subnet are kept for the scope
+/// upstream gave them (RFC 7871 section 7.3), at most `MAX_SCOPES` per
+/// question.  Once it holds too many entries or bytes, the least recently
+/// used ones are evicted.
+pub struct ResponseCache {
+    max_entries: usize,
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+}
+
+impl ResponseCache {
+    /// A cache of at most `max_entries` answers and, unless it is 0,
+    /// approximately `max_bytes` of memory.
+    pub fn new(max_entries: usize, max_bytes: usize) -> ResponseCache {
+        ResponseCache {
+            max_entries,
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        }
+    }
+
+    /// The cached answer to the only question of `query`, with the time it
+    /// spent in the cache taken off its TTLs.  A query with a Client Subnet
+    /// gets the answer for the narrowest scope its subnet is in, if any, and
+    /// otherwise the one for everyone.
+    pub fn get(&self, variant: Variant, query: &DnsMessage) -> Option<Vec<DnsResourceRecord>> {
+        let question = key(variant, query)?;
+        let subnet = subnet_option(query).and_then(ClientSubnet::from_option);
+        let mut entries = self.entries.lock().unwrap();
+        let scope = subnet.and_then(|subnet| entries.scope(&question, &subnet));
+        let mut answer = None;
+        if let Some(scope) = scope {
+            answer = entries.records(&(question.clone(), Some(scope)));
+        }
+        if answer.is_none() {
+            answer = entries.records(&(question, None));
+        }
+        drop(entries);
+        let counter = if answer.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        answer
+    }
+
+    /// Cache the answer of a response to one question, unless it is
+    /// negative, truncated or not to be cached at all (TTL 0).  If upstream
+    /// tailored it to a Client Subnet, it is kept for the scope returned.
+    pub fn insert(&self, variant: Variant, response: &DnsMessage) {
+        let header = &response.header;
+        if header.rcode != DnsRcode::NoErrorCondition
+            || header.truncated
+            || response.answer.is_empty()
+        {
+            return;
+        }
+        let question = match key(variant, response) {
+            Some(key) => key,
+            None => return,
+        };
+        let scope = match subnet_option(response).map(ClientSubnet::from_option) {
+            Some(Some(subnet)) => scope(&subnet),
+            Some(None) => return,
+            None => None,
+        };
+        let ttl = response.answer.iter().map(|rr| rr.ttl).min().unwrap_or(0);
+        let size = size_of::<(CacheKey, Entry)>()
+            + memory::name_size(&question.1)
+            + memory::records_size(&response.answer);
+        if ttl == 0 || self.max_entries == 0 || (self.max_bytes != 0 && size > self.max_bytes) {
+            return;
+        }
+
+        let key = (question, scope);
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.remove(&key) && scope.is_some() {
+            let oldest = entries
+                .scopes
+                .get(&key.0)
+                .filter(|scopes| scopes.len() >= MAX_SCOPES)
+                .map(|scopes| scopes[0]);
+            if let Some(oldest) = oldest {
+                entries.remove(&(key.0.clone(), Some(oldest)));
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        while entries.map.len() >= self.max_entries
+            || (self.max_bytes != 0 && entries.bytes + size > self.max_bytes)
+        {
+            if entries.pop_front() {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let now = Instant::now();
+        entries.insert(
+            key,
+            Entry {
+                records: response.answer.clone(),
+                stored: now,
+                expires: now + Duration::from_secs(ttl.into()),
+                size,
+            },
+        );
+    }
+
+    /// Drop the answers for `name`, or all of them, returning how many
+    /// there were.
+    pub fn flush(&self, name: Option<&DomainName>) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<_> = entries
+            .map
+            .keys()
+            .filter(|key| match name {
+                Some(name) => (key.0).1 == *name,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        for key in &keys {
+            entries.remove(key);
+        }
+        keys.len()
+    }
+
+    /// How many answers are cached, including expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    /// Whether no answers are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The approximate memory the answers take.
+    pub fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+
+    /// How many lookups were answered from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How many lookups were not.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// How many answers were dropped to make room for others.
+    pub fn evictions(&self) -> usize {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+/// The Client Subnet option of a message, if it carries one.
+fn subnet_option(message: &DnsMessage) -> Option<&EdnsOption> {
+    let edns = message.edns.as_ref()?;
+    edns.options
+        .iter()
+        .find(|opt| opt.code == EDNS_CLIENT_SUBNET)
+}
+
+/// The scope of an answer for `subnet`, no longer than the source prefix
+/// it was asked for, or `None` if it is for everyone.
+fn scope(subnet: &ClientSubnet) -> Option<Scope> {
+    match subnet.scope_prefix.min(subnet.source_prefix) {
+        0 => None,
+        prefix => Some((ClientSubnet::new(subnet.addr, prefix).addr, prefix)),
+    }
+}
+
+fn key(variant: Variant, message: &DnsMessage) -> Option<QuestionKey> {
+    match message.question.as_slice() {
+        [q] if q.qtype != DnsType::AXFR => {
+            Some((variant, q.qname.clone(), q.code(), q.qclass as u16))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_cache() {
+        let cache = ResponseCache::new(10, 0);
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        let rr = |ttl| DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        assert_eq!(cache.get(Variant::Stable, &query), None);
+
+        // Negative and uncacheable answers are not kept
+        cache.insert(
+            Variant::Stable,
+            &DnsMessage::response_to(&query).with_rcode(DnsRcode::NameError),
+        );
+        cache.insert(
+            Variant::Stable,
+            &DnsMessage::response_to(&query).with_answer(rr(0)),
+        );
+        assert_eq!(cache.get(Variant::Stable, &query), None);
+
+        cache.insert(
+            Variant::Stable,
+            &DnsMessage::response_to(&query).with_answer(rr(300)),
+        );
+        let other_case = DnsMessage::query("EXAMPLE.com".into(), DnsType::A);
+        assert_eq!(cache.get(Variant::Stable, &other_case), Some(vec![rr(300)]));
+        assert_eq!(cache.get(Variant::Canary, &query), None);
+        let other_type = DnsMessage::query("example.com".into(), DnsType::AAAA);
+        assert_eq!(cache.get(Variant::Stable, &other_type), None);
+        assert_eq!((cache.hits(), cache.misses()), (1, 4));
+    }
+
+    #[test]
+    fn test_cache_scopes() {
+        let response = |addr: &str, scope, last| {
+            let query = DnsMessage::query("example.com".into(), DnsType::A);
+            let mut subnet = ClientSubnet::new(addr.parse().unwrap(), 24);
+            subnet.scope_prefix = scope;
+            let mut response = DnsMessage::response_to(&query).with_answer(DnsResourceRecord {
+                name: "example.com".into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 300,
+                data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, last)),
+            });
+            response.edns = Some(Edns {
+                options: vec![subnet.to_option()],
+                ..Default::default()
+            });
+            response
+        };
+        let cached = |cache: &ResponseCache, addr, prefix| {
+            let mut query = response(addr, 0, 0);
+            let subnet = ClientSubnet::new(addr.parse().unwrap(), prefix);
+            query.edns.as_mut().unwrap().options = vec![subnet.to_option()];
+            cache
+                .get(Variant::Stable, &query)
+                .map(|answer| match answer[0].data {
+                    DnsRRData::A(addr) => addr.octets()[3],
+                    _ => unreachable!(),
+                })
+        };
+
+        // The narrowest scope wins, and one longer than the source prefix
+        // is cut down to it
+        let cache = ResponseCache::new(100, 0);
+        cache.insert(Variant::Stable, &response("10.1.2.0", 8, 1));
+        cache.insert(Variant::Stable, &response("10.1.2.0", 32, 2));
+        assert_eq!(cached(&cache, "10.9.9.9", 24), Some(1));
+        assert_eq!(cached(&cache, "10.1.2.9", 24), Some(2));
+        assert_eq!(cached(&cache, "10.1.2.9", 16), Some(1));
+        assert_eq!(cached(&cache, "11.1.2.9", 24), None);
+
+        // A scope of 0 is for everyone
+        cache.insert(Variant::Stable, &response("10.1.2.0", 0, 3));
+        assert_eq!(cached(&cache, "11.1.2.9", 24), Some(3));
+        assert_eq!(cache.len(), 3);
+
+        // Past MAX_SCOPES, the oldest tailored answer goes
+        for i in 0..MAX_SCOPES {
+            cache.insert(Variant::Stable, &response(&format!("10.2.{}.0", i), 24, 4));
+        }
+        assert_eq!((cache.len(), cache.evictions()), (MAX_SCOPES + 1, 2));
+        assert_eq!(cached(&cache, "10.1.2.9", 24), Some(3));
+        assert_eq!(cached(&cache, "10.2.0.9", 24), Some(4));
+        assert_eq!(cache.flush(Some(&"example.com".into())), MAX_SCOPES + 1);
+        assert_eq!((cache.len(), cache.bytes()), (0, 0));
+    }
+
+    #[test]
+    fn test_decay() {
+        let rr = |ttl| DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let mut records = vec![rr(300), rr(60), rr(10)];
+        decay(&mut records, Duration::from_millis(30500));
+        assert_eq!(records, [rr(270), rr(30), rr(0)]);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let response = |name: &str| {
+            let query = DnsMessage::query(name.into(), DnsType::A);
+            DnsMessage::response_to(&query).with_answer(DnsResourceRecord {
+                name: name.into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 300,
+                data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            })
+        };
+        let cached =
+            |cache: &ResponseCache, name| cache.get(Variant::Stable, &response(name)).is_some();
+
+        // The least recently used answer goes first
+        let cache = ResponseCache::new(2, 0);
+        cache.insert(Variant::Stable, &response("a.example"));
+        cache.insert(Variant::Stable, &response("b.example"));
+        assert!(cached(&cache, "a.example"));
+        cache.insert(Variant::Stable, &response("c.example"));
+        assert!(cached(&cache, "a.example"));
+        assert!(!cached(&cache, "b.example"));
+        assert!(cached(&cache, "c.example"));
+        assert_eq!((cache.len(), cache.evictions()), (2, 1));
+
+        // Replacing an answer doesn't count it twice
+        let one = cache.bytes() / 2;
+        cache.insert(Variant::Stable, &response("c.example"));
+        assert_eq!(cache.bytes(), 2 * one);
+
+        let cache = ResponseCache::new(10, 2 * one);
+        for name in &["a.example", "b.example", "c.example"] {
+            cache.insert(Variant::Stable, &response(name));
+        }
+        assert_eq!((cache.len(), cache.bytes()), (2, 2 * one));
+        assert!(!cached(&cache, "a.example"));
+
+        assert_eq!(cache.flush(Some(&"B.example".into())), 1);
+        assert_eq!((cache.len(), cache.bytes()), (1, one));
+        assert_eq!(cache.flush(None), 1);
+        assert_eq!((cache.len(), cache.bytes()), (0, 0));
+    }
+}
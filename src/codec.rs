@@ -1,60 +1,220 @@
 use bytes::{BufMut, BytesMut};
+use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio::codec::{Decoder, Encoder};
 
 use crate::message::{DnsClass, DnsOpcode, DnsRRData, DnsRcode, DnsType};
 use crate::message::{DnsHeader, DnsMessage, DnsQuestion, DnsResourceRecord};
+use crate::message::{DomainName, Edns, EdnsOption, Tsig, EDNS_PADDING};
 
-macro_rules! or_continue {
-    ( $x:expr ) => {
-        match $x {
-            Ok(v) => v,
-            Err(e) => {
-                error!("{}", e);
-                continue;
+/// What is wrong with a message that can't be decoded or encoded.  The
+/// framed traits need an `io::Error`, which carries one of these; get it
+/// back with `e.get_ref().and_then(|e| e.downcast_ref::<DnsCodecError>())`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsCodecError {
+    /// The message, or an RR's rdata, ends too early.
+    Truncated,
+    UnknownOpcode(u8),
+    UnknownType(u16),
+    UnknownClass(u16),
+    /// A label type other than a length or a pointer.
+    UnknownLabelType(u8),
+    /// A compression pointer which does not point backwards.
+    BadPointer(usize),
+    /// A name longer than 255 bytes.
+    NameTooLong,
+    /// The rdata of an RR of this type doesn't make sense.
+    MalformedRdata(u16),
+    /// The rdata of an RR of this type is shorter than its length says.
+    RdataLength(u16),
+    /// A second OPT record.
+    DuplicateOpt,
+    /// Bytes after the last record, in strict mode.
+    TrailingData(usize),
+    /// A message of more bytes than the limits allow.
+    TooLong(usize),
+    /// A section of more records than the limits allow.
+    TooManyRecords(u16),
+    /// A name of more labels than the limits allow.
+    TooManyLabels,
+    /// A name with more compression pointers than the limits allow.
+    TooManyJumps,
+    /// A name which can't be put on the wire.
+    Name(NameError),
+    /// A character-string of more than 255 bytes, which can't be put on
+    /// the wire.
+    StringTooLong(usize),
+    /// A CAA record with an empty tag.
+    EmptyTag,
+}
+
+impl DnsCodecError {
+    /// Reading past the end of the message is `UnexpectedEof`, a bad name
+    /// to encode is `InvalidInput`, and other malformed input `InvalidData`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DnsCodecError::Truncated => ErrorKind::UnexpectedEof,
+            DnsCodecError::Name(_) | DnsCodecError::StringTooLong(_) | DnsCodecError::EmptyTag => {
+                ErrorKind::InvalidInput
+            }
+            _ => ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for DnsCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsCodecError::Truncated => write!(f, "message truncated"),
+            DnsCodecError::UnknownOpcode(x) => write!(f, "opcode {} not recognized", x),
+            DnsCodecError::UnknownType(x) => write!(f, "unknown type {}", x),
+            DnsCodecError::UnknownClass(x) => write!(f, "unknown class {}", x),
+            DnsCodecError::UnknownLabelType(x) => write!(f, "unknown label type {:#x}", x),
+            DnsCodecError::BadPointer(x) => {
+                write!(f, "compression pointer to {} does not point backwards", x)
+            }
+            DnsCodecError::NameTooLong => write!(f, "name longer than 255 bytes"),
+            DnsCodecError::MalformedRdata(x) => write!(f, "malformed rdata of type {}", x),
+            DnsCodecError::RdataLength(x) => {
+                write!(f, "rdata of type {} doesn't fill its length", x)
             }
+            DnsCodecError::DuplicateOpt => write!(f, "duplicate OPT"),
+            DnsCodecError::TrailingData(n) => write!(f, "{} bytes after the last record", n),
+            DnsCodecError::TooLong(n) => write!(f, "message of {} bytes", n),
+            DnsCodecError::TooManyRecords(n) => write!(f, "section of {} records", n),
+            DnsCodecError::TooManyLabels => write!(f, "name with too many labels"),
+            DnsCodecError::TooManyJumps => write!(f, "name with too many compression pointers"),
+            DnsCodecError::Name(e) => write!(f, "can't encode {}", e),
+            DnsCodecError::StringTooLong(n) => write!(f, "character-string of {} bytes", n),
+            DnsCodecError::EmptyTag => write!(f, "CAA record with an empty tag"),
+        }
+    }
+}
+
+impl std::error::Error for DnsCodecError {}
+
+impl From<DnsCodecError> for Error {
+    fn from(e: DnsCodecError) -> Error {
+        Error::new(e.kind(), e)
+    }
+}
+
+impl From<NameError> for DnsCodecError {
+    fn from(e: NameError) -> DnsCodecError {
+        DnsCodecError::Name(e)
+    }
+}
+
+fn truncated() -> DnsCodecError {
+    DnsCodecError::Truncated
+}
+
+/// Caps on what the decoder takes from a message, so that a hostile one
+/// can't make it allocate much or spin.  Messages over a cap are rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodeLimits {
+    /// Bytes in a message (default: 65535, what TCP can carry).
+    pub max_size: usize,
+    /// Records in each section (default: 4096).
+    pub max_records: u16,
+    /// Labels in a name (default: 127, what fits in 255 bytes).
+    pub max_labels: usize,
+    /// Compression pointers followed while reading a name (default: 32).
+    pub max_jumps: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_size: 65535,
+            max_records: 4096,
+            max_labels: 127,
+            max_jumps: 32,
         }
-    };
+    }
 }
 
 #[derive(Clone, Default, Debug)]
 pub struct DnsMessageCodec {
     tcp: bool,
-    len: Option<usize>, // only for tcp
     offset: usize,
+    padding: Option<usize>,
+    strict: bool,
+    limits: DecodeLimits,
 }
 
 impl DnsMessageCodec {
     pub fn new(tcp: bool) -> DnsMessageCodec {
         DnsMessageCodec {
             tcp,
-            len: None,
             offset: 0,
+            padding: None,
+            strict: false,
+            limits: DecodeLimits::default(),
         }
     }
+
+    /// Pad encoded EDNS messages to a multiple of `block_size` bytes with
+    /// the Padding option (RFC 7830), replacing any they carry.  RFC 8467
+    /// recommends 128 for queries and 468 for responses.  UDP messages are
+    /// only padded as far as the payload size allows.
+    pub fn padding(mut self, block_size: usize) -> DnsMessageCodec {
+        self.padding = Some(block_size).filter(|&n| n > 0);
+        self
+    }
+
+    /// Reject messages with anything the lenient default skips: records
+    /// which can't be parsed (so that the section counts don't match),
+    /// rdata shorter than its RDLENGTH, a second OPT, and trailing bytes.
+    pub fn strict(mut self, strict: bool) -> DnsMessageCodec {
+        self.strict = strict;
+        self
+    }
+
+    pub fn limits(mut self, limits: DecodeLimits) -> DnsMessageCodec {
+        self.limits = limits;
+        self
+    }
 }
 
 impl Decoder for DnsMessageCodec {
     type Item = DnsMessage;
     type Error = Error;
 
+    /// Over TCP, `Ok(None)` means the message is incomplete.  Over UDP, each
+    /// buffer is a whole datagram, so a short one is an error.
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 12 {
-            return Ok(None);
-        }
-
-        if self.tcp && self.len.is_none() {
+        let len = if self.tcp {
+            if src.len() < 2 {
+                return Ok(None);
+            }
             let len = (src[0] as usize) << 8 | src[1] as usize;
-            self.len = Some(len);
             debug!("TCP mode DNS length = {}", len);
-        }
-        if let Some(len) = self.len {
             if src.len() < 2 + len {
                 return Ok(None);
-            } else {
-                src.split_to(2);
             }
+            src.split_to(2);
+            len
+        } else {
+            src.len()
+        };
+
+        let message = src.split_to(len);
+        self.offset = 0;
+        let result = self.next_message(&message);
+        self.offset = 0;
+        result.map(Some).map_err(Error::from)
+    }
+}
+
+impl DnsMessageCodec {
+    fn next_message(&mut self, src: &[u8]) -> Result<DnsMessage, DnsCodecError> {
+        if src.len() < 12 {
+            return Err(truncated());
+        }
+        if src.len() > self.limits.max_size {
+            return Err(DnsCodecError::TooLong(src.len()));
         }
 
         let id = ((src[self.offset] as u16) << 8) | (src[self.offset + 1] as u16);
@@ -65,52 +225,52 @@ impl Decoder for DnsMessageCodec {
         let rd = src[self.offset + 2] & 1;
         let ra = (src[self.offset + 3] >> 7) & 1;
         let _z = (src[self.offset + 3] >> 4) & 0x7;
-        let rcode = src[self.offset + 3] & 0xf;
+        let mut rcode = (src[self.offset + 3] & 0xf) as u16;
         let qdcount = ((src[self.offset + 4] as u16) << 8) + (src[self.offset + 5] as u16);
         let ancount = ((src[self.offset + 6] as u16) << 8) + (src[self.offset + 7] as u16);
         let nscount = ((src[self.offset + 8] as u16) << 8) + (src[self.offset + 9] as u16);
         let arcount = ((src[self.offset + 10] as u16) << 8) + (src[self.offset + 11] as u16);
+        for &count in &[qdcount, ancount, nscount, arcount] {
+            if count > self.limits.max_records {
+                return Err(DnsCodecError::TooManyRecords(count));
+            }
+        }
 
-        let header = DnsHeader {
+        let mut header = DnsHeader {
             id,
             query: qr == 0,
             opcode: match DnsOpcode::try_from(opcode) {
                 Some(opcode) => opcode,
-                None => {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        format!("opcode {} not recognized", opcode),
-                    ));
-                }
+                None => return Err(DnsCodecError::UnknownOpcode(opcode)),
             },
             authoritative: aa == 1,
             truncated: tc == 1,
             recur_desired: rd == 1,
             recur_available: ra == 1,
-            rcode: match DnsRcode::try_from(rcode) {
-                Some(rcode) => rcode,
-                None => {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        format!("response code {} not recognized", rcode),
-                    ));
-                }
-            },
+            rcode: DnsRcode::NoErrorCondition, // Known once the OPT is parsed
         };
-
         self.offset += 12;
 
         debug!("Parse qdcount={}", qdcount);
         let mut question = Vec::new();
         for _ in 0..qdcount {
-            let qname = or_continue!(self.next_name(src));
-            let qtype = or_continue!(self.next_type(src));
-            let qclass = or_continue!(self.next_class(src));
-            question.push(DnsQuestion {
-                qname,
-                qtype,
-                qclass,
-            });
+            let qname = self.next_name(src)?;
+            let qtype = self.next_type(src);
+            let qclass = self.next_class(src);
+            match (qtype, qclass) {
+                (Ok((qtype, qcode)), Ok(qclass)) => question.push(DnsQuestion {
+                    qname,
+                    qtype,
+                    qcode,
+                    qclass,
+                }),
+                (Err(e), _) | (_, Err(e)) => {
+                    if self.strict || e == DnsCodecError::Truncated {
+                        return Err(e);
+                    }
+                    error!("{}", e);
+                }
+            }
         }
 
         debug!("Parse ancount={}", ancount);
@@ -118,6 +278,7 @@ impl Decoder for DnsMessageCodec {
         for _ in 0..ancount {
             match self.next_rr(src) {
                 Ok(rr) => answer.push(rr),
+                Err(e) if self.strict || e == DnsCodecError::Truncated => return Err(e),
                 Err(e) => error!("error parsing answer {}", e),
             }
         }
@@ -127,248 +288,473 @@ impl Decoder for DnsMessageCodec {
         for _ in 0..nscount {
             match self.next_rr(src) {
                 Ok(rr) => authority.push(rr),
+                Err(e) if self.strict || e == DnsCodecError::Truncated => return Err(e),
                 Err(e) => error!("error parsing authority {}", e),
             }
         }
 
         debug!("Parse arcount={}", arcount);
         let mut additional = Vec::new();
-        for _ in 0..arcount {
+        let mut edns = None;
+        let mut tsig = None;
+        for i in 0..arcount {
+            // OPT is always owned by the root, so its type follows a zero byte
+            let is_opt = src.get(self.offset..self.offset + 3) == Some(&[0, 0, DnsType::OPT as u8]);
+            if is_opt {
+                let (extended_rcode, opt) = self.next_opt(src)?;
+                if edns.is_some() {
+                    if self.strict {
+                        return Err(DnsCodecError::DuplicateOpt);
+                    }
+                    error!("error parsing additional: duplicate OPT");
+                } else {
+                    rcode |= (extended_rcode as u16) << 4;
+                    edns = Some(opt);
+                }
+                continue;
+            }
+            let start = self.offset;
             match self.next_rr(src) {
+                Ok(ref rr) if rr.rtype == DnsType::TSIG && i == arcount - 1 => {
+                    match self.tsig(rr, &src[..start]) {
+                        Ok(t) => tsig = Some(t),
+                        Err(e) if self.strict => return Err(e),
+                        Err(e) => error!("error parsing additional: {}", e),
+                    }
+                }
                 Ok(rr) => additional.push(rr),
+                Err(e) if self.strict || e == DnsCodecError::Truncated => return Err(e),
                 Err(e) => error!("error parsing additional: {}", e),
             }
         }
 
-        src.split_to(self.offset);
-        self.offset = 0;
-        self.len = None;
+        if self.strict && self.offset < src.len() {
+            return Err(DnsCodecError::TrailingData(src.len() - self.offset));
+        }
 
-        Ok(Some(DnsMessage {
+        header.rcode = match DnsRcode::try_from(rcode) {
+            Some(rcode) => rcode,
+            None => {
+                warn!("Response code {} not recognized, taken as SERVFAIL", rcode);
+                DnsRcode::ServerFailure
+            }
+        };
+
+        Ok(DnsMessage {
             header,
             question,
             answer,
             authority,
             additional,
-        }))
+            edns,
+            tsig,
+        })
     }
-}
 
-impl DnsMessageCodec {
-    /// This function will skip this RR when error occurs.
-    fn next_rr(
-        &mut self,
-        src: &mut BytesMut,
-    ) -> Result<DnsResourceRecord, <Self as Decoder>::Error> {
+    /// Parse the rdata of a TSIG record, given the message it signs.
+    fn tsig(&self, rr: &DnsResourceRecord, signed: &[u8]) -> Result<Tsig, DnsCodecError> {
+        let data = match rr.data {
+            DnsRRData::Unknown(_, ref data) => data,
+            _ => unreachable!(),
+        };
+        let mut codec = DnsMessageCodec::new(false);
+        let algorithm = codec.next_name(data)?;
+        let time_signed = (codec.next_u16(data)? as u64) << 32 | codec.next_u32(data)? as u64;
+        let fudge = codec.next_u16(data)?;
+        let len = codec.next_u16(data)? as usize;
+        let mac = codec.next_bytes(data, len)?.to_vec();
+        let original_id = codec.next_u16(data)?;
+        let error = codec.next_u16(data)?;
+        let len = codec.next_u16(data)? as usize;
+        let other = codec.next_bytes(data, len)?.to_vec();
+
+        let mut signed = signed.to_vec();
+        signed[0] = (original_id >> 8) as u8;
+        signed[1] = original_id as u8;
+        let arcount = ((signed[10] as u16) << 8 | signed[11] as u16) - 1;
+        signed[10] = (arcount >> 8) as u8;
+        signed[11] = arcount as u8;
+        Ok(Tsig {
+            key: rr.name.clone(),
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+            signed,
+        })
+    }
+
+    fn next_u8(&mut self, src: &[u8]) -> Result<u8, DnsCodecError> {
+        let x = *src.get(self.offset).ok_or_else(truncated)?;
+        self.offset += 1;
+        Ok(x)
+    }
+
+    fn next_u16(&mut self, src: &[u8]) -> Result<u16, DnsCodecError> {
+        Ok((self.next_u8(src)? as u16) << 8 | self.next_u8(src)? as u16)
+    }
+
+    fn next_u32(&mut self, src: &[u8]) -> Result<u32, DnsCodecError> {
+        Ok((self.next_u16(src)? as u32) << 16 | self.next_u16(src)? as u32)
+    }
+
+    fn next_bytes<'a>(&mut self, src: &'a [u8], len: usize) -> Result<&'a [u8], DnsCodecError> {
+        let bytes = src
+            .get(self.offset..self.offset + len)
+            .ok_or_else(truncated)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    /// The rest of `src`, which is cut at the end of the rdata.
+    fn rest(&mut self, src: &[u8]) -> Vec<u8> {
+        let rest = src.get(self.offset..).unwrap_or(&[]).to_vec();
+        self.offset = self.offset.max(src.len());
+        rest
+    }
+
+    /// Errors in the rdata only cost this RR, which is skipped.  Running
+    /// out of message before the rdata is fatal.
+    fn next_rr(&mut self, src: &[u8]) -> Result<DnsResourceRecord, DnsCodecError> {
         let name = self.next_name(src)?;
 
-        // Get rdlen before
-        let rdlen = (src[self.offset + 8] as u16) << 8 | src[self.offset + 9] as u16;
-        let final_pos = self.offset + 10 + rdlen as usize;
+        // Types without a DnsType are passed through as they are (RFC 3597)
+        let type_code = self.next_u16(src)?;
+        let class_code = self.next_u16(src)?;
+        let ttl = self.next_u32(src)?;
+        let rdlen = self.next_u16(src)?;
+        let final_pos = self.offset + rdlen as usize;
         debug!("RDLEN = {}, Final Pos = {}", rdlen, final_pos);
+        if final_pos > src.len() {
+            return Err(truncated());
+        }
 
-        // Make sure the final position is correct!
-        let rtype = match self.next_type(src) {
-            Ok(ty) => ty,
-            Err(e) => {
+        let rtype = DnsType::try_from(type_code).unwrap_or(DnsType::Unknown);
+        let rclass = match DnsClass::try_from(class_code) {
+            Some(cls) => cls,
+            None => {
                 self.offset = final_pos;
-                return Err(e);
+                return Err(DnsCodecError::UnknownClass(class_code));
             }
         };
 
-        let rclass = match self.next_class(src) {
-            Ok(cls) => cls,
-            Err(e) => {
-                self.offset = final_pos;
-                return Err(e);
-            }
+        // Only updates have empty rdata, e.g. to delete an RRset (RFC 2136)
+        let data = if rdlen == 0 {
+            Ok(DnsRRData::Unknown(type_code, vec![]))
+        } else {
+            self.next_rdata(&src[..final_pos], rclass, rtype, type_code)
         };
+        let end = self.offset;
+        self.offset = final_pos;
+        let data = data.map_err(|e| {
+            debug!("Error in rdata: {}", e);
+            DnsCodecError::MalformedRdata(type_code)
+        })?;
+        if end != final_pos {
+            return Err(DnsCodecError::RdataLength(type_code));
+        }
 
-        let ttl = ((src[self.offset] as u32) << 24)
-            | ((src[self.offset + 1] as u32) << 16)
-            | ((src[self.offset + 2] as u32) << 8)
-            | (src[self.offset + 3] as u32);
-        self.offset += 4;
-        self.offset += 2; // Skip rdlen
+        Ok(DnsResourceRecord {
+            name,
+            rtype,
+            rclass,
+            ttl,
+            data,
+        })
+    }
 
+    /// `src` ends with the rdata.
+    fn next_rdata(
+        &mut self,
+        src: &[u8],
+        rclass: DnsClass,
+        rtype: DnsType,
+        type_code: u16,
+    ) -> Result<DnsRRData, DnsCodecError> {
         let data = match (rclass, rtype) {
             (DnsClass::Internet, DnsType::A) => {
-                let res = DnsRRData::A(Ipv4Addr::new(
-                    src[self.offset],
-                    src[self.offset + 1],
-                    src[self.offset + 2],
-                    src[self.offset + 3],
-                ));
-                self.offset += rdlen as usize;
-                res
+                let b = self.next_bytes(src, 4)?;
+                DnsRRData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
             }
             (DnsClass::Internet, DnsType::AAAA) => {
-                let res = DnsRRData::AAAA(Ipv6Addr::new(
-                    ((src[self.offset + 0] as u16) << 8) | (src[self.offset + 1] as u16),
-                    ((src[self.offset + 2] as u16) << 8) | (src[self.offset + 3] as u16),
-                    ((src[self.offset + 4] as u16) << 8) | (src[self.offset + 5] as u16),
-                    ((src[self.offset + 6] as u16) << 8) | (src[self.offset + 7] as u16),
-                    ((src[self.offset + 8] as u16) << 8) | (src[self.offset + 9] as u16),
-                    ((src[self.offset + 10] as u16) << 8) | (src[self.offset + 11] as u16),
-                    ((src[self.offset + 12] as u16) << 8) | (src[self.offset + 13] as u16),
-                    ((src[self.offset + 14] as u16) << 8) | (src[self.offset + 15] as u16),
-                ));
-                self.offset += rdlen as usize;
-                res
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.next_bytes(src, 16)?);
+                DnsRRData::AAAA(Ipv6Addr::from(octets))
             }
             (DnsClass::Internet, DnsType::MX) => {
-                let preference = (src[self.offset + 0] as u16) << 8 | (src[self.offset + 1] as u16);
-                self.offset += 2;
+                let preference = self.next_u16(src)?;
                 DnsRRData::MX(preference, self.next_name(src)?)
             }
             (DnsClass::Internet, DnsType::CNAME) => DnsRRData::CNAME(self.next_name(src)?),
             (DnsClass::Internet, DnsType::TXT) => {
                 debug!("TXT began at offset={}", self.offset);
                 let mut txt = vec![];
-                while self.offset != final_pos {
-                    let len = src[self.offset] as usize;
-                    txt.push(
-                        String::from_utf8_lossy(&src[self.offset + 1..self.offset + len as usize])
-                            .to_string(),
-                    );
-                    self.offset += 1 + len;
+                while self.offset < src.len() {
+                    txt.push(self.next_string(src)?);
                 }
-                let res = DnsRRData::TXT(txt);
-                res
+                DnsRRData::TXT(txt)
             }
             (DnsClass::Internet, DnsType::SOA) => {
-                let (mname, rname, serial, refresh, retry, expire, minimum);
-                mname = self.next_name(src)?;
-                rname = self.next_name(src)?;
-                serial = (src[self.offset] as u32) << 24
-                    | (src[self.offset + 1] as u32) << 16
-                    | (src[self.offset + 2] as u32) << 8
-                    | (src[self.offset + 3] as u32);
-                self.offset += 4;
-                refresh = (src[self.offset] as u32) << 24
-                    | (src[self.offset + 1] as u32) << 16
-                    | (src[self.offset + 2] as u32) << 8
-                    | (src[self.offset + 3] as u32);
-                self.offset += 4;
-                retry = (src[self.offset] as u32) << 24
-                    | (src[self.offset + 1] as u32) << 16
-                    | (src[self.offset + 2] as u32) << 8
-                    | (src[self.offset + 3] as u32);
-                self.offset += 4;
-                expire = (src[self.offset] as u32) << 24
-                    | (src[self.offset + 1] as u32) << 16
-                    | (src[self.offset + 2] as u32) << 8
-                    | (src[self.offset + 3] as u32);
-                self.offset += 4;
-                minimum = (src[self.offset] as u32) << 24
-                    | (src[self.offset + 1] as u32) << 16
-                    | (src[self.offset + 2] as u32) << 8
-                    | (src[self.offset + 3] as u32);
-                self.offset += 4;
+                let mname = self.next_name(src)?;
+                let rname = self.next_name(src)?;
+                let serial = self.next_u32(src)?;
+                let refresh = self.next_u32(src)?;
+                let retry = self.next_u32(src)?;
+                let expire = self.next_u32(src)?;
+                let minimum = self.next_u32(src)?;
                 DnsRRData::SOA(mname, rname, serial, refresh, retry, expire, minimum)
             }
-            (DnsClass::Internet, DnsType::NS) => {
-                let nsdname = self.next_name(src)?;
-                DnsRRData::NS(nsdname)
+            (DnsClass::Internet, DnsType::NS) => DnsRRData::NS(self.next_name(src)?),
+            (DnsClass::Internet, DnsType::PTR) => DnsRRData::PTR(self.next_name(src)?),
+            (DnsClass::Internet, DnsType::HINFO) => {
+                let cpu = self.next_string(src)?;
+                let os = self.next_string(src)?;
+                DnsRRData::HINFO(cpu, os)
+            }
+            (DnsClass::Internet, DnsType::CAA) => {
+                let flags = self.next_u8(src)?;
+                let tag = self.next_string(src)?;
+                if tag.is_empty() {
+                    return Err(DnsCodecError::EmptyTag);
+                }
+                DnsRRData::CAA(flags, tag, self.rest(src))
+            }
+            (DnsClass::Internet, DnsType::SRV) => {
+                let priority = self.next_u16(src)?;
+                let weight = self.next_u16(src)?;
+                let port = self.next_u16(src)?;
+                DnsRRData::SRV(priority, weight, port, self.next_name(src)?)
+            }
+            (DnsClass::Internet, DnsType::NAPTR) => {
+                let order = self.next_u16(src)?;
+                let preference = self.next_u16(src)?;
+                let flags = self.next_string(src)?;
+                let services = self.next_string(src)?;
+                let regexp = self.next_string(src)?;
+                let replacement = self.next_name(src)?;
+                DnsRRData::NAPTR(order, preference, flags, services, regexp, replacement)
+            }
+            (DnsClass::Internet, DnsType::DS) => {
+                let key_tag = self.next_u16(src)?;
+                let algorithm = self.next_u8(src)?;
+                let digest_type = self.next_u8(src)?;
+                DnsRRData::DS(key_tag, algorithm, digest_type, self.rest(src))
+            }
+            (DnsClass::Internet, DnsType::RRSIG) => {
+                let type_covered = self.next_u16(src)?;
+                let algorithm = self.next_u8(src)?;
+                let labels = self.next_u8(src)?;
+                let original_ttl = self.next_u32(src)?;
+                let expiration = self.next_u32(src)?;
+                let inception = self.next_u32(src)?;
+                let key_tag = self.next_u16(src)?;
+                let signer = self.next_name(src)?;
+                DnsRRData::RRSIG(
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    self.rest(src),
+                )
+            }
+            (DnsClass::Internet, DnsType::NSEC) => {
+                let next = self.next_name(src)?;
+                DnsRRData::NSEC(next, self.rest(src))
+            }
+            (DnsClass::Internet, DnsType::DNSKEY) => {
+                let flags = self.next_u16(src)?;
+                let protocol = self.next_u8(src)?;
+                let algorithm = self.next_u8(src)?;
+                DnsRRData::DNSKEY(flags, protocol, algorithm, self.rest(src))
+            }
+            (DnsClass::Internet, DnsType::NSEC3) => {
+                let hash_algorithm = self.next_u8(src)?;
+                let flags = self.next_u8(src)?;
+                let iterations = self.next_u16(src)?;
+                let salt_len = self.next_u8(src)? as usize;
+                let salt = self.next_bytes(src, salt_len)?.to_vec();
+                let hash_len = self.next_u8(src)? as usize;
+                let next = self.next_bytes(src, hash_len)?.to_vec();
+                DnsRRData::NSEC3(
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next,
+                    self.rest(src),
+                )
             }
             (_, _) => {
-                self.offset += rdlen as usize; // Skip this RR
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("unknown rdata {}", rtype as u16),
-                ));
+                debug!("Passing through rdata of type {}", type_code);
+                DnsRRData::Unknown(type_code, self.rest(src))
             }
         };
+        Ok(data)
+    }
 
-        Ok(DnsResourceRecord {
-            name,
-            rtype,
-            rclass,
-            ttl,
-            data,
-        })
+    /// A <character-string>: a length byte followed by that many bytes.
+    fn next_string(&mut self, src: &[u8]) -> Result<Vec<u8>, DnsCodecError> {
+        let len = self.next_u8(src)? as usize;
+        Ok(self.next_bytes(src, len)?.to_vec())
     }
 
-    fn next_name(&mut self, src: &mut BytesMut) -> Result<Vec<String>, <Self as Decoder>::Error> {
-        let mut name = Vec::new();
-        let mut label_len = src[self.offset];
-        self.offset += 1;
+    /// Parse an OPT record into the upper bits of the RCODE and the rest.
+    fn next_opt(&mut self, src: &[u8]) -> Result<(u8, Edns), DnsCodecError> {
+        self.offset += 3; // Skip the root name and type
+        let udp_size = self.next_u16(src)?;
+        let extended_rcode = self.next_u8(src)?;
+        let version = self.next_u8(src)?;
+        let flags = self.next_u16(src)?;
+        let rdlen = self.next_u16(src)?;
+        let final_pos = self.offset + rdlen as usize;
+        if final_pos > src.len() {
+            return Err(truncated());
+        }
 
-        while label_len != 0 && (label_len >> 6) & 0x3 != 0x3 {
-            debug!("Found label at offset {}", self.offset);
+        let rdata = &src[..final_pos];
+        let mut options = vec![];
+        while self.offset < final_pos {
+            let code = self.next_u16(rdata)?;
+            let len = self.next_u16(rdata)? as usize;
+            let data = self.next_bytes(rdata, len)?.to_vec();
+            options.push(EdnsOption { code, data });
+        }
+        debug!("Found OPT, udp_size={}, options={:?}", udp_size, options);
 
-            // Label
-            name.push(
-                String::from_utf8_lossy(&src[self.offset..self.offset + label_len as usize])
-                    .into_owned(),
-            );
-            self.offset += label_len as usize;
-            label_len = src[self.offset];
-            self.offset += 1;
-            debug!("{:?}", name);
-        }
-
-        if (label_len >> 6) & 0x3 == 0x3 {
-            let mut i = (label_len & 0b111111) as usize | (src[self.offset] as usize);
-            self.offset += 1; // Skip the second byte of the pointer
-            debug!("Found pointer to {}", i);
-
-            label_len = src[i];
-            i += 1;
-
-            while label_len != 0 {
-                // Jump to the actual label
-                while (label_len >> 6) & 0x3 == 0x3 {
-                    i = (label_len & 0b111111) as usize | (src[i] as usize);
-                    debug!("Indirect pointer, jump to {}", i);
-                    label_len = src[i];
+        Ok((
+            extended_rcode,
+            Edns {
+                udp_size,
+                version,
+                flags,
+                options,
+            },
+        ))
+    }
+
+    fn next_name(&mut self, src: &[u8]) -> Result<DomainName, DnsCodecError> {
+        let mut name = Vec::new();
+        let mut wire_len = 1;
+        let mut i = self.offset;
+        let mut jumps = 0;
+
+        loop {
+            let label_len = *src.get(i).ok_or_else(truncated)? as usize;
+            match label_len >> 6 {
+                0 if label_len == 0 => {
                     i += 1;
+                    break;
                 }
-
-                // Do the actual parse
-                name.push(String::from_utf8_lossy(&src[i..i + label_len as usize]).into_owned());
-                i += label_len as usize;
-                label_len = src[i];
-                i += 1;
-                debug!("{:?}", name);
+                0 => {
+                    debug!("Found label at offset {}", i);
+                    let label = src.get(i + 1..i + 1 + label_len).ok_or_else(truncated)?;
+                    if name.len() == self.limits.max_labels {
+                        return Err(DnsCodecError::TooManyLabels);
+                    }
+                    name.push(String::from_utf8_lossy(label).into_owned());
+                    wire_len += 1 + label_len;
+                    if wire_len > 255 {
+                        return Err(DnsCodecError::NameTooLong);
+                    }
+                    i += 1 + label_len;
+                }
+                3 => {
+                    let low = *src.get(i + 1).ok_or_else(truncated)? as usize;
+                    let target = (label_len & 0b111111) << 8 | low;
+                    debug!("Found pointer to {}", target);
+                    // Only pointing backwards rules out loops, and the
+                    // jump limit long chains of pointers to pointers
+                    if target >= i {
+                        return Err(DnsCodecError::BadPointer(target));
+                    }
+                    if jumps == self.limits.max_jumps {
+                        return Err(DnsCodecError::TooManyJumps);
+                    }
+                    if jumps == 0 {
+                        self.offset = i + 2;
+                    }
+                    jumps += 1;
+                    i = target;
+                }
+                _ => return Err(DnsCodecError::UnknownLabelType(label_len as u8)),
             }
         }
+        if jumps == 0 {
+            self.offset = i;
+        }
 
-        Ok(name)
+        debug!("{:?}", name);
+        Ok(name.into())
     }
 
-    fn next_type(&mut self, src: &mut BytesMut) -> Result<DnsType, <Self as Decoder>::Error> {
-        let x = ((src[self.offset] as u16) << 8) | (src[self.offset + 1] as u16);
-        debug!("Found type {} at offset {}", x, self.offset);
-        self.offset += 2;
-        let ty = match DnsType::try_from(x) {
-            Some(ty) => ty,
-            None => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("unknown type {}", x),
-                ));
-            }
-        };
-        Ok(ty)
+    /// A question's type, and its number if it has no variant, so that
+    /// questions for new types can be passed on as they are.
+    fn next_type(&mut self, src: &[u8]) -> Result<(DnsType, u16), DnsCodecError> {
+        debug!("Found type at offset {}", self.offset);
+        let x = self.next_u16(src)?;
+        Ok(match DnsType::try_from(x) {
+            Some(ty) => (ty, 0),
+            None => (DnsType::Unknown, x),
+        })
     }
 
-    fn next_class(&mut self, src: &mut BytesMut) -> Result<DnsClass, <Self as Decoder>::Error> {
-        let x = ((src[self.offset] as u16) << 8) | (src[self.offset + 1] as u16);
-        self.offset += 2;
+    fn next_class(&mut self, src: &[u8]) -> Result<DnsClass, DnsCodecError> {
+        let x = self.next_u16(src)?;
         let qclass = match DnsClass::try_from(x) {
             Some(qclass) => qclass,
-            None => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("unknown class {}", x),
-                ));
-            }
+            None => return Err(DnsCodecError::UnknownClass(x)),
         };
         Ok(qclass)
     }
 }
 
+/// Why a name can't be put on the wire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameError {
+    /// A label is empty or longer than 63 bytes.
+    LabelLength(usize),
+    /// The name takes more than 255 bytes on the wire.
+    NameLength(usize),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::LabelLength(n) => write!(f, "label of {} bytes", n),
+            NameError::NameLength(n) => write!(f, "name of {} bytes", n),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+fn name_length(name: &DomainName) -> u16 {
+    let mut len = 0u16;
+    for i in name {
+        len += 1;
+        len += i.as_bytes().len() as u16;
+    }
+    len += 1; // final zero
+    return len;
+}
+
+/// Put a <character-string>: a length byte followed by the bytes.
+fn encode_string(s: &[u8], buf: &mut Vec<u8>) -> Result<(), DnsCodecError> {
+    if s.len() > 255 {
+        return Err(DnsCodecError::StringTooLong(s.len()));
+    }
+    buf.put_u8(s.len() as u8);
+    buf.put_slice(s);
+    Ok(())
+}
+
 impl Encoder for DnsMessageCodec {
     type Item = DnsMessage;
     type Error = std::io::Error;
@@ -378,50 +764,108 @@ impl Encoder for DnsMessageCodec {
         item: DnsMessage,
         buf: &mut BytesMut,
     ) -> Result<(), <Self as Encoder>::Error> {
-        let mut this = BytesMut::with_capacity(4096);
-        buf.reserve(4096);
+        self.encode_message(item, buf).map_err(Error::from)
+    }
+}
+
+impl DnsMessageCodec {
+    fn encode_message(
+        &mut self,
+        item: DnsMessage,
+        buf: &mut BytesMut,
+    ) -> Result<(), DnsCodecError> {
+        // Vecs grow as needed, while BytesMut panics when it's full
+        let mut this = Vec::with_capacity(512);
 
         self.encode_header(&item, &mut this)?;
         for question in item.question {
             self.encode_name(&question.qname, &mut this)?;
-            this.put_u16_be(question.qtype as u16);
+            this.put_u16_be(question.code());
             this.put_u16_be(question.qclass as u16);
         }
-        for answer in item.answer {
-            self.encode_rr(&answer, &mut this)?;
+        // Where each RR ends, so that truncation can cut between them
+        let mut ends = vec![this.len()];
+        let counts = [item.answer.len(), item.authority.len()];
+        for rr in item
+            .answer
+            .iter()
+            .chain(&item.authority)
+            .chain(&item.additional)
+        {
+            self.encode_rr(rr, &mut this)?;
+            ends.push(this.len());
         }
-        for authority in item.authority {
-            self.encode_rr(&authority, &mut this)?;
+        // Upstream only sends more than 512 bytes if the client said it can
+        // take them, so the OPT of a response bounds what may be relayed
+        let mut udp_size = 512;
+        let mut opt = Vec::new();
+        if let Some(ref edns) = item.edns {
+            self.encode_opt(edns, item.header.rcode, &mut opt)?;
+            udp_size = udp_size.max(edns.udp_size as usize);
         }
-        for additional in item.additional {
-            self.encode_rr(&additional, &mut this)?;
+        let mut tsig = Vec::new();
+        if let Some(ref t) = item.tsig {
+            self.encode_tsig(t, &mut tsig)?;
         }
 
-        if self.tcp {
-            buf.put_u16_be(this.len() as u16);
-        } else if this.len() > 512 {
-            debug!("Buffer length {} exceeds 512, truncating", buf.len());
+        if !self.tcp && this.len() + opt.len() + tsig.len() > udp_size {
+            debug!(
+                "Message length {} exceeds {}, truncating",
+                this.len(),
+                udp_size
+            );
+            // Keep as many whole RRs as fit next to the OPT and TSIG, and
+            // the questions even if none do
+            let limit = udp_size.saturating_sub(opt.len() + tsig.len());
+            let kept = ends.iter().skip(1).take_while(|&&end| end <= limit).count();
+            this.truncate(ends[kept]);
+            let answers = kept.min(counts[0]);
+            let authorities = (kept - answers).min(counts[1]);
+            let additionals = kept - answers - authorities
+                + item.edns.is_some() as usize
+                + item.tsig.is_some() as usize;
+            for (i, &n) in [answers, authorities, additionals].iter().enumerate() {
+                this[6 + 2 * i] = (n >> 8) as u8;
+                this[7 + 2 * i] = n as u8;
+            }
             this[2] |= 0b10;
-            this.truncate(512);
-        } else {
+        } else if !self.tcp {
             this[2] &= 0b11111101;
         }
-        buf.extend(this);
+        if let (Some(block_size), Some(edns)) = (self.padding, item.edns.as_ref()) {
+            let mut padded = Vec::new();
+            let len = this.len() + tsig.len();
+            self.encode_padded_opt(edns, item.header.rcode, len, block_size, &mut padded)?;
+            if self.tcp || len + padded.len() <= udp_size {
+                opt = padded;
+            }
+        }
+        let len = this.len() + opt.len() + tsig.len();
+        buf.reserve(2 + len);
+        if self.tcp {
+            buf.put_u16_be(len as u16);
+        }
+        buf.extend_from_slice(&this);
+        buf.extend_from_slice(&opt);
+        buf.extend_from_slice(&tsig);
 
         Ok(())
     }
-}
 
-impl DnsMessageCodec {
     fn encode_header(
         &mut self,
         message: &DnsMessage,
-        buf: &mut BytesMut,
-    ) -> Result<(), <Self as Encoder>::Error> {
+        buf: &mut Vec<u8>,
+    ) -> Result<(), DnsCodecError> {
+        // Extended RCODEs make no sense to a client which doesn't do EDNS
+        let rcode = match message.header.rcode {
+            rcode if rcode as u16 > 0xf && message.edns.is_none() => DnsRcode::ServerFailure,
+            rcode => rcode,
+        };
         buf.put_u16_be(message.header.id);
         buf.put_u8(
             ((!message.header.query as u8) << 7)
-                | ((message.header.opcode as u8) & 0xf << 3)
+                | (((message.header.opcode as u8) & 0xf) << 3)
                 | ((message.header.authoritative as u8) << 2)
                 | ((message.header.truncated as u8) << 1)
                 | message.header.recur_desired as u8,
@@ -429,20 +873,100 @@ impl DnsMessageCodec {
         buf.put_u8(
             ((message.header.recur_available as u8) << 7) |
             (0 << 4) | // Z bits
-            ((message.header.rcode as u8) & 0xf),
+            ((rcode as u16 as u8) & 0xf),
         );
         buf.put_u16_be(message.question.len() as u16);
         buf.put_u16_be(message.answer.len() as u16);
         buf.put_u16_be(message.authority.len() as u16);
-        buf.put_u16_be(message.additional.len() as u16);
+        buf.put_u16_be(
+            message.additional.len() as u16
+                + message.edns.is_some() as u16
+                + message.tsig.is_some() as u16,
+        );
         Ok(())
     }
 
-    fn encode_name(
+    fn encode_opt(
         &mut self,
-        name: &Vec<String>,
-        buf: &mut BytesMut,
-    ) -> Result<(), <Self as Encoder>::Error> {
+        edns: &Edns,
+        rcode: DnsRcode,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), DnsCodecError> {
+        let rdlen: usize = edns.options.iter().map(|opt| 4 + opt.data.len()).sum();
+        buf.put_u8(0); // Root
+        buf.put_u16_be(DnsType::OPT as u16);
+        buf.put_u16_be(edns.udp_size);
+        buf.put_u8((rcode as u16 >> 4) as u8);
+        buf.put_u8(edns.version);
+        buf.put_u16_be(edns.flags);
+        buf.put_u16_be(rdlen as u16);
+        for opt in &edns.options {
+            buf.put_u16_be(opt.code);
+            buf.put_u16_be(opt.data.len() as u16);
+            buf.put_slice(&opt.data);
+        }
+        Ok(())
+    }
+
+    fn encode_tsig(&mut self, tsig: &Tsig, buf: &mut Vec<u8>) -> Result<(), DnsCodecError> {
+        self.encode_name(&tsig.key, buf)?;
+        buf.put_u16_be(DnsType::TSIG as u16);
+        buf.put_u16_be(DnsClass::Any as u16);
+        buf.put_u32_be(0);
+        let rdlen = name_length(&tsig.algorithm) as usize + 16 + tsig.mac.len() + tsig.other.len();
+        buf.put_u16_be(rdlen as u16);
+        self.encode_name(&tsig.algorithm, buf)?;
+        buf.put_u16_be((tsig.time_signed >> 32) as u16);
+        buf.put_u32_be(tsig.time_signed as u32);
+        buf.put_u16_be(tsig.fudge);
+        buf.put_u16_be(tsig.mac.len() as u16);
+        buf.put_slice(&tsig.mac);
+        buf.put_u16_be(tsig.original_id);
+        buf.put_u16_be(tsig.error);
+        buf.put_u16_be(tsig.other.len() as u16);
+        buf.put_slice(&tsig.other);
+        Ok(())
+    }
+
+    /// Encode the OPT with a Padding option which brings the message, `len`
+    /// bytes without the OPT, to a multiple of `block_size`.
+    fn encode_padded_opt(
+        &mut self,
+        edns: &Edns,
+        rcode: DnsRcode,
+        len: usize,
+        block_size: usize,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), DnsCodecError> {
+        let mut edns = edns.clone();
+        edns.options.retain(|opt| opt.code != EDNS_PADDING);
+        let opt_len: usize = 11
+            + 4
+            + edns
+                .options
+                .iter()
+                .map(|opt| 4 + opt.data.len())
+                .sum::<usize>();
+        let padding = (block_size - (len + opt_len) % block_size) % block_size;
+        edns.options.push(EdnsOption {
+            code: EDNS_PADDING,
+            data: vec![0; padding],
+        });
+        self.encode_opt(&edns, rcode, buf)
+    }
+
+    fn encode_name(&mut self, name: &DomainName, buf: &mut Vec<u8>) -> Result<(), DnsCodecError> {
+        let mut len = 1;
+        for label in name {
+            let n = label.len();
+            if n == 0 || n > 63 {
+                return Err(NameError::LabelLength(n).into());
+            }
+            len += 1 + n;
+        }
+        if len > 255 {
+            return Err(NameError::NameLength(len).into());
+        }
         for label in name {
             buf.put_u8(label.as_bytes().len() as u8);
             buf.put_slice(label.as_bytes());
@@ -454,20 +978,10 @@ impl DnsMessageCodec {
     fn encode_rr(
         &mut self,
         rr: &DnsResourceRecord,
-        buf: &mut BytesMut,
-    ) -> Result<(), <Self as Encoder>::Error> {
-        fn name_length(name: &Vec<String>) -> u16 {
-            let mut len = 0u16;
-            for i in name {
-                len += 1;
-                len += i.as_bytes().len() as u16;
-            }
-            len += 1; // final zero
-            return len;
-        }
-
+        buf: &mut Vec<u8>,
+    ) -> Result<(), DnsCodecError> {
         self.encode_name(&rr.name, buf)?;
-        buf.put_u16_be(rr.rtype as u16);
+        buf.put_u16_be(rr.code());
         buf.put_u16_be(rr.rclass as u16);
         buf.put_u32_be(rr.ttl);
         match rr.data {
@@ -494,12 +1008,11 @@ impl DnsMessageCodec {
             DnsRRData::TXT(ref txt) => {
                 let mut rdlen = 0;
                 for i in txt {
-                    rdlen += i.as_bytes().len() + 1;
+                    rdlen += i.len() + 1;
                 }
                 buf.put_u16_be(rdlen as u16);
                 for i in txt {
-                    buf.put_u8(i.as_bytes().len() as u8);
-                    buf.put(i.as_bytes());
+                    encode_string(i, buf)?;
                 }
             }
             DnsRRData::SOA(ref mname, ref rname, serial, refresh, retry, expire, minimum) => {
@@ -512,10 +1025,98 @@ impl DnsMessageCodec {
                 buf.put_u32_be(expire);
                 buf.put_u32_be(minimum);
             }
-            DnsRRData::NS(ref name) => {
+            DnsRRData::NS(ref name) | DnsRRData::PTR(ref name) => {
                 buf.put_u16_be(name_length(name));
                 self.encode_name(name, buf)?;
             }
+            DnsRRData::SRV(priority, weight, port, ref target) => {
+                buf.put_u16_be(6 + name_length(target));
+                buf.put_u16_be(priority);
+                buf.put_u16_be(weight);
+                buf.put_u16_be(port);
+                self.encode_name(target, buf)?;
+            }
+            DnsRRData::NAPTR(order, pref, ref flags, ref services, ref regexp, ref replacement) => {
+                let strings = [flags, services, regexp];
+                let rdlen: usize = strings.iter().map(|s| 1 + s.len()).sum();
+                buf.put_u16_be(4 + rdlen as u16 + name_length(replacement));
+                buf.put_u16_be(order);
+                buf.put_u16_be(pref);
+                for s in &strings {
+                    encode_string(s, buf)?;
+                }
+                self.encode_name(replacement, buf)?;
+            }
+            DnsRRData::DS(key_tag, algorithm, digest_type, ref digest) => {
+                buf.put_u16_be(4 + digest.len() as u16);
+                buf.put_u16_be(key_tag);
+                buf.put_u8(algorithm);
+                buf.put_u8(digest_type);
+                buf.put_slice(digest);
+            }
+            DnsRRData::RRSIG(
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer,
+                ref signature,
+            ) => {
+                buf.put_u16_be(18 + name_length(signer) + signature.len() as u16);
+                buf.put_u16_be(type_covered);
+                buf.put_u8(algorithm);
+                buf.put_u8(labels);
+                buf.put_u32_be(original_ttl);
+                buf.put_u32_be(expiration);
+                buf.put_u32_be(inception);
+                buf.put_u16_be(key_tag);
+                self.encode_name(signer, buf)?;
+                buf.put_slice(signature);
+            }
+            DnsRRData::NSEC(ref next, ref types) => {
+                buf.put_u16_be(name_length(next) + types.len() as u16);
+                self.encode_name(next, buf)?;
+                buf.put_slice(types);
+            }
+            DnsRRData::DNSKEY(flags, protocol, algorithm, ref key) => {
+                buf.put_u16_be(4 + key.len() as u16);
+                buf.put_u16_be(flags);
+                buf.put_u8(protocol);
+                buf.put_u8(algorithm);
+                buf.put_slice(key);
+            }
+            DnsRRData::NSEC3(hash_algorithm, flags, iterations, ref salt, ref next, ref types) => {
+                buf.put_u16_be(6 + (salt.len() + next.len() + types.len()) as u16);
+                buf.put_u8(hash_algorithm);
+                buf.put_u8(flags);
+                buf.put_u16_be(iterations);
+                buf.put_u8(salt.len() as u8);
+                buf.put_slice(salt);
+                buf.put_u8(next.len() as u8);
+                buf.put_slice(next);
+                buf.put_slice(types);
+            }
+            DnsRRData::HINFO(ref cpu, ref os) => {
+                buf.put_u16_be((2 + cpu.len() + os.len()) as u16);
+                encode_string(cpu, buf)?;
+                encode_string(os, buf)?;
+            }
+            DnsRRData::Unknown(_, ref data) => {
+                buf.put_u16_be(data.len() as u16);
+                buf.put_slice(data);
+            }
+            DnsRRData::CAA(flags, ref tag, ref value) => {
+                if tag.is_empty() {
+                    return Err(DnsCodecError::EmptyTag);
+                }
+                buf.put_u16_be((2 + tag.len() + value.len()) as u16);
+                buf.put_u8(flags);
+                encode_string(tag, buf)?;
+                buf.put_slice(value);
+            }
         }
         Ok(())
     }
@@ -524,6 +1125,9 @@ impl DnsMessageCodec {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::select;
 
     #[test]
     fn test_both_1() {
@@ -535,8 +1139,9 @@ mod tests {
                 ..Default::default()
             },
             question: vec![DnsQuestion {
-                qname: vec!["ksqsf".to_owned(), "moe".to_owned()],
+                qname: vec!["ksqsf", "moe"].into(),
                 qtype: DnsType::AAAA,
+                qcode: 0,
                 qclass: DnsClass::Any,
             }],
             ..Default::default()
@@ -563,12 +1168,13 @@ mod tests {
                 ..Default::default()
             },
             question: vec![DnsQuestion {
-                qname: vec!["ksqsf".to_owned(), "moe".to_owned()],
+                qname: vec!["ksqsf", "moe"].into(),
                 qtype: DnsType::AAAA,
+                qcode: 0,
                 qclass: DnsClass::Any,
             }],
             answer: vec![DnsResourceRecord {
-                name: vec!["ksqsf".to_owned(), "moe".to_owned()],
+                name: vec!["ksqsf", "moe"].into(),
                 rtype: DnsType::A,
                 rclass: DnsClass::Internet,
                 ttl: 120,
@@ -605,12 +1211,13 @@ mod tests {
                 ..Default::default()
             },
             question: vec![DnsQuestion {
-                qname: vec!["ksqsf".to_owned(), "moe".to_owned()],
+                qname: vec!["ksqsf", "moe"].into(),
                 qtype: DnsType::AAAA,
+                qcode: 0,
                 qclass: DnsClass::Any,
             }],
             answer: vec![DnsResourceRecord {
-                name: vec!["ksqsf".to_owned(), "moe".to_owned()],
+                name: vec!["ksqsf", "moe"].into(),
                 rtype: DnsType::A,
                 rclass: DnsClass::Internet,
                 ttl: 120,
@@ -629,4 +1236,818 @@ mod tests {
             _ => (),
         }
     }
+
+    #[test]
+    fn test_truncation() {
+        let rr = DnsResourceRecord {
+            name: vec!["ksqsf", "moe"].into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 120,
+            data: DnsRRData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        let message = DnsMessage {
+            question: vec![DnsQuestion {
+                qname: vec!["ksqsf", "moe"].into(),
+                qtype: DnsType::A,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            answer: vec![rr.clone(); 10],
+            additional: vec![rr.clone(); 30],
+            ..Default::default()
+        };
+        let mut codec = DnsMessageCodec::new(false);
+
+        // 12 bytes of header, 15 of question and 25 per RR
+        let mut buf = BytesMut::new();
+        codec.encode(message.clone(), &mut buf).expect("encode");
+        assert_eq!(buf.len(), 27 + 19 * 25);
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        assert!(decoded.header.truncated);
+        assert_eq!(decoded.question[0].qname, message.question[0].qname);
+        assert_eq!(decoded.answer.len(), 10);
+        assert_eq!(decoded.additional.len(), 9);
+
+        // The OPT is kept and takes 11 bytes
+        let message = DnsMessage {
+            edns: Some(Edns::default()),
+            ..message
+        };
+        codec.encode(message.clone(), &mut buf).expect("encode");
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        assert!(decoded.header.truncated);
+        assert_eq!(decoded.additional.len(), 8);
+        assert_eq!(decoded.edns, message.edns);
+
+        let message = DnsMessage {
+            additional: vec![],
+            ..message
+        };
+        codec.encode(message, &mut buf).expect("encode");
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        assert!(!decoded.header.truncated);
+        assert_eq!(decoded.answer.len(), 10);
+    }
+
+    #[test]
+    fn test_opcode() {
+        for &opcode in &[DnsOpcode::Notify, DnsOpcode::Update] {
+            let message = DnsMessage {
+                header: DnsHeader {
+                    opcode,
+                    rcode: DnsRcode::NotImplemented,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            let mut codec = DnsMessageCodec::new(false);
+            codec.encode(message, &mut buf).expect("encode");
+            let decoded = codec
+                .decode(&mut buf)
+                .expect("no error")
+                .expect("parse complete");
+            assert_eq!(decoded.header.opcode, opcode);
+            assert_eq!(decoded.header.rcode, DnsRcode::NotImplemented);
+        }
+    }
+
+    #[test]
+    fn test_unknown_qtype() {
+        // Questions for HTTPS and TYPE65535 records of "a"
+        for &code in &[65, 65535] {
+            let mut packet = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            packet.extend_from_slice(&[1, b'a', 0]);
+            packet.extend_from_slice(&[(code >> 8) as u8, code as u8, 0, 1]);
+            for &strict in &[false, true] {
+                let mut codec = DnsMessageCodec::new(false).strict(strict);
+                let decoded = codec
+                    .decode(&mut BytesMut::from(&packet[..]))
+                    .expect("no error")
+                    .expect("parse complete");
+                assert_eq!(decoded.question[0].qtype, DnsType::Unknown);
+                assert_eq!(decoded.question[0].code(), code);
+                let mut buf = BytesMut::new();
+                codec.encode(decoded, &mut buf).expect("encode");
+                assert_eq!(&buf[..], &packet[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_edns() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                id: 12345,
+                rcode: DnsRcode::BadCookie,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: vec!["ksqsf", "moe"].into(),
+                qtype: DnsType::A,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            edns: Some(Edns {
+                udp_size: 1232,
+                flags: crate::message::EDNS_DO,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        let mut codec = DnsMessageCodec::new(false);
+        codec.encode(message.clone(), &mut buf).expect("encode");
+        assert_eq!(buf[11], 1); // arcount
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        assert!(decoded.additional.is_empty());
+        assert_eq!(decoded.edns, message.edns);
+        assert_eq!(decoded.header.rcode, DnsRcode::BadCookie);
+
+        let message = DnsMessage {
+            edns: None,
+            ..message
+        };
+        codec.encode(message, &mut buf).expect("encode");
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        assert_eq!(decoded.header.rcode, DnsRcode::ServerFailure);
+    }
+
+    #[test]
+    fn test_padding() {
+        let message = DnsMessage {
+            question: vec![DnsQuestion {
+                qname: vec!["ksqsf", "moe"].into(),
+                qtype: DnsType::A,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            edns: Some(Edns {
+                options: vec![EdnsOption {
+                    code: EDNS_PADDING,
+                    data: vec![0; 3],
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        let mut codec = DnsMessageCodec::new(true).padding(128);
+        codec.encode(message.clone(), &mut buf).expect("encode");
+        assert_eq!(buf.len(), 2 + 128);
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("no error")
+            .expect("parse complete");
+        let options = decoded.edns.unwrap().options;
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].code, EDNS_PADDING);
+
+        // Padding to 1024 bytes would exceed the UDP payload size of 512
+        let mut codec = DnsMessageCodec::new(false).padding(1024);
+        codec.encode(message.clone(), &mut buf).expect("encode");
+        assert_eq!(buf.len(), 12 + 15 + 11 + 7);
+        buf.clear();
+        let mut codec = DnsMessageCodec::new(false).padding(468);
+        codec.encode(message, &mut buf).expect("encode");
+        assert_eq!(buf.len(), 468);
+    }
+
+    #[test]
+    fn test_rdata() {
+        let name: DomainName = vec!["ksqsf", "moe"].into();
+        let records = vec![
+            (DnsType::PTR, DnsRRData::PTR(name.clone())),
+            (
+                DnsType::HINFO,
+                DnsRRData::HINFO(b"RFC8482".to_vec(), vec![]),
+            ),
+            (
+                DnsType::CAA,
+                DnsRRData::CAA(128, b"issue".to_vec(), b"letsencrypt.org".to_vec()),
+            ),
+            (
+                DnsType::SRV,
+                DnsRRData::SRV(10, 60, 5060, vec!["sip", "ksqsf", "moe"].into()),
+            ),
+            (
+                DnsType::NAPTR,
+                DnsRRData::NAPTR(
+                    100,
+                    10,
+                    b"S".to_vec(),
+                    b"SIP+D2U".to_vec(),
+                    vec![],
+                    vec!["_sip", "_udp", "ksqsf"].into(),
+                ),
+            ),
+            (DnsType::DS, DnsRRData::DS(60485, 5, 1, vec![0x2b; 20])),
+            (
+                DnsType::RRSIG,
+                DnsRRData::RRSIG(
+                    DnsType::A as u16,
+                    13,
+                    2,
+                    3600,
+                    1_700_000_000,
+                    1_690_000_000,
+                    60485,
+                    name.clone(),
+                    vec![0xa5; 64],
+                ),
+            ),
+            (
+                DnsType::NSEC,
+                DnsRRData::NSEC(name.clone(), vec![0, 6, 0x40, 0x01, 0, 0, 0, 0x03]),
+            ),
+            (
+                DnsType::DNSKEY,
+                DnsRRData::DNSKEY(257, 3, 13, vec![0x5a; 64]),
+            ),
+            (DnsType::Unknown, DnsRRData::Unknown(65, vec![0, 1, 0, 0])),
+            (
+                DnsType::WKS,
+                DnsRRData::Unknown(11, vec![127, 0, 0, 1, 6, 0x80]),
+            ),
+            (
+                DnsType::NSEC3,
+                DnsRRData::NSEC3(1, 0, 10, vec![0xab, 0xcd], vec![0x11; 20], vec![0, 1, 0x40]),
+            ),
+        ];
+        for (rtype, data) in records {
+            let rr = DnsResourceRecord {
+                name: name.clone(),
+                rtype,
+                rclass: DnsClass::Internet,
+                ttl: 120,
+                data,
+            };
+            let message = DnsMessage {
+                answer: vec![rr.clone()],
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            let mut codec = DnsMessageCodec::new(false);
+            codec.encode(message, &mut buf).expect("encode");
+            let decoded = codec
+                .decode(&mut buf)
+                .expect("no error")
+                .expect("parse complete");
+            assert_eq!(decoded.answer, vec![rr]);
+        }
+    }
+
+    #[test]
+    fn test_name_pointers() {
+        let mut codec = DnsMessageCodec::new(false);
+        // ksqsf.moe at 0, www + pointer to it at 11, a pointer loop at 17
+        let buf = BytesMut::from(&b"\x05ksqsf\x03moe\x00\x03www\xc0\x00\xc0\x11\xc0\x15\x01a"[..]);
+        codec.offset = 11;
+        let name = codec.next_name(&buf).expect("pointer");
+        assert_eq!(name, vec!["www", "ksqsf", "moe"].into());
+        assert_eq!(codec.offset, 17);
+        assert!(codec.next_name(&buf).is_err()); // points at itself
+        codec.offset = 19;
+        assert!(codec.next_name(&buf).is_err()); // points forwards
+        codec.offset = 21;
+        assert!(codec.next_name(&buf).is_err()); // runs past the end
+    }
+
+    #[test]
+    fn test_name_limits() {
+        let encode = |labels: Vec<String>| {
+            let message = DnsMessage {
+                question: vec![DnsQuestion {
+                    qname: labels.into(),
+                    qtype: DnsType::A,
+                    qcode: 0,
+                    qclass: DnsClass::Internet,
+                }],
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            let result = DnsMessageCodec::new(false).encode(message, &mut buf);
+            result.map_err(|e| {
+                assert!(buf.is_empty());
+                assert_eq!(e.kind(), ErrorKind::InvalidInput);
+                match e.get_ref().unwrap().downcast_ref::<DnsCodecError>() {
+                    Some(&DnsCodecError::Name(e)) => e,
+                    _ => panic!("not a name error: {}", e),
+                }
+            })
+        };
+        assert!(encode(vec!["a".repeat(63)]).is_ok());
+        assert_eq!(
+            encode(vec!["a".repeat(64)]),
+            Err(NameError::LabelLength(64))
+        );
+        assert_eq!(
+            encode(vec!["a".to_owned(), String::new()]),
+            Err(NameError::LabelLength(0))
+        );
+        // 4 * 64 + 1 = 257 bytes, while 3 * 64 + 62 + 1 = 255 just fits
+        assert!(encode(
+            vec!["a".repeat(63); 3]
+                .into_iter()
+                .chain(Some("a".repeat(61)))
+                .collect()
+        )
+        .is_ok());
+        assert_eq!(
+            encode(vec!["a".repeat(63); 4]),
+            Err(NameError::NameLength(257))
+        );
+    }
+
+    #[test]
+    fn test_string_limits() {
+        let encode = |data: DnsRRData| {
+            let message = DnsMessage {
+                answer: vec![DnsResourceRecord {
+                    name: "a.lan".parse().unwrap(),
+                    rtype: data.rtype(),
+                    rclass: DnsClass::Internet,
+                    ttl: 60,
+                    data,
+                }],
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            let result = DnsMessageCodec::new(false).encode(message, &mut buf);
+            result.map_err(|e| {
+                assert!(buf.is_empty());
+                assert_eq!(e.kind(), ErrorKind::InvalidInput);
+                e.get_ref()
+                    .and_then(|e| e.downcast_ref::<DnsCodecError>())
+                    .cloned()
+            })
+        };
+        let long = vec![b'a'; 256];
+        assert!(encode(DnsRRData::TXT(vec![long[1..].to_vec()])).is_ok());
+        assert_eq!(
+            encode(DnsRRData::TXT(vec![vec![], long.clone()])),
+            Err(Some(DnsCodecError::StringTooLong(256)))
+        );
+        assert_eq!(
+            encode(DnsRRData::HINFO(vec![], long.clone())),
+            Err(Some(DnsCodecError::StringTooLong(256)))
+        );
+        let naptr = |regexp| DnsRRData::NAPTR(1, 1, vec![], vec![], regexp, DomainName::default());
+        assert!(encode(naptr(long[1..].to_vec())).is_ok());
+        assert_eq!(
+            encode(naptr(long.clone())),
+            Err(Some(DnsCodecError::StringTooLong(256)))
+        );
+        let caa = |tag| DnsRRData::CAA(0, tag, b"ca.example".to_vec());
+        assert!(encode(caa(long[1..].to_vec())).is_ok());
+        assert_eq!(
+            encode(caa(long)),
+            Err(Some(DnsCodecError::StringTooLong(256)))
+        );
+        assert_eq!(encode(caa(vec![])), Err(Some(DnsCodecError::EmptyTag)));
+
+        // Bytes that aren't UTF-8 come back as they went out
+        let message = |data: DnsRRData| DnsMessage {
+            answer: vec![DnsResourceRecord {
+                name: "a.lan".parse().unwrap(),
+                rtype: data.rtype(),
+                rclass: DnsClass::Internet,
+                ttl: 60,
+                data,
+            }],
+            ..Default::default()
+        };
+        let mut codec = DnsMessageCodec::new(false).strict(true);
+        for data in &[
+            DnsRRData::HINFO(vec![0xff, 0], b"\x80".to_vec()),
+            caa(vec![0xc3, b' ']),
+        ] {
+            let mut buf = BytesMut::new();
+            codec.encode(message(data.clone()), &mut buf).unwrap();
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(&decoded.answer[0].data, data);
+        }
+
+        // A CAA tag can't be empty on the wire either
+        let mut buf = BytesMut::new();
+        codec.encode(message(caa(b"x".to_vec())), &mut buf).unwrap();
+        let at = buf.len() - b"ca.example".len() - 2;
+        buf[at] = 0;
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            e.get_ref()
+                .and_then(|e| e.downcast_ref::<DnsCodecError>())
+                .cloned(),
+            Some(DnsCodecError::MalformedRdata(DnsType::CAA as u16))
+        );
+    }
+
+    #[test]
+    fn test_garbage() {
+        let message = DnsMessage {
+            question: vec![DnsQuestion {
+                qname: vec!["ksqsf", "moe"].into(),
+                ..Default::default()
+            }],
+            answer: vec![DnsResourceRecord {
+                name: vec!["ksqsf", "moe"].into(),
+                rtype: DnsType::MX,
+                rclass: DnsClass::Internet,
+                ttl: 120,
+                data: DnsRRData::MX(10, vec!["mail", "ksqsf"].into()),
+            }],
+            edns: Some(Default::default()),
+            ..Default::default()
+        };
+        let mut packet = BytesMut::new();
+        DnsMessageCodec::new(false)
+            .encode(message, &mut packet)
+            .expect("encode");
+
+        // Every truncation is an error over UDP, and incomplete over TCP
+        for len in 0..packet.len() {
+            let mut buf = BytesMut::from(&packet[..len]);
+            assert!(DnsMessageCodec::new(false).decode(&mut buf).is_err());
+            let mut buf = BytesMut::from(&[0, packet.len() as u8][..]);
+            buf.extend_from_slice(&packet[..len]);
+            assert!(DnsMessageCodec::new(true)
+                .decode(&mut buf)
+                .unwrap()
+                .is_none());
+        }
+
+        // Errors say what is wrong
+        let error = |buf: &[u8]| {
+            let e = DnsMessageCodec::new(false)
+                .decode(&mut BytesMut::from(buf))
+                .unwrap_err();
+            e.get_ref()
+                .and_then(|e| e.downcast_ref::<DnsCodecError>())
+                .cloned()
+        };
+        assert_eq!(error(&packet[..5]), Some(DnsCodecError::Truncated));
+        let mut buf = packet.clone();
+        buf[2] |= 3 << 3;
+        assert_eq!(error(&buf), Some(DnsCodecError::UnknownOpcode(3)));
+
+        // Nothing panics, whatever the bytes
+        let mut seed = 0x2545_f491u32;
+        for _ in 0..1000 {
+            let mut buf = packet.clone();
+            for _ in 0..4 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let i = (seed >> 8) as usize % buf.len();
+                buf[i] = (seed >> 24) as u8;
+            }
+            let _ = DnsMessageCodec::new(false).decode(&mut buf);
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let message = DnsMessage {
+            question: vec![DnsQuestion {
+                qname: vec!["ksqsf", "moe"].into(),
+                ..Default::default()
+            }],
+            answer: vec![DnsResourceRecord {
+                name: vec!["ksqsf", "moe"].into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 120,
+                data: DnsRRData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            }],
+            ..Default::default()
+        };
+        let mut packet = BytesMut::new();
+        DnsMessageCodec::new(false)
+            .encode(message, &mut packet)
+            .expect("encode");
+        let decode = |strict, buf: &[u8]| {
+            DnsMessageCodec::new(false)
+                .strict(strict)
+                .decode(&mut BytesMut::from(buf))
+                .map(Option::unwrap)
+                .map_err(|e| e.into_inner().unwrap().downcast::<DnsCodecError>().unwrap())
+        };
+        assert!(decode(true, &packet).is_ok());
+
+        let mut junk = packet.to_vec();
+        junk.extend_from_slice(&[0, 0]);
+        assert!(decode(false, &junk).is_ok());
+        assert_eq!(
+            decode(true, &junk).unwrap_err(),
+            Box::new(DnsCodecError::TrailingData(2))
+        );
+
+        // The answer's class is at 12 + 15 + 13
+        let mut bad_class = packet.to_vec();
+        bad_class[41] = 99;
+        assert!(decode(false, &bad_class).unwrap().answer.is_empty());
+        assert_eq!(
+            decode(true, &bad_class).unwrap_err(),
+            Box::new(DnsCodecError::UnknownClass(99))
+        );
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        // A chain of 40 pointers, each to the one before, ending at "a"
+        let mut buf = BytesMut::from(&b"\x01a\x00\xc0\x00"[..]);
+        for i in 0..39 {
+            buf.extend_from_slice(&[0xc0, 3 + 2 * i]);
+        }
+        let mut codec = DnsMessageCodec::new(false);
+        codec.offset = 81;
+        assert_eq!(codec.next_name(&buf), Err(DnsCodecError::TooManyJumps));
+        let mut codec = codec.limits(DecodeLimits {
+            max_jumps: 40,
+            ..Default::default()
+        });
+        codec.offset = 81;
+        assert_eq!(codec.next_name(&buf), Ok(vec!["a"].into()));
+
+        let limits = DecodeLimits {
+            max_size: 100,
+            max_records: 2,
+            max_labels: 3,
+            ..Default::default()
+        };
+        let decode = |buf: &[u8]| {
+            DnsMessageCodec::new(false)
+                .limits(limits)
+                .decode(&mut BytesMut::from(buf))
+                .map_err(|e| e.into_inner().unwrap().downcast::<DnsCodecError>().unwrap())
+        };
+        let header = |qdcount| vec![0, 0, 0, 0, 0, qdcount, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            decode(&header(3)).unwrap_err(),
+            Box::new(DnsCodecError::TooManyRecords(3))
+        );
+        let mut long = header(0);
+        long.resize(101, 0);
+        assert_eq!(
+            decode(&long).unwrap_err(),
+            Box::new(DnsCodecError::TooLong(101))
+        );
+        let mut deep = header(1);
+        deep.extend_from_slice(b"\x01a\x01b\x01c\x01d\x00\x00\x01\x00\x01");
+        assert_eq!(
+            decode(&deep).unwrap_err(),
+            Box::new(DnsCodecError::TooManyLabels)
+        );
+        deep.drain(12..14);
+        assert!(decode(&deep).is_ok());
+    }
+
+    fn name() -> impl Strategy<Value = DomainName> {
+        vec("[a-zA-Z0-9_-]{1,16}", 1..5).prop_map(DomainName::from)
+    }
+
+    fn text() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..40)
+    }
+
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..32)
+    }
+
+    fn rdata() -> impl Strategy<Value = DnsRRData> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(|b| DnsRRData::A(b.into())),
+            any::<[u8; 16]>().prop_map(|b| DnsRRData::AAAA(b.into())),
+            (any::<u16>(), name()).prop_map(|(pref, name)| DnsRRData::MX(pref, name)),
+            name().prop_map(DnsRRData::CNAME),
+            vec(text(), 1..4).prop_map(DnsRRData::TXT),
+            (name(), name(), any::<(u32, u32, u32, u32, u32)>())
+                .prop_map(|(m, r, t)| { DnsRRData::SOA(m, r, t.0, t.1, t.2, t.3, t.4) }),
+            name().prop_map(DnsRRData::NS),
+            name().prop_map(DnsRRData::PTR),
+            (text(), text()).prop_map(|(cpu, os)| DnsRRData::HINFO(cpu, os)),
+            (any::<u8>(), vec(any::<u8>(), 1..16), bytes())
+                .prop_map(|(flags, tag, value)| DnsRRData::CAA(flags, tag, value)),
+            (any::<(u16, u16, u16)>(), name())
+                .prop_map(|(t, target)| DnsRRData::SRV(t.0, t.1, t.2, target)),
+            (any::<(u16, u16)>(), text(), text(), text(), name()).prop_map(
+                |((order, pref), flags, services, regexp, replacement)| {
+                    DnsRRData::NAPTR(order, pref, flags, services, regexp, replacement)
+                }
+            ),
+            (any::<(u16, u8, u8)>(), bytes())
+                .prop_map(|(t, digest)| DnsRRData::DS(t.0, t.1, t.2, digest)),
+            (any::<(u16, u8, u8, u32, u32, u32, u16)>(), name(), bytes()).prop_map(
+                |(t, signer, signature)| {
+                    DnsRRData::RRSIG(t.0, t.1, t.2, t.3, t.4, t.5, t.6, signer, signature)
+                }
+            ),
+            (name(), bytes()).prop_map(|(next, types)| DnsRRData::NSEC(next, types)),
+            (any::<(u16, u8, u8)>(), bytes())
+                .prop_map(|(t, key)| DnsRRData::DNSKEY(t.0, t.1, t.2, key)),
+            (any::<(u8, u8, u16)>(), bytes(), bytes(), bytes()).prop_map(
+                |(t, salt, next, types)| DnsRRData::NSEC3(t.0, t.1, t.2, salt, next, types)
+            ),
+            // Private use types, which uind has no variant for
+            (65280u16.., vec(any::<u8>(), 1..32))
+                .prop_map(|(rtype, data)| DnsRRData::Unknown(rtype, data)),
+        ]
+    }
+
+    fn record() -> impl Strategy<Value = DnsResourceRecord> {
+        (name(), rdata(), any::<u32>()).prop_map(|(name, data, ttl)| DnsResourceRecord {
+            name,
+            rtype: data.rtype(),
+            rclass: DnsClass::Internet,
+            ttl,
+            data,
+        })
+    }
+
+    fn question() -> impl Strategy<Value = DnsQuestion> {
+        let qtypes = vec![
+            DnsType::A,
+            DnsType::AAAA,
+            DnsType::MX,
+            DnsType::TXT,
+            DnsType::PTR,
+            DnsType::SOA,
+            DnsType::AXFR,
+            DnsType::Any,
+        ];
+        let qclasses = vec![DnsClass::Internet, DnsClass::Chaos];
+        (name(), select(qtypes), select(qclasses)).prop_map(|(qname, qtype, qclass)| DnsQuestion {
+            qname,
+            qtype,
+            qcode: 0,
+            qclass,
+        })
+    }
+
+    fn header() -> impl Strategy<Value = DnsHeader> {
+        let opcodes = vec![DnsOpcode::Query, DnsOpcode::Notify, DnsOpcode::Update];
+        let rcodes = vec![
+            DnsRcode::NoErrorCondition,
+            DnsRcode::ServerFailure,
+            DnsRcode::NameError,
+            DnsRcode::Refused,
+            DnsRcode::NotAuth,
+            DnsRcode::BadVersion,
+            DnsRcode::BadCookie,
+        ];
+        (
+            any::<(u16, bool, bool, bool, bool)>(),
+            select(opcodes),
+            select(rcodes),
+        )
+            .prop_map(|((id, query, aa, rd, ra), opcode, rcode)| DnsHeader {
+                id,
+                query,
+                opcode,
+                authoritative: aa,
+                truncated: false,
+                recur_desired: rd,
+                recur_available: ra,
+                rcode,
+            })
+    }
+
+    fn edns() -> impl Strategy<Value = Edns> {
+        let option = (0..EDNS_PADDING, bytes()).prop_map(|(code, data)| EdnsOption { code, data });
+        (512u16.., any::<(u8, u16)>(), vec(option, 0..3)).prop_map(
+            |(udp_size, (version, flags), options)| Edns {
+                udp_size,
+                version,
+                flags,
+                options,
+            },
+        )
+    }
+
+    fn message() -> impl Strategy<Value = DnsMessage> {
+        let records = || vec(record(), 0..20);
+        (
+            header(),
+            vec(question(), 0..3),
+            (records(), records(), records()),
+            proptest::option::of(edns()),
+        )
+            .prop_map(|(mut header, question, sections, edns)| {
+                // Extended RCODEs need the OPT to travel in
+                if edns.is_none() && header.rcode as u16 > 0xf {
+                    header.rcode = DnsRcode::ServerFailure;
+                }
+                DnsMessage {
+                    header,
+                    question,
+                    answer: sections.0,
+                    authority: sections.1,
+                    additional: sections.2,
+                    edns,
+                    tsig: None,
+                }
+            })
+    }
+
+    /// Replace the owner names equal to the first question's name with a
+    /// pointer to it, as a compressing encoder would.
+    fn compress(message: &DnsMessage, packet: &[u8]) -> Vec<u8> {
+        let qname = &message.question[0].qname;
+        let mut out = packet[..12].to_vec();
+        let mut i = 12;
+        for question in &message.question {
+            let len = name_length(&question.qname) as usize + 4;
+            out.extend_from_slice(&packet[i..i + len]);
+            i += len;
+        }
+        let rrs = message
+            .answer
+            .iter()
+            .chain(&message.authority)
+            .chain(&message.additional);
+        for rr in rrs {
+            let len = name_length(&rr.name) as usize;
+            if rr.name == *qname {
+                out.extend_from_slice(&[0xc0, 12]);
+            } else {
+                out.extend_from_slice(&packet[i..i + len]);
+            }
+            i += len;
+            let rdlen = (packet[i + 8] as usize) << 8 | packet[i + 9] as usize;
+            out.extend_from_slice(&packet[i..i + 10 + rdlen]);
+            i += 10 + rdlen;
+        }
+        out.extend_from_slice(&packet[i..]);
+        out
+    }
+
+    proptest! {
+        #[test]
+        fn test_tcp_round_trip(message in message()) {
+            let mut codec = DnsMessageCodec::new(true);
+            let mut buf = BytesMut::new();
+            codec.encode(message.clone(), &mut buf).expect("encode");
+            let decoded = codec.decode(&mut buf).expect("decode");
+            prop_assert_eq!(decoded, Some(message));
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn test_udp_round_trip(message in message()) {
+            let mut codec = DnsMessageCodec::new(false);
+            let mut buf = BytesMut::new();
+            codec.encode(message.clone(), &mut buf).expect("encode");
+            let limit = message.edns.as_ref().map_or(512, |edns| edns.udp_size as usize);
+            prop_assert!(buf.len() <= limit.max(512));
+            let decoded = codec.decode(&mut buf).expect("decode").expect("complete");
+            if decoded.header.truncated {
+                // Whole records are dropped from the end
+                prop_assert_eq!(&decoded.question, &message.question);
+                prop_assert!(message.answer.starts_with(&decoded.answer));
+                prop_assert!(message.authority.starts_with(&decoded.authority));
+                prop_assert!(message.additional.starts_with(&decoded.additional));
+                prop_assert_eq!(&decoded.edns, &message.edns);
+            } else {
+                prop_assert_eq!(decoded, message);
+            }
+        }
+
+        #[test]
+        fn test_compressed_round_trip(mut message in message(), question in question()) {
+            message.question.insert(0, question);
+            let qname = message.question[0].qname.clone();
+            for rr in message.answer.iter_mut().chain(&mut message.additional).step_by(2) {
+                rr.name = qname.clone();
+            }
+            let mut buf = BytesMut::new();
+            DnsMessageCodec::new(true).encode(message.clone(), &mut buf).expect("encode");
+            let packet = compress(&message, &buf[2..]);
+            if !message.answer.is_empty() || !message.additional.is_empty() {
+                prop_assert!(packet.len() < buf.len() - 2);
+            }
+            let decoded = DnsMessageCodec::new(false)
+                .strict(true)
+                .decode(&mut BytesMut::from(packet))
+                .expect("decode");
+            prop_assert_eq!(decoded, Some(message));
+        }
+    }
 }
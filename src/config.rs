@@ -0,0 +1,238 @@
+//! The TOML config file, an alternative to the command line for setups
+//! with more than a few settings:
+//!
+//! ```toml
+//! [listen]
+//! address = ["127.0.0.1:5353", "[::1]:5353"]
+//!
+//! [upstream]
+//! servers = ["1.1.1.1:53", "8.8.8.8:53"]
+//! strategy = "fastest"
+//!
+//! [local]
+//! file = "dnsrelay.txt"
+//! zones = ["home.arpa"]
+//!
+//! [cache]
+//! size = 50000
+//! min_ttl = 60
+//!
+//! [log]
+//! level = "info"
+//! ```
+//!
+//! Values are written like the arguments of the corresponding options, as
+//! strings or, where those are numbers, integers.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use toml::Value;
+
+use crate::memory;
+use crate::message::{DomainName, NamePolicy};
+use crate::upstream::{Strategy, UpstreamServer};
+
+/// The settings of a config file.  Those it doesn't have are `None` or
+/// empty.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub doh_listen: Option<SocketAddr>,
+    pub doh_cert: Option<PathBuf>,
+    pub doh_key: Option<PathBuf>,
+    pub doh_client_ca: Option<PathBuf>,
+    pub admin_listen: Option<SocketAddr>,
+    pub admin_token_file: Option<PathBuf>,
+    pub control: Option<PathBuf>,
+
+    pub upstreams: Vec<UpstreamServer>,
+    pub strategy: Option<Strategy>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    pub probe_name: Option<DomainName>,
+
+    pub conf_file: Option<String>,
+    pub zone_files: Vec<String>,
+    pub hosts_files: Vec<String>,
+    pub blocklists: Vec<String>,
+    pub allowlists: Vec<String>,
+    pub local_zones: Vec<DomainName>,
+    pub name_policy: Option<NamePolicy>,
+    pub watch: Option<Duration>,
+
+    pub cache_size: Option<usize>,
+    pub cache_memory: Option<usize>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: Option<String>,
+}
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Config, String> {
+        let root: Value = s
+            .parse()
+            .map_err(|e| format!("Error parsing config file: {}", e))?;
+        let mut config = Config::default();
+        for (section, table) in root.as_table().unwrap() {
+            let table = table
+                .as_table()
+                .ok_or_else(|| format!("{} must be a section", section))?;
+            for (key, value) in table {
+                let name = format!("{}.{}", section, key);
+                let value = Setting(&name, value);
+                match (section.as_str(), key.as_str()) {
+                    ("listen", "address") => config.listen = value.list()?,
+                    ("listen", "doh") => config.doh_listen = Some(value.parse()?),
+                    ("listen", "doh_cert") => config.doh_cert = Some(value.parse()?),
+                    ("listen", "doh_key") => config.doh_key = Some(value.parse()?),
+                    ("listen", "doh_client_ca") => config.doh_client_ca = Some(value.parse()?),
+                    ("listen", "admin") => config.admin_listen = Some(value.parse()?),
+                    ("listen", "admin_token_file") => {
+                        config.admin_token_file = Some(value.parse()?)
+                    }
+                    ("listen", "control") => config.control = Some(value.parse()?),
+                    ("upstream", "servers") => config.upstreams = value.list()?,
+                    ("upstream", "strategy") => config.strategy = Some(value.parse()?),
+                    ("upstream", "timeout") => config.timeout = Some(value.millis()?),
+                    ("upstream", "retries") => config.retries = Some(value.parse()?),
+                    ("upstream", "probe_name") => config.probe_name = Some(value.name()?),
+                    ("local", "file") => config.conf_file = Some(value.parse()?),
+                    ("local", "zone_files") => config.zone_files = value.list()?,
+                    ("local", "hosts") => config.hosts_files = value.list()?,
+                    ("local", "blocklists") => config.blocklists = value.list()?,
+                    ("local", "allowlists") => config.allowlists = value.list()?,
+                    ("local", "zones") => {
+                        config.local_zones = value
+                            .list::<String>()?
+                            .iter()
+                            .map(|zone| DomainName::from_idn(zone))
+                            .collect::<Result<_, _>>()?
+                    }
+                    ("local", "names") => config.name_policy = Some(value.parse()?),
+                    ("local", "watch") => config.watch = Some(value.millis()?),
+                    ("cache", "size") => config.cache_size = Some(value.parse()?),
+                    ("cache", "memory") => config.cache_memory = Some(value.size()?),
+                    ("cache", "min_ttl") => config.min_ttl = Some(value.parse()?),
+                    ("cache", "max_ttl") => config.max_ttl = Some(value.parse()?),
+                    ("log", "level") => {
+                        let level: String = value.parse()?;
+                        if !LOG_LEVELS.contains(&level.as_str()) {
+                            return Err(format!("Unknown log level {}", level));
+                        }
+                        config.log_level = Some(level);
+                    }
+                    _ => return Err(format!("Unknown setting {}", name)),
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A value, with its name for errors.
+struct Setting<'a>(&'a str, &'a Value);
+
+impl<'a> Setting<'a> {
+    fn parse<T>(&self) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_value(self.1)
+    }
+
+    /// A value, or an array of values.
+    fn list<T>(&self) -> Result<Vec<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.1 {
+            Value::Array(values) => values.iter().map(|v| self.parse_value(v)).collect(),
+            value => Ok(vec![self.parse_value(value)?]),
+        }
+    }
+
+    fn millis(&self) -> Result<Duration, String> {
+        self.parse().map(Duration::from_millis)
+    }
+
+    /// A size like `64M`, or a number of bytes.
+    fn size(&self) -> Result<usize, String> {
+        let size: String = self.parse()?;
+        memory::parse_size(&size).ok_or_else(|| format!("{}: invalid size {}", self.0, size))
+    }
+
+    fn name(&self) -> Result<DomainName, String> {
+        DomainName::from_idn(&self.parse::<String>()?)
+    }
+
+    fn parse_value<T>(&self, value: &Value) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let s = match value {
+            Value::String(s) => s.clone(),
+            Value::Integer(n) => n.to_string(),
+            _ => return Err(format!("{} must be a string or a number", self.0)),
+        };
+        s.parse().map_err(|e| format!("{}: {}", self.0, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = r#"
+[listen]
+address = ["127.0.0.1:5353", "[::1]:5353"]
+
+[upstream]
+servers = ["1.1.1.1:53", "8.8.8.8:53/3"]
+strategy = "weighted"
+timeout = 500
+
+[local]
+file = "dnsrelay.txt"
+hosts = "/etc/hosts"
+zones = ["home.arpa"]
+
+[cache]
+size = 50000
+memory = "64M"
+
+[log]
+level = "debug"
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.upstreams.len(), 2);
+        assert_eq!(config.strategy, Some(Strategy::Weighted));
+        assert_eq!(config.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.hosts_files, vec![String::from("/etc/hosts")]);
+        assert_eq!(config.local_zones, vec![DomainName::from("home.arpa")]);
+        assert_eq!(config.cache_size, Some(50000));
+        assert_eq!(config.cache_memory, Some(64 << 20));
+        assert_eq!(config.log_level, Some(String::from("debug")));
+        assert_eq!(config.min_ttl, None);
+
+        assert!("[cache]\nsize = -1".parse::<Config>().is_err());
+        assert!("[cache]\nsizes = 1".parse::<Config>().is_err());
+        assert!("[log]\nlevel = \"loud\"".parse::<Config>().is_err());
+        assert!("listen = 1".parse::<Config>().is_err());
+    }
+}
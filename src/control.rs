@@ -0,0 +1,209 @@
+//! The control socket: a Unix socket to manage a running server through,
+//! with `uind ctl`, where even a loopback HTTP port is unwelcome.
+//!
+//! A client sends one command per connection, as a line, and reads the
+//! reply until the server closes the connection:
+//!
+//! ```text
+//! flush [NAME]   drop cached answers, or only those of NAME (with `cache`)
+//! stats          query counters, memory, cache and upstream servers
+//! reload         load the local files again, like SIGHUP
+//! ```
+//!
+//! Failed commands are answered with `error: ` and the reason.
+
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tokio::prelude::*;
+
+#[cfg(feature = "cache")]
+use crate::cache::ResponseCache;
+#[cfg(feature = "cache")]
+use crate::message::DomainName;
+use crate::stats::Stats;
+use crate::upstream::Upstreams;
+
+/// Longest command accepted.
+const MAX_COMMAND: u64 = 1024;
+
+/// What the control socket manages of a running server.
+pub(crate) struct Controlled {
+    pub stats: Arc<Stats>,
+    #[cfg(feature = "cache")]
+    pub cache: Arc<ResponseCache>,
+    pub upstreams: Arc<Upstreams>,
+    /// Reloads the local files, replying with the number of local names or
+    /// why they couldn't be loaded.
+    pub reload: mpsc::UnboundedSender<oneshot::Sender<Result<usize, String>>>,
+}
+
+/// Listen on `path`, replacing a socket no server listens on any more, and
+/// only letting the owner connect.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let context =
+        |e: io::Error| io::Error::new(e.kind(), format!("error binding {}: {}", path.display(), e));
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+            fs::remove_file(path).map_err(context)?;
+        }
+    }
+    let listener = UnixListener::bind(path).map_err(context)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(context)?;
+    Ok(listener)
+}
+
+/// Serve commands on `listener`.
+pub(crate) fn serve(
+    listener: UnixListener,
+    controlled: Controlled,
+) -> impl Future<Item = (), Error = ()> {
+    let controlled = Arc::new(controlled);
+    listener
+        .incoming()
+        .map_err(|e| error!("error in control socket: {}", e))
+        .for_each(move |stream| {
+            let controlled = controlled.clone();
+            let session =
+                tokio::io::read_until(BufReader::new(stream.take(MAX_COMMAND)), b'\n', vec![])
+                    .map_err(|e| debug!("Control client: {}", e))
+                    .and_then(move |(reader, line)| {
+                        let stream = reader.into_inner().into_inner();
+                        let line = String::from_utf8_lossy(&line).into_owned();
+                        respond(&controlled, &line).and_then(|reply| {
+                            tokio::io::write_all(stream, reply)
+                                .map(|_| ())
+                                .map_err(|e| debug!("Control client: {}", e))
+                        })
+                    });
+            tokio::spawn(session);
+            Ok(())
+        })
+}
+
+fn respond(controlled: &Controlled, line: &str) -> impl Future<Item = String, Error = ()> {
+    let mut words = line.split_whitespace();
+    let reply = match (words.next(), words.next(), words.next()) {
+        #[cfg(feature = "cache")]
+        (Some("flush"), name, None) => {
+            let name = match name.map(DomainName::from_idn) {
+                Some(Ok(name)) => Some(name),
+                Some(Err(e)) => return Either::A(future::ok(format!("error: {}\n", e))),
+                None => None,
+            };
+            let flushed = controlled.cache.flush(name.as_ref());
+            info!("Control: flushed {} cached answers", flushed);
+            format!("Flushed {} cached answers\n", flushed)
+        }
+        (Some("stats"), None, None) => stats(controlled),
+        (Some("reload"), None, None) => {
+            let (reply, reloaded) = oneshot::channel();
+            if controlled.reload.unbounded_send(reply).is_err() {
+                return Either::A(future::ok(String::from("error: Nothing to reload\n")));
+            }
+            return Either::B(reloaded.then(|result| {
+                Ok(match result {
+                    Ok(Ok(names)) => format!("Reloaded {} local names\n", names),
+                    Ok(Err(e)) => format!("error: {}\n", e),
+                    Err(_) => String::from("error: Reloading was cancelled\n"),
+                })
+            }));
+        }
+        (Some(command), _, _) => format!("error: Unknown command {}\n", command),
+        (None, _, _) => String::from("error: No command\n"),
+    };
+    Either::A(future::ok(reply))
+}
+
+fn stats(controlled: &Controlled) -> String {
+    let mut lines = controlled.stats.summary();
+    #[cfg(feature = "cache")]
+    lines.push(format!(
+        "Cache: entries={} bytes={} hits={} misses={} evictions={}",
+        controlled.cache.len(),
+        controlled.cache.bytes(),
+        controlled.cache.hits(),
+        controlled.cache.misses(),
+        controlled.cache.evictions(),
+    ));
+    for (addr, rtt, up) in controlled.upstreams.status() {
+        let state = if up { "up" } else { "down" };
+        lines.push(match rtt {
+            Some(rtt) => format!("Upstream {}: {} rtt={:?}", addr, state, rtt),
+            None => format!("Upstream {}: {} rtt unknown", addr, state),
+        });
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Send `command` to the server listening on `path`, returning its reply,
+/// or the error it answered with.
+pub fn send(path: &Path, command: &str) -> Result<String, String> {
+    let context = |e: io::Error| format!("Error talking to {}: {}", path.display(), e);
+    let mut stream = UnixStream::connect(path).map_err(context)?;
+    // Reloading can take a while
+    stream
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .map_err(context)?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .map_err(context)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).map_err(context)?;
+    if reply.starts_with("error: ") {
+        Err(reply["error: ".len()..].trim_end().to_owned())
+    } else {
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Strategy;
+
+    #[test]
+    fn test_respond() {
+        let (reload, reloads) = mpsc::unbounded();
+        let controlled = Controlled {
+            stats: Arc::new(Stats::default()),
+            #[cfg(feature = "cache")]
+            cache: Arc::new(ResponseCache::new(10, 0)),
+            upstreams: Arc::new(Upstreams::new(
+                vec!["192.0.2.53:53"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into()],
+                Strategy::First,
+            )),
+            reload,
+        };
+        let ask = |line| respond(&controlled, line).wait().unwrap();
+
+        if cfg!(feature = "cache") {
+            assert_eq!(ask("flush\n"), "Flushed 0 cached answers\n");
+            assert_eq!(ask("flush example.com"), "Flushed 0 cached answers\n");
+            assert!(ask("stats\n").contains("\nCache: entries=0 "));
+        }
+        let stats = ask("stats\n");
+        assert!(stats.starts_with("Stable: queries=0 "));
+        assert!(stats.ends_with("Upstream 192.0.2.53:53: up rtt unknown\n"));
+        assert_eq!(ask("halt\n"), "error: Unknown command halt\n");
+        assert_eq!(ask("\n"), "error: No command\n");
+        assert_eq!(ask("stats now\n"), "error: Unknown command stats\n");
+
+        let reloaded = respond(&controlled, "reload\n");
+        let (reply, reloads) = reloads.into_future().wait().ok().unwrap();
+        reply.unwrap().send(Ok(3)).unwrap();
+        assert_eq!(reloaded.wait().unwrap(), "Reloaded 3 local names\n");
+        drop(reloads);
+        assert_eq!(ask("reload\n"), "error: Nothing to reload\n");
+    }
+}
@@ -0,0 +1,121 @@
+//! Running in the background for traditional init scripts: detaching from
+//! the terminal, and a PID file to signal the server by.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+/// A process detached from its terminal, whose parent waits for it to be
+/// serving before exiting.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Detached {
+    ready: fs::File,
+}
+
+/// Fork into the background, in a new session.  This must happen before
+/// any thread is started, as only the calling thread survives the fork.
+///
+/// The parent waits until the child is `ready`, then exits with 0, or with
+/// 1 if the child exits first, which has reported why on stdout already.
+#[cfg(unix)]
+pub fn detach() -> io::Result<Detached> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (mut waiting, ready) = (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]));
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => {
+                drop(ready);
+                let mut byte = [0];
+                let status = match waiting.read(&mut byte) {
+                    Ok(1) => 0,
+                    _ => 1,
+                };
+                process::exit(status);
+            }
+        }
+        drop(waiting);
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Not being a session leader, the daemon can't acquire a terminal
+        match libc::fork() {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(Detached { ready }),
+            _ => libc::_exit(0),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Detached {
+    /// Let the parent exit, and let go of the terminal.  Nothing is logged
+    /// from now on, as stderr is closed.
+    pub fn ready(mut self) -> io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let null = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..3 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.ready.write_all(&[0])
+    }
+}
+
+/// A file with the server's process ID, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> io::Result<PidFile> {
+        fs::write(&path, format!("{}\n", process::id())).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Can't write PID file {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Can't remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("uind-test-{}.pid", process::id()));
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
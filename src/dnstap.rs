@@ -0,0 +1,350 @@
+//! dnstap logging: the messages uind exchanges with clients and upstream
+//! servers, as protobuf messages in Frame Streams over a Unix socket, for
+//! readers like `fstrm_capture`, `dnstap-read` and passive DNS collectors.
+//!
+//! See https://dnstap.info and https://github.com/farsightsec/fstrm.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::codec::Encoder;
+
+use crate::codec::DnsMessageCodec;
+use crate::message::DnsMessage;
+
+/// The types of dnstap messages uind writes: as a forwarder, it is the
+/// server of its clients and the client of its upstreams.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+impl Kind {
+    fn is_query(self) -> bool {
+        self == Kind::ClientQuery || self == Kind::ForwarderQuery
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Udp = 1,
+    Tcp = 2,
+    Doh = 4,
+}
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+/// Events waiting to be written, beyond which they are dropped.
+const BACKLOG: usize = 10000;
+
+/// Writes events to a reader on a thread of its own.  Cloned writers share
+/// the connection, which is closed when the last of them is dropped.
+#[derive(Clone)]
+pub struct Dnstap {
+    path: PathBuf,
+    identity: Option<String>,
+    events: SyncSender<Vec<u8>>,
+}
+
+impl Dnstap {
+    /// Write to the reader listening on the Unix socket at `path`,
+    /// connecting again whenever it goes away.  Events are dropped while
+    /// there is none, or it falls behind.
+    pub fn connect(path: PathBuf, identity: Option<String>) -> Dnstap {
+        let (events, pending) = mpsc::sync_channel(BACKLOG);
+        let socket = path.clone();
+        thread::spawn(move || write_frames(&socket, pending));
+        Dnstap {
+            path,
+            identity,
+            events,
+        }
+    }
+
+    /// Log `message`, which `query_addr` sent to `response_addr` for a
+    /// query, or the other way round for a response.
+    pub fn log(
+        &self,
+        kind: Kind,
+        protocol: Protocol,
+        query_addr: Option<SocketAddr>,
+        response_addr: Option<SocketAddr>,
+        message: &DnsMessage,
+    ) {
+        let mut wire = BytesMut::new();
+        if DnsMessageCodec::new(false)
+            .encode(message.clone(), &mut wire)
+            .is_err()
+        {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut event = vec![];
+        put_varint_field(&mut event, 1, kind as u64);
+        if let Some(addr) = query_addr.or(response_addr) {
+            put_varint_field(&mut event, 2, if addr.is_ipv4() { 1 } else { 2 });
+        }
+        put_varint_field(&mut event, 3, protocol as u64);
+        if let Some(addr) = query_addr {
+            put_bytes_field(&mut event, 4, &ip_bytes(addr.ip()));
+        }
+        if let Some(addr) = response_addr {
+            put_bytes_field(&mut event, 5, &ip_bytes(addr.ip()));
+        }
+        if let Some(addr) = query_addr {
+            put_varint_field(&mut event, 6, u64::from(addr.port()));
+        }
+        if let Some(addr) = response_addr {
+            put_varint_field(&mut event, 7, u64::from(addr.port()));
+        }
+        let (sec, nsec, data) = if kind.is_query() {
+            (8, 9, 10)
+        } else {
+            (12, 13, 14)
+        };
+        put_varint_field(&mut event, sec, now.as_secs());
+        put_fixed32_field(&mut event, nsec, now.subsec_nanos());
+        put_bytes_field(&mut event, data, &wire);
+
+        let mut frame = vec![];
+        if let Some(ref identity) = self.identity {
+            put_bytes_field(&mut frame, 1, identity.as_bytes());
+        }
+        put_bytes_field(
+            &mut frame,
+            2,
+            concat!("uind ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        put_bytes_field(&mut frame, 14, &event);
+        // Type MESSAGE
+        put_varint_field(&mut frame, 15, 1);
+        let _ = self.events.try_send(frame);
+    }
+}
+
+impl fmt::Debug for Dnstap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dnstap({})", self.path.display())
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, n: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, n);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, n: u32) {
+    put_varint(buf, field << 3 | 5);
+    buf.put_u32_le(n);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Frame Streams control frames.
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+fn write_frames(path: &Path, events: Receiver<Vec<u8>>) {
+    let mut warned = false;
+    loop {
+        let mut stream = match handshake(path) {
+            Ok(stream) => {
+                info!("dnstap: writing to {}", path.display());
+                warned = false;
+                stream
+            }
+            Err(e) => {
+                if !warned {
+                    warn!("dnstap: can't write to {}: {}", path.display(), e);
+                    warned = true;
+                }
+                // Events while there is no reader are dropped
+                loop {
+                    match events.try_recv() {
+                        Ok(_) => {}
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        loop {
+            let frame = match events.recv() {
+                Ok(frame) => frame,
+                Err(_) => {
+                    // No more events: say goodbye
+                    match write_control(&mut stream, CONTROL_STOP, false)
+                        .and_then(|_| read_control(&mut stream))
+                    {
+                        Ok(CONTROL_FINISH) => {}
+                        _ => debug!("dnstap: {} didn't finish", path.display()),
+                    }
+                    return;
+                }
+            };
+            let mut data = Vec::with_capacity(4 + frame.len());
+            data.put_u32_be(frame.len() as u32);
+            data.extend_from_slice(&frame);
+            let written = stream.write_all(&data);
+            if let Err(e) = written {
+                warn!("dnstap: error writing to {}: {}", path.display(), e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn handshake(path: &Path) -> io::Result<std::os::unix::net::UnixStream> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write_control(&mut stream, CONTROL_READY, true)?;
+    if read_control(&mut stream)? != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "reader didn't accept dnstap",
+        ));
+    }
+    write_control(&mut stream, CONTROL_START, true)?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn handshake(_path: &Path) -> io::Result<std::net::TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "dnstap is only supported on Unix",
+    ))
+}
+
+fn write_control<W: Write>(stream: &mut W, control: u32, content_type: bool) -> io::Result<()> {
+    let mut frame = vec![];
+    frame.put_u32_be(control);
+    if content_type {
+        frame.put_u32_be(FIELD_CONTENT_TYPE);
+        frame.put_u32_be(CONTENT_TYPE.len() as u32);
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut data = vec![];
+    // An escape, as no data frame is empty
+    data.put_u32_be(0);
+    data.put_u32_be(frame.len() as u32);
+    data.extend_from_slice(&frame);
+    stream.write_all(&data)
+}
+
+/// Read a control frame, returning its type.
+fn read_control<R: Read>(stream: &mut R) -> io::Result<u32> {
+    let mut word = [0; 4];
+    stream.read_exact(&mut word)?;
+    if Cursor::new(word).get_u32_be() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    stream.read_exact(&mut word)?;
+    let mut frame = vec![0; Cursor::new(word).get_u32_be() as usize];
+    stream.read_exact(&mut frame)?;
+    if frame.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short control frame",
+        ));
+    }
+    Ok(Cursor::new(frame).get_u32_be())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mut buf = vec![];
+        put_varint_field(&mut buf, 1, 300);
+        put_fixed32_field(&mut buf, 9, 1);
+        put_bytes_field(&mut buf, 14, b"ab");
+        assert_eq!(
+            buf,
+            [0x08, 0xac, 0x02, 0x4d, 1, 0, 0, 0, 0x72, 2, b'a', b'b']
+        );
+
+        let mut control = vec![];
+        write_control(&mut control, CONTROL_READY, true).unwrap();
+        assert_eq!(control.len(), 12 + 4 + 4 + CONTENT_TYPE.len());
+        assert_eq!(read_control(&mut &control[..]).unwrap(), CONTROL_READY);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dnstap() {
+        use crate::message::DnsType;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("uind-dnstap-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let dnstap = Dnstap::connect(path.clone(), Some("test".into()));
+        let (mut reader, _) = listener.accept().unwrap();
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_READY);
+        write_control(&mut reader, CONTROL_ACCEPT, true).unwrap();
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_START);
+
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        dnstap.log(
+            Kind::ClientQuery,
+            Protocol::Udp,
+            Some("192.0.2.1:5353".parse().unwrap()),
+            None,
+            &query,
+        );
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut frame = vec![0; Cursor::new(len).get_u32_be() as usize];
+        reader.read_exact(&mut frame).unwrap();
+        // Identity first, and type MESSAGE last
+        assert_eq!(&frame[..6], b"\x0a\x04test");
+        assert_eq!(&frame[frame.len() - 2..], [0x78, 1]);
+
+        drop(dnstap);
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_STOP);
+        write_control(&mut reader, CONTROL_FINISH, false).unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,560 @@
+//! DNS-over-HTTPS (RFC 8484): upstreams, to which wire-format queries are
+//! POSTed over HTTP/2, and a listener for clients such as browsers.
+
+use bytes::BytesMut;
+use futures::future::{self, Either, FutureResult};
+use hyper::client::connect::dns::{Name, Resolve};
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use rustls::internal::pemfile;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::str;
+use std::sync::Arc;
+use std::vec;
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio_rustls::TlsAcceptor;
+
+use crate::codec::{DecodeLimits, DnsMessageCodec};
+use crate::http::{json_string, param, percent_decode, status_response};
+use crate::message::*;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+const DNS_JSON: &str = "application/dns-json";
+
+/// Where the listener answers queries in wire format.
+const PATH: &str = "/dns-query";
+
+/// Where it answers them in JSON.
+const JSON_PATH: &str = "/resolve";
+
+/// Where a DoH URL's server is: its host name is resolved once, with the
+/// system's resolver, which may well be this relay.
+pub fn locate(url: &str) -> Result<SocketAddr, String> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| format!("Error parsing URL {}: {}", url, e))?;
+    if uri.scheme_part().map(|scheme| scheme.as_str()) != Some("https") {
+        return Err(format!("{} is not an https URL", url));
+    }
+    let host = uri.host().ok_or(format!("{} has no host", url))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_part().map_or(443, |port| port.as_u16());
+    (host, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(format!("Can't resolve {}", host))
+}
+
+/// Connects to the hosts of the URLs at the addresses they were located
+/// at, instead of resolving them again.
+#[derive(Clone)]
+struct Bootstrap(Arc<HashMap<String, IpAddr>>);
+
+impl Resolve for Bootstrap {
+    type Addrs = vec::IntoIter<IpAddr>;
+    type Future = FutureResult<Self::Addrs, io::Error>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        future::result(match self.0.get(name.as_str()) {
+            Some(&ip) => Ok(vec![ip].into_iter()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown DoH host {}", name),
+            )),
+        })
+    }
+}
+
+/// An HTTP/2 client for DoH servers, which keeps connections open between
+/// queries.
+pub struct Client {
+    client: hyper::Client<HttpsConnector<HttpConnector<Bootstrap>>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client").finish()
+    }
+}
+
+impl Client {
+    /// A client for servers at the given URLs and addresses.
+    pub fn new(servers: &[(String, SocketAddr)]) -> Client {
+        let hosts = servers
+            .iter()
+            .filter_map(|(url, addr)| {
+                let uri: Uri = url.parse().ok()?;
+                Some((uri.host()?.to_owned(), addr.ip()))
+            })
+            .collect();
+        let mut http = HttpConnector::new_with_resolver(Bootstrap(Arc::new(hosts)));
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        let mut tls = rustls::ClientConfig::new();
+        tls.root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        tls.alpn_protocols = vec![b"h2".to_vec()];
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build(HttpsConnector::from((http, tls)));
+        Client { client }
+    }
+
+    /// POST `query` to `url`.  HTTP errors are answered with SERVFAIL.
+    pub fn exchange(
+        &self,
+        url: &str,
+        query: DnsMessage,
+    ) -> impl Future<Item = DnsMessage, Error = io::Error> {
+        let mut buf = BytesMut::new();
+        let servfail = DnsMessage::response_to(&query).with_rcode(DnsRcode::ServerFailure);
+        let request = DnsMessageCodec::new(false)
+            .encode(query, &mut buf)
+            .and_then(|_| {
+                Request::post(url)
+                    .header(CONTENT_TYPE, DNS_MESSAGE)
+                    .header(ACCEPT, DNS_MESSAGE)
+                    .body(Body::from(buf.freeze()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            });
+        let url = url.to_owned();
+        let client = self.client.clone();
+        future::result(request)
+            .and_then(move |request| {
+                client
+                    .request(request)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            })
+            .and_then(move |response| {
+                if response.status() != StatusCode::OK {
+                    warn!("{} answered HTTP {}", url, response.status());
+                    return Either::A(future::ok(servfail));
+                }
+                Either::B(
+                    response
+                        .into_body()
+                        .concat2()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                        .and_then(|body| {
+                            DnsMessageCodec::new(false)
+                                .decode(&mut BytesMut::from(&body[..]))?
+                                .ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::UnexpectedEof, "Empty response")
+                                })
+                        }),
+                )
+            })
+    }
+}
+
+/// The TLS side of a listener, with a certificate chain and a PKCS #8 or
+/// RSA private key from PEM files.  With `client_ca`, clients must present
+/// a certificate issued by one of the CA certificates in it.
+pub fn tls_acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let certs = read_pem(cert, pemfile::certs)?;
+    let key = read_pem(key, pemfile::pkcs8_private_keys)?
+        .into_iter()
+        .chain(read_pem(key, pemfile::rsa_private_keys)?)
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no private key in {}", key.display()),
+            )
+        })?;
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for ca in read_pem(path, pemfile::certs)? {
+                roots.add(&ca).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("bad CA certificate in {}: {}", path.display(), e),
+                    )
+                })?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no CA certificate in {}", path.display()),
+                ));
+            }
+            rustls::AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => rustls::NoClientAuth::new(),
+    };
+    let mut tls = rustls::ServerConfig::new(verifier);
+    tls.set_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    tls.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: fn(&mut dyn BufRead) -> Result<Vec<T>, ()>,
+) -> io::Result<Vec<T>> {
+    let file = File::open(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("error opening {}: {}", path.display(), e))
+    })?;
+    parse(&mut BufReader::new(file)).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("error parsing {}", path.display()),
+        )
+    })
+}
+
+/// Serve DoH clients on `listener`, over TLS unless `tls` is `None` (e.g.
+/// behind a reverse proxy which terminates it).  Queries, from a GET's
+/// `dns` parameter or a POST's body, are answered by `resolve`.
+pub fn serve<F, R>(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    limits: DecodeLimits,
+    resolve: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn(DnsMessage, SocketAddr) -> R + Send + Sync + 'static,
+    R: Future<Item = Option<DnsMessage>, Error = ()> + Send + 'static,
+{
+    let resolve = Arc::new(resolve);
+    listener
+        .incoming()
+        .map_err(|e| error!("error in DoH listener: {}", e))
+        .for_each(move |stream| {
+            let client = match stream.peer_addr() {
+                Ok(client) => client,
+                Err(_) => return Ok(()),
+            };
+            let resolve = resolve.clone();
+            let service =
+                service_fn(move |request| respond(request, client, limits, resolve.clone()));
+            let other = |e| io::Error::new(io::ErrorKind::Other, e);
+            let connection = match tls {
+                Some(ref tls) => Either::A(tls.accept(stream).and_then(move |stream| {
+                    Http::new().serve_connection(stream, service).map_err(other)
+                })),
+                None => Either::B(Http::new().serve_connection(stream, service).map_err(other)),
+            };
+            tokio::spawn(connection.map_err(move |e| debug!("DoH client {}: {}", client, e)));
+            Ok(())
+        })
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
+
+/// Answer a request: with HTTP 403 if the query is dropped, and 502 if it
+/// can't be answered.  A POST body longer than a message may be is cut off
+/// and answered with 413, before it is all read.
+fn respond<F, R>(
+    request: Request<Body>,
+    client: SocketAddr,
+    limits: DecodeLimits,
+    resolve: Arc<F>,
+) -> ResponseFuture
+where
+    F: Fn(DnsMessage, SocketAddr) -> R + Send + Sync + 'static,
+    R: Future<Item = Option<DnsMessage>, Error = ()> + Send + 'static,
+{
+    if request.uri().path() == JSON_PATH {
+        return respond_json(&request, client, resolve);
+    }
+    if request.uri().path() != PATH {
+        return reject(StatusCode::NOT_FOUND);
+    }
+    let query = match *request.method() {
+        Method::GET => {
+            let query = param(request.uri(), "dns").and_then(|query| {
+                base64::decode_config(query.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+            });
+            match query {
+                Some(query) => Either::A(future::ok(Some(query))),
+                None => return reject(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if content_type.map(|value| value.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                return reject(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let length = request
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            let max_size = limits.max_size;
+            if length.map_or(false, |length| length > max_size as u64) {
+                return reject(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            // The length may be left out, or wrong
+            Either::B(
+                request
+                    .into_body()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .fold(vec![], move |mut body, chunk| {
+                        if body.len() + chunk.len() > max_size {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "body too large",
+                            ));
+                        }
+                        body.extend_from_slice(&chunk);
+                        Ok(body)
+                    })
+                    .then(|body| match body {
+                        Ok(body) => Ok(Some(body)),
+                        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => Ok(None),
+                        Err(e) => Err(e),
+                    }),
+            )
+        }
+        _ => return reject(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    Box::new(query.and_then(move |query| {
+        let query = match query {
+            Some(query) => query,
+            None => return Either::A(future::ok(status_response(StatusCode::PAYLOAD_TOO_LARGE))),
+        };
+        let query = match DnsMessageCodec::new(false)
+            .limits(limits)
+            .decode(&mut BytesMut::from(query))
+        {
+            Ok(Some(ref query)) if !query.is_query() => None,
+            Ok(query) => query,
+            Err(_) => None,
+        };
+        let query = match query {
+            Some(query) => query,
+            None => return Either::A(future::ok(status_response(StatusCode::BAD_REQUEST))),
+        };
+        info!("Message {:x} from {} is DoH query", query.header.id, client);
+        Either::B(
+            resolve(query, client).then(|response| Ok(http_response(response, message_response))),
+        )
+    }))
+}
+
+/// Answer a query of the JSON API, `?name=NAME[&type=TYPE]`, with the type
+/// as a mnemonic or a number, as Google's and Cloudflare's do.
+fn respond_json<F, R>(
+    request: &Request<Body>,
+    client: SocketAddr,
+    resolve: Arc<F>,
+) -> ResponseFuture
+where
+    F: Fn(DnsMessage, SocketAddr) -> R + Send + Sync + 'static,
+    R: Future<Item = Option<DnsMessage>, Error = ()> + Send + 'static,
+{
+    if *request.method() != Method::GET {
+        return reject(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let name = param(request.uri(), "name")
+        .and_then(percent_decode)
+        .and_then(|name| DomainName::from_idn(&name).ok());
+    let qtype = match param(request.uri(), "type") {
+        Some(qtype) => qtype.parse().ok().or_else(|| type_code(&qtype).ok()),
+        None => Some(DnsType::A as u16),
+    };
+    let query = match (name, qtype) {
+        (Some(name), Some(qtype)) => {
+            let mut query = DnsMessage::query(name, DnsType::A);
+            query.question[0].set_code(qtype);
+            query
+        }
+        _ => return reject(StatusCode::BAD_REQUEST),
+    };
+    info!(
+        "Message {:x} from {} is DoH JSON query",
+        query.header.id, client
+    );
+    let question = query.question.clone();
+    Box::new(resolve(query, client).then(|response| {
+        // Local answers come without the question
+        let response = response.map(|response| {
+            response.map(|mut response| {
+                if response.question.is_empty() {
+                    response.question = question;
+                }
+                response
+            })
+        });
+        Ok(http_response(response, json_response))
+    }))
+}
+
+fn reject(status: StatusCode) -> ResponseFuture {
+    Box::new(future::ok(status_response(status)))
+}
+
+fn http_response(
+    response: Result<Option<DnsMessage>, ()>,
+    encode: fn(DnsMessage) -> Response<Body>,
+) -> Response<Body> {
+    match response {
+        Ok(Some(response)) => encode(response),
+        Ok(None) => status_response(StatusCode::FORBIDDEN),
+        Err(_) => status_response(StatusCode::BAD_GATEWAY),
+    }
+}
+
+/// A response carrying `message`, which HTTP caches may keep for as long as
+/// its shortest TTL.
+fn message_response(message: DnsMessage) -> Response<Body> {
+    let ttl = min_ttl(&message);
+    let mut buf = BytesMut::new();
+    if let Err(e) = DnsMessageCodec::new(false).encode(message, &mut buf) {
+        error!("error encoding DoH response: {}", e);
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    cacheable_response(DNS_MESSAGE, ttl, Body::from(buf.freeze()))
+}
+
+fn json_response(message: DnsMessage) -> Response<Body> {
+    cacheable_response(DNS_JSON, min_ttl(&message), Body::from(to_json(&message)))
+}
+
+fn min_ttl(message: &DnsMessage) -> Option<u32> {
+    message
+        .answer
+        .iter()
+        .chain(&message.authority)
+        .map(|rr| rr.ttl)
+        .min()
+}
+
+fn cacheable_response(content_type: &str, ttl: Option<u32>, body: Body) -> Response<Body> {
+    let mut response = Response::builder();
+    response.header(CONTENT_TYPE, content_type);
+    if let Some(ttl) = ttl {
+        response.header(CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    response.body(body).unwrap()
+}
+
+/// The JSON API's form of a response: its status, flags, question, and
+/// answer and authority records with their data in presentation format.
+fn to_json(message: &DnsMessage) -> String {
+    let header = &message.header;
+    let questions: Vec<_> = message
+        .question
+        .iter()
+        .map(|q| {
+            format!(
+                r#"{{"name":{},"type":{}}}"#,
+                json_string(&Absolute(&q.qname).to_string()),
+                q.code()
+            )
+        })
+        .collect();
+    let mut json = format!(
+        r#"{{"Status":{},"TC":{},"RD":{},"RA":{},"AD":false,"CD":false,"Question":[{}]"#,
+        header.rcode as u16,
+        header.truncated,
+        header.recur_desired,
+        header.recur_available,
+        questions.join(",")
+    );
+    for (section, records) in &[
+        ("Answer", &message.answer),
+        ("Authority", &message.authority),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        let records: Vec<_> = records
+            .iter()
+            .map(|rr| {
+                let rtype = match rr.data {
+                    DnsRRData::Unknown(code, _) => code,
+                    _ => rr.rtype as u16,
+                };
+                format!(
+                    r#"{{"name":{},"type":{},"TTL":{},"data":{}}}"#,
+                    json_string(&Absolute(&rr.name).to_string()),
+                    rtype,
+                    rr.ttl,
+                    json_string(&rr.data.to_string())
+                )
+            })
+            .collect();
+        json += &format!(r#","{}":[{}]"#, section, records.join(","));
+    }
+    json + "}"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_to_json() {
+        let query = DnsMessage::query("example.com".into(), DnsType::TXT);
+        let rr = |data| DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::TXT,
+            rclass: DnsClass::Internet,
+            ttl: 300,
+            data,
+        };
+        let response = DnsMessage::response_to(&query)
+            .with_answer(rr(DnsRRData::TXT(vec![b"say \"hi\"".to_vec()])))
+            .with_answer(rr(DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1))));
+        let json: serde_json::Value = serde_json::from_str(&to_json(&response)).unwrap();
+        assert_eq!(json["Status"], 0);
+        assert_eq!(json["RD"], true);
+        assert_eq!(json["Question"][0]["name"], "example.com.");
+        assert_eq!(json["Question"][0]["type"], 16);
+        assert_eq!(json["Answer"][0]["data"], r#""say \"hi\"""#);
+        assert_eq!(json["Answer"][1]["TTL"], 300);
+        assert_eq!(json["Answer"][1]["data"], "192.0.2.1");
+        assert!(json.get("Authority").is_none());
+    }
+
+    #[test]
+    fn test_post_size() {
+        let limits = DecodeLimits {
+            max_size: 512,
+            ..Default::default()
+        };
+        let resolve = Arc::new(|query: DnsMessage, _: SocketAddr| {
+            future::ok::<_, ()>(Some(DnsMessage::response_to(&query)))
+        });
+        let post = |body: Vec<u8>, length: Option<usize>| {
+            let mut request = Request::builder();
+            request
+                .method("POST")
+                .uri(PATH)
+                .header(CONTENT_TYPE, DNS_MESSAGE);
+            if let Some(length) = length {
+                request.header(CONTENT_LENGTH, length);
+            }
+            let request = request.body(Body::from(body)).unwrap();
+            let client = "127.0.0.1:5353".parse().unwrap();
+            let response = respond(request, client, limits, resolve.clone());
+            response.wait().unwrap().status()
+        };
+        let mut query = BytesMut::new();
+        let message = DnsMessage::query("example.com".into(), DnsType::A);
+        DnsMessageCodec::new(false)
+            .encode(message, &mut query)
+            .unwrap();
+        assert_eq!(post(query.to_vec(), None), StatusCode::OK);
+        assert_eq!(post(vec![0; 513], None), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(post(vec![], Some(1 << 20)), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
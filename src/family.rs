@@ -0,0 +1,131 @@
+//! Answering with one address family alone: AAAA questions get empty
+//! answers on networks with broken IPv6, so that dual-stack clients don't
+//! try it first and hang, or A questions on IPv6-only networks.
+
+use std::str::FromStr;
+
+use crate::message::*;
+
+/// Answer with IPv4 or IPv6 addresses alone, for the names under `suffix`
+/// or all names.
+///
+/// Written as `FAMILY[@NAME]`, e.g. `ipv4` or `ipv6@lab.example`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FamilyFilter {
+    /// The type of the addresses kept, A or AAAA.
+    pub keep: DnsType,
+    pub suffix: Option<DomainName>,
+}
+
+impl FamilyFilter {
+    /// Whether the filter hides records of `rtype` for `name`.
+    pub fn hides(&self, rtype: DnsType, name: &DomainName) -> bool {
+        let hidden = match self.keep {
+            DnsType::A => DnsType::AAAA,
+            _ => DnsType::A,
+        };
+        rtype == hidden && self.suffix.as_ref().map_or(true, |s| name.ends_with(s))
+    }
+}
+
+impl FromStr for FamilyFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FamilyFilter, String> {
+        let mut parts = s.splitn(2, '@');
+        let keep = match parts.next().unwrap() {
+            "ipv4" => DnsType::A,
+            "ipv6" => DnsType::AAAA,
+            family => return Err(format!("Unknown address family {}", family)),
+        };
+        let suffix = match parts.next() {
+            Some(name) => Some(DomainName::from_idn(name)?),
+            None => None,
+        };
+        Ok(FamilyFilter { keep, suffix })
+    }
+}
+
+/// Take the questions for hidden addresses out of `questions`, to be
+/// answered with no records, and return how many there were.
+pub fn filter_questions(filters: &[FamilyFilter], questions: &mut Vec<DnsQuestion>) -> usize {
+    let before = questions.len();
+    questions.retain(|q| !filters.iter().any(|f| f.hides(q.qtype, &q.qname)));
+    before - questions.len()
+}
+
+/// Remove hidden addresses from a response, e.g. from the answers to ANY
+/// questions or the additional section, and return how many there were.
+pub fn strip(filters: &[FamilyFilter], message: &mut DnsMessage) -> usize {
+    let mut removed = 0;
+    for records in &mut [
+        &mut message.answer,
+        &mut message.authority,
+        &mut message.additional,
+    ] {
+        let before = records.len();
+        records.retain(|rr| !filters.iter().any(|f| f.hides(rr.rtype, &rr.name)));
+        removed += before - records.len();
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_family_filter() {
+        let filters: Vec<FamilyFilter> = ["ipv4@lan", "ipv6@lab.example"]
+            .iter()
+            .map(|f| f.parse().unwrap())
+            .collect();
+        assert_eq!(
+            "ipv4".parse(),
+            Ok(FamilyFilter {
+                keep: DnsType::A,
+                suffix: None,
+            })
+        );
+        assert!("ipv5".parse::<FamilyFilter>().is_err());
+
+        let question = |name: &str, qtype| DnsQuestion {
+            qname: name.into(),
+            qtype,
+            qcode: 0,
+            qclass: DnsClass::Internet,
+        };
+        let mut questions = vec![
+            question("nas.lan", DnsType::AAAA),
+            question("nas.lan", DnsType::A),
+            question("host.lab.example", DnsType::A),
+            question("example", DnsType::AAAA),
+        ];
+        assert_eq!(filter_questions(&filters, &mut questions), 2);
+        assert_eq!(
+            questions,
+            [
+                question("nas.lan", DnsType::A),
+                question("example", DnsType::AAAA)
+            ]
+        );
+
+        let rr = |name: &str, data: DnsRRData| DnsResourceRecord {
+            name: name.into(),
+            rtype: data.rtype(),
+            rclass: DnsClass::Internet,
+            ttl: 60,
+            data,
+        };
+        let a = rr("nas.lan", DnsRRData::A(Ipv4Addr::new(192, 168, 1, 2)));
+        let aaaa = rr("nas.lan", DnsRRData::AAAA(Ipv6Addr::LOCALHOST));
+        let query = DnsMessage::query("nas.lan".into(), DnsType::Any);
+        let mut response =
+            DnsMessage::response_to(&query).with_answers(vec![a.clone(), aaaa.clone()]);
+        response.additional.push(aaaa);
+        assert_eq!(strip(&filters, &mut response), 2);
+        assert_eq!(response.answer, [a]);
+        assert!(response.additional.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! A history of the questions clients asked, in an SQLite database, for
+//! ad-hoc queries like
+//!
+//! ```sql
+//! SELECT datetime(time, 'unixepoch', 'localtime'), client FROM queries
+//! WHERE name = 'example.com' AND time > strftime('%s', 'now', '-1 day');
+//! ```
+//!
+//! Rows older than the retention window are deleted every minute.  Links
+//! to the system's libsqlite3, or a copy built in with `history-bundled`.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::message::DnsMessage;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS queries (
+    time INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    name TEXT NOT NULL,
+    type TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS queries_time ON queries (time);
+CREATE INDEX IF NOT EXISTS queries_name ON queries (name);
+";
+
+/// Questions waiting to be written, beyond which they are dropped.
+const BACKLOG: usize = 10000;
+/// How often rows past the retention window are deleted.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A question, as recorded.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    time: i64,
+    client: String,
+    name: String,
+    qtype: String,
+}
+
+/// Records questions on a thread of its own, which writes them in batches.
+#[derive(Debug, Clone)]
+pub struct History {
+    rows: SyncSender<Row>,
+}
+
+impl History {
+    /// Open or create the database at `path`, keeping questions for
+    /// `retention`.
+    pub fn open(path: &Path, retention: Duration) -> io::Result<History> {
+        let db = open(path)
+            .and_then(|db| db.execute_batch(SCHEMA).map(|_| db))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Can't open history {}: {}", path.display(), e),
+                )
+            })?;
+        let (rows, pending) = mpsc::sync_channel(BACKLOG);
+        thread::spawn(move || write_rows(db, pending, retention));
+        Ok(History { rows })
+    }
+
+    /// Record the questions of a query from `client`.
+    pub fn record(&self, client: IpAddr, message: &DnsMessage) {
+        let time = unix_time();
+        for q in &message.question {
+            let row = Row {
+                time,
+                client: client.to_string(),
+                name: q.qname.to_string().to_ascii_lowercase(),
+                qtype: q.qtype.to_string(),
+            };
+            if self.rows.try_send(row).is_err() {
+                debug!("History is behind, dropping a question");
+            }
+        }
+    }
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Write rows as they arrive, and purge the old ones every
+/// `PURGE_INTERVAL` whether rows arrive or not.
+fn write_rows(mut db: Connection, rows: Receiver<Row>, retention: Duration) {
+    purge(&db, retention);
+    let mut purged = Instant::now();
+    loop {
+        let wait = PURGE_INTERVAL
+            .checked_sub(purged.elapsed())
+            .unwrap_or_default();
+        match rows.recv_timeout(wait) {
+            Ok(row) => {
+                // Whatever else has arrived goes into the same transaction
+                let batch: Vec<_> = Some(row).into_iter().chain(rows.try_iter()).collect();
+                if let Err(e) = insert(&mut db, &batch) {
+                    error!("Can't write history: {}", e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if purged.elapsed() >= PURGE_INTERVAL {
+            purge(&db, retention);
+            purged = Instant::now();
+        }
+    }
+}
+
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let db = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    // Readers may lock the database for a while
+    db.busy_timeout(Duration::from_secs(5))?;
+    Ok(db)
+}
+
+/// Insert rows in one transaction, which is rolled back if any fails.
+fn insert(db: &mut Connection, rows: &[Row]) -> rusqlite::Result<()> {
+    let tx = db.transaction()?;
+    {
+        let mut statement = tx.prepare_cached("INSERT INTO queries VALUES (?, ?, ?, ?)")?;
+        for row in rows {
+            statement.execute(params![row.time, row.client, row.name, row.qtype])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Delete the rows past the retention window.
+fn purge(db: &Connection, retention: Duration) {
+    let before = unix_time() - retention.as_secs() as i64;
+    if let Err(e) = db.execute("DELETE FROM queries WHERE time < ?", params![before]) {
+        error!("Can't purge history: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::DnsType;
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("uind-history-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = open(&path).unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+
+        insert(
+            &mut db,
+            &[Row {
+                time: 0,
+                client: String::from("192.0.2.1"),
+                name: String::from("old.example"),
+                qtype: String::from("A"),
+            }],
+        )
+        .unwrap();
+
+        let (rows, pending) = mpsc::sync_channel(BACKLOG);
+        let history = History { rows };
+        let query = DnsMessage::query("It's.Example.COM".into(), DnsType::AAAA);
+        history.record("192.0.2.2".parse().unwrap(), &query);
+        drop(history);
+        write_rows(db, pending, Duration::from_secs(3600));
+
+        let db = open(&path).unwrap();
+        let query_int =
+            |sql: &str| -> i64 { db.query_row(sql, params![], |row| row.get(0)).unwrap() };
+        assert_eq!(query_int("SELECT count(*) FROM queries"), 1);
+        assert_eq!(
+            query_int(
+                "SELECT count(*) FROM queries WHERE client = '192.0.2.2' \
+                 AND name = 'it''s.example.com' AND type = 'AAAA'"
+            ),
+            1
+        );
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+}
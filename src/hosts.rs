@@ -0,0 +1,234 @@
+//! Host tables: the legacy `dnsrelay.txt` format (`NAME ADDRESS` per line,
+//! `REVERSE-NAME NAME` for PTR entries, `NAME -> TARGET` for CNAME ones or
+//! `NAME TYPE DATA` in the master file syntax for any other) and the hosts(5) format
+//! (`ADDRESS NAME [ALIAS...]` per line).
+
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::message::{type_name, DnsRRData, DnsType, DomainName, NamePolicy};
+use crate::zonefile;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Dnsrelay,
+    Hosts,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "dnsrelay" => Ok(Format::Dnsrelay),
+            "hosts" => Ok(Format::Hosts),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
+}
+
+/// Parse a host table into `(name, data)` entries, in file order.  The data
+/// is an A or AAAA address, a PTR or CNAME target, or in the legacy format,
+/// the data of a record of any type.
+///
+/// Names are checked against `name_policy` and stripped of a trailing dot.
+/// Regex rules in the legacy format (`/REGEX/ ADDRESS`, see `rules`) are
+/// skipped.
+pub fn parse<R: BufRead>(
+    format: Format,
+    reader: R,
+    name_policy: NamePolicy,
+) -> Result<Vec<(DomainName, DnsRRData)>, String> {
+    let mut entries = vec![];
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("Error reading line {}", e))?;
+        let lineno = lineno + 1;
+
+        let (addr, names, alias): (&str, Vec<&str>, bool) = match format {
+            Format::Dnsrelay => {
+                if line.trim_start().starts_with("#") || line.trim_start().starts_with("/") {
+                    continue;
+                }
+                let parts: Vec<_> = line.split_whitespace().collect();
+                if parts.len() > 2 && parts[1].parse::<DnsType>().is_ok() {
+                    let rr = zonefile::parse_record(&line, lineno, 0, name_policy)?;
+                    entries.push((rr.name, rr.data));
+                    continue;
+                }
+                match parts.len() {
+                    0 => continue,
+                    2 => (parts[1], vec![parts[0]], false),
+                    3 if parts[1] == "->" => (parts[2], vec![parts[0]], true),
+                    _ => {
+                        warn!("Line {} is malformed, ignoring", lineno);
+                        continue;
+                    }
+                }
+            }
+            Format::Hosts => {
+                let line = line.split('#').next().unwrap();
+                let mut parts = line.split_whitespace();
+                match parts.next() {
+                    Some(addr) => (addr, parts.collect(), false),
+                    None => continue,
+                }
+            }
+        };
+
+        let to_name = |name: &str| -> Result<DomainName, String> {
+            DomainName::from_idn(name)
+                .ok()
+                .filter(|name| name_policy.check(name))
+                .ok_or(format!("Invalid domain name at line {}", lineno))
+        };
+        let names = names
+            .into_iter()
+            .map(to_name)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let data = match addr.parse() {
+            _ if alias => DnsRRData::CNAME(to_name(addr)?),
+            Ok(IpAddr::V4(addr)) => DnsRRData::A(addr),
+            Ok(IpAddr::V6(addr)) => DnsRRData::AAAA(addr),
+            Err(_) if format == Format::Dnsrelay && is_reverse(&names[0]) => {
+                DnsRRData::PTR(to_name(addr)?)
+            }
+            _ => return Err(format!("Can't parse IP address at line {}", lineno)),
+        };
+        for name in names {
+            entries.push((name, data.clone()));
+        }
+    }
+    Ok(entries)
+}
+
+/// The name reverse lookups of `addr` ask for, e.g. `4.3.2.1.in-addr.arpa`
+/// for 1.2.3.4.
+pub fn reverse_name(addr: IpAddr) -> DomainName {
+    let mut labels: Vec<String> = match addr {
+        IpAddr::V4(addr) => addr.octets().iter().rev().map(u8::to_string).collect(),
+        IpAddr::V6(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|b| vec![format!("{:x}", b & 0xf), format!("{:x}", b >> 4)])
+            .collect(),
+    };
+    match addr {
+        IpAddr::V4(_) => labels.extend(vec!["in-addr".to_owned(), "arpa".to_owned()]),
+        IpAddr::V6(_) => labels.extend(vec!["ip6".to_owned(), "arpa".to_owned()]),
+    }
+    labels.into()
+}
+
+/// Whether `name` is under `in-addr.arpa` or `ip6.arpa`.
+pub fn is_reverse(name: &DomainName) -> bool {
+    name.ends_with(&"in-addr.arpa".into()) || name.ends_with(&"ip6.arpa".into())
+}
+
+/// Write entries as a host table, dropping duplicates.  Entries the format
+/// can't hold (anything but addresses in hosts files) are skipped with a
+/// warning.
+pub fn write<W: Write>(
+    format: Format,
+    entries: &[(DomainName, DnsRRData)],
+    mut writer: W,
+) -> io::Result<()> {
+    let mut seen = vec![];
+    for entry in entries {
+        if seen.contains(&entry) {
+            continue;
+        }
+        seen.push(entry);
+        let (name, data) = entry;
+        match (format, data) {
+            (Format::Dnsrelay, DnsRRData::A(addr)) => writeln!(writer, "{} {}", name, addr)?,
+            (Format::Dnsrelay, DnsRRData::AAAA(addr)) => writeln!(writer, "{} {}", name, addr)?,
+            (Format::Dnsrelay, DnsRRData::PTR(target)) => writeln!(writer, "{} {}", name, target)?,
+            (Format::Dnsrelay, DnsRRData::CNAME(target)) => {
+                writeln!(writer, "{} -> {}", name, target)?
+            }
+            (Format::Dnsrelay, DnsRRData::Unknown(code, _)) => {
+                writeln!(writer, "{} {} {}", name, type_name(*code), data)?
+            }
+            (Format::Dnsrelay, _) => writeln!(writer, "{} {} {}", name, data.rtype(), data)?,
+            (Format::Hosts, DnsRRData::A(addr)) => writeln!(writer, "{}\t{}", addr, name)?,
+            (Format::Hosts, DnsRRData::AAAA(addr)) => writeln!(writer, "{}\t{}", addr, name)?,
+            _ => warn!(
+                "Skipping {} {:?}: not supported by {:?}",
+                name, data, format
+            ),
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let hosts = "127.0.0.1 localhost Example.com. # comment\n\n::1 localhost\n";
+        let entries = parse(Format::Hosts, hosts.as_bytes(), NamePolicy::Strict).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].0, vec!["Example", "com"].into());
+
+        let mut out = vec![];
+        write(Format::Dnsrelay, &entries, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "localhost 127.0.0.1\nExample.com 127.0.0.1\nlocalhost ::1\n"
+        );
+
+        let relay = "# comment\nexample.com 10.0.0.1\nexample.com 10.0.0.1\nbad line here\n";
+        let entries = parse(Format::Dnsrelay, relay.as_bytes(), NamePolicy::Strict).unwrap();
+        let mut out = vec![];
+        write(Format::Hosts, &entries, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "10.0.0.1\texample.com\n");
+
+        assert_eq!(
+            reverse_name("1.2.3.4".parse().unwrap()),
+            "4.3.2.1.in-addr.arpa".into()
+        );
+        assert_eq!(
+            reverse_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa".into()
+        );
+
+        let relay = "4.3.2.1.in-addr.arpa host.lan\nhost.lan 1.2.3.4\n";
+        let entries = parse(Format::Dnsrelay, relay.as_bytes(), NamePolicy::Strict).unwrap();
+        assert_eq!(entries[0].1, DnsRRData::PTR(vec!["host", "lan"].into()));
+        let mut out = vec![];
+        write(Format::Hosts, &entries, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "1.2.3.4\thost.lan\n");
+
+        let relay = "lan MX 10 mail.lan.\nlan TXT \"v=spf1 mx -all\"\n\
+                     _sip._tcp.lan SRV 10 60 5060 sip.lan.\n";
+        let entries = parse(Format::Dnsrelay, relay.as_bytes(), NamePolicy::Standard).unwrap();
+        assert_eq!(entries[0].1, DnsRRData::MX(10, vec!["mail", "lan"].into()));
+        assert_eq!(entries[1].1, DnsRRData::TXT(vec!["v=spf1 mx -all".into()]));
+        assert_eq!(entries[2].0, vec!["_sip", "_tcp", "lan"].into());
+        let mut out = vec![];
+        write(Format::Dnsrelay, &entries, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), relay);
+
+        let relay = "git.lan -> nas.lan\n";
+        let entries = parse(Format::Dnsrelay, relay.as_bytes(), NamePolicy::Strict).unwrap();
+        assert_eq!(entries[0].1, DnsRRData::CNAME(vec!["nas", "lan"].into()));
+        let mut out = vec![];
+        write(Format::Dnsrelay, &entries, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), relay);
+
+        let entries = parse(Format::Dnsrelay, "a.com ::1".as_bytes(), NamePolicy::Strict).unwrap();
+        assert_eq!(entries[0].1, DnsRRData::AAAA("::1".parse().unwrap()));
+        assert!(parse(
+            Format::Dnsrelay,
+            "a.com b.com".as_bytes(),
+            NamePolicy::Strict
+        )
+        .is_err());
+        assert!(parse(Format::Hosts, "1.2.3.4 a_b".as_bytes(), NamePolicy::Strict).is_err());
+    }
+}
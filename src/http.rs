@@ -0,0 +1,55 @@
+//! Bits shared by the HTTP interfaces: the DoH listener and the admin API.
+
+use hyper::{Body, Response, StatusCode, Uri};
+use std::str;
+
+/// The value of a parameter in the query string of `uri`.
+pub fn param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?.split('&').find_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        if parts.next() == Some(name) {
+            parts.next()
+        } else {
+            None
+        }
+    })
+}
+
+/// Undo the `%XX` escapes of a query parameter.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).and_then(|hex| str::from_utf8(hex).ok())?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                json.push('\\');
+                json.push(c);
+            }
+            _ if c < ' ' => json += &format!("\\u{:04x}", c as u32),
+            _ => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
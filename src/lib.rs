@@ -0,0 +1,62 @@
+#![feature(drain_filter)]
+#![feature(slice_concat_ext)]
+
+//! UIND is a very simple DNS server and proxy.
+//!
+//! The relay can be embedded with [`Server`](server/struct.Server.html).
+
+#[macro_use]
+extern crate log;
+
+pub mod acl;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod affinity;
+pub mod authority;
+#[cfg(feature = "blocklists")]
+pub mod blocklist;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod codec;
+#[cfg(feature = "config-file")]
+pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod daemon;
+#[cfg(feature = "dnstap")]
+pub mod dnstap;
+#[cfg(feature = "doh")]
+pub mod doh;
+pub mod family;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod hosts;
+#[cfg(any(feature = "doh", feature = "admin"))]
+mod http;
+pub mod memory;
+pub mod message;
+#[cfg(feature = "pipe")]
+pub mod pipe;
+pub mod privilege;
+pub mod ratelimit;
+pub mod rewrite;
+pub mod rules;
+pub mod scrub;
+#[cfg(feature = "secondary")]
+pub mod secondary;
+pub mod server;
+#[cfg(windows)]
+pub mod service;
+pub mod stats;
+#[cfg(feature = "transfer")]
+pub mod transfer;
+#[cfg(feature = "tsig")]
+pub mod tsig;
+#[cfg(feature = "update")]
+pub mod update;
+pub mod upstream;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod zonefile;
+
+pub use crate::server::{Server, StopHandle};
@@ -1,371 +1,1220 @@
-#![feature(drain_filter)]
-#![feature(slice_concat_ext)]
-
-use futures::future::{self, Either};
-use futures::prelude::*;
-use futures::sync::mpsc;
-use std::collections::HashMap;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::env;
+use std::fmt::Display;
 use std::fs;
-use std::io::{BufRead, BufReader};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
 use std::time::Duration;
-use tokio::codec::Decoder;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::net::{UdpFramed, UdpSocket};
-use tokio::prelude::*;
-use ttl_cache::TtlCache;
+use tokio::codec::{Decoder, Encoder};
 
 #[macro_use]
 extern crate log;
 
-mod codec;
-mod message;
-
-use crate::codec::DnsMessageCodec;
-use crate::message::*;
+use uind::affinity;
+#[cfg(feature = "blocklists")]
+use uind::blocklist::Blocklist;
+use uind::codec::DnsMessageCodec;
+#[cfg(feature = "config-file")]
+use uind::config::Config;
+use uind::hosts::{self, Format};
+use uind::memory;
+use uind::message::*;
+#[cfg(feature = "pipe")]
+use uind::pipe::PipeConfig;
+use uind::privilege::Account;
+use uind::rules::RegexRule;
+#[cfg(feature = "canary")]
+use uind::server::CanaryConfig;
+use uind::server::EntryTable;
+use uind::server::LocalConfig;
+use uind::server::ViewConfig;
+use uind::upstream::Strategy;
+use uind::zonefile;
+use uind::Server;
 
 fn main() {
-    let config = match init() {
-        Ok(conf) => conf,
-        Err(e) => {
-            println!("{}", e);
-            return;
-        }
+    let matches = app().get_matches();
+    let result = match matches.subcommand() {
+        ("check", Some(matches)) => check(matches),
+        ("query", Some(matches)) => query(matches),
+        ("convert", Some(matches)) => convert(matches),
+        ("run", Some(matches)) => run(matches),
+        #[cfg(windows)]
+        ("service", Some(matches)) => service(matches),
+        #[cfg(unix)]
+        ("ctl", Some(matches)) => ctl(matches),
+        _ => run(&matches),
     };
-    debug!("Using config: {:#?}", config);
-    let dns_addr = config.dns_addr;
-    let local_entries_udp = config.local;
-    let local_entries_tcp = local_entries_udp.clone();
-
-    let udp_sock = UdpSocket::bind(&"0.0.0.0:53".parse().unwrap()).unwrap();
-    let tcp_sock = TcpListener::bind(&"0.0.0.0:53".parse().unwrap()).unwrap();
-    let (udp_out, udp_in) = UdpFramed::new(udp_sock, DnsMessageCodec::new(false)).split();
-    let (tx, rx) = mpsc::unbounded::<(DnsMessage, SocketAddr)>();
-
-    let clients: Arc<Mutex<TtlCache<u16, (SocketAddr, Vec<DnsResourceRecord>)>>> =
-        Arc::new(Mutex::new(TtlCache::new(100000)));
-    let ttl = Duration::from_secs(2);
-
-    let udp_sender = rx
-        .fold(udp_out, |udp_out, (message, addr)| {
-            udp_out.send((message, addr)).map_err(|e| error!("{}", e))
+    if let Err(e) = result {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    let app = App::new("uind")
+        .version(clap::crate_version!())
+        .about("A very simple DNS server and proxy")
+        .setting(AppSettings::VersionlessSubcommands)
+        .args(&server_args())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Serve queries (the default)")
+                .args(&server_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Load the configuration and report errors, without serving")
+                .args(&server_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("Ask a server, like a running uind, and print the response")
+                .arg(
+                    Arg::with_name("server")
+                        .short("s")
+                        .long("server")
+                        .value_name("IP:PORT")
+                        .default_value("127.0.0.1:53")
+                        .help("The server to ask"),
+                )
+                .arg(Arg::with_name("NAME").required(true))
+                .arg(Arg::with_name("TYPE").default_value("A")),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert a host table between formats")
+                .arg(option(
+                    "from",
+                    "FORMAT",
+                    "Input format: dnsrelay (default) or hosts",
+                ))
+                .arg(option(
+                    "to",
+                    "FORMAT",
+                    "Output format: hosts (default) or dnsrelay",
+                ))
+                .arg(option(
+                    "names",
+                    "POLICY",
+                    "How strictly names are validated",
+                ))
+                .arg(Arg::with_name("INPUT").help("Input file (default: standard input)"))
+                .arg(Arg::with_name("OUTPUT").help("Output file (default: standard output)")),
+        );
+    if cfg!(windows) {
+        app.subcommand(
+            SubCommand::with_name("service")
+                .about("Serve queries as a Windows service")
+                .args(&server_args()),
+        )
+    } else {
+        app.subcommand(
+            SubCommand::with_name("ctl")
+                .about("Control a running server over its control socket")
+                .arg(
+                    option("socket", "FILE", "The server's control socket")
+                        .short("s")
+                        .default_value("/run/uind.sock"),
+                )
+                .arg(
+                    Arg::with_name("COMMAND")
+                        .required(true)
+                        .possible_values(&["flush", "stats", "reload"]),
+                )
+                .arg(Arg::with_name("NAME").help("The name to flush (default: all)")),
+        )
+    }
+}
+
+/// The options of the server, shared by `run` and `check`, and allowed
+/// without a subcommand too.
+fn server_args() -> Vec<Arg<'static, 'static>> {
+    let mut args = vec![
+        Arg::with_name("debug")
+            .short("d")
+            .multiple(true)
+            .help("Log more: -d for information, -dd for debugging"),
+        option("log-level", "LEVEL", "Log level")
+            .short("L")
+            .possible_values(&["off", "error", "warn", "info", "debug", "trace"]),
+        options("listen", "IP:PORT", "Address to serve UDP and TCP on").short("l"),
+        option("upstream", "IP:PORT[/WEIGHT][,...]", "Upstream servers").short("u"),
+        option(
+            "strategy",
+            "STRATEGY",
+            "How queries are spread over upstream servers",
+        ),
+        option(
+            "upstream-timeout",
+            "MS",
+            "How long to wait for upstream servers",
+        ),
+        option("upstream-retries", "N", "How often UDP queries are resent"),
+        option(
+            "probe-name",
+            "NAME",
+            "Name upstream servers are probed with",
+        ),
+        option("memory-budget", "SIZE", "Memory to use at most"),
+        option("workers", "N", "Worker threads"),
+        option("cpu-affinity", "CPUS", "CPUs to pin the worker threads to"),
+        option("rx-cpu", "CPU", "CPU to receive packets on"),
+        option("user", "USER", "User to switch to once listening"),
+        option("group", "GROUP", "Group to switch to once listening"),
+        option("pid-file", "FILE", "File to write the process ID to"),
+        option(
+            "shutdown-grace",
+            "MS",
+            "How long to wait for queries in flight when stopping",
+        ),
+        option("min-ttl", "SECONDS", "Lower bound of relayed TTLs"),
+        option("max-ttl", "SECONDS", "Upper bound of relayed TTLs"),
+        flag(
+            "minimal-responses",
+            "Leave out authority and additional records",
+        ),
+        flag("round-robin", "Rotate the address records of answers"),
+        flag("minimal-any", "Answer ANY queries with a HINFO record"),
+        options(
+            "minimal-any-on",
+            "IP:PORT",
+            "Answer ANY queries minimally on one listener",
+        ),
+        options("rewrite", "RULE", "Rewrite addresses or names of answers"),
+        options(
+            "only-family",
+            "FAMILY[@NAME]",
+            "Answer with IPv4 or IPv6 addresses alone",
+        ),
+        flag(
+            "randomize-case",
+            "Randomize the case of names sent upstream",
+        ),
+        flag("no-scrub", "Relay upstream responses unchecked"),
+        option(
+            "client-subnet",
+            "MODE",
+            "EDNS Client Subnet: strip, pass or synthesize",
+        ),
+        options(
+            "local-zone",
+            "ZONE",
+            "Answer a zone from local entries alone",
+        ),
+        options(
+            "zone-file",
+            "[ORIGIN=]FILE",
+            "Add the records of a zone file",
+        ),
+        options("hosts", "FILE", "Add the entries of a hosts file"),
+        option(
+            "version-bind",
+            "STRING",
+            "Answer version.bind with a string",
+        ),
+        option("id-server", "STRING", "Answer id.server with a string"),
+        option("names", "POLICY", "How strictly names are validated"),
+        options(
+            "qtype-policy",
+            "RULE",
+            "Refuse, drop or log queries for types",
+        ),
+        option(
+            "rate-limit",
+            "QPS[,BURST][=ACTION]",
+            "Drop or refuse queries beyond a rate per client",
+        ),
+        options(
+            "view",
+            "NAME@NETWORK[,...]=FILE[,IP:PORT]",
+            "Serve clients in networks with other local entries and upstream",
+        ),
+        Arg::with_name("UPSTREAM")
+            .help("Upstream servers, IP:PORT[/WEIGHT][,...] (default: 202.141.178.13:53)"),
+        Arg::with_name("CONF-FILE").help("Local hosts file (default: dnsrelay.txt)"),
+    ];
+    if cfg!(feature = "config-file") {
+        args.push(option("config", "FILE", "Read settings from a TOML file").short("c"));
+    }
+    if cfg!(unix) {
+        args.push(flag("daemon", "Run in the background"));
+        args.push(option(
+            "control",
+            "SOCKET",
+            "Unix socket to take `uind ctl` commands on",
+        ));
+    }
+    if cfg!(feature = "update") {
+        args.push(options(
+            "update-zone",
+            "ZONE",
+            "Accept dynamic updates of a zone",
+        ));
+        args.push(flag(
+            "persist-updates",
+            "Write updates back to the config file",
+        ));
+    }
+    if cfg!(feature = "transfer") {
+        args.push(options(
+            "transfer-zone",
+            "ZONE",
+            "Serve transfers of a zone",
+        ));
+    }
+    if cfg!(feature = "secondary") {
+        args.push(options(
+            "secondary-zone",
+            "ZONE@MASTER",
+            "Keep a copy of a zone",
+        ));
+    }
+    if cfg!(feature = "tsig") {
+        args.push(options(
+            "tsig-key",
+            "[ALGORITHM:]NAME:SECRET",
+            "Add a TSIG key",
+        ));
+    }
+    if cfg!(feature = "cache") {
+        args.push(option("cache-size", "N", "Cache entries"));
+        args.push(option("cache-memory", "SIZE", "Cache memory"));
+    }
+    if cfg!(feature = "pipe") {
+        args.push(options(
+            "pipe",
+            "SUFFIX=COMMAND",
+            "Answer names under a suffix with a command",
+        ));
+    }
+    if cfg!(feature = "blocklists") {
+        args.push(options("blocklist", "FILE", "Refuse the names of a list"));
+        args.push(options("allowlist", "FILE", "Unblock the names of a list"));
+    }
+    if cfg!(feature = "dnstap") {
+        args.push(option(
+            "dnstap",
+            "SOCKET",
+            "Log messages to a dnstap reader",
+        ));
+    }
+    if cfg!(feature = "watch") {
+        args.push(option("watch", "MS", "Reload when the config files change"));
+    }
+    if cfg!(feature = "mirror") {
+        args.push(option("mirror", "IP:PORT", "Copy queries to a server"));
+    }
+    if cfg!(feature = "canary") {
+        args.push(option("canary", "IP:PORT", "Upstream of the canary"));
+        args.push(option(
+            "canary-conf",
+            "FILE",
+            "Local hosts file of the canary",
+        ));
+        args.push(option(
+            "canary-percent",
+            "N",
+            "Percentage of clients served by the canary",
+        ));
+        args.push(options(
+            "canary-client",
+            "IP",
+            "Always serve a client by the canary",
+        ));
+    }
+    if cfg!(feature = "doh") {
+        args.push(option(
+            "doh-listen",
+            "IP:PORT",
+            "Address to serve DNS-over-HTTPS on",
+        ));
+        args.push(option("doh-cert", "FILE", "TLS certificate chain"));
+        args.push(option("doh-key", "FILE", "TLS private key"));
+        args.push(option(
+            "doh-client-ca",
+            "FILE",
+            "CA certificates DoH clients must be issued by",
+        ));
+    }
+    if cfg!(feature = "admin") {
+        args.push(option(
+            "admin-listen",
+            "IP:PORT",
+            "Loopback address to serve the admin API on",
+        ));
+        args.push(option(
+            "admin-token-file",
+            "FILE",
+            "File with the bearer token admin API clients must send",
+        ));
+    }
+    if cfg!(feature = "history") {
+        args.push(option(
+            "history",
+            "FILE",
+            "Record queries in an SQLite database",
+        ));
+        args.push(option(
+            "history-days",
+            "N",
+            "How long recorded queries are kept",
+        ));
+    }
+    args
+}
+
+fn option(name: &'static str, value: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).long(name).value_name(value).help(help)
+}
+
+/// An option which may be given several times.
+fn options(name: &'static str, value: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    option(name, value, help).multiple(true).number_of_values(1)
+}
+
+fn flag(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).long(name).help(help)
+}
+
+/// The value of option `name`, parsed.
+fn value<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Error parsing --{} {}: {}", name, s, e)),
+        None => Ok(None),
+    }
+}
+
+/// The values of option `name`, parsed.
+fn values<T>(matches: &ArgMatches, name: &str) -> Result<Vec<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    matches
+        .values_of(name)
+        .into_iter()
+        .flatten()
+        .map(|s| {
+            s.parse()
+                .map_err(|e| format!("Error parsing --{} {}: {}", name, s, e))
         })
-        .map_err(|e| error!("error in sender: {:?}", e));
-
-    let udp_dispatcher = udp_in
-        .map_err(DispatcherError::from)
-        .fold(tx, move |tx, (mut message, addr)| {
-            let id = message.header.id;
-
-            if message.is_query() {
-                info!("Message {:x} from {} is UDP query", id, addr);
-                debug!("Message is {:#?}", message);
-
-                // Filter out questions of type A which have local entries
-                let answers_local = filter_questions(&mut message.question, &local_entries_udp);
-                debug!("After filtration: {:#?}", message);
-
-                // If no question raised, the server won't reply, let's construct a reply
-                let message = if message.question.len() == 0 {
-                    from_answer(id, &answers_local)
-                } else {
-                    message
-                };
-                let dest = if message.question.len() == 0 {
-                    addr
-                } else {
-                    dns_addr
-                };
-
-                // Send packets
-                let fut = tx
-                    .send((message.clone(), dest))
-                    .map_err(DispatcherError::from);
-                debug!("UDP send to {} {:?}", dest, message);
-                if message.question.len() > 0 {
-                    clients
-                        .lock()
-                        .unwrap()
-                        .insert(id, (addr, answers_local), ttl);
+        .collect()
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    let server = init(matches)?;
+    debug!("Using config: {:#?}", server);
+    server.run().map_err(|e| {
+        if e.kind() == ErrorKind::PermissionDenied {
+            format!(
+                "{} (unprivileged users can use e.g. --listen 127.0.0.1:5353)",
+                e
+            )
+        } else {
+            e.to_string()
+        }
+    })
+}
+
+/// Serve under the Windows service control manager, which started us.
+#[cfg(windows)]
+fn service(matches: &ArgMatches) -> Result<(), String> {
+    let server = init(matches)?;
+    uind::service::run(server).map_err(|e| format!("Error running as a service: {}", e))
+}
+
+/// `uind ctl [--socket FILE] COMMAND [NAME]`
+#[cfg(unix)]
+fn ctl(matches: &ArgMatches) -> Result<(), String> {
+    let socket: PathBuf = value(matches, "socket")?.unwrap();
+    let mut command = matches.value_of("COMMAND").unwrap().to_owned();
+    if let Some(name) = matches.value_of("NAME") {
+        if command != "flush" {
+            return Err(format!("{} takes no name", command));
+        }
+        command = format!("{} {}", command, name);
+    }
+    print!("{}", uind::control::send(&socket, &command)?);
+    Ok(())
+}
+
+/// Load the configuration as `run` would, without serving it.
+fn check(matches: &ArgMatches) -> Result<(), String> {
+    init(matches)?;
+    println!("Configuration OK");
+    Ok(())
+}
+
+/// `uind query [--server IP:PORT] NAME [TYPE]`
+fn query(matches: &ArgMatches) -> Result<(), String> {
+    let server: std::net::SocketAddr = value(matches, "server")?.unwrap();
+    let name = DomainName::from_idn(matches.value_of("NAME").unwrap())?;
+    let mut query = DnsMessage::query(name, DnsType::A);
+    query.question[0].set_code(type_code(matches.value_of("TYPE").unwrap())?);
+    query.header.id = rand::random();
+
+    let mut codec = DnsMessageCodec::new(false);
+    let mut buf = bytes::BytesMut::new();
+    codec
+        .encode(query.clone(), &mut buf)
+        .map_err(|e| format!("Error encoding query: {}", e))?;
+    let local: std::net::SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let context = |e: io::Error| format!("Error asking {}: {}", server, e);
+    let socket = UdpSocket::bind(local).map_err(context)?;
+    socket
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .map_err(context)?;
+    socket.send_to(&buf, server).map_err(context)?;
+    let mut packet = vec![0; 65535];
+    loop {
+        let (len, from) = socket.recv_from(&mut packet).map_err(context)?;
+        let response = codec
+            .decode(&mut bytes::BytesMut::from(&packet[..len]))
+            .map_err(|e| format!("Error decoding response: {}", e))?;
+        match response {
+            Some(ref response) if from == server && response.header.id == query.header.id => {
+                print!("{}", response);
+                return Ok(());
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn init(matches: &ArgMatches) -> Result<Server, String> {
+    let mut server = Server::new();
+    let mut dns_addrs = vec![];
+    let mut listen = vec![];
+    let mut conf_file = default_conf_file();
+    let mut debug = String::new();
+    let mut name_policy = NamePolicy::default();
+    let mut watch_delay = None;
+    let mut zone_files = vec![];
+    let mut hosts_files = vec![];
+    let (mut blocklists, mut allowlists) = (vec![], vec![]);
+    let (mut min_ttl, mut max_ttl) = (0, None);
+    let mut strategy = Strategy::default();
+    #[cfg(feature = "doh")]
+    let (mut doh_cert, mut doh_key, mut doh_client_ca) = (None, None, None);
+
+    // Options on the command line override the config file
+    #[cfg(feature = "config-file")]
+    {
+        if let Some(path) = matches.value_of("config") {
+            let config = load_config(path)?;
+            listen = config.listen;
+            #[cfg(feature = "doh")]
+            {
+                if let Some(addr) = config.doh_listen {
+                    server = server.doh_listen(addr);
                 }
-                Either::A(fut)
-            } else {
-                info!("Message {:x} from {} is UDP response", id, addr);
-                if let Some((client_addr, answers_local)) = clients.lock().unwrap().remove(&id) {
-                    message.answer.extend(answers_local);
-                    report_answers(&message);
-                    debug!("Message is {:#?}, sending to {}", message, client_addr);
-                    Either::A(
-                        tx.send((message, client_addr))
-                            .map_err(DispatcherError::from),
-                    )
-                } else {
-                    Either::B(future::ok(tx))
+                doh_cert = config.doh_cert;
+                doh_key = config.doh_key;
+                doh_client_ca = config.doh_client_ca;
+            }
+            #[cfg(not(feature = "doh"))]
+            {
+                if config.doh_listen.is_some() {
+                    return Err(String::from("listen.doh requires the doh feature"));
                 }
             }
-        })
-        .map_err(|e| error!("error in udp dispatcher: {:?}", e));
-
-    let tcp_dispatcher = tcp_sock
-        .incoming()
-        .for_each(move |stream| {
-            let local_entries = local_entries_tcp.clone();
-            let client_addr = stream.peer_addr().expect("peer_addr");
-            let (sink, stream) = DnsMessageCodec::new(true).framed(stream).split();
-
-            let forwarder = stream
-                .inspect(move |message| {
-                    info!(
-                        "Message {:x} from {} is TCP query",
-                        message.header.id, client_addr
-                    )
-                })
-                .map_err(|e| error!("error in tcp stream {}", e))
-                .fold(sink, move |sink, mut message| {
-                    let local_entries = local_entries.clone();
-
-                    // Connect to DNS server
-                    TcpStream::connect(&dns_addr)
-                        .map(|conn| DnsMessageCodec::new(true).framed(conn))
-                        .map_err(|e| error!("error in tcp request {}", e))
-                        // Send query to DNS server
-                        .map(move |codec| {
-                            let id = message.header.id;
-                            let local_answers =
-                                filter_questions(&mut message.question, &local_entries);
-                            if message.question.len() > 0 {
-                                Either::A(
-                                    codec
-                                        .send(message)
-                                        .map_err(|e| error!("error sending tcp {}", e))
-                                        .map(move |codec| (id, codec, local_answers, true)),
-                                )
-                            } else {
-                                Either::B(future::ok((id, codec, local_answers, false)))
-                            }
-                        })
-                        .flatten()
-                        // Get response
-                        .map(|(id, codec, local_answers, requested)| {
-                            if requested {
-                                Either::A(
-                                    codec
-                                        .into_future()
-                                        .map_err(|e| error!("error into fut {:?}", e))
-                                        .timeout(Duration::from_secs(2))
-                                        .map_err(|_| error!("tcp timeout"))
-                                        .map(move |(resp, _codec)| (resp, local_answers)),
-                                )
-                            } else {
-                                Either::B(future::ok((
-                                    Some(from_answer(id, &local_answers)),
-                                    vec![],
-                                )))
-                            }
-                        })
-                        .flatten()
-                        .then(|result| match result {
-                            Ok((Some(mut response), local_answers)) => {
-                                info!("Message {:x} is TCP response", response.header.id);
-                                debug!("Response is {:#?}", response);
-                                response.answer.extend(local_answers);
-                                Ok(response)
-                            }
-                            _ => {
-                                error!("can't get response!");
-                                Err(())
-                            }
-                        })
-                        // Send to client
-                        .inspect(|message| report_answers(message))
-                        .map(|message| sink.send(message).map_err(|e| error!("{}", e)))
-                        .flatten()
-                    // Done!
-                })
-                .map(|_| ());
-            tokio::spawn(forwarder);
-
-            future::ok(())
-        })
-        .map_err(|e| error!("error in tcp dispatcher: {:?}", e));
-
-    let udp = udp_sender.join(udp_dispatcher).map(|_| ());
-    tokio::run(udp.join(tcp_dispatcher).map(|_| ()));
-}
+            #[cfg(feature = "admin")]
+            {
+                if let Some(addr) = config.admin_listen {
+                    server = server.admin_listen(addr);
+                }
+                if let Some(path) = config.admin_token_file {
+                    server = server.admin_token_file(path);
+                }
+            }
+            #[cfg(not(feature = "admin"))]
+            {
+                if config.admin_listen.is_some() || config.admin_token_file.is_some() {
+                    return Err(String::from("listen.admin requires the admin feature"));
+                }
+            }
+            #[cfg(unix)]
+            {
+                if let Some(path) = config.control {
+                    server = server.control_socket(path);
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                if config.control.is_some() {
+                    return Err(String::from("listen.control requires Unix"));
+                }
+            }
+            dns_addrs = config.upstreams;
+            strategy = config.strategy.unwrap_or_default();
+            if let Some(timeout) = config.timeout {
+                server = server.timeout(timeout);
+            }
+            if let Some(retries) = config.retries {
+                server = server.retries(retries);
+            }
+            if let Some(name) = config.probe_name {
+                server = server.probe_name(name);
+            }
+            if let Some(file) = config.conf_file {
+                conf_file = file;
+            }
+            zone_files = config.zone_files;
+            hosts_files = config.hosts_files;
+            blocklists = config.blocklists;
+            allowlists = config.allowlists;
+            for zone in config.local_zones {
+                server = server.local_zone(zone);
+            }
+            name_policy = config.name_policy.unwrap_or_default();
+            watch_delay = config.watch;
+            #[cfg(feature = "cache")]
+            {
+                if let Some(size) = config.cache_size {
+                    server = server.cache_size(size);
+                }
+                if let Some(size) = config.cache_memory {
+                    server = server.cache_memory(size);
+                }
+            }
+            #[cfg(not(feature = "cache"))]
+            {
+                if config.cache_size.is_some() || config.cache_memory.is_some() {
+                    return Err(String::from("cache.size requires the cache feature"));
+                }
+            }
+            if let Some(ttl) = config.min_ttl {
+                min_ttl = ttl;
+                server = server.min_ttl(ttl);
+            }
+            if let Some(ttl) = config.max_ttl {
+                max_ttl = Some(ttl);
+                server = server.max_ttl(ttl);
+            }
+            if let Some(level) = config.log_level {
+                debug = format!("uind={}", level);
+            }
+        }
+    }
 
-fn init() -> Result<ServerConfig, String> {
-    let mut config: ServerConfig = Default::default();
-    let args: Vec<_> = env::args().collect();
-    let mut dns_addr = String::from("202.141.178.13:53");
-    let mut conf_file = String::from("dnsrelay.txt");
-    let mut debug = "";
-
-    if 1 < args.len() && args[1].starts_with("-d") {
-        if args[1] == "-d" {
-            debug = "uind=info";
-        } else if args[1] == "-dd" {
-            debug = "uind=debug";
-        } else {
-            return Err(format!("Unknown option {}", args[0]));
+    match matches.occurrences_of("debug") {
+        0 => {}
+        1 => debug = String::from("uind=info"),
+        _ => debug = String::from("uind=debug"),
+    }
+    if let Some(level) = matches.value_of("log-level") {
+        debug = format!("uind={}", level);
+    }
+    #[cfg(feature = "mirror")]
+    {
+        if let Some(addr) = value(matches, "mirror")? {
+            server = server.mirror(addr);
         }
-        if 2 < args.len() {
-            dns_addr = args[2].clone();
+    }
+    if let Some(size) = matches.value_of("memory-budget") {
+        server = server.memory_budget(
+            memory::parse_size(size).ok_or(format!("Error parsing memory budget {}", size))?,
+        );
+    }
+    if matches.is_present("listen") {
+        listen = values(matches, "listen")?;
+    }
+    for addr in listen {
+        server = server.listen(addr);
+    }
+    #[cfg(feature = "doh")]
+    {
+        if let Some(addr) = value(matches, "doh-listen")? {
+            server = server.doh_listen(addr);
         }
-        if 3 < args.len() {
-            conf_file = args[3].clone();
+        if let Some(cert) = value(matches, "doh-cert")? {
+            doh_cert = Some(cert);
         }
-    } else {
-        if 1 < args.len() {
-            dns_addr = args[1].clone();
+        if let Some(key) = value(matches, "doh-key")? {
+            doh_key = Some(key);
+        }
+        if let Some(ca) = value(matches, "doh-client-ca")? {
+            doh_client_ca = Some(ca);
+        }
+    }
+    #[cfg(feature = "admin")]
+    {
+        if let Some(addr) = value(matches, "admin-listen")? {
+            server = server.admin_listen(addr);
         }
-        if 2 < args.len() {
-            conf_file = args[2].clone();
+        if let Some(path) = value(matches, "admin-token-file")? {
+            server = server.admin_token_file(path);
         }
     }
+    if let Some(workers) = value(matches, "workers")? {
+        server = server.workers(workers);
+    }
+    if let Some(cpus) = matches.value_of("cpu-affinity") {
+        server = server.cpu_affinity(
+            affinity::parse_cpu_list(cpus).ok_or(format!("Error parsing CPU list {}", cpus))?,
+        );
+    }
+    if let Some(cpu) = value(matches, "rx-cpu")? {
+        server = server.rx_cpu(cpu);
+    }
+    #[cfg(unix)]
+    {
+        server = server.daemon(matches.is_present("daemon"));
+        if let Some(path) = value(matches, "control")? {
+            server = server.control_socket(path);
+        }
+    }
+    if let Some(path) = value(matches, "pid-file")? {
+        server = server.pid_file(path);
+    }
+    if let Some(ms) = value(matches, "shutdown-grace")? {
+        server = server.shutdown_grace(Duration::from_millis(ms));
+    }
+    if matches.is_present("user") || matches.is_present("group") {
+        server = server.run_as(Account::lookup(
+            matches.value_of("user"),
+            matches.value_of("group"),
+        )?);
+    }
+    #[cfg(feature = "cache")]
+    {
+        if let Some(size) = value(matches, "cache-size")? {
+            server = server.cache_size(size);
+        }
+        if let Some(size) = matches.value_of("cache-memory") {
+            server = server.cache_memory(
+                memory::parse_size(size).ok_or(format!("Error parsing cache memory {}", size))?,
+            );
+        }
+    }
+    if let Some(ttl) = value(matches, "min-ttl")? {
+        min_ttl = ttl;
+        server = server.min_ttl(ttl);
+    }
+    if let Some(ttl) = value(matches, "max-ttl")? {
+        max_ttl = Some(ttl);
+        server = server.max_ttl(ttl);
+    }
+    if let Some(s) = value(matches, "strategy")? {
+        strategy = s;
+    }
+    if let Some(timeout) = matches.value_of("upstream-timeout") {
+        server = server.timeout(Duration::from_millis(
+            timeout
+                .parse()
+                .ok()
+                .filter(|&ms| ms > 0)
+                .ok_or(format!("Invalid upstream timeout {}", timeout))?,
+        ));
+    }
+    if let Some(retries) = matches.value_of("upstream-retries") {
+        server = server.retries(
+            retries
+                .parse()
+                .ok()
+                .filter(|&n| n <= 10)
+                .ok_or(format!("Invalid retry count {}", retries))?,
+        );
+    }
+    if let Some(name) = matches.value_of("probe-name") {
+        server = server.probe_name(DomainName::from_idn(name)?);
+    }
+    if matches.is_present("minimal-responses") {
+        server = server.minimal_responses(true);
+    }
+    if matches.is_present("round-robin") {
+        server = server.round_robin(true);
+    }
+    if matches.is_present("minimal-any") {
+        server = server.minimal_any(true);
+    }
+    for addr in values(matches, "minimal-any-on")? {
+        server = server.minimal_any_on(addr);
+    }
+    for rule in values(matches, "rewrite")? {
+        server = server.rewrite_rule(rule);
+    }
+    for filter in values(matches, "only-family")? {
+        server = server.family_filter(filter);
+    }
+    if matches.is_present("randomize-case") {
+        server = server.randomize_case(true);
+    }
+    if matches.is_present("no-scrub") {
+        server = server.scrub(false);
+    }
+    if let Some(mode) = value(matches, "client-subnet")? {
+        server = server.client_subnet(mode);
+    }
+    #[cfg(feature = "update")]
+    {
+        for zone in values(matches, "update-zone")? {
+            server = server.update_zone(zone);
+        }
+    }
+    if let Some(delay) = value(matches, "watch")? {
+        watch_delay = Some(Duration::from_millis(delay));
+    }
+    #[cfg(feature = "transfer")]
+    {
+        for zone in values(matches, "transfer-zone")? {
+            server = server.transfer_zone(zone);
+        }
+    }
+    for zone in matches.values_of("local-zone").into_iter().flatten() {
+        server = server.local_zone(DomainName::from_idn(zone)?);
+    }
+    #[cfg(feature = "secondary")]
+    {
+        for zone in values(matches, "secondary-zone")? {
+            server = server.secondary_zone(zone);
+        }
+    }
+    zone_files.extend(values(matches, "zone-file")?);
+    hosts_files.extend(values(matches, "hosts")?);
+    blocklists.extend(values(matches, "blocklist")?);
+    allowlists.extend(values(matches, "allowlist")?);
+    #[cfg(feature = "tsig")]
+    {
+        for key in values(matches, "tsig-key")? {
+            server = server.tsig_key(key);
+        }
+    }
+    if let Some(version) = matches.value_of("version-bind") {
+        server = server.version(version.to_owned());
+    }
+    if let Some(identity) = matches.value_of("id-server") {
+        server = server.identity(identity.to_owned());
+    }
+    #[cfg(feature = "dnstap")]
+    {
+        if let Some(socket) = matches.value_of("dnstap") {
+            server = server.dnstap(PathBuf::from(socket));
+        }
+    }
+    #[cfg(feature = "history")]
+    {
+        if let Some(path) = value(matches, "history")? {
+            let days: u64 = value(matches, "history-days")?.unwrap_or(7);
+            server = server.history(path, Duration::from_secs(days * 24 * 3600));
+        }
+    }
+    if let Some(policy) = value(matches, "names")? {
+        name_policy = policy;
+    }
+    for rule in values(matches, "qtype-policy")? {
+        server = server.qtype_rule(rule);
+    }
+    if let Some(limit) = value(matches, "rate-limit")? {
+        server = server.rate_limit(limit);
+    }
+    #[cfg(feature = "pipe")]
+    {
+        for pipe in matches.values_of("pipe").into_iter().flatten() {
+            let mut parts = pipe.splitn(2, '=');
+            let suffix = parts.next().unwrap();
+            let command = parts.next().ok_or("--pipe requires SUFFIX=COMMAND")?;
+            server = server.pipe(PipeConfig {
+                suffix: DomainName::from_idn(suffix)?,
+                command: command.to_owned(),
+                timeout: Duration::from_secs(2),
+            });
+        }
+    }
+    if max_ttl.map_or(false, |max_ttl| max_ttl < min_ttl) {
+        return Err(String::from("--min-ttl must not exceed --max-ttl"));
+    }
+    #[cfg(feature = "doh")]
+    {
+        match (doh_cert, doh_key) {
+            (Some(cert), Some(key)) => server = server.doh_certificate(cert, key),
+            (None, None) if doh_client_ca.is_some() => {
+                return Err(String::from("--doh-client-ca requires --doh-cert"));
+            }
+            (None, None) => {}
+            _ => return Err(String::from("--doh-cert and --doh-key go together")),
+        }
+        if let Some(ca) = doh_client_ca {
+            server = server.doh_client_ca(ca);
+        }
+    }
+    // With --upstream, a lone positional argument is the config file
+    let (upstreams, conf) = match (matches.value_of("upstream"), matches.value_of("UPSTREAM")) {
+        (Some(_), Some(_)) if matches.is_present("CONF-FILE") => {
+            return Err(String::from("Upstream servers given twice"));
+        }
+        (Some(upstreams), conf) => (Some(upstreams), conf),
+        (None, upstreams) => (upstreams, matches.value_of("CONF-FILE")),
+    };
+    if let Some(upstreams) = upstreams {
+        dns_addrs = upstreams
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+    }
+    if dns_addrs.is_empty() {
+        dns_addrs.push("202.141.178.13:53".parse()?);
+    }
+    if let Some(file) = conf {
+        conf_file = file.to_owned();
+    }
 
-    config.dns_addr = dns_addr
-        .parse()
-        .map_err(|_| format!("Error parsing DNS server address {}", dns_addr))?;
+    server = server.upstreams(dns_addrs, strategy);
 
-    let file =
-        fs::File::open(conf_file).map_err(|e| format!("Error opening config file: {}", e))?;
-    let reader = BufReader::new(file);
-    for (lineno, line) in reader.lines().enumerate() {
-        let line = line.map_err(|e| format!("Error reading line {}", e))?;
+    let mut views = vec![];
+    let mut view_files = vec![];
+    for view in matches.values_of("view").into_iter().flatten() {
+        let (view, file) = parse_view(view)?;
+        views.push(view);
+        view_files.push(file);
+    }
 
-        if line.trim_start().starts_with("#") {
-            continue;
+    let canary_file = match matches.value_of("canary-conf") {
+        Some(file) if cfg!(feature = "canary") => Some(file.to_owned()),
+        _ => None,
+    };
+    let files = LocalFiles {
+        conf_file: conf_file.clone(),
+        zone_files,
+        hosts_files,
+        blocklists,
+        allowlists,
+        view_files,
+        canary_file,
+        name_policy,
+    };
+    let LocalConfig {
+        local,
+        regex_rules,
+        #[cfg(feature = "blocklists")]
+        blocklist,
+        views: view_tables,
+        canary: canary_table,
+    } = files.load()?;
+    for rule in regex_rules {
+        server = server.regex_rule(rule);
+    }
+    #[cfg(feature = "watch")]
+    {
+        if let Some(delay) = watch_delay {
+            server = server.watch(files.paths(), delay);
         }
+    }
+    #[cfg(not(feature = "watch"))]
+    {
+        if watch_delay.is_some() {
+            return Err(String::from("local.watch requires the watch feature"));
+        }
+    }
+    #[cfg(feature = "blocklists")]
+    {
+        server = server.blocklist(blocklist);
+    }
+    server = server.reload_with(move || files.load());
+    #[cfg(feature = "update")]
+    {
+        if matches.is_present("persist-updates") {
+            server = server.persist_updates(PathBuf::from(&conf_file));
+        }
+    }
 
-        let parts: Vec<_> = line.split_whitespace().collect();
-        if parts.len() != 2 {
-            if parts.len() != 0 {
-                warn!("Line {} is malformed, ignoring", lineno + 1);
-            }
-            continue;
+    #[cfg(feature = "canary")]
+    {
+        if let Some(dns_addr) = value(matches, "canary")? {
+            server = server.canary(CanaryConfig {
+                dns_addr,
+                local: canary_table.unwrap_or_else(|| local.clone()),
+                percent: match matches.value_of("canary-percent") {
+                    Some(percent) => percent
+                        .parse()
+                        .ok()
+                        .filter(|&p| p <= 100)
+                        .ok_or(format!("Invalid canary percentage {}", percent))?,
+                    None => 10,
+                },
+                clients: values(matches, "canary-client")?,
+            });
         }
-        let (domain_name, answer) = (parts[0], parts[1]);
-        let answer = answer
-            .parse()
-            .map_err(|_| format!("Can't parse IP address at line {}", lineno + 1))?;
-        let domain_name: Vec<_> = domain_name.split(".").map(String::from).collect();
-        let answer = DnsResourceRecord {
-            name: domain_name.clone(),
-            rclass: DnsClass::Internet,
-            rtype: DnsType::A,
-            data: DnsRRData::A(answer),
-            ttl: 10,
-        };
-        let entry = config.local.entry(domain_name).or_insert(vec![]);
-        (*entry).push(answer);
     }
+    #[cfg(not(feature = "canary"))]
+    let _ = canary_table;
+
+    for (mut view, local) in views.into_iter().zip(view_tables) {
+        view.local = local;
+        server = server.view(view);
+    }
+
+    server = server.local_entries(local).name_policy(name_policy);
 
     if let Err(_) = env::var("RUST_LOG") {
-        env::set_var("RUST_LOG", debug);
+        env::set_var("RUST_LOG", &debug);
     }
 
     env_logger::init();
     info!("Server config loaded!");
 
-    Ok(config)
+    Ok(server)
 }
 
-fn report_answers(message: &DnsMessage) {
-    let report: Vec<_> = message
-        .answer
-        .iter()
-        .filter(|x| match x.data {
-            DnsRRData::A(_) | DnsRRData::AAAA(_) => true,
-            _ => false,
-        })
-        .map(|x| {
-            (
-                &x.name,
-                match x.data {
-                    DnsRRData::A(ip4) => IpAddr::V4(ip4),
-                    DnsRRData::AAAA(ip6) => IpAddr::V6(ip6),
-                    _ => unreachable!(),
-                },
-            )
-        })
-        .collect();
-    for (name, ip) in report {
-        println!("{:x}: {}: {}", message.header.id, name.join("."), ip)
+#[cfg(feature = "config-file")]
+fn load_config(path: &str) -> Result<Config, String> {
+    let config = fs::read_to_string(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+    config
+        .parse()
+        .map_err(|e| format!("Error in {}: {}", path, e))
+}
+
+/// `dnsrelay.txt` in the working directory, or else in the per-user config
+/// directory, so that uind runs without write access outside `$HOME`.
+fn default_conf_file() -> String {
+    let local = String::from("dnsrelay.txt");
+    if Path::new(&local).exists() {
+        return local;
+    }
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    match config_home {
+        Some(dir) => {
+            let conf_file = dir.join("uind").join("dnsrelay.txt");
+            if conf_file.exists() {
+                conf_file.to_string_lossy().into_owned()
+            } else {
+                local
+            }
+        }
+        None => local,
     }
 }
 
-fn from_answer(id: u16, answer: &Vec<DnsResourceRecord>) -> DnsMessage {
-    let refused = answer.iter().fold(false, |refused, x| {
-        refused
-            || match x.data {
-                DnsRRData::A(x) => x == Ipv4Addr::new(0, 0, 0, 0),
-                _ => false,
+/// The files the local configuration is loaded from, at startup and again
+/// on SIGHUP.
+struct LocalFiles {
+    conf_file: String,
+    zone_files: Vec<String>,
+    hosts_files: Vec<String>,
+    blocklists: Vec<String>,
+    allowlists: Vec<String>,
+    /// The entries of each view.
+    view_files: Vec<String>,
+    /// The canary's entries, if not those of `conf_file`.
+    canary_file: Option<String>,
+    name_policy: NamePolicy,
+}
+
+impl LocalFiles {
+    fn load(&self) -> Result<LocalConfig, String> {
+        let mut local = load_entries(&self.conf_file, self.name_policy)?;
+        for zone_file in &self.zone_files {
+            for rr in load_zone_file(zone_file, self.name_policy)? {
+                local
+                    .entry(rr.name.clone())
+                    .or_insert_with(Vec::new)
+                    .push(rr);
             }
-    });
-    DnsMessage {
-        header: DnsHeader {
-            id: id,
-            authoritative: false,
-            query: false,
-            opcode: DnsOpcode::Query,
-            truncated: false,
-            recur_available: false,
-            recur_desired: true,
-            rcode: if refused {
-                DnsRcode::Refused
-            } else {
-                DnsRcode::NoErrorCondition
+        }
+        for hosts_file in &self.hosts_files {
+            load_hosts_file(&mut local, hosts_file, self.name_policy)?;
+        }
+        #[cfg(not(feature = "blocklists"))]
+        {
+            if !self.blocklists.is_empty() || !self.allowlists.is_empty() {
+                return Err(String::from("Blocklists require the blocklists feature"));
+            }
+        }
+        Ok(LocalConfig {
+            local,
+            regex_rules: load_regex_rules(&self.conf_file)?,
+            #[cfg(feature = "blocklists")]
+            blocklist: load_blocklists(&self.blocklists, &self.allowlists)?,
+            views: self
+                .view_files
+                .iter()
+                .map(|file| load_entries(file, self.name_policy))
+                .collect::<Result<_, _>>()?,
+            canary: match self.canary_file {
+                Some(ref file) => Some(load_entries(file, self.name_policy)?),
+                None => None,
             },
-        },
-        answer: if refused { vec![] } else { answer.clone() },
-        ..Default::default()
+        })
+    }
+
+    #[cfg(feature = "watch")]
+    fn paths(&self) -> Vec<PathBuf> {
+        let zone_files = self
+            .zone_files
+            .iter()
+            .map(|arg| arg.splitn(2, '=').last().unwrap());
+        Some(&self.conf_file)
+            .into_iter()
+            .map(String::as_str)
+            .chain(zone_files)
+            .chain(self.hosts_files.iter().map(String::as_str))
+            .chain(self.blocklists.iter().map(String::as_str))
+            .chain(self.allowlists.iter().map(String::as_str))
+            .chain(self.view_files.iter().map(String::as_str))
+            .chain(self.canary_file.iter().map(String::as_str))
+            .map(PathBuf::from)
+            .collect()
     }
 }
 
-fn filter_questions(
-    questions: &mut Vec<DnsQuestion>,
-    local_entries: &EntryTable,
-) -> Vec<DnsResourceRecord> {
-    questions
-        .drain_filter(|x| local_entries.contains_key(&x.qname) && x.qtype == DnsType::A)
-        .map(|q| local_entries[&q.qname].clone())
-        .flatten()
+/// The `/REGEX/ ADDRESS` lines of a config file.
+fn load_regex_rules(conf_file: &str) -> Result<Vec<RegexRule>, String> {
+    let conf =
+        fs::read_to_string(conf_file).map_err(|e| format!("Error opening config file: {}", e))?;
+    conf.lines()
+        .filter(|line| line.trim_start().starts_with('/'))
+        .map(str::parse)
         .collect()
 }
 
-type EntryTable = HashMap<DomainName, Vec<DnsResourceRecord>>;
+fn load_entries(conf_file: &str, name_policy: NamePolicy) -> Result<EntryTable, String> {
+    let mut local = EntryTable::new();
+    let file =
+        fs::File::open(conf_file).map_err(|e| format!("Error opening config file: {}", e))?;
+    let entries = hosts::parse(Format::Dnsrelay, BufReader::new(file), name_policy)?;
+    add_entries(&mut local, entries);
+    Ok(local)
+}
 
-#[derive(Debug, Clone)]
-struct ServerConfig {
-    dns_addr: SocketAddr,
-    local: EntryTable,
+/// Add the entries of a hosts(5) file, e.g. `/etc/hosts`.
+fn load_hosts_file(
+    local: &mut EntryTable,
+    path: &str,
+    name_policy: NamePolicy,
+) -> Result<(), String> {
+    let file = fs::File::open(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+    let entries = hosts::parse(Format::Hosts, BufReader::new(file), name_policy)
+        .map_err(|e| format!("Error in {}: {}", path, e))?;
+    add_entries(local, entries);
+    Ok(())
 }
 
-impl Default for ServerConfig {
-    fn default() -> ServerConfig {
-        ServerConfig {
-            dns_addr: "202.141.178.13:53".parse().unwrap(),
-            local: HashMap::new(),
-        }
+fn add_entries(local: &mut EntryTable, entries: Vec<(DomainName, DnsRRData)>) {
+    for (domain_name, data) in entries {
+        let answer = DnsResourceRecord {
+            name: domain_name.clone(),
+            rclass: DnsClass::Internet,
+            rtype: data.rtype(),
+            data,
+            ttl: 10,
+        };
+        let entry = local.entry(domain_name).or_insert(vec![]);
+        (*entry).push(answer);
     }
 }
 
-#[derive(Debug)]
-enum DispatcherError<T> {
-    ChannelError(mpsc::SendError<T>),
-    NetworkError(std::io::Error),
+#[cfg(feature = "blocklists")]
+fn load_blocklists(paths: &[String], allowlists: &[String]) -> Result<Blocklist, String> {
+    let mut blocklist = Blocklist::new();
+    let open = |path: &String| {
+        fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Error opening {}: {}", path, e))
+    };
+    for path in paths {
+        blocklist
+            .read(open(path)?)
+            .map_err(|e| format!("Error in {}: {}", path, e))?;
+    }
+    for path in allowlists {
+        blocklist
+            .read_allowed(open(path)?)
+            .map_err(|e| format!("Error in {}: {}", path, e))?;
+    }
+    Ok(blocklist)
 }
 
-impl<T> From<mpsc::SendError<T>> for DispatcherError<T> {
-    fn from(e: mpsc::SendError<T>) -> Self {
-        DispatcherError::ChannelError(e)
-    }
+/// Load `[ORIGIN=]FILE`, a zone file whose relative names are under
+/// `ORIGIN`, or else the root.
+fn load_zone_file(arg: &str, name_policy: NamePolicy) -> Result<Vec<DnsResourceRecord>, String> {
+    let mut parts = arg.splitn(2, '=');
+    let (origin, path) = match (parts.next(), parts.next()) {
+        (Some(origin), Some(path)) => (DomainName::from_idn(origin)?, path),
+        _ => (DomainName::default(), arg),
+    };
+    let file = fs::File::open(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+    zonefile::parse(BufReader::new(file), origin, name_policy)
+        .map_err(|e| format!("Error in {}: {}", path, e))
 }
 
-impl<T> From<std::io::Error> for DispatcherError<T> {
-    fn from(e: std::io::Error) -> Self {
-        DispatcherError::NetworkError(e)
+/// Parse `NAME@NETWORK[,...]=FILE[,IP:PORT]`, a view of the local entries of
+/// `FILE`, forwarding to `IP:PORT` if given, for clients in the networks.
+/// The view comes without its entries, which `LocalFiles` loads from the
+/// file returned along.
+fn parse_view(arg: &str) -> Result<(ViewConfig, String), String> {
+    let mut parts = arg.splitn(2, '=');
+    let (clients, rest) = match (parts.next(), parts.next()) {
+        (Some(clients), Some(rest)) => (clients, rest),
+        _ => return Err(format!("--view {} requires NAME@NETWORK=FILE", arg)),
+    };
+    let mut parts = clients.splitn(2, '@');
+    let (name, networks) = match (parts.next(), parts.next()) {
+        (Some(name), Some(networks)) if !name.is_empty() => (name, networks),
+        _ => return Err(format!("--view {} requires NAME@NETWORK=FILE", arg)),
+    };
+    let clients = networks
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    let mut parts = rest.splitn(2, ',');
+    let file = parts.next().unwrap().to_owned();
+    let dns_addr = match parts.next() {
+        Some(addr) => Some(
+            addr.parse()
+                .map_err(|e| format!("Error parsing upstream of view {}: {}", name, e))?,
+        ),
+        None => None,
+    };
+    let view = ViewConfig {
+        name: name.to_owned(),
+        clients,
+        local: EntryTable::new(),
+        dns_addr,
+    };
+    Ok((view, file))
+}
+
+/// `uind convert [--from FORMAT] [--to FORMAT] [--names POLICY] [INPUT] [OUTPUT]`
+fn convert(matches: &ArgMatches) -> Result<(), String> {
+    let from = value(matches, "from")?.unwrap_or(Format::Dnsrelay);
+    let to = value(matches, "to")?.unwrap_or(Format::Hosts);
+    let name_policy = value(matches, "names")?.unwrap_or_default();
+
+    if let Err(_) = env::var("RUST_LOG") {
+        env::set_var("RUST_LOG", "uind=warn");
     }
+    env_logger::init();
+
+    let input: Box<dyn Read> = match matches.value_of("INPUT") {
+        None | Some("-") => Box::new(io::stdin()),
+        Some(path) => {
+            Box::new(fs::File::open(path).map_err(|e| format!("Error opening {}: {}", path, e))?)
+        }
+    };
+    let entries = hosts::parse(from, BufReader::new(input), name_policy)?;
+
+    let output: Box<dyn Write> = match matches.value_of("OUTPUT") {
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => {
+            Box::new(fs::File::create(path).map_err(|e| format!("Error creating {}: {}", path, e))?)
+        }
+    };
+    hosts::write(to, &entries, output).map_err(|e| format!("Error writing output: {}", e))
 }
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::message::{DnsRRData, DnsResourceRecord, DomainName};
+
+/// Approximate accounting of the memory held by the server's tables.
+///
+/// A limit of 0 means unlimited.  Local tables are loaded up front and
+/// always admitted; transient state like pending queries is refused once
+/// the budget is exhausted.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    limit: usize,
+    local: AtomicUsize,
+    pending: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(any(feature = "metrics", feature = "admin", unix))]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.local.load(Ordering::Relaxed) + self.pending.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "metrics", feature = "admin", unix))]
+    pub fn local(&self) -> usize {
+        self.local.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "metrics", feature = "admin", unix))]
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn set_local(&self, bytes: usize) {
+        self.local.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Replace the pending usage with a fresh count, e.g. after entries
+    /// expired without being released.
+    pub fn set_pending(&self, bytes: usize) {
+        self.pending.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Charge `bytes` of pending state.  Returns false, charging nothing, if
+    /// that would exceed the budget.
+    pub fn charge_pending(&self, bytes: usize) -> bool {
+        if self.limit != 0 && self.used() + bytes > self.limit {
+            return false;
+        }
+        self.pending.fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+
+    pub fn release_pending(&self, bytes: usize) {
+        let pending = self.pending.load(Ordering::Relaxed);
+        self.pending
+            .store(pending.saturating_sub(bytes), Ordering::Relaxed);
+    }
+}
+
+pub fn name_size(name: &DomainName) -> usize {
+    name.iter()
+        .map(|label| size_of::<String>() + label.len())
+        .sum()
+}
+
+pub fn rr_size(rr: &DnsResourceRecord) -> usize {
+    let data = match rr.data {
+        DnsRRData::A(_) | DnsRRData::AAAA(_) => 0,
+        DnsRRData::MX(_, ref name)
+        | DnsRRData::CNAME(ref name)
+        | DnsRRData::NS(ref name)
+        | DnsRRData::PTR(ref name)
+        | DnsRRData::SRV(.., ref name) => name_size(name),
+        DnsRRData::TXT(ref txt) => txt.iter().map(|s| size_of::<Vec<u8>>() + s.len()).sum(),
+        DnsRRData::SOA(ref mname, ref rname, ..) => name_size(mname) + name_size(rname),
+        DnsRRData::CAA(_, ref tag, ref value) => tag.len() + value.len(),
+        DnsRRData::HINFO(ref cpu, ref os) => 2 * size_of::<Vec<u8>>() + cpu.len() + os.len(),
+        DnsRRData::NAPTR(_, _, ref flags, ref services, ref regexp, ref replacement) => {
+            3 * size_of::<Vec<u8>>()
+                + flags.len()
+                + services.len()
+                + regexp.len()
+                + name_size(replacement)
+        }
+        DnsRRData::DS(.., ref digest) => digest.len(),
+        DnsRRData::RRSIG(.., ref signer, ref signature) => name_size(signer) + signature.len(),
+        DnsRRData::NSEC(ref next, ref types) => name_size(next) + types.len(),
+        DnsRRData::DNSKEY(.., ref key) => key.len(),
+        DnsRRData::Unknown(_, ref data) => data.len(),
+        DnsRRData::NSEC3(.., ref salt, ref next, ref types) => {
+            salt.len() + next.len() + types.len()
+        }
+    };
+    size_of::<DnsResourceRecord>() + name_size(&rr.name) + data
+}
+
+pub fn records_size(records: &[DnsResourceRecord]) -> usize {
+    records.iter().map(rr_size).sum()
+}
+
+pub fn table_size(table: &HashMap<DomainName, Vec<DnsResourceRecord>>) -> usize {
+    table
+        .iter()
+        .map(|(name, records)| name_size(name) + records_size(records))
+        .sum()
+}
+
+/// Parse a byte count with an optional K, M or G suffix.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (digits, unit) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
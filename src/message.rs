@@ -1,23 +1,418 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Deref;
+use std::str::FromStr;
 
-pub type DomainName = Vec<String>;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
+/// A domain name as a list of labels, without the root label.
+///
+/// Names compare and hash ignoring ASCII case, as DNS names do, but keep
+/// the case they were received with for logging and for the wire.
 #[derive(Clone, Debug, Default)]
+pub struct DomainName(Vec<String>);
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &DomainName) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in &self.0 {
+            state.write_usize(label.len());
+            for b in label.bytes() {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+impl Deref for DomainName {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl From<Vec<String>> for DomainName {
+    fn from(labels: Vec<String>) -> DomainName {
+        DomainName(labels)
+    }
+}
+
+impl<'a> From<Vec<&'a str>> for DomainName {
+    fn from(labels: Vec<&'a str>) -> DomainName {
+        labels.into_iter().map(String::from).collect()
+    }
+}
+
+impl DomainName {
+    /// Whether this name is `suffix` or under it.
+    pub fn ends_with(&self, suffix: &DomainName) -> bool {
+        self.0.len() >= suffix.0.len()
+            && self.0[self.0.len() - suffix.0.len()..]
+                .iter()
+                .zip(&suffix.0)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Whether the names are equal, case included.
+    pub fn eq_exact(&self, other: &DomainName) -> bool {
+        self.0 == other.0
+    }
+
+    /// The name with each ASCII letter in random case (the "0x20 bit"),
+    /// which an answer has to echo exactly.
+    pub fn randomize_case(&self) -> DomainName {
+        self.0
+            .iter()
+            .map(|label| {
+                label
+                    .chars()
+                    .map(|c| {
+                        if rand::random() {
+                            c.to_ascii_uppercase()
+                        } else {
+                            c.to_ascii_lowercase()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Parse a name which may contain Unicode labels, like `例え.テスト`,
+    /// converting them to their ASCII form (`xn--r8jz45g.xn--zckzah`).
+    pub fn from_idn(s: &str) -> Result<DomainName, String> {
+        if s.is_ascii() {
+            return s.parse();
+        }
+        #[cfg(feature = "idn")]
+        return idna::domain_to_ascii(s)
+            .map_err(|_| format!("Invalid domain name {}", s))?
+            .parse();
+        #[cfg(not(feature = "idn"))]
+        Err(format!("Unicode name {} requires the idn feature", s))
+    }
+
+    /// Dot notation like `Display`, but with `xn--` labels decoded.
+    pub fn to_unicode(&self) -> String {
+        let labels: Vec<_> = self
+            .0
+            .iter()
+            .map(|label| {
+                let ascii = DomainName(vec![label.clone()]).to_string();
+                if cfg!(feature = "idn")
+                    && label.len() > 4
+                    && label[..4].eq_ignore_ascii_case("xn--")
+                {
+                    #[cfg(feature = "idn")]
+                    match idna::domain_to_unicode(&ascii) {
+                        (unicode, Ok(())) => unicode,
+                        (_, Err(_)) => ascii,
+                    }
+                    #[cfg(not(feature = "idn"))]
+                    ascii
+                } else {
+                    ascii
+                }
+            })
+            .collect();
+        if labels.is_empty() {
+            String::from(".")
+        } else {
+            labels.join(".")
+        }
+    }
+
+    /// The name without its first label, or `None` for the root.
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.0.is_empty() {
+            None
+        } else {
+            Some(DomainName(self.0[1..].to_vec()))
+        }
+    }
+}
+
+/// Dot notation, escaping dots, backslashes and non-printable bytes within
+/// labels like `\DDD`.  The root is `.`.
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, ".");
+        }
+        for (i, label) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            for b in label.bytes() {
+                match b {
+                    b'.' | b'\\' => write!(f, "\\{}", b as char)?,
+                    _ if b.is_ascii_graphic() => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = String;
+
+    /// Parse dot notation with the escapes of `Display`.  The trailing dot
+    /// is optional.
+    fn from_str(s: &str) -> Result<DomainName, String> {
+        let err = || format!("Invalid domain name {}", s);
+        if s == "." {
+            return Ok(DomainName::default());
+        }
+        let mut labels = vec![];
+        let mut label = vec![];
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'.' if label.is_empty() => return Err(err()),
+                b'.' => labels.push(std::mem::replace(&mut label, vec![])),
+                b'\\' => match bytes.next() {
+                    Some(d) if d.is_ascii_digit() => {
+                        let digits = [
+                            d,
+                            bytes.next().ok_or_else(err)?,
+                            bytes.next().ok_or_else(err)?,
+                        ];
+                        let digits = std::str::from_utf8(&digits).map_err(|_| err())?;
+                        label.push(digits.parse().map_err(|_| err())?);
+                    }
+                    Some(c) => label.push(c),
+                    None => return Err(err()),
+                },
+                _ => label.push(b),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        }
+        let name: DomainName = labels
+            .iter()
+            .map(|label| String::from_utf8_lossy(label).into_owned())
+            .collect();
+        if name.is_empty() || !NamePolicy::Permissive.check(&name) {
+            return Err(err());
+        }
+        Ok(name)
+    }
+}
+
+/// Split at dots, without escapes or validation; for names known to be
+/// well-formed.  Use `parse` for anything else.
+impl<'a> From<&'a str> for DomainName {
+    fn from(s: &'a str) -> DomainName {
+        let s = if s.ends_with('.') {
+            &s[..s.len() - 1]
+        } else {
+            s
+        };
+        if s.is_empty() {
+            return DomainName::default();
+        }
+        s.split('.').map(String::from).collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a DomainName {
+    type Item = &'a String;
+    type IntoIter = std::slice::Iter<'a, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl FromIterator<String> for DomainName {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> DomainName {
+        DomainName(iter.into_iter().collect())
+    }
+}
+
+/// How strictly domain names are validated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NamePolicy {
+    /// Letters, digits and hyphens only (RFC 952/1123 hostnames).
+    Strict,
+    /// Strict, plus underscores for service labels like `_dmarc` or `_sip`.
+    Standard,
+    /// Any label, as long as it fits on the wire.
+    Permissive,
+}
+
+impl NamePolicy {
+    pub fn check(self, name: &DomainName) -> bool {
+        let wire_len: usize = name.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+        wire_len <= 255 && name.iter().all(|label| self.check_label(label))
+    }
+
+    fn check_label(self, label: &str) -> bool {
+        let bytes = label.as_bytes();
+        if bytes.is_empty() || bytes.len() > 63 {
+            return false;
+        }
+        let allowed = |c: u8| match self {
+            NamePolicy::Strict => c.is_ascii_alphanumeric() || c == b'-',
+            NamePolicy::Standard => c.is_ascii_alphanumeric() || c == b'-' || c == b'_',
+            NamePolicy::Permissive => true,
+        };
+        self == NamePolicy::Permissive
+            || (bytes.iter().all(|&c| allowed(c))
+                && bytes[0] != b'-'
+                && bytes[bytes.len() - 1] != b'-')
+    }
+}
+
+impl FromStr for NamePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NamePolicy, String> {
+        match s {
+            "strict" => Ok(NamePolicy::Strict),
+            "standard" => Ok(NamePolicy::Standard),
+            "permissive" => Ok(NamePolicy::Permissive),
+            _ => Err(format!("Unknown name policy {}", s)),
+        }
+    }
+}
+
+impl Default for NamePolicy {
+    fn default() -> NamePolicy {
+        NamePolicy::Permissive
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct DnsMessage {
     pub header: DnsHeader,
     pub question: Vec<DnsQuestion>,
     pub answer: Vec<DnsResourceRecord>,
     pub authority: Vec<DnsResourceRecord>,
     pub additional: Vec<DnsResourceRecord>,
+    /// The OPT pseudo-record, which travels in the additional section but
+    /// is not a resource record.
+    pub edns: Option<Edns>,
+    /// The TSIG pseudo-record, which ends the additional section.
+    pub tsig: Option<Tsig>,
 }
 
 impl DnsMessage {
+    /// A recursive query for `qtype` records of `name` in the Internet
+    /// class, with ID 0.
+    pub fn query(name: DomainName, qtype: DnsType) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                query: true,
+                recur_desired: true,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: name,
+                qtype,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// An empty response to `query`, with its ID, opcode, RD flag and
+    /// question.
+    pub fn response_to(query: &DnsMessage) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                id: query.header.id,
+                query: false,
+                opcode: query.header.opcode,
+                recur_desired: query.header.recur_desired,
+                ..Default::default()
+            },
+            question: query.question.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_id(mut self, id: u16) -> DnsMessage {
+        self.header.id = id;
+        self
+    }
+
+    pub fn with_rcode(mut self, rcode: DnsRcode) -> DnsMessage {
+        self.header.rcode = rcode;
+        self
+    }
+
+    pub fn with_authoritative(mut self, authoritative: bool) -> DnsMessage {
+        self.header.authoritative = authoritative;
+        self
+    }
+
+    pub fn with_recur_desired(mut self, recur_desired: bool) -> DnsMessage {
+        self.header.recur_desired = recur_desired;
+        self
+    }
+
+    pub fn with_recur_available(mut self, recur_available: bool) -> DnsMessage {
+        self.header.recur_available = recur_available;
+        self
+    }
+
+    pub fn with_answer(mut self, rr: DnsResourceRecord) -> DnsMessage {
+        self.answer.push(rr);
+        self
+    }
+
+    pub fn with_answers<I>(mut self, rrs: I) -> DnsMessage
+    where
+        I: IntoIterator<Item = DnsResourceRecord>,
+    {
+        self.answer.extend(rrs);
+        self
+    }
+
+    pub fn with_authority(mut self, rr: DnsResourceRecord) -> DnsMessage {
+        self.authority.push(rr);
+        self
+    }
+
+    pub fn with_additional(mut self, rr: DnsResourceRecord) -> DnsMessage {
+        self.additional.push(rr);
+        self
+    }
+
+    pub fn with_edns(mut self, edns: Edns) -> DnsMessage {
+        self.edns = Some(edns);
+        self
+    }
+
     pub fn is_query(&self) -> bool {
         self.header.query
     }
 }
 
-#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct DnsHeader {
     pub id: u16,
     pub query: bool,
@@ -35,6 +430,8 @@ pub enum DnsOpcode {
     Query,
     InverseQuery,
     Status,
+    Notify = 4,
+    Update,
 }
 
 impl DnsOpcode {
@@ -43,24 +440,43 @@ impl DnsOpcode {
             0 => Some(DnsOpcode::Query),
             1 => Some(DnsOpcode::InverseQuery),
             2 => Some(DnsOpcode::Status),
+            4 => Some(DnsOpcode::Notify),
+            5 => Some(DnsOpcode::Update),
             _ => None,
         }
     }
 }
 
-#[repr(u8)]
+/// A response code.  Codes above 15 are extended RCODEs, whose upper 8 bits
+/// travel in the OPT record and which can only be sent with EDNS.
+#[repr(u16)]
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum DnsRcode {
-    NoErrorCondition,
+    NoErrorCondition = 0,
     FormatError,
     ServerFailure,
     NameError,
     NotImplemented,
     Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    DSOTypeNI,
+    /// BADVERS, or BADSIG in a TSIG record.
+    BadVersion = 16,
+    BadKey,
+    BadTime,
+    BadMode,
+    BadName,
+    BadAlgorithm,
+    BadTruncation,
+    BadCookie,
 }
 
 impl DnsRcode {
-    pub fn try_from(x: u8) -> Option<DnsRcode> {
+    pub fn try_from(x: u16) -> Option<DnsRcode> {
         match x {
             0 => Some(DnsRcode::NoErrorCondition),
             1 => Some(DnsRcode::FormatError),
@@ -68,18 +484,202 @@ impl DnsRcode {
             3 => Some(DnsRcode::NameError),
             4 => Some(DnsRcode::NotImplemented),
             5 => Some(DnsRcode::Refused),
+            6 => Some(DnsRcode::YXDomain),
+            7 => Some(DnsRcode::YXRRSet),
+            8 => Some(DnsRcode::NXRRSet),
+            9 => Some(DnsRcode::NotAuth),
+            10 => Some(DnsRcode::NotZone),
+            11 => Some(DnsRcode::DSOTypeNI),
+            16 => Some(DnsRcode::BadVersion),
+            17 => Some(DnsRcode::BadKey),
+            18 => Some(DnsRcode::BadTime),
+            19 => Some(DnsRcode::BadMode),
+            20 => Some(DnsRcode::BadName),
+            21 => Some(DnsRcode::BadAlgorithm),
+            22 => Some(DnsRcode::BadTruncation),
+            23 => Some(DnsRcode::BadCookie),
             _ => None,
         }
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// EDNS0 information carried by the OPT pseudo-record (RFC 6891).
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns {
+    /// The sender's UDP payload size.
+    pub udp_size: u16,
+    pub version: u8,
+    /// Flags, of which only DO (`EDNS_DO`) is defined.
+    pub flags: u16,
+    pub options: Vec<EdnsOption>,
+}
+
+/// The DNSSEC OK flag.
+pub const EDNS_DO: u16 = 0x8000;
+
+/// The Client Subnet option code (RFC 7871).
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+
+/// The Padding option code (RFC 7830).
+pub const EDNS_PADDING: u16 = 12;
+
+impl Default for Edns {
+    fn default() -> Edns {
+        Edns {
+            udp_size: 512,
+            version: 0,
+            flags: 0,
+            options: vec![],
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// A transaction signature (RFC 8945).
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tsig {
+    pub key: DomainName,
+    pub algorithm: DomainName,
+    /// Seconds since the epoch, in 48 bits.
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+    /// What the MAC was computed over: the message before the TSIG record,
+    /// with the original ID and an ARCOUNT that doesn't count the TSIG.
+    /// Only the decoder fills it in.
+    pub signed: Vec<u8>,
+}
+
+/// The contents of a Client Subnet option.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClientSubnet {
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+    /// The address, with the bits past the source prefix cleared.
+    pub addr: IpAddr,
+}
+
+impl ClientSubnet {
+    /// The subnet of `addr` with `prefix` bits, at most the address length.
+    pub fn new(addr: IpAddr, prefix: u8) -> ClientSubnet {
+        let (octets, prefix) = match addr {
+            IpAddr::V4(addr) => (addr.octets().to_vec(), prefix.min(32)),
+            IpAddr::V6(addr) => (addr.octets().to_vec(), prefix.min(128)),
+        };
+        ClientSubnet {
+            source_prefix: prefix,
+            scope_prefix: 0,
+            addr: to_addr(&octets, prefix).unwrap(),
+        }
+    }
+
+    /// Parse a Client Subnet option, or `None` if the option is another or
+    /// malformed.
+    pub fn from_option(opt: &EdnsOption) -> Option<ClientSubnet> {
+        if opt.code != EDNS_CLIENT_SUBNET || opt.data.len() < 4 {
+            return None;
+        }
+        let family = (opt.data[0] as u16) << 8 | opt.data[1] as u16;
+        let source_prefix = opt.data[2];
+        let scope_prefix = opt.data[3];
+        let addr = &opt.data[4..];
+        let max = match family {
+            1 => 32,
+            2 => 128,
+            _ => return None,
+        };
+        if source_prefix > max || addr.len() != (source_prefix as usize + 7) / 8 {
+            return None;
+        }
+        let mut octets = vec![0; max as usize / 8];
+        octets[..addr.len()].copy_from_slice(addr);
+        Some(ClientSubnet {
+            source_prefix,
+            scope_prefix,
+            addr: to_addr(&octets, source_prefix)?,
+        })
+    }
+
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+        };
+        let mut data = vec![0, family, self.source_prefix, self.scope_prefix];
+        data.extend(&octets[..(self.source_prefix as usize + 7) / 8]);
+        EdnsOption {
+            code: EDNS_CLIENT_SUBNET,
+            data,
+        }
+    }
+}
+
+/// An address from 4 or 16 octets, keeping only the first `prefix` bits.
+fn to_addr(octets: &[u8], prefix: u8) -> Option<IpAddr> {
+    let mut masked = [0; 16];
+    for (i, &b) in octets.iter().enumerate() {
+        let bits = (prefix as usize).saturating_sub(i * 8).min(8);
+        masked[i] = if bits == 0 {
+            0
+        } else {
+            b & (0xff << (8 - bits))
+        };
+    }
+    match octets.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(
+            masked[0], masked[1], masked[2], masked[3],
+        ))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(masked))),
+        _ => None,
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct DnsQuestion {
     pub qname: DomainName,
     pub qtype: DnsType,
+    /// The number of the type when `qtype` is `DnsType::Unknown`, else 0.
+    pub qcode: u16,
     pub qclass: DnsClass,
 }
 
+impl DnsQuestion {
+    /// The number of the type asked for.
+    pub fn code(&self) -> u16 {
+        match self.qtype {
+            DnsType::Unknown => self.qcode,
+            qtype => qtype as u16,
+        }
+    }
+
+    /// Ask for the type numbered `code`, which may have no variant.
+    pub fn set_code(&mut self, code: u16) {
+        match DnsType::try_from(code) {
+            Some(qtype) => {
+                self.qtype = qtype;
+                self.qcode = 0;
+            }
+            None => {
+                self.qtype = DnsType::Unknown;
+                self.qcode = code;
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug, PartialEq)]
 pub struct DnsResourceRecord {
     pub name: DomainName,
@@ -89,20 +689,83 @@ pub struct DnsResourceRecord {
     pub data: DnsRRData,
 }
 
+impl DnsResourceRecord {
+    /// The number of the record's type.
+    pub fn code(&self) -> u16 {
+        match self.data {
+            DnsRRData::Unknown(code, _) => code,
+            _ => self.rtype as u16,
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone, Debug, PartialEq)]
 pub enum DnsRRData {
     A(Ipv4Addr),
     AAAA(Ipv6Addr),
-    MX(u16, Vec<String>),
+    MX(u16, DomainName),
     CNAME(DomainName),
-    TXT(Vec<String>),
-    SOA(Vec<String>, Vec<String>, u32, u32, u32, u32, u32),
-    NS(Vec<String>),
+    TXT(Vec<Vec<u8>>),
+    SOA(DomainName, DomainName, u32, u32, u32, u32, u32),
+    NS(DomainName),
+    PTR(DomainName),
+    /// CPU and OS.
+    HINFO(Vec<u8>, Vec<u8>),
+    /// Flags, tag and value.
+    CAA(u8, Vec<u8>, Vec<u8>),
+    /// Priority, weight, port and target.
+    SRV(u16, u16, u16, DomainName),
+    /// Order, preference, flags, services, regexp and replacement.
+    NAPTR(u16, u16, Vec<u8>, Vec<u8>, Vec<u8>, DomainName),
+    /// Key tag, algorithm, digest type and digest.
+    DS(u16, u8, u8, Vec<u8>),
+    /// Type covered, algorithm, labels, original TTL, signature expiration,
+    /// signature inception, key tag, signer's name and signature.
+    RRSIG(u16, u8, u8, u32, u32, u32, u16, DomainName, Vec<u8>),
+    /// Next domain name and type bit maps.
+    NSEC(DomainName, Vec<u8>),
+    /// Flags, protocol, algorithm and public key.
+    DNSKEY(u16, u8, u8, Vec<u8>),
+    /// Hash algorithm, flags, iterations, salt, next hashed owner name and
+    /// type bit maps.
+    NSEC3(u8, u8, u16, Vec<u8>, Vec<u8>, Vec<u8>),
+    /// Type and raw rdata of a record uind can't parse, relayed verbatim.
+    Unknown(u16, Vec<u8>),
 }
 
-#[repr(u8)]
+impl DnsRRData {
+    /// The type of records with this data.
+    pub fn rtype(&self) -> DnsType {
+        match self {
+            DnsRRData::A(_) => DnsType::A,
+            DnsRRData::AAAA(_) => DnsType::AAAA,
+            DnsRRData::MX(..) => DnsType::MX,
+            DnsRRData::CNAME(_) => DnsType::CNAME,
+            DnsRRData::TXT(_) => DnsType::TXT,
+            DnsRRData::SOA(..) => DnsType::SOA,
+            DnsRRData::NS(_) => DnsType::NS,
+            DnsRRData::PTR(_) => DnsType::PTR,
+            DnsRRData::HINFO(..) => DnsType::HINFO,
+            DnsRRData::CAA(..) => DnsType::CAA,
+            DnsRRData::SRV(..) => DnsType::SRV,
+            DnsRRData::NAPTR(..) => DnsType::NAPTR,
+            DnsRRData::DS(..) => DnsType::DS,
+            DnsRRData::RRSIG(..) => DnsType::RRSIG,
+            DnsRRData::NSEC(..) => DnsType::NSEC,
+            DnsRRData::DNSKEY(..) => DnsType::DNSKEY,
+            DnsRRData::NSEC3(..) => DnsType::NSEC3,
+            DnsRRData::Unknown(code, _) => DnsType::try_from(*code).unwrap_or(DnsType::Unknown),
+        }
+    }
+}
+
+#[repr(u16)]
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum DnsType {
+    /// A type without a variant here.  Its number is kept in
+    /// `DnsRRData::Unknown`.
+    Unknown = 0,
     A = 1,
     NS,
     MD,
@@ -120,10 +783,20 @@ pub enum DnsType {
     MX,
     TXT,
     AAAA = 28,
+    SRV = 33,
+    NAPTR = 35,
+    OPT = 41,
+    DS = 43,
+    RRSIG = 46,
+    NSEC,
+    DNSKEY,
+    NSEC3 = 50,
+    TSIG = 250,
     AXFR = 252,
     MAILB,
     MAILA,
     Any,
+    CAA = 257,
 }
 
 impl DnsType {
@@ -146,29 +819,145 @@ impl DnsType {
             15 => Some(DnsType::MX),
             16 => Some(DnsType::TXT),
             28 => Some(DnsType::AAAA),
+            33 => Some(DnsType::SRV),
+            35 => Some(DnsType::NAPTR),
+            41 => Some(DnsType::OPT),
+            43 => Some(DnsType::DS),
+            46 => Some(DnsType::RRSIG),
+            47 => Some(DnsType::NSEC),
+            48 => Some(DnsType::DNSKEY),
+            50 => Some(DnsType::NSEC3),
+            250 => Some(DnsType::TSIG),
             252 => Some(DnsType::AXFR),
             253 => Some(DnsType::MAILB),
             254 => Some(DnsType::MAILA),
             255 => Some(DnsType::Any),
+            257 => Some(DnsType::CAA),
             _ => None,
         }
     }
 }
 
+impl fmt::Display for DnsType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsType::Any => write!(f, "ANY"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl FromStr for DnsType {
+    type Err = String;
+
+    /// Parse a type mnemonic like `AAAA`, or the generic `TYPE28`.
+    fn from_str(s: &str) -> Result<DnsType, String> {
+        let ty = match s.to_ascii_uppercase().as_str() {
+            "A" => DnsType::A,
+            "NS" => DnsType::NS,
+            "MD" => DnsType::MD,
+            "MF" => DnsType::MF,
+            "CNAME" => DnsType::CNAME,
+            "SOA" => DnsType::SOA,
+            "MB" => DnsType::MB,
+            "MG" => DnsType::MG,
+            "MR" => DnsType::MR,
+            "NULL" => DnsType::NULL,
+            "WKS" => DnsType::WKS,
+            "PTR" => DnsType::PTR,
+            "HINFO" => DnsType::HINFO,
+            "MINFO" => DnsType::MINFO,
+            "MX" => DnsType::MX,
+            "TXT" => DnsType::TXT,
+            "AAAA" => DnsType::AAAA,
+            "SRV" => DnsType::SRV,
+            "NAPTR" => DnsType::NAPTR,
+            "OPT" => DnsType::OPT,
+            "DS" => DnsType::DS,
+            "RRSIG" => DnsType::RRSIG,
+            "NSEC" => DnsType::NSEC,
+            "DNSKEY" => DnsType::DNSKEY,
+            "NSEC3" => DnsType::NSEC3,
+            "TSIG" => DnsType::TSIG,
+            "AXFR" => DnsType::AXFR,
+            "MAILB" => DnsType::MAILB,
+            "MAILA" => DnsType::MAILA,
+            "ANY" | "*" => DnsType::Any,
+            "CAA" => DnsType::CAA,
+            "UNKNOWN" => DnsType::Unknown,
+            other if other.starts_with("TYPE") => other[4..]
+                .parse()
+                .ok()
+                .and_then(DnsType::try_from)
+                .ok_or(format!("Unsupported type {}", s))?,
+            _ => return Err(format!("Unknown type {}", s)),
+        };
+        Ok(ty)
+    }
+}
+
+/// Parse a type mnemonic like `AAAA`, or the generic `TYPE65535`, into the
+/// number of the type, which may have no variant.
+pub fn type_code(s: &str) -> Result<u16, String> {
+    let upper = s.to_ascii_uppercase();
+    if upper.starts_with("TYPE") {
+        return upper[4..]
+            .parse()
+            .map_err(|_| format!("Error parsing type {}", s));
+    }
+    match s.parse()? {
+        DnsType::Unknown => Err(format!("Unknown type {}, write TYPE and its number", s)),
+        qtype => Ok(qtype as u16),
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum DnsClass {
     Internet = 1,
     _CSNet,
-    _CHAOS,
+    Chaos,
     _Hesiod,
-    Any = 255,
+    /// Only used in updates (RFC 2136).
+    None = 254,
+    Any,
+}
+
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsClass::Internet => write!(f, "IN"),
+            DnsClass::_CSNet => write!(f, "CS"),
+            DnsClass::Chaos => write!(f, "CH"),
+            DnsClass::_Hesiod => write!(f, "HS"),
+            DnsClass::None => write!(f, "NONE"),
+            DnsClass::Any => write!(f, "ANY"),
+        }
+    }
+}
+
+impl FromStr for DnsClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DnsClass, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "IN" => Ok(DnsClass::Internet),
+            "CS" => Ok(DnsClass::_CSNet),
+            "CH" => Ok(DnsClass::Chaos),
+            "HS" => Ok(DnsClass::_Hesiod),
+            "NONE" => Ok(DnsClass::None),
+            "ANY" => Ok(DnsClass::Any),
+            _ => Err(format!("Unknown class {}", s)),
+        }
+    }
 }
 
 impl DnsClass {
     pub fn try_from(x: u16) -> Option<DnsClass> {
         match x {
             1 => Some(DnsClass::Internet),
+            3 => Some(DnsClass::Chaos),
+            254 => Some(DnsClass::None),
             255 => Some(DnsClass::Any),
             _ => None,
         }
@@ -198,3 +987,727 @@ impl Default for DnsRcode {
         DnsRcode::NoErrorCondition
     }
 }
+
+impl fmt::Display for DnsOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DnsOpcode::Query => "QUERY",
+            DnsOpcode::InverseQuery => "IQUERY",
+            DnsOpcode::Status => "STATUS",
+            DnsOpcode::Notify => "NOTIFY",
+            DnsOpcode::Update => "UPDATE",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DnsOpcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DnsOpcode, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "QUERY" => Ok(DnsOpcode::Query),
+            "IQUERY" => Ok(DnsOpcode::InverseQuery),
+            "STATUS" => Ok(DnsOpcode::Status),
+            "NOTIFY" => Ok(DnsOpcode::Notify),
+            "UPDATE" => Ok(DnsOpcode::Update),
+            _ => Err(format!("Unknown opcode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for DnsRcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DnsRcode::NoErrorCondition => "NOERROR",
+            DnsRcode::FormatError => "FORMERR",
+            DnsRcode::ServerFailure => "SERVFAIL",
+            DnsRcode::NameError => "NXDOMAIN",
+            DnsRcode::NotImplemented => "NOTIMP",
+            DnsRcode::Refused => "REFUSED",
+            DnsRcode::YXDomain => "YXDOMAIN",
+            DnsRcode::YXRRSet => "YXRRSET",
+            DnsRcode::NXRRSet => "NXRRSET",
+            DnsRcode::NotAuth => "NOTAUTH",
+            DnsRcode::NotZone => "NOTZONE",
+            DnsRcode::DSOTypeNI => "DSOTYPENI",
+            DnsRcode::BadVersion => "BADVERS",
+            DnsRcode::BadKey => "BADKEY",
+            DnsRcode::BadTime => "BADTIME",
+            DnsRcode::BadMode => "BADMODE",
+            DnsRcode::BadName => "BADNAME",
+            DnsRcode::BadAlgorithm => "BADALG",
+            DnsRcode::BadTruncation => "BADTRUNC",
+            DnsRcode::BadCookie => "BADCOOKIE",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DnsRcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DnsRcode, String> {
+        (0..24)
+            .filter_map(DnsRcode::try_from)
+            .find(|rcode| rcode.to_string().eq_ignore_ascii_case(s))
+            .or_else(|| {
+                if s.eq_ignore_ascii_case("BADSIG") {
+                    Some(DnsRcode::BadVersion)
+                } else {
+                    None
+                }
+            })
+            .ok_or(format!("Unknown rcode {}", s))
+    }
+}
+
+/// Serialize as the `Display` text, e.g. `"example.com"` or `"AAAA"`, and
+/// deserialize with `FromStr`.
+#[cfg(feature = "serde")]
+macro_rules! serde_as_text {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<$ty, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "serde")]
+serde_as_text!(DomainName, DnsType, DnsClass, DnsOpcode, DnsRcode);
+
+/// Like dig: the header, the EDNS and TSIG pseudo-sections, and the records
+/// of each section in presentation format.
+impl fmt::Display for DnsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            header.opcode, header.rcode, header.id
+        )?;
+        let flags = [
+            (!header.query, "qr"),
+            (header.authoritative, "aa"),
+            (header.truncated, "tc"),
+            (header.recur_desired, "rd"),
+            (header.recur_available, "ra"),
+        ];
+        write!(f, ";; flags:")?;
+        for &(set, flag) in &flags {
+            if set {
+                write!(f, " {}", flag)?;
+            }
+        }
+        let additional =
+            self.additional.len() + self.edns.is_some() as usize + self.tsig.is_some() as usize;
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.question.len(),
+            self.answer.len(),
+            self.authority.len(),
+            additional
+        )?;
+
+        if let Some(ref edns) = self.edns {
+            writeln!(f, "\n;; OPT PSEUDOSECTION:")?;
+            write!(f, "; EDNS: version: {}, flags:", edns.version)?;
+            if edns.flags & EDNS_DO != 0 {
+                write!(f, " do")?;
+            }
+            writeln!(f, "; udp: {}", edns.udp_size)?;
+            for option in &edns.options {
+                match option.code {
+                    EDNS_CLIENT_SUBNET => match ClientSubnet::from_option(option) {
+                        Some(ecs) => writeln!(
+                            f,
+                            "; CLIENT-SUBNET: {}/{}/{}",
+                            ecs.addr, ecs.source_prefix, ecs.scope_prefix
+                        )?,
+                        None => writeln!(f, "; CLIENT-SUBNET: {}", Hex(&option.data))?,
+                    },
+                    EDNS_PADDING => writeln!(f, "; PADDING: ({} bytes)", option.data.len())?,
+                    code => writeln!(f, "; OPT={}: {}", code, Hex(&option.data))?,
+                }
+            }
+        }
+        if !self.question.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for question in &self.question {
+                writeln!(f, "{}", question)?;
+            }
+        }
+        let sections = [
+            ("ANSWER", &self.answer),
+            ("AUTHORITY", &self.authority),
+            ("ADDITIONAL", &self.additional),
+        ];
+        for &(name, records) in &sections {
+            if !records.is_empty() {
+                writeln!(f, "\n;; {} SECTION:", name)?;
+                for rr in records {
+                    writeln!(f, "{}", rr)?;
+                }
+            }
+        }
+        if let Some(ref tsig) = self.tsig {
+            writeln!(f, "\n;; TSIG PSEUDOSECTION:")?;
+            let error = match DnsRcode::try_from(tsig.error) {
+                Some(DnsRcode::BadVersion) => String::from("BADSIG"),
+                Some(rcode) => rcode.to_string(),
+                None => tsig.error.to_string(),
+            };
+            writeln!(
+                f,
+                "{}\t0\tANY\tTSIG\t{} {} {} {} {} {} {} {}",
+                Absolute(&tsig.key),
+                Absolute(&tsig.algorithm),
+                tsig.time_signed,
+                tsig.fudge,
+                tsig.mac.len(),
+                Base64(&tsig.mac),
+                tsig.original_id,
+                error,
+                tsig.other.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            Absolute(&self.qname),
+            self.qclass,
+            type_name(self.code())
+        )
+    }
+}
+
+/// The presentation format of master files, e.g.
+/// `example.com.\t300\tIN\tA\t192.0.2.1`.
+impl fmt::Display for DnsResourceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rtype = type_name(self.code());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            Absolute(&self.name),
+            self.ttl,
+            self.rclass,
+            rtype,
+            self.data
+        )
+    }
+}
+
+/// The presentation format of the rdata alone, with the generic syntax of
+/// RFC 3597 (`\# LENGTH HEX`) for unknown types.
+impl fmt::Display for DnsRRData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsRRData::A(addr) => write!(f, "{}", addr),
+            DnsRRData::AAAA(addr) => write!(f, "{}", addr),
+            DnsRRData::MX(preference, exchange) => {
+                write!(f, "{} {}", preference, Absolute(exchange))
+            }
+            DnsRRData::CNAME(name) | DnsRRData::NS(name) | DnsRRData::PTR(name) => {
+                write!(f, "{}", Absolute(name))
+            }
+            DnsRRData::TXT(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", Quoted(string))?;
+                }
+                Ok(())
+            }
+            DnsRRData::SOA(mname, rname, serial, refresh, retry, expire, minimum) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                Absolute(mname),
+                Absolute(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRRData::HINFO(cpu, os) => write!(f, "{} {}", Quoted(cpu), Quoted(os)),
+            DnsRRData::CAA(flags, tag, value) => {
+                write!(f, "{} {} {}", flags, Escaped(tag), Quoted(value))
+            }
+            DnsRRData::SRV(priority, weight, port, target) => {
+                write!(f, "{} {} {} {}", priority, weight, port, Absolute(target))
+            }
+            DnsRRData::NAPTR(order, preference, flags, services, regexp, replacement) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                order,
+                preference,
+                Quoted(flags),
+                Quoted(services),
+                Quoted(regexp),
+                Absolute(replacement)
+            ),
+            DnsRRData::DS(key_tag, algorithm, digest_type, digest) => {
+                write!(
+                    f,
+                    "{} {} {} {}",
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    Hex(digest)
+                )
+            }
+            DnsRRData::RRSIG(
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            ) => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_name(*type_covered),
+                algorithm,
+                labels,
+                original_ttl,
+                Timestamp(*expiration),
+                Timestamp(*inception),
+                key_tag,
+                Absolute(signer),
+                Base64(signature)
+            ),
+            DnsRRData::NSEC(next, types) => write!(f, "{}{}", Absolute(next), TypeBitmap(types)),
+            DnsRRData::DNSKEY(flags, protocol, algorithm, key) => {
+                write!(f, "{} {} {} {}", flags, protocol, algorithm, Base64(key))
+            }
+            DnsRRData::NSEC3(algorithm, flags, iterations, salt, next, types) => {
+                write!(f, "{} {} {} ", algorithm, flags, iterations)?;
+                if salt.is_empty() {
+                    write!(f, "-")?;
+                } else {
+                    write!(f, "{}", Hex(salt))?;
+                }
+                write!(f, " {}{}", Base32Hex(next), TypeBitmap(types))
+            }
+            DnsRRData::Unknown(_, data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " {}", Hex(data))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A type mnemonic, or `TYPEnnn` for types without one.
+pub(crate) fn type_name(code: u16) -> String {
+    match DnsType::try_from(code) {
+        Some(rtype) => rtype.to_string(),
+        None => format!("TYPE{}", code),
+    }
+}
+
+/// A name with the trailing dot of an absolute name.
+pub(crate) struct Absolute<'a>(pub(crate) &'a DomainName);
+
+impl<'a> fmt::Display for Absolute<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, ".")
+        } else {
+            write!(f, "{}.", self.0)
+        }
+    }
+}
+
+/// A character string in quotes, with quotes, backslashes and
+/// non-printable bytes escaped.
+struct Quoted<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for &b in self.0 {
+            match b {
+                b' ' => write!(f, " ")?,
+                _ => escape(b, f)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// Bytes written out unquoted, so spaces are escaped too.
+struct Escaped<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Escaped<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &b in self.0 {
+            escape(b, f)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape(b: u8, f: &mut fmt::Formatter) -> fmt::Result {
+    match b {
+        b'"' | b'\\' => write!(f, "\\{}", b as char),
+        _ if b.is_ascii_graphic() => write!(f, "{}", b as char),
+        _ => write!(f, "\\{:03}", b),
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// The unpadded Base32 encoding with the extended hex alphabet of NSEC3
+/// hashes (RFC 4648 section 7).
+struct Base32Hex<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Base32Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+        let (mut bits, mut value) = (0, 0u32);
+        for &b in self.0 {
+            value = (value << 8) | b as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                write!(f, "{}", ALPHABET[(value >> bits) as usize & 31] as char)?;
+            }
+        }
+        if bits > 0 {
+            write!(
+                f,
+                "{}",
+                ALPHABET[(value << (5 - bits)) as usize & 31] as char
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The padded Base64 encoding of keys and signatures (RFC 4648 section 4).
+struct Base64<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Base64<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for chunk in self.0.chunks(3) {
+            let value = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |v, (i, &b)| v | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let c = ALPHABET[(value >> (18 - 6 * i)) as usize & 63];
+                    write!(f, "{}", c as char)?;
+                } else {
+                    write!(f, "=")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The types in an NSEC or NSEC3 type bit map, each after a space.
+struct TypeBitmap<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for TypeBitmap<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        while rest.len() >= 2 {
+            let (window, len) = (rest[0] as u16, rest[1] as usize);
+            let bitmap = &rest[2..(2 + len).min(rest.len())];
+            for (i, &byte) in bitmap.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        write!(f, " {}", type_name(window * 256 + i as u16 * 8 + bit))?;
+                    }
+                }
+            }
+            rest = &rest[(2 + len).min(rest.len())..];
+        }
+        Ok(())
+    }
+}
+
+/// An RRSIG time as `YYYYMMDDHHmmSS` in UTC.
+struct Timestamp(u32);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (days, secs) = (self.0 as i64 / 86400, self.0 % 86400);
+        // Civil from days, after Howard Hinnant's algorithm
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        write!(
+            f,
+            "{:04}{:02}{:02}{:02}{:02}{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        DomainName::from(s)
+    }
+
+    #[test]
+    fn test_name_policy() {
+        let service = name("_dmarc.ksqsf.moe");
+        assert!(!NamePolicy::Strict.check(&service));
+        assert!(NamePolicy::Standard.check(&service));
+        assert!(NamePolicy::Strict.check(&name("www.ksqsf.moe")));
+        assert!(!NamePolicy::Standard.check(&name("-bad.ksqsf.moe")));
+        assert!(!NamePolicy::Permissive.check(&name("ksqsf..moe")));
+        assert!(!NamePolicy::Permissive.check(&vec!["a".repeat(64)].into()));
+        assert!(NamePolicy::Permissive.check(&name("sp ace.ksqsf.moe")));
+        assert_eq!(name("sp ace.ksqsf.moe").to_string(), "sp\\032ace.ksqsf.moe");
+    }
+
+    #[test]
+    fn test_domain_name() {
+        let mut table = std::collections::HashMap::new();
+        table.insert(name("Example.COM"), 1);
+        assert_eq!(table.get(&name("example.com")), Some(&1));
+        assert_ne!(name("example.com"), name("example.co"));
+        assert_eq!(name("Example.COM").to_string(), "Example.COM");
+        assert!(!name("Example.COM").eq_exact(&name("example.com")));
+        let random = name("a-long-name-to-randomize.example.com").randomize_case();
+        assert_eq!(random, name("a-long-name-to-randomize.example.com"));
+        assert!(random.to_string().chars().any(|c| c.is_ascii_uppercase()));
+    }
+
+    #[test]
+    #[cfg(feature = "idn")]
+    fn test_idn() {
+        let idn = DomainName::from_idn("例え.テスト").unwrap();
+        assert_eq!(idn, name("xn--r8jz45g.xn--zckzah"));
+        assert_eq!(idn.to_unicode(), "例え.テスト");
+        assert_eq!(name("www.XN--bad").to_unicode(), "www.XN--bad");
+    }
+
+    #[test]
+    fn test_name_syntax() {
+        let escaped: DomainName = "a\\.b\\032c.ksqsf.moe.".parse().unwrap();
+        assert_eq!(escaped.len(), 3);
+        assert_eq!(escaped[0], "a.b c");
+        assert_eq!(escaped.to_string(), "a\\.b\\032c.ksqsf.moe");
+        assert_eq!(".".parse::<DomainName>().unwrap().to_string(), ".");
+        assert!("ksqsf..moe".parse::<DomainName>().is_err());
+        assert!("".parse::<DomainName>().is_err());
+        assert!("a\\25".parse::<DomainName>().is_err());
+
+        assert!(escaped.ends_with(&name("KSQSF.moe")));
+        assert!(!escaped.ends_with(&name("sf.moe")));
+        assert_eq!(escaped.parent().unwrap(), name("ksqsf.moe"));
+        assert_eq!(DomainName::default().parent(), None);
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        let subnet = ClientSubnet::new("192.168.37.5".parse().unwrap(), 20);
+        assert_eq!(subnet.addr, "192.168.32.0".parse::<IpAddr>().unwrap());
+        let opt = subnet.to_option();
+        assert_eq!(opt.data, vec![0, 1, 20, 0, 192, 168, 32]);
+        assert_eq!(ClientSubnet::from_option(&opt), Some(subnet));
+
+        let subnet = ClientSubnet::new("2001:db8::1".parse().unwrap(), 200);
+        assert_eq!(subnet.source_prefix, 128);
+        assert_eq!(ClientSubnet::from_option(&subnet.to_option()), Some(subnet));
+
+        let opt = EdnsOption {
+            code: EDNS_CLIENT_SUBNET,
+            data: vec![0, 1, 24, 0, 10, 0],
+        };
+        assert_eq!(ClientSubnet::from_option(&opt), None);
+    }
+
+    #[test]
+    fn test_presentation() {
+        let rr = |name: &str, rtype, data| DnsResourceRecord {
+            name: name.into(),
+            rtype,
+            rclass: DnsClass::Internet,
+            ttl: 300,
+            data,
+        };
+        let a = rr(
+            "example.com",
+            DnsType::A,
+            DnsRRData::A("192.0.2.1".parse().unwrap()),
+        );
+        assert_eq!(a.to_string(), "example.com.\t300\tIN\tA\t192.0.2.1");
+        let txt = DnsRRData::TXT(vec!["v=spf1 -all".into(), "say \"hi\"\n".into()]);
+        assert_eq!(txt.to_string(), r#""v=spf1 -all" "say \"hi\"\010""#);
+        let unknown = rr(
+            "example.com",
+            DnsType::Unknown,
+            DnsRRData::Unknown(65, vec![0, 1]),
+        );
+        assert_eq!(
+            unknown.to_string(),
+            "example.com.\t300\tIN\tTYPE65\t\\# 2 0001"
+        );
+        let soa = DnsRRData::SOA(name("ns.lan"), name(""), 1, 2, 3, 4, 5);
+        assert_eq!(soa.to_string(), "ns.lan. . 1 2 3 4 5");
+        // A, MX, RRSIG and NSEC in the first window
+        let nsec = DnsRRData::NSEC(name("b.lan"), vec![0, 6, 0x40, 0x01, 0, 0, 0, 0x03]);
+        assert_eq!(nsec.to_string(), "b.lan. A MX RRSIG NSEC");
+        assert_eq!(Timestamp(1_600_000_000).to_string(), "20200913122640");
+        assert_eq!(Base32Hex(b"foobar").to_string(), "CPNMUOJ1E8");
+        assert_eq!(Base64(b"foobar").to_string(), "Zm9vYmFy");
+        assert_eq!(Base64(b"fooba").to_string(), "Zm9vYmE=");
+        assert_eq!(Base64(b"foob").to_string(), "Zm9vYg==");
+
+        let message = DnsMessage {
+            header: DnsHeader {
+                id: 4660,
+                query: false,
+                recur_desired: true,
+                recur_available: true,
+                rcode: DnsRcode::NameError,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: name("nowhere.example.com"),
+                qtype: DnsType::AAAA,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            authority: vec![rr("example.com", DnsType::SOA, soa)],
+            edns: Some(Edns {
+                udp_size: 1232,
+                flags: EDNS_DO,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            message.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NXDOMAIN, id: 4660\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 0, AUTHORITY: 1, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags: do; udp: 1232\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;nowhere.example.com.\t\tIN\tAAAA\n\
+             \n\
+             ;; AUTHORITY SECTION:\n\
+             example.com.\t300\tIN\tSOA\tns.lan. . 1 2 3 4 5\n"
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let query = DnsMessage::query(name("example.com"), DnsType::A).with_id(7);
+        assert!(query.is_query());
+        assert!(query.header.recur_desired);
+        assert_eq!(query.question[0].qclass, DnsClass::Internet);
+
+        let a = DnsResourceRecord {
+            name: name("example.com"),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 300,
+            data: DnsRRData::A("192.0.2.1".parse().unwrap()),
+        };
+        let response = DnsMessage::response_to(&query)
+            .with_authoritative(true)
+            .with_answer(a.clone())
+            .with_rcode(DnsRcode::NoErrorCondition);
+        assert!(!response.is_query());
+        assert_eq!(response.header.id, 7);
+        assert!(response.header.recur_desired && response.header.authoritative);
+        assert_eq!(response.question, query.question);
+        assert_eq!(response.answer, vec![a]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                id: 1,
+                rcode: DnsRcode::NameError,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: name("example.com"),
+                qtype: DnsType::MX,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            answer: vec![DnsResourceRecord {
+                name: name("example.com"),
+                rtype: DnsType::MX,
+                rclass: DnsClass::Internet,
+                ttl: 60,
+                data: DnsRRData::MX(10, name("mail.example.com")),
+            }],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains(r#""qname":"example.com","qtype":"MX","qcode":0,"qclass":"IN""#));
+        assert!(json.contains(r#""data":{"MX":[10,"mail.example.com"]}"#));
+        assert!(json.contains(r#""rcode":"NXDOMAIN""#));
+        let decoded: DnsMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.to_string(), message.to_string());
+
+        let rr: DnsResourceRecord = serde_json::from_str(
+            r#"{"name":"a.lan","rtype":"AAAA","rclass":"IN","ttl":5,"data":{"AAAA":"fd00::1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(rr.data, DnsRRData::AAAA("fd00::1".parse().unwrap()));
+    }
+}
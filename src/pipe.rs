@@ -0,0 +1,327 @@
+//! Delegation of names to an external program, speaking the PowerDNS pipe
+//! backend protocol (ABI version 1).
+//!
+//! uind starts the program and greets it with `HELO\t1`, to which it must
+//! answer `OK\t<banner>`.  Each question is then sent as
+//!
+//! ```text
+//! Q\t<qname>\t<qclass>\t<qtype>\t-1\t<remote-ip>
+//! ```
+//!
+//! and answered by zero or more records, followed by `END` (or `FAIL`):
+//!
+//! ```text
+//! DATA\t<qname>\t<qclass>\t<qtype>\t<ttl>\t-1\t<content>
+//! END
+//! ```
+//!
+//! `LOG\t<message>` lines may be interleaved and are logged.  A program that
+//! exits, misbehaves or doesn't answer in time is killed and restarted on
+//! the next question.
+
+use futures::prelude::*;
+use futures::sync::oneshot;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::FutureExt;
+
+use crate::message::*;
+
+/// Which names go to which program.
+#[derive(Clone, Debug)]
+pub struct PipeConfig {
+    /// Names equal to or under this suffix are delegated.
+    pub suffix: DomainName,
+    /// The program and its arguments, separated by whitespace.
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl PipeConfig {
+    pub fn matches(&self, name: &DomainName) -> bool {
+        name.ends_with(&self.suffix)
+    }
+}
+
+struct Request {
+    question: DnsQuestion,
+    remote: IpAddr,
+    reply: oneshot::Sender<Result<Vec<DnsResourceRecord>, String>>,
+}
+
+/// A supervised external program answering questions one at a time.
+pub struct PipeBackend {
+    pub config: PipeConfig,
+    requests: Mutex<Sender<Request>>,
+}
+
+impl PipeBackend {
+    /// Start the supervisor thread.  The program itself is started on the
+    /// first question.
+    pub fn spawn(config: PipeConfig) -> PipeBackend {
+        let (tx, rx) = mpsc::channel();
+        let command = config.command.clone();
+        let timeout = config.timeout;
+        thread::spawn(move || supervise(&command, timeout, rx));
+        PipeBackend {
+            config,
+            requests: Mutex::new(tx),
+        }
+    }
+
+    pub fn query(
+        &self,
+        question: DnsQuestion,
+        remote: IpAddr,
+    ) -> impl Future<Item = Vec<DnsResourceRecord>, Error = String> {
+        let (reply, response) = oneshot::channel();
+        let request = Request {
+            question,
+            remote,
+            reply,
+        };
+        let _ = self.requests.lock().unwrap().send(request);
+        response
+            .map_err(|_| String::from("pipe backend is gone"))
+            .and_then(|result| result)
+            // The supervisor kills the program before this fires, unless it
+            // is busy with earlier questions
+            .timeout(self.config.timeout * 2)
+            .map_err(|e| match e.into_inner() {
+                Some(e) => e,
+                None => String::from("pipe backend timed out"),
+            })
+    }
+}
+
+impl std::fmt::Debug for PipeBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "PipeBackend({:?})", self.config)
+    }
+}
+
+fn supervise(command: &str, timeout: Duration, requests: Receiver<Request>) {
+    let mut process: Option<Process> = None;
+    for request in requests {
+        if process.is_none() {
+            match Process::start(command, timeout) {
+                Ok(p) => process = Some(p),
+                Err(e) => error!("Can't start pipe backend {}: {}", command, e),
+            }
+        }
+        let result = match process {
+            Some(ref mut p) => p.ask(&request.question, request.remote),
+            None => Err(String::from("pipe backend is not running")),
+        };
+        let result = match result {
+            Ok(Some(records)) => Ok(records),
+            Ok(None) => Err(String::from("lookup failed")),
+            Err(e) => {
+                warn!("Pipe backend {}: {}", command, e);
+                if let Some(mut p) = process.take() {
+                    let _ = p.child.kill();
+                    let _ = p.child.wait();
+                }
+                Err(e)
+            }
+        };
+        let _ = request.reply.send(result);
+    }
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    timeout: Duration,
+}
+
+impl Process {
+    fn start(command: &str, timeout: Duration) -> io::Result<Process> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        // Reading happens on its own thread so that a hung program can be
+        // timed out
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut process = Process {
+            child,
+            stdin,
+            lines,
+            timeout,
+        };
+        process.send("HELO\t1")?;
+        match process.recv() {
+            Ok(ref line) if line.starts_with("OK") => {
+                info!("Pipe backend {} started: {}", command, line);
+                Ok(process)
+            }
+            Ok(line) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected greeting {:?}", line),
+            )),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.stdin, "{}", line)?;
+        self.stdin.flush()
+    }
+
+    fn recv(&mut self) -> Result<String, String> {
+        match self.lines.recv_timeout(self.timeout) {
+            Ok(line) => Ok(line),
+            Err(RecvTimeoutError::Timeout) => Err(String::from("timed out")),
+            Err(RecvTimeoutError::Disconnected) => Err(String::from("exited")),
+        }
+    }
+
+    fn ask(
+        &mut self,
+        question: &DnsQuestion,
+        remote: IpAddr,
+    ) -> Result<Option<Vec<DnsResourceRecord>>, String> {
+        self.send(&format!(
+            "Q\t{}\t{}\t{}\t-1\t{}",
+            question.qname, question.qclass, question.qtype, remote
+        ))
+        .map_err(|e| e.to_string())?;
+
+        let mut records = vec![];
+        loop {
+            let line = self.recv()?;
+            let fields: Vec<_> = line.splitn(7, '\t').collect();
+            match fields[0] {
+                "END" => return Ok(Some(records)),
+                "FAIL" => return Ok(None),
+                "LOG" => info!("Pipe backend: {}", fields.get(1).unwrap_or(&"")),
+                "DATA" if fields.len() == 7 => match parse_data(&fields) {
+                    Some(rr) => records.push(rr),
+                    None => warn!("Pipe backend sent unusable record {:?}", line),
+                },
+                _ => return Err(format!("unexpected line {:?}", line)),
+            }
+        }
+    }
+}
+
+/// Parse the fields of a `DATA` line into a record.
+fn parse_data(fields: &[&str]) -> Option<DnsResourceRecord> {
+    let name: DomainName = fields[1].parse().ok()?;
+    if fields[2] != "IN" {
+        return None;
+    }
+    let rtype: DnsType = fields[3].parse().ok()?;
+    let ttl = fields[4].parse().ok()?;
+    let content = fields[6];
+    let to_name = |s: &str| s.parse::<DomainName>().ok();
+    let data = match rtype {
+        DnsType::A => DnsRRData::A(content.parse().ok()?),
+        DnsType::AAAA => DnsRRData::AAAA(content.parse().ok()?),
+        DnsType::CNAME => DnsRRData::CNAME(to_name(content)?),
+        DnsType::NS => DnsRRData::NS(to_name(content)?),
+        DnsType::PTR => DnsRRData::PTR(to_name(content)?),
+        DnsType::TXT => DnsRRData::TXT(vec![content.trim_matches('"').into()]),
+        DnsType::MX => {
+            let mut parts = content.split_whitespace();
+            let pref = parts.next()?.parse().ok()?;
+            DnsRRData::MX(pref, to_name(parts.next()?)?)
+        }
+        DnsType::HINFO => {
+            // "CPU" "OS"
+            let parts: Vec<_> = content.split('"').collect();
+            DnsRRData::HINFO((*parts.get(1)?).into(), (*parts.get(3)?).into())
+        }
+        DnsType::CAA => {
+            let mut parts = content.splitn(3, ' ');
+            let flags = parts.next()?.parse().ok()?;
+            let tag = parts.next()?.into();
+            let value = parts.next()?.trim_matches('"').as_bytes().to_vec();
+            DnsRRData::CAA(flags, tag, value)
+        }
+        DnsType::SRV => {
+            let mut parts = content.split_whitespace();
+            let mut number = || parts.next()?.parse().ok();
+            let (priority, weight, port) = (number()?, number()?, number()?);
+            DnsRRData::SRV(priority, weight, port, to_name(parts.next()?)?)
+        }
+        DnsType::NAPTR => {
+            let mut parts = content.split_whitespace();
+            let order = parts.next()?.parse().ok()?;
+            let pref = parts.next()?.parse().ok()?;
+            let mut string = || Some(parts.next()?.trim_matches('"').into());
+            let (flags, services, regexp) = (string()?, string()?, string()?);
+            DnsRRData::NAPTR(
+                order,
+                pref,
+                flags,
+                services,
+                regexp,
+                to_name(parts.next()?)?,
+            )
+        }
+        _ => return None,
+    };
+    Some(DnsResourceRecord {
+        name,
+        rtype,
+        rclass: DnsClass::Internet,
+        ttl,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data() {
+        let line = "DATA\thost.dyn.lan\tIN\tMX\t60\t-1\t10 mail.dyn.lan.";
+        let fields: Vec<_> = line.splitn(7, '\t').collect();
+        let rr = parse_data(&fields).expect("record");
+        assert_eq!(rr.name, vec!["host", "dyn", "lan"].into());
+        assert_eq!(rr.ttl, 60);
+        assert_eq!(
+            rr.data,
+            DnsRRData::MX(10, vec!["mail", "dyn", "lan"].into())
+        );
+
+        let config = PipeConfig {
+            suffix: vec!["dyn", "lan"].into(),
+            command: String::new(),
+            timeout: Duration::from_secs(1),
+        };
+        assert!(config.matches(&rr.name));
+        assert!(config.matches(&vec!["DYN", "lan"].into()));
+        assert!(!config.matches(&vec!["lan"].into()));
+    }
+}
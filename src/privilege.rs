@@ -0,0 +1,141 @@
+//! Switching to an unprivileged account once the sockets are bound, so that
+//! no packet is parsed as root.  Only supported on Unix.
+
+use std::io;
+
+/// The account to run as: a user, in its primary group unless another is
+/// given, or only a group.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Account {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+#[cfg(unix)]
+impl Account {
+    /// Look up a user and a group, by name or number.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Account, String> {
+        let mut account = Account::default();
+        if let Some(user) = user {
+            let (uid, gid) = match user_ids(user) {
+                Some((uid, gid)) => (uid, Some(gid)),
+                None => match user.parse() {
+                    Ok(uid) => (uid, None),
+                    Err(_) => return Err(format!("No such user {}", user)),
+                },
+            };
+            account.uid = Some(uid);
+            account.gid = gid;
+        }
+        if let Some(group) = group {
+            let gid = match group_id(group) {
+                Some(gid) => gid,
+                None => group
+                    .parse()
+                    .map_err(|_| format!("No such group {}", group))?,
+            };
+            account.gid = Some(gid);
+        }
+        if account.uid.is_some() && account.gid.is_none() {
+            return Err(format!("User {} has no group, give one", user.unwrap()));
+        }
+        Ok(account)
+    }
+
+    /// Switch the process, all its threads, to the account.  The group goes
+    /// first, while it still may be changed.
+    pub fn switch(&self) -> io::Result<()> {
+        let context = |what, id| {
+            let e = io::Error::last_os_error();
+            io::Error::new(e.kind(), format!("Can't switch to {} {}: {}", what, id, e))
+        };
+        unsafe {
+            if let Some(gid) = self.gid {
+                // Drop the supplementary groups too
+                if libc::setgroups(1, &gid) != 0 {
+                    return Err(context("group", gid));
+                }
+                if libc::setgid(gid) != 0 {
+                    return Err(context("group", gid));
+                }
+            }
+            if let Some(uid) = self.uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(context("user", uid));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl Account {
+    pub fn lookup(_user: Option<&str>, _group: Option<&str>) -> Result<Account, String> {
+        Err(String::from("Switching users is only supported on Unix"))
+    }
+
+    pub fn switch(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "switching users is only supported on Unix",
+        ))
+    }
+}
+
+/// The user ID and group ID of a user name.
+#[cfg(unix)]
+fn user_ids(name: &str) -> Option<(u32, u32)> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // Looked up before serving, while there is a single thread
+    unsafe {
+        let entry = libc::getpwnam(name.as_ptr());
+        if entry.is_null() {
+            None
+        } else {
+            Some(((*entry).pw_uid, (*entry).pw_gid))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    unsafe {
+        let entry = libc::getgrnam(name.as_ptr());
+        if entry.is_null() {
+            None
+        } else {
+            Some((*entry).gr_gid)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            Account::lookup(Some("root"), None),
+            Ok(Account {
+                uid: Some(0),
+                gid: Some(0)
+            })
+        );
+        assert_eq!(
+            Account::lookup(Some("root"), Some("123")).map(|account| account.gid),
+            Ok(Some(123))
+        );
+        assert_eq!(
+            Account::lookup(None, Some("456")),
+            Ok(Account {
+                uid: None,
+                gid: Some(456)
+            })
+        );
+        assert!(Account::lookup(Some("no such user"), None).is_err());
+        assert!(Account::lookup(Some("12345"), None).is_err());
+    }
+}
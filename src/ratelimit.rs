@@ -0,0 +1,184 @@
+//! Per-client rate limiting, so that one client asking thousands of
+//! questions a second can't starve the others, or the upstream servers.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::acl::Action;
+
+/// Clients tracked at most.  Beyond, those which have been quiet long
+/// enough to be back at their burst are forgotten.
+const MAX_CLIENTS: usize = 100_000;
+
+/// How many queries a client may send: `rate` a second on average, and
+/// `burst` at once.  Queries beyond are dropped or refused.
+///
+/// Written as `QPS[,BURST][=ACTION]`, e.g. `20,100=refuse`.  The burst
+/// defaults to a second's worth, and the action to `drop`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+    pub action: Action,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateLimit, String> {
+        let mut parts = s.splitn(2, '=');
+        let mut numbers = parts.next().unwrap().splitn(2, ',');
+        let number = |n: &str| {
+            n.parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or(format!("Error parsing rate limit {}", s))
+        };
+        let rate = number(numbers.next().unwrap())?;
+        let burst = match numbers.next() {
+            Some(burst) => number(burst)?,
+            None => rate,
+        };
+        let action = match parts.next() {
+            Some(action) => match action.parse()? {
+                action @ Action::Drop | action @ Action::Refuse => action,
+                _ => {
+                    return Err(format!(
+                        "Rate limits drop or refuse queries, not {}",
+                        action
+                    ))
+                }
+            },
+            None => Action::Drop,
+        };
+        Ok(RateLimit {
+            rate,
+            burst,
+            action,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the last query was over the limit.
+    limited: bool,
+}
+
+/// A token bucket for each client.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+/// Whether a query is within its client's limit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// Over the limit, the first query since the client last wasn't.
+    Exceeded,
+    /// Over the limit, like the query before.
+    Limited,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        self.limit.action
+    }
+
+    /// Take a token for a query from `client`.
+    pub fn check(&self, client: IpAddr) -> Verdict {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Verdict {
+        let (rate, burst) = (f64::from(self.limit.rate), f64::from(self.limit.burst));
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.updated);
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            (bucket.tokens + elapsed * rate).min(burst)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            limited: false,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        let verdict = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Verdict::Allowed
+        } else if bucket.limited {
+            Verdict::Limited
+        } else {
+            Verdict::Exceeded
+        };
+        bucket.limited = verdict != Verdict::Allowed;
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        assert_eq!(
+            "20,100=refuse".parse(),
+            Ok(RateLimit {
+                rate: 20,
+                burst: 100,
+                action: Action::Refuse,
+            })
+        );
+        assert_eq!(
+            "5".parse(),
+            Ok(RateLimit {
+                rate: 5,
+                burst: 5,
+                action: Action::Drop,
+            })
+        );
+        assert!("0".parse::<RateLimit>().is_err());
+        assert!("5,x".parse::<RateLimit>().is_err());
+        assert!("5=log".parse::<RateLimit>().is_err());
+
+        let limiter = RateLimiter::new("2,3".parse().unwrap());
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(a, start), Verdict::Allowed);
+        }
+        assert_eq!(limiter.check_at(a, start), Verdict::Exceeded);
+        assert_eq!(limiter.check_at(a, start), Verdict::Limited);
+        assert_eq!(limiter.check_at(b, start), Verdict::Allowed);
+        // Two tokens a second come back
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(a, later), Verdict::Allowed);
+        assert_eq!(limiter.check_at(a, later), Verdict::Exceeded);
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(a, much_later), Verdict::Allowed);
+        }
+        assert_eq!(limiter.check_at(a, much_later), Verdict::Exceeded);
+    }
+}
@@ -0,0 +1,247 @@
+//! Rules rewriting responses: replacing addresses in upstream answers,
+//! answering a name with the records of another, and dropping records.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::acl::Network;
+use crate::message::*;
+
+/// A rewrite rule, written as
+///
+/// * `address:OLD=NEW`: answer the address `NEW` in place of `OLD`,
+/// * `name:NAME=TARGET`: answer questions for `NAME` with the records of
+///   `TARGET`, under the name `NAME`,
+/// * `drop:address=NETWORK`, `drop:type=TYPE` or `drop:name=NAME`: drop
+///   address records in a network, records of a type, or records of a
+///   name and the names under it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RewriteRule {
+    Address(IpAddr, IpAddr),
+    Name(DomainName, DomainName),
+    Drop(Predicate),
+}
+
+/// Which records a drop rule drops.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Address(Network),
+    /// The number of the type.
+    Type(u16),
+    Name(DomainName),
+}
+
+impl Predicate {
+    fn matches(&self, rr: &DnsResourceRecord) -> bool {
+        match (self, &rr.data) {
+            (Predicate::Address(net), DnsRRData::A(addr)) => net.contains(&IpAddr::V4(*addr)),
+            (Predicate::Address(net), DnsRRData::AAAA(addr)) => net.contains(&IpAddr::V6(*addr)),
+            (Predicate::Address(_), _) => false,
+            (Predicate::Type(rtype), _) => rr.code() == *rtype,
+            (Predicate::Name(name), _) => rr.name.ends_with(name),
+        }
+    }
+}
+
+impl FromStr for RewriteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RewriteRule, String> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap();
+        let mut parts = parts
+            .next()
+            .ok_or(format!("Missing rule in rewrite {}", s))?
+            .splitn(2, '=');
+        let (left, right) = match (parts.next(), parts.next()) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Err(format!("Rewrite {} requires a =", s)),
+        };
+        let address = |s: &str| {
+            s.parse::<IpAddr>()
+                .map_err(|_| format!("Error parsing address {}", s))
+        };
+        match kind {
+            "address" => {
+                let (old, new) = (address(left)?, address(right)?);
+                if old.is_ipv4() != new.is_ipv4() {
+                    return Err(format!("Rewrite {} changes the address family", s));
+                }
+                Ok(RewriteRule::Address(old, new))
+            }
+            "name" => Ok(RewriteRule::Name(
+                DomainName::from_idn(left)?,
+                DomainName::from_idn(right)?,
+            )),
+            "drop" => {
+                let predicate = match left {
+                    "address" => Predicate::Address(right.parse()?),
+                    "type" => Predicate::Type(type_code(right)?),
+                    "name" => Predicate::Name(DomainName::from_idn(right)?),
+                    _ => return Err(format!("Unknown drop predicate {}", left)),
+                };
+                Ok(RewriteRule::Drop(predicate))
+            }
+            _ => Err(format!("Unknown rewrite {}", kind)),
+        }
+    }
+}
+
+/// Apply the address and drop rules to an upstream response, returning
+/// how many records they changed.
+pub fn rewrite(rules: &[RewriteRule], message: &mut DnsMessage) -> usize {
+    let mut changed = 0;
+    for rule in rules {
+        match rule {
+            RewriteRule::Address(old, new) => {
+                for rr in &mut message.answer {
+                    let data = match (&rr.data, new) {
+                        (DnsRRData::A(addr), IpAddr::V4(new)) if IpAddr::V4(*addr) == *old => {
+                            DnsRRData::A(*new)
+                        }
+                        (DnsRRData::AAAA(addr), IpAddr::V6(new)) if IpAddr::V6(*addr) == *old => {
+                            DnsRRData::AAAA(*new)
+                        }
+                        _ => continue,
+                    };
+                    rr.data = data;
+                    changed += 1;
+                }
+            }
+            RewriteRule::Drop(predicate) => {
+                for records in &mut [
+                    &mut message.answer,
+                    &mut message.authority,
+                    &mut message.additional,
+                ] {
+                    let before = records.len();
+                    records.retain(|rr| !predicate.matches(rr));
+                    changed += before - records.len();
+                }
+            }
+            RewriteRule::Name(..) => {}
+        }
+    }
+    changed
+}
+
+/// Ask for the targets of name rules in place of their names, and return
+/// CNAME records standing for the renames, which [`flatten`] takes out of
+/// the response again.  Questions for CNAME records, or any, are asked as
+/// they are.
+///
+/// [`flatten`]: fn.flatten.html
+pub fn rename(rules: &[RewriteRule], questions: &mut [DnsQuestion]) -> Vec<DnsResourceRecord> {
+    let mut renames = vec![];
+    for q in questions {
+        if q.qtype == DnsType::CNAME || q.qtype == DnsType::Any {
+            continue;
+        }
+        let target = rules.iter().find_map(|rule| match rule {
+            RewriteRule::Name(name, target) if *name == q.qname => Some(target),
+            _ => None,
+        });
+        if let Some(target) = target {
+            renames.push(DnsResourceRecord {
+                name: q.qname.clone(),
+                rtype: DnsType::CNAME,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::CNAME(target.clone()),
+            });
+            q.qname = target.clone();
+        }
+    }
+    renames
+}
+
+/// Take the CNAME records of [`rename`] out of a response, and give the
+/// records of their targets the names asked for.
+///
+/// [`rename`]: fn.rename.html
+pub fn flatten(rules: &[RewriteRule], message: &mut DnsMessage) {
+    for rule in rules {
+        let (name, target) = match rule {
+            RewriteRule::Name(name, target) => (name, target),
+            _ => continue,
+        };
+        let rename = DnsRRData::CNAME(target.clone());
+        let asked = match message
+            .answer
+            .iter()
+            .position(|rr| rr.name == *name && rr.data == rename)
+        {
+            Some(i) => message.answer.remove(i).name,
+            None => continue,
+        };
+        for rr in &mut message.answer {
+            if rr.name == *target {
+                rr.name = asked.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_rewrite() {
+        let rules: Vec<RewriteRule> = [
+            "address:203.0.113.5=10.0.0.5",
+            "name:cloud.vendor.example=vendor.lan",
+            "drop:address=192.168.0.0/16",
+            "drop:type=TXT",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        assert_eq!(
+            "drop:name=tracker.example".parse(),
+            Ok(RewriteRule::Drop(Predicate::Name("tracker.example".into())))
+        );
+        assert!("address:203.0.113.5=::1".parse::<RewriteRule>().is_err());
+        assert!("drop:port=53".parse::<RewriteRule>().is_err());
+        assert!("name:a.example".parse::<RewriteRule>().is_err());
+
+        let rr = |name: &str, data: DnsRRData| DnsResourceRecord {
+            name: name.into(),
+            rtype: data.rtype(),
+            rclass: DnsClass::Internet,
+            ttl: 60,
+            data,
+        };
+        let a = |name, a, b, c, d| rr(name, DnsRRData::A(Ipv4Addr::new(a, b, c, d)));
+
+        let query = DnsMessage::query("www.example".into(), DnsType::A);
+        let mut response = DnsMessage::response_to(&query).with_answers(vec![
+            a("www.example", 203, 0, 113, 5),
+            a("www.example", 192, 168, 1, 1),
+            a("www.example", 198, 51, 100, 1),
+        ]);
+        response
+            .additional
+            .push(rr("www.example", DnsRRData::TXT(vec![])));
+        assert_eq!(rewrite(&rules, &mut response), 3);
+        assert_eq!(
+            response.answer,
+            [
+                a("www.example", 10, 0, 0, 5),
+                a("www.example", 198, 51, 100, 1)
+            ]
+        );
+        assert!(response.additional.is_empty());
+
+        let mut query = DnsMessage::query("Cloud.Vendor.example".into(), DnsType::A);
+        let renames = rename(&rules, &mut query.question);
+        assert_eq!(query.question[0].qname, "vendor.lan".into());
+        let mut response =
+            DnsMessage::response_to(&query).with_answers(vec![a("vendor.lan", 10, 0, 0, 9)]);
+        response.question[0].qname = "Cloud.Vendor.example".into();
+        response.answer.splice(0..0, renames);
+        flatten(&rules, &mut response);
+        assert_eq!(response.answer, [a("Cloud.Vendor.example", 10, 0, 0, 9)]);
+    }
+}
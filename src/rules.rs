@@ -0,0 +1,168 @@
+//! Local rules answering names which match a regular expression, for the
+//! long tail of hostnames that follow a pattern, like ad servers'.
+//!
+//! Without the `regex-rules` feature, there are no rules: parsing one is an
+//! error.
+
+#[cfg(feature = "regex-rules")]
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::message::*;
+
+/// Answers `A` or `AAAA` questions for names matching `pattern`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegexRule {
+    pub pattern: String,
+    pub data: DnsRRData,
+}
+
+impl FromStr for RegexRule {
+    type Err = String;
+
+    /// `/REGEX/ ADDRESS`, with an IPv4 or IPv6 address, e.g.
+    /// `/^ad[0-9]*\./ 0.0.0.0`.
+    fn from_str(s: &str) -> Result<RegexRule, String> {
+        let err = || format!("Invalid regex rule {}", s);
+        let s = s.trim();
+        let end = s.rfind('/').filter(|&end| end > 0).ok_or_else(err)?;
+        if !s.starts_with('/') {
+            return Err(err());
+        }
+        let pattern = &s[1..end];
+        if cfg!(not(feature = "regex-rules")) {
+            return Err(format!("Regex rule {} requires the regex-rules feature", s));
+        }
+        #[cfg(feature = "regex-rules")]
+        Regex::new(pattern).map_err(|e| format!("Invalid regex /{}/: {}", pattern, e))?;
+        let data = match s[end + 1..].trim().parse() {
+            Ok(IpAddr::V4(addr)) => DnsRRData::A(addr),
+            Ok(IpAddr::V6(addr)) => DnsRRData::AAAA(addr),
+            Err(_) => return Err(err()),
+        };
+        Ok(RegexRule {
+            pattern: pattern.to_owned(),
+            data,
+        })
+    }
+}
+
+/// Rules compiled together, so that a name is matched against all of them
+/// at once.  The first matching rule for the question's type answers.
+#[derive(Clone, Debug)]
+pub struct RegexRules {
+    #[cfg(feature = "regex-rules")]
+    set: RegexSet,
+    data: Vec<DnsRRData>,
+}
+
+impl RegexRules {
+    /// Names are matched ignoring case, in dot notation without the
+    /// trailing dot.
+    #[cfg(feature = "regex-rules")]
+    pub fn new(rules: &[RegexRule]) -> Result<RegexRules, String> {
+        let set = RegexSetBuilder::new(rules.iter().map(|rule| &rule.pattern))
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("Error compiling regex rules: {}", e))?;
+        Ok(RegexRules {
+            set,
+            data: rules.iter().map(|rule| rule.data.clone()).collect(),
+        })
+    }
+
+    #[cfg(not(feature = "regex-rules"))]
+    pub fn new(rules: &[RegexRule]) -> Result<RegexRules, String> {
+        match rules.first() {
+            Some(rule) => Err(format!(
+                "Regex rule /{}/ requires the regex-rules feature",
+                rule.pattern
+            )),
+            None => Ok(RegexRules { data: vec![] }),
+        }
+    }
+
+    /// The indices of the rules `name` matches, in order.
+    #[cfg(feature = "regex-rules")]
+    fn matches(&self, name: &str) -> Vec<usize> {
+        self.set.matches(name).into_iter().collect()
+    }
+
+    #[cfg(not(feature = "regex-rules"))]
+    fn matches(&self, _name: &str) -> Vec<usize> {
+        vec![]
+    }
+
+    /// The record the first matching rule answers an `A` or `AAAA`
+    /// question with.
+    pub fn answer(&self, question: &DnsQuestion) -> Option<DnsResourceRecord> {
+        let rtype = match question.qtype {
+            DnsType::A | DnsType::AAAA if !self.data.is_empty() => question.qtype,
+            _ => return None,
+        };
+        let data = self
+            .matches(&question.qname.to_string())
+            .into_iter()
+            .map(|i| &self.data[i])
+            .find(|data| match data {
+                DnsRRData::A(_) => rtype == DnsType::A,
+                _ => rtype == DnsType::AAAA,
+            })?;
+        Some(DnsResourceRecord {
+            name: question.qname.clone(),
+            rtype,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: data.clone(),
+        })
+    }
+}
+
+impl Default for RegexRules {
+    fn default() -> RegexRules {
+        RegexRules::new(&[]).unwrap()
+    }
+}
+
+#[cfg(all(test, feature = "regex-rules"))]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_regex_rules() {
+        let rules: Vec<RegexRule> = [r"/^ad[0-9]*\./ 0.0.0.0", r"/\.tracker\.example$/ ::"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        assert!("/unclosed 0.0.0.0".parse::<RegexRule>().is_err());
+        assert!("/(/ 0.0.0.0".parse::<RegexRule>().is_err());
+        assert!("/ok/ host".parse::<RegexRule>().is_err());
+
+        let rules = RegexRules::new(&rules).unwrap();
+        let question = |name: &str, qtype| DnsQuestion {
+            qname: name.into(),
+            qtype,
+            qcode: 0,
+            qclass: DnsClass::Internet,
+        };
+        let answer = rules
+            .answer(&question("AD12.example.com", DnsType::A))
+            .unwrap();
+        assert_eq!(answer.data, DnsRRData::A(Ipv4Addr::new(0, 0, 0, 0)));
+        assert_eq!(answer.name, "AD12.example.com".into());
+        assert!(rules
+            .answer(&question("ad12.example.com", DnsType::AAAA))
+            .is_none());
+        assert!(rules
+            .answer(&question("bad.example.com", DnsType::A))
+            .is_none());
+        assert!(rules
+            .answer(&question("x.tracker.example", DnsType::AAAA))
+            .is_some());
+        assert!(RegexRules::default()
+            .answer(&question("ad1.example.com", DnsType::A))
+            .is_none());
+    }
+}
@@ -0,0 +1,112 @@
+//! Removing records from upstream responses which have nothing to do with
+//! the question, as a poisoned or forged response would carry.
+
+use crate::message::*;
+
+/// Remove the records outside the bailiwick of the question, and return
+/// how many there were:
+///
+/// * answers not owned by the question's name or an alias it leads to,
+/// * authority records not owned by a zone above these names,
+/// * additional records outside those zones, or, without any, outside the
+///   parents of the names.
+///
+/// Responses to several questions, or none, and zone transfers, are left
+/// alone.
+pub fn scrub(message: &mut DnsMessage) -> usize {
+    if message.question.len() != 1 || message.question[0].qtype == DnsType::AXFR {
+        return 0;
+    }
+    let before = message.answer.len() + message.authority.len() + message.additional.len();
+
+    // Follow the CNAME chain, in whatever order its links come
+    let mut names = vec![message.question[0].qname.clone()];
+    loop {
+        let next = message.answer.iter().find_map(|rr| match rr.data {
+            DnsRRData::CNAME(ref target) if names.contains(&rr.name) && !names.contains(target) => {
+                Some(target.clone())
+            }
+            _ => None,
+        });
+        match next {
+            Some(target) => names.push(target),
+            None => break,
+        }
+    }
+    message.answer.retain(|rr| names.contains(&rr.name));
+    message
+        .authority
+        .retain(|rr| names.iter().any(|name| name.ends_with(&rr.name)));
+
+    let mut zones: Vec<DomainName> = message.authority.iter().map(|rr| rr.name.clone()).collect();
+    if zones.is_empty() {
+        zones = names
+            .iter()
+            .map(|name| name.parent().unwrap_or_default())
+            .collect();
+    }
+    message
+        .additional
+        .retain(|rr| zones.iter().any(|zone| rr.name.ends_with(zone)));
+
+    before - (message.answer.len() + message.authority.len() + message.additional.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_scrub() {
+        let rr = |name: &str, data| DnsResourceRecord {
+            name: name.into(),
+            rtype: match data {
+                DnsRRData::CNAME(_) => DnsType::CNAME,
+                DnsRRData::NS(_) => DnsType::NS,
+                _ => DnsType::A,
+            },
+            rclass: DnsClass::Internet,
+            ttl: 300,
+            data,
+        };
+        let a = |name| rr(name, DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        let query = DnsMessage::query("www.example.com".into(), DnsType::A);
+        let mut response = DnsMessage::response_to(&query)
+            .with_answer(a("cdn.example.net"))
+            .with_answer(rr(
+                "www.example.com",
+                DnsRRData::CNAME("cdn.example.net".into()),
+            ))
+            .with_answer(a("www.bank.com"))
+            .with_authority(rr("example.net", DnsRRData::NS("ns.example.net".into())))
+            .with_authority(rr("bank.com", DnsRRData::NS("ns.evil.org".into())))
+            .with_additional(a("ns.example.net"))
+            .with_additional(a("ns.evil.org"));
+        assert_eq!(scrub(&mut response), 3);
+        let names: Vec<_> = response
+            .answer
+            .iter()
+            .chain(&response.authority)
+            .chain(&response.additional)
+            .map(|rr| rr.name.to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "cdn.example.net",
+                "www.example.com",
+                "example.net",
+                "ns.example.net"
+            ]
+        );
+
+        // Without authority records, additional ones stay near the question
+        let query = DnsMessage::query("example.com".into(), DnsType::MX);
+        let mut response = DnsMessage::response_to(&query)
+            .with_additional(a("mail.example.com"))
+            .with_additional(a("mail.example.org"));
+        assert_eq!(scrub(&mut response), 1);
+        assert_eq!(response.additional[0].name, "mail.example.com".into());
+    }
+}
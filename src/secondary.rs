@@ -0,0 +1,458 @@
+//! Secondary zones: copies of zones on a master server, kept fresh by zone
+//! transfers (AXFR) and answered authoritatively.
+
+use futures::future::{self, Either, Loop};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::slice;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Framed};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use crate::codec::DnsMessageCodec;
+use crate::message::*;
+use crate::server::EntryTable;
+use crate::tsig::{self, TsigKey};
+
+/// How long to wait before retrying a zone which hasn't been transferred
+/// yet, in seconds.
+const INITIAL_RETRY: u64 = 60;
+
+/// How long a refresh, including the transfer, may take.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A zone to copy from a master, transferred with a TSIG key if one is
+/// given.
+///
+/// Written as `ZONE@MASTER[,KEY]`, e.g. `lan@192.168.1.1` or
+/// `lan@[fd00::1]:5353,xfr`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecondaryZone {
+    pub zone: DomainName,
+    pub master: SocketAddr,
+    pub key: Option<DomainName>,
+}
+
+impl FromStr for SecondaryZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SecondaryZone, String> {
+        let mut parts = s.splitn(2, ',');
+        let zone = parts.next().unwrap();
+        let key = match parts.next() {
+            Some(key) => Some(DomainName::from_idn(key)?),
+            None => None,
+        };
+        let mut parts = zone.splitn(2, '@');
+        let zone = DomainName::from_idn(parts.next().unwrap())?;
+        let master = parts
+            .next()
+            .ok_or(format!("Missing master in secondary zone {}", s))?;
+        let master = master
+            .parse()
+            .or_else(|_| master.parse().map(|ip: IpAddr| SocketAddr::new(ip, 53)))
+            .map_err(|_| format!("Error parsing master address {}", master))?;
+        Ok(SecondaryZone { zone, master, key })
+    }
+}
+
+/// A transferred copy of a zone.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    soa: DnsResourceRecord,
+    records: EntryTable,
+    expires: Instant,
+}
+
+impl Zone {
+    /// The serial, refresh, retry and expire fields of the SOA record.
+    fn timers(&self) -> (u32, u64, u64, u64) {
+        match self.soa.data {
+            DnsRRData::SOA(_, _, serial, refresh, retry, expire, _) => {
+                (serial, refresh as u64, retry as u64, expire as u64)
+            }
+            _ => unreachable!("zones are made from SOA records"),
+        }
+    }
+
+    fn serial(&self) -> u32 {
+        self.timers().0
+    }
+
+    fn refresh(&self) -> u64 {
+        self.timers().1
+    }
+
+    fn retry(&self) -> u64 {
+        self.timers().2
+    }
+
+    /// Count the expire interval from now on, after the master was reached.
+    fn renew(&mut self) {
+        self.expires = Instant::now() + Duration::from_secs(self.timers().3);
+    }
+}
+
+/// The secondary zones which have been transferred, by name.
+pub type Zones = RwLock<HashMap<DomainName, Zone>>;
+
+/// Answer a query for a name in a secondary zone which hasn't expired.
+pub fn answer(zones: &Zones, message: &DnsMessage) -> Option<DnsMessage> {
+    let question = message.question.first()?;
+    if message.question.len() != 1 || question.qclass != DnsClass::Internet {
+        return None;
+    }
+    let zones = zones.read().unwrap();
+    let now = Instant::now();
+    let zone = zones
+        .values()
+        .filter(|zone| question.qname.ends_with(&zone.soa.name) && zone.expires > now)
+        .max_by_key(|zone| zone.soa.name.len())?;
+
+    let mut response = DnsMessage::response_to(message).with_authoritative(true);
+    match zone.records.get(&question.qname) {
+        Some(rrs) => {
+            let wanted = |rr: &DnsResourceRecord| {
+                question.qtype == DnsType::Any || question.code() == rr.code()
+            };
+            response.answer = rrs.iter().filter(|rr| wanted(rr)).cloned().collect();
+            // An alias stands in for every other type
+            if response.answer.is_empty() {
+                response.answer = rrs
+                    .iter()
+                    .filter(|rr| rr.rtype == DnsType::CNAME)
+                    .cloned()
+                    .collect();
+            }
+        }
+        None => response.header.rcode = DnsRcode::NameError,
+    }
+    if response.answer.is_empty() {
+        response.authority.push(zone.soa.clone());
+    }
+    Some(response)
+}
+
+/// Keep a secondary zone fresh, forever: ask its master for the serial
+/// after each SOA refresh interval, or retry interval after a failure, and
+/// transfer the zone when it has changed.
+pub fn maintain(
+    secondary: SecondaryZone,
+    key: Option<TsigKey>,
+    zones: Arc<Zones>,
+) -> impl Future<Item = (), Error = ()> {
+    future::lazy(move || {
+        future::loop_fn((), move |()| {
+            let name = secondary.zone.clone();
+            let master = secondary.master;
+            let zones = zones.clone();
+            let serial = zones.read().unwrap().get(&name).map(Zone::serial);
+            refresh(name.clone(), master, key.clone(), serial).then(move |result| {
+                let mut zones = zones.write().unwrap();
+                let wait = match result {
+                    Ok(Some(zone)) => {
+                        info!(
+                            "Transferred {} serial {} from {}",
+                            name,
+                            zone.serial(),
+                            master
+                        );
+                        let wait = zone.refresh();
+                        zones.insert(name, zone);
+                        wait
+                    }
+                    Ok(None) => zones.get_mut(&name).map_or(INITIAL_RETRY, |zone| {
+                        zone.renew();
+                        zone.refresh()
+                    }),
+                    Err(e) => {
+                        warn!("Can't refresh {} from {}: {}", name, master, e);
+                        zones.get(&name).map_or(INITIAL_RETRY, Zone::retry)
+                    }
+                };
+                Delay::new(Instant::now() + Duration::from_secs(wait))
+                    .then(|_| Ok(Loop::Continue(())))
+            })
+        })
+    })
+}
+
+type Connection = Framed<TcpStream, DnsMessageCodec>;
+
+/// Transfer a zone from its master, unless the copy with `serial` is still
+/// current.
+fn refresh(
+    zone: DomainName,
+    master: SocketAddr,
+    key: Option<TsigKey>,
+    serial: Option<u32>,
+) -> impl Future<Item = Option<Zone>, Error = io::Error> {
+    let mut transfer = Transfer::new(zone, key);
+    TcpStream::connect(&master)
+        .map(|conn| DnsMessageCodec::new(true).framed(conn))
+        .and_then(move |conn| {
+            future::result(transfer.query(DnsType::SOA))
+                .and_then(move |query| ask(conn, query))
+                .and_then(move |(response, conn)| {
+                    transfer.receive(response)?;
+                    Ok((transfer, conn))
+                })
+        })
+        .and_then(move |(mut transfer, conn)| {
+            let master_serial = transfer.serial();
+            if serial.map_or(false, |serial| !newer(master_serial, serial)) {
+                return Either::A(future::ok(None));
+            }
+            Either::B(
+                future::result(transfer.query(DnsType::AXFR))
+                    .and_then(move |query| conn.send(query))
+                    .and_then(move |conn| receive(conn, transfer))
+                    .map(Some),
+            )
+        })
+        .timeout(TIMEOUT)
+        .map_err(|e| {
+            e.into_inner()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        })
+}
+
+/// Send a query and wait for the first message of the answer.
+fn ask(
+    conn: Connection,
+    query: DnsMessage,
+) -> impl Future<Item = (DnsMessage, Connection), Error = io::Error> {
+    conn.send(query)
+        .and_then(|conn| conn.into_future().map_err(|(e, _)| e))
+        .and_then(|(response, conn)| match response {
+            Some(response) => Ok((response, conn)),
+            None => Err(closed()),
+        })
+}
+
+/// Receive the messages of a transfer until it's complete.
+fn receive(conn: Connection, transfer: Transfer) -> impl Future<Item = Zone, Error = io::Error> {
+    future::loop_fn((conn, transfer), |(conn, mut transfer)| {
+        conn.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(message, conn)| {
+                if transfer.receive(message.ok_or_else(closed)?)? {
+                    Ok(Loop::Break(transfer.into_zone()))
+                } else {
+                    Ok(Loop::Continue((conn, transfer)))
+                }
+            })
+    })
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by master")
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether `a` is a later serial than `b`, in serial number arithmetic
+/// (RFC 1982).
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The answer to a query about a zone, as it comes in.
+struct Transfer {
+    zone: DomainName,
+    key: Option<TsigKey>,
+    id: u16,
+    /// The MAC of the query or the last message, if they're signed.
+    mac: Vec<u8>,
+    next: bool,
+    soa: Option<DnsResourceRecord>,
+    records: EntryTable,
+}
+
+impl Transfer {
+    fn new(zone: DomainName, key: Option<TsigKey>) -> Transfer {
+        Transfer {
+            zone,
+            key,
+            id: rand::random(),
+            mac: vec![],
+            next: false,
+            soa: None,
+            records: EntryTable::new(),
+        }
+    }
+
+    /// Start over with a new query.
+    fn query(&mut self, qtype: DnsType) -> io::Result<DnsMessage> {
+        self.id = rand::random();
+        let mut query = DnsMessage::query(self.zone.clone(), qtype)
+            .with_id(self.id)
+            .with_recur_desired(false);
+        if let Some(ref key) = self.key {
+            key.sign(&mut query, &[], tsig::now())?;
+            self.mac = query.tsig.as_ref().unwrap().mac.clone();
+        }
+        self.next = false;
+        self.soa = None;
+        self.records.clear();
+        Ok(query)
+    }
+
+    /// Take in a message of the answer.  Returns whether the answer is
+    /// complete, i.e. if it's a zone transfer, the SOA record came again.
+    fn receive(&mut self, message: DnsMessage) -> io::Result<bool> {
+        if message.header.id != self.id {
+            return Err(invalid(format!(
+                "Unexpected message {:x}",
+                message.header.id
+            )));
+        }
+        if message.header.rcode != DnsRcode::NoErrorCondition {
+            return Err(invalid(format!(
+                "{} answered with {:?}",
+                self.zone, message.header.rcode
+            )));
+        }
+        if let Some(ref key) = self.key {
+            // Every message has to be signed, though RFC 8945 allows gaps
+            match tsig::verify_after(
+                slice::from_ref(key),
+                &message,
+                &self.mac,
+                self.next,
+                tsig::now(),
+            ) {
+                Ok(Some(_)) => self.mac = message.tsig.as_ref().unwrap().mac.clone(),
+                Ok(None) => return Err(invalid(format!("Unsigned message for {}", self.zone))),
+                Err(error) => return Err(invalid(format!("Bad TSIG: {:?}", error))),
+            }
+        }
+        self.next = true;
+
+        for rr in message.answer {
+            if self.soa.is_none() {
+                if rr.rtype != DnsType::SOA || rr.name != self.zone {
+                    return Err(invalid(format!("No SOA record for {}", self.zone)));
+                }
+                self.soa = Some(rr.clone());
+            } else if rr.rtype == DnsType::SOA && rr.name == self.zone {
+                return Ok(true);
+            } else if !rr.name.ends_with(&self.zone) {
+                warn!("Ignoring {} in transfer of {}", rr.name, self.zone);
+                continue;
+            }
+            self.records.entry(rr.name.clone()).or_default().push(rr);
+        }
+        if self.soa.is_none() {
+            return Err(invalid(format!("No SOA record for {}", self.zone)));
+        }
+        Ok(false)
+    }
+
+    fn serial(&self) -> u32 {
+        match self.soa {
+            Some(DnsResourceRecord {
+                data: DnsRRData::SOA(_, _, serial, _, _, _, _),
+                ..
+            }) => serial,
+            _ => 0,
+        }
+    }
+
+    fn into_zone(self) -> Zone {
+        let mut zone = Zone {
+            soa: self.soa.expect("transfer is complete"),
+            records: self.records,
+            expires: Instant::now(),
+        };
+        zone.renew();
+        zone
+    }
+}
+
+#[cfg(all(test, feature = "transfer"))]
+mod tests {
+    use super::*;
+    use crate::authority;
+    use crate::transfer;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_transfer() {
+        let name: DomainName = "lan".into();
+        let key: TsigKey = "xfr:c2VjcmV0".parse().unwrap();
+        let mut table = EntryTable::new();
+        table.insert(name.clone(), vec![authority::soa(&name)]);
+        for i in 0..100 {
+            let host: DomainName = format!("host{}.lan", i).as_str().into();
+            let rr = DnsResourceRecord {
+                name: host.clone(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(10, 0, 0, i)),
+            };
+            table.insert(host, vec![rr]);
+        }
+
+        // What the master side does, see `server::transfer_answer`
+        let mut receiver = Transfer::new(name.clone(), Some(key.clone()));
+        let query = receiver.query(DnsType::AXFR).unwrap();
+        let (id, query_mac) = (receiver.id, receiver.mac.clone());
+        let mut messages = transfer::axfr(&table, &query).unwrap();
+        let mut mac = query.tsig.unwrap().mac;
+        for (i, message) in messages.iter_mut().enumerate() {
+            if i == 0 {
+                key.sign(message, &mac, tsig::now()).unwrap();
+            } else {
+                key.sign_next(message, &mac, tsig::now()).unwrap();
+            }
+            mac = message.tsig.as_ref().unwrap().mac.clone();
+            // Messages are only checked after going over the wire
+            let mut codec = DnsMessageCodec::new(true);
+            let mut buf = bytes::BytesMut::new();
+            tokio::codec::Encoder::encode(&mut codec, message.clone(), &mut buf).unwrap();
+            *message = codec.decode(&mut buf).unwrap().unwrap();
+        }
+        assert_eq!(messages.len(), 2);
+        assert!(!receiver.receive(messages[0].clone()).unwrap());
+        assert!(receiver.receive(messages[1].clone()).unwrap());
+        let zone = receiver.into_zone();
+        assert_eq!(zone.records.len(), 101);
+
+        let zones: Zones = RwLock::new(HashMap::new());
+        zones.write().unwrap().insert(name.clone(), zone);
+        let ask = |qname: &str, qtype| DnsMessage::query(qname.into(), qtype);
+        let response = answer(&zones, &ask("host7.lan", DnsType::A)).unwrap();
+        assert!(response.header.authoritative);
+        assert_eq!(
+            response.answer[0].data,
+            DnsRRData::A(Ipv4Addr::new(10, 0, 0, 7))
+        );
+        let response = answer(&zones, &ask("host7.lan", DnsType::AAAA)).unwrap();
+        assert_eq!(response.header.rcode, DnsRcode::NoErrorCondition);
+        assert_eq!(response.authority[0].rtype, DnsType::SOA);
+        let response = answer(&zones, &ask("nowhere.lan", DnsType::A)).unwrap();
+        assert_eq!(response.header.rcode, DnsRcode::NameError);
+        assert!(answer(&zones, &ask("example.com", DnsType::A)).is_none());
+
+        // A tampered message is rejected
+        let mut receiver = Transfer::new(name.clone(), Some(key.clone()));
+        receiver.id = id;
+        receiver.mac = query_mac;
+        let mut tampered = messages[0].clone();
+        tampered.tsig.as_mut().unwrap().signed[40] ^= 1;
+        assert!(receiver.receive(tampered).is_err());
+
+        assert!(newer(1, 0));
+        assert!(newer(0, u32::max_value()));
+        assert!(!newer(5, 5));
+    }
+}
@@ -0,0 +1,4499 @@
+use bytes::BytesMut;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::stream;
+use futures::sync::{mpsc, oneshot};
+use net2::{TcpBuilder, UdpBuilder};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+#[cfg(any(feature = "update", feature = "admin"))]
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::TcpListener;
+#[cfg(feature = "mirror")]
+use tokio::net::TcpStream;
+use tokio::net::{UdpFramed, UdpSocket};
+#[cfg(feature = "mirror")]
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::runtime::{self, TaskExecutor};
+use tokio::timer::Delay;
+use tokio::timer::Interval;
+use ttl_cache::TtlCache;
+
+#[cfg(any(feature = "update", feature = "transfer"))]
+use crate::acl::ZoneAccess;
+use crate::acl::{self, Action, Network, QtypeRule};
+#[cfg(feature = "admin")]
+use crate::admin;
+use crate::affinity::{self, CpuSet};
+use crate::authority;
+#[cfg(feature = "blocklists")]
+use crate::blocklist::Blocklist;
+#[cfg(feature = "cache")]
+use crate::cache::ResponseCache;
+use crate::codec::{DecodeLimits, DnsMessageCodec};
+#[cfg(unix)]
+use crate::control::{self, Controlled};
+#[cfg(unix)]
+use crate::daemon;
+use crate::daemon::PidFile;
+#[cfg(feature = "dnstap")]
+use crate::dnstap::{self, Dnstap};
+#[cfg(feature = "doh")]
+use crate::doh;
+use crate::family::{self, FamilyFilter};
+#[cfg(feature = "history")]
+use crate::history::History;
+use crate::hosts;
+use crate::memory::{self, MemoryBudget};
+use crate::message::*;
+#[cfg(feature = "pipe")]
+use crate::pipe::{PipeBackend, PipeConfig};
+use crate::privilege::Account;
+use crate::ratelimit::{RateLimit, RateLimiter, Verdict};
+use crate::rewrite::{self, RewriteRule};
+use crate::rules::{RegexRule, RegexRules};
+use crate::scrub;
+#[cfg(feature = "secondary")]
+use crate::secondary::{self, SecondaryZone};
+use crate::stats::{Stats, Variant};
+#[cfg(feature = "transfer")]
+use crate::transfer;
+#[cfg(any(feature = "update", feature = "transfer"))]
+use crate::tsig;
+#[cfg(feature = "tsig")]
+use crate::tsig::TsigKey;
+#[cfg(feature = "update")]
+use crate::update;
+use crate::upstream::{self, Strategy, UpstreamServer, Upstreams};
+#[cfg(feature = "watch")]
+use crate::watch;
+
+pub type EntryTable = HashMap<DomainName, Vec<DnsResourceRecord>>;
+
+/// A relay server, configured builder-style.
+///
+/// ```no_run
+/// let server = uind::Server::new()
+///     .listen("127.0.0.1:5353".parse().unwrap())
+///     .upstream("8.8.8.8:53".parse().unwrap());
+/// let mut runtime = tokio::runtime::Runtime::new().unwrap();
+/// let handle = server.start(&runtime.executor()).unwrap();
+/// // ...
+/// handle.stop();
+/// runtime.shutdown_on_idle();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Server {
+    config: ServerConfig,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Default::default()
+    }
+
+    /// Address to serve UDP and TCP on (default: `[::]:53`, which takes
+    /// IPv4 clients too, or 0.0.0.0:53 without IPv6).  With port 0,
+    /// both are bound to the same ephemeral port.  Each call adds another
+    /// address; queries to upstream servers go out of the first.
+    pub fn listen(mut self, addr: SocketAddr) -> Server {
+        self.config.listen_addrs.push(addr);
+        self
+    }
+
+    /// Also serve DNS-over-HTTPS (RFC 8484) on this address, at
+    /// `/dns-query`.
+    #[cfg(feature = "doh")]
+    pub fn doh_listen(mut self, addr: SocketAddr) -> Server {
+        self.config.doh_listen = Some(addr);
+        self
+    }
+
+    /// Serve the admin API on this address, which must be a loopback one.
+    #[cfg(feature = "admin")]
+    pub fn admin_listen(mut self, addr: SocketAddr) -> Server {
+        self.config.admin_listen = Some(addr);
+        self
+    }
+
+    /// Require the admin API's clients to send the token in this file as a
+    /// bearer token.
+    #[cfg(feature = "admin")]
+    pub fn admin_token_file(mut self, path: PathBuf) -> Server {
+        self.config.admin_token_file = Some(path);
+        self
+    }
+
+    /// Take commands from `uind ctl` on a Unix socket at `path`, which only
+    /// its owner may connect to.
+    #[cfg(unix)]
+    pub fn control_socket(mut self, path: PathBuf) -> Server {
+        self.config.control_path = Some(path);
+        self
+    }
+
+    /// The certificate chain and private key (PEM files) to serve DoH with.
+    /// Without them, it is served over plain HTTP, e.g. behind a reverse
+    /// proxy.
+    #[cfg(feature = "doh")]
+    pub fn doh_certificate(mut self, cert: PathBuf, key: PathBuf) -> Server {
+        self.config.doh_tls = Some((cert, key));
+        self
+    }
+
+    /// The CA certificates (a PEM file) DoH clients must present a
+    /// certificate issued by.  Only takes effect with `doh_certificate`.
+    #[cfg(feature = "doh")]
+    pub fn doh_client_ca(mut self, path: PathBuf) -> Server {
+        self.config.doh_client_ca = Some(path);
+        self
+    }
+
+    /// The DNS server queries without local answers are forwarded to.
+    pub fn upstream(mut self, addr: SocketAddr) -> Server {
+        self.config.dns_addrs = vec![addr.into()];
+        self
+    }
+
+    /// Several DNS servers to forward to, as `strategy` says.
+    pub fn upstreams(mut self, servers: Vec<UpstreamServer>, strategy: Strategy) -> Server {
+        self.config.dns_addrs = servers;
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// How long to wait for upstream to answer a query (default: 1 second).
+    /// Over UDP, the wait doubles with each retry.
+    pub fn timeout(mut self, timeout: Duration) -> Server {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// How many times to resend a query over UDP which got no response
+    /// (default: 2).  After the last attempt, the client gets SERVFAIL.
+    pub fn retries(mut self, retries: u32) -> Server {
+        self.config.retries = retries;
+        self
+    }
+
+    /// Answer questions for names matching a regular expression, unless
+    /// the local table has an answer.  Rules are tried in the order added.
+    pub fn regex_rule(mut self, rule: RegexRule) -> Server {
+        self.config.regex_rules.push(rule);
+        self
+    }
+
+    /// Refuse queries for the names of `blocklist` and the names under them,
+    /// unless it allows them.
+    #[cfg(feature = "blocklists")]
+    pub fn blocklist(mut self, blocklist: Blocklist) -> Server {
+        self.config.blocklist = Arc::new(RwLock::new(blocklist));
+        self
+    }
+
+    /// On SIGHUP, or a change to a watched file, replace the local tables,
+    /// regex rules and blocklist with what `reload` returns, or keep them
+    /// if it fails.  Pending queries and the cache are kept.
+    pub fn reload_with<F>(mut self, reload: F) -> Server
+    where
+        F: Fn() -> Result<LocalConfig, String> + Send + Sync + 'static,
+    {
+        self.config.reloader = Some(Reloader(Arc::new(reload)));
+        self
+    }
+
+    /// Reload `delay` after one of `paths` changed, and no more changes
+    /// followed.  Needs [`reload_with`](#method.reload_with).
+    #[cfg(feature = "watch")]
+    pub fn watch(mut self, paths: Vec<PathBuf>, delay: Duration) -> Server {
+        self.config.watch_paths = paths;
+        self.config.watch_delay = delay;
+        self
+    }
+
+    /// Replace the local table.
+    pub fn local_entries(mut self, local: EntryTable) -> Server {
+        self.config.local = local;
+        self
+    }
+
+    /// Add a record to the local table.
+    pub fn local_record(mut self, rr: DnsResourceRecord) -> Server {
+        self.config
+            .local
+            .entry(rr.name.clone())
+            .or_default()
+            .push(rr);
+        self
+    }
+
+    /// Duplicate client queries to a shadow upstream.
+    #[cfg(feature = "mirror")]
+    pub fn mirror(mut self, addr: SocketAddr) -> Server {
+        self.config.mirror_addr = Some(addr);
+        self
+    }
+
+    /// Serve part of the clients with a staged configuration.
+    #[cfg(feature = "canary")]
+    pub fn canary(mut self, mut canary: CanaryConfig) -> Server {
+        let local = mem::replace(&mut canary.local, EntryTable::new());
+        self.config.canary_table = Arc::new(RwLock::new(local));
+        self.config.canary = Some(canary);
+        self
+    }
+
+    /// Serve the clients in some networks with local entries and an
+    /// upstream server of their own.  The first view a client is in
+    /// applies; clients in none are served as usual.
+    pub fn view(mut self, mut view: ViewConfig) -> Server {
+        let local = mem::replace(&mut view.local, EntryTable::new());
+        self.config.view_tables.push(Arc::new(RwLock::new(local)));
+        self.config.views.push(view);
+        self
+    }
+
+    /// Cap the approximate memory held by the server, in bytes.  0 means
+    /// unlimited.
+    pub fn memory_budget(mut self, bytes: usize) -> Server {
+        self.config.memory_budget = bytes;
+        self
+    }
+
+    /// How strictly question names are validated.  Queries for names the
+    /// policy rejects are answered with FORMERR.
+    pub fn name_policy(mut self, policy: NamePolicy) -> Server {
+        self.config.name_policy = policy;
+        self
+    }
+
+    /// Add a per-type query policy.  For each question type, the first
+    /// matching rule applies.
+    pub fn qtype_rule(mut self, rule: QtypeRule) -> Server {
+        self.config.qtype_rules.push(rule);
+        self
+    }
+
+    /// Limit how many queries each client may send, dropping or refusing
+    /// those beyond.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Server {
+        self.config.rate_limiter = Some(Arc::new(RateLimiter::new(limit)));
+        self
+    }
+
+    /// Delegate names to an external program.
+    #[cfg(feature = "pipe")]
+    pub fn pipe(mut self, pipe: PipeConfig) -> Server {
+        self.config.pipes.push(pipe);
+        self
+    }
+
+    /// Send queries upstream with names in random case, and only accept
+    /// responses which echo it (draft-vixie-dnsext-dns0x20).  Some servers
+    /// don't preserve case, and can't be used with it.
+    pub fn randomize_case(mut self, randomize: bool) -> Server {
+        self.config.randomize_case = randomize;
+        self
+    }
+
+    /// Remove records which have nothing to do with the question from
+    /// upstream responses (default: on).  See `scrub::scrub`.
+    pub fn scrub(mut self, scrub: bool) -> Server {
+        self.config.scrub = scrub;
+        self
+    }
+
+    /// Cache up to this many upstream answers (default: 10000), or none
+    /// with 0.
+    #[cfg(feature = "cache")]
+    pub fn cache_size(mut self, entries: usize) -> Server {
+        self.config.cache_size = entries;
+        self
+    }
+
+    /// Keep the cached answers within approximately this many bytes
+    /// (default: unlimited), evicting the least recently used ones.
+    #[cfg(feature = "cache")]
+    pub fn cache_memory(mut self, bytes: usize) -> Server {
+        self.config.cache_memory = bytes;
+        self
+    }
+
+    /// Raise the TTLs of relayed and cached records to at least this many
+    /// seconds.
+    pub fn min_ttl(mut self, ttl: u32) -> Server {
+        self.config.min_ttl = ttl;
+        self
+    }
+
+    /// Lower the TTLs of relayed and cached records to at most this many
+    /// seconds.
+    pub fn max_ttl(mut self, ttl: u32) -> Server {
+        self.config.max_ttl = Some(ttl);
+        self
+    }
+
+    /// The name whose NS records upstreams are probed for (default: the
+    /// root).  See `upstream::maintain`.
+    pub fn probe_name(mut self, name: DomainName) -> Server {
+        self.config.probe_name = name;
+        self
+    }
+
+    /// Caps on the size and shape of messages from clients.
+    pub fn decode_limits(mut self, limits: DecodeLimits) -> Server {
+        self.config.decode_limits = limits;
+        self
+    }
+
+    /// Answer ANY queries with a synthesized HINFO record (RFC 8482), rather
+    /// than forwarding them, on all listeners.
+    pub fn minimal_any(mut self, minimal: bool) -> Server {
+        self.config.minimal_any = minimal;
+        self
+    }
+
+    /// Answer ANY queries minimally on the listener at `addr` alone, one
+    /// of those given to `listen` or `doh_listen`.
+    pub fn minimal_any_on(mut self, addr: SocketAddr) -> Server {
+        self.config.minimal_any_on.push(addr);
+        self
+    }
+
+    /// Strip the authority and additional sections from responses.
+    pub fn minimal_responses(mut self, minimal: bool) -> Server {
+        self.config.minimal_responses = minimal;
+        self
+    }
+
+    /// Rotate the address records of relayed and cached answers, a step
+    /// for each response, so that clients taking the first spread out.
+    pub fn round_robin(mut self, round_robin: bool) -> Server {
+        self.config.round_robin = round_robin;
+        self
+    }
+
+    /// Rewrite responses with `rule`, after the rules added before.
+    pub fn rewrite_rule(mut self, rule: RewriteRule) -> Server {
+        self.config.rewrite_rules.push(rule);
+        self
+    }
+
+    /// Answer with the addresses of one family alone, giving empty answers
+    /// to questions for the other.
+    pub fn family_filter(mut self, filter: FamilyFilter) -> Server {
+        self.config.family_filters.push(filter);
+        self
+    }
+
+    /// The string `version.bind` and `version.server` CHAOS TXT queries are
+    /// answered with.  Without it, they are refused.
+    pub fn version(mut self, version: String) -> Server {
+        self.config.version = Some(version);
+        self
+    }
+
+    /// The string `id.server` and `hostname.bind` CHAOS TXT queries are
+    /// answered with.  Without it, they are refused.
+    pub fn identity(mut self, identity: String) -> Server {
+        self.config.identity = Some(identity);
+        self
+    }
+
+    /// Log the messages exchanged with clients and upstreams to the dnstap
+    /// reader listening on the Unix socket at `path`.
+    #[cfg(feature = "dnstap")]
+    pub fn dnstap(mut self, path: PathBuf) -> Server {
+        self.config.dnstap_path = Some(path);
+        self
+    }
+
+    /// Record the questions clients ask in the SQLite database at `path`,
+    /// for `retention`.
+    #[cfg(feature = "history")]
+    pub fn history(mut self, path: PathBuf, retention: Duration) -> Server {
+        self.config.history = Some((path, retention));
+        self
+    }
+
+    /// What to do with the EDNS Client Subnet of forwarded queries (default:
+    /// pass it through).
+    pub fn client_subnet(mut self, mode: EcsMode) -> Server {
+        self.config.ecs = mode;
+        self
+    }
+
+    /// Accept dynamic updates (RFC 2136) of A and AAAA records in a zone.
+    #[cfg(feature = "update")]
+    pub fn update_zone(mut self, zone: ZoneAccess) -> Server {
+        self.config.update_zones.push(zone);
+        self
+    }
+
+    /// Allow transfers of a zone of the local table over TCP.
+    #[cfg(feature = "transfer")]
+    pub fn transfer_zone(mut self, zone: ZoneAccess) -> Server {
+        self.config.transfer_zones.push(zone);
+        self
+    }
+
+    /// Answer names under a zone from the local table alone, authoritatively.
+    /// Names without entries get NXDOMAIN, with a made-up SOA record.
+    pub fn local_zone(mut self, zone: DomainName) -> Server {
+        self.config.local_zones.push(zone);
+        self
+    }
+
+    /// Copy a zone from its master and answer it authoritatively.
+    #[cfg(feature = "secondary")]
+    pub fn secondary_zone(mut self, zone: SecondaryZone) -> Server {
+        self.config.secondary_zones.push(zone);
+        self
+    }
+
+    /// Add a TSIG key, which clients can sign updates and transfers with.
+    #[cfg(feature = "tsig")]
+    pub fn tsig_key(mut self, key: TsigKey) -> Server {
+        self.config.tsig_keys.push(key);
+        self
+    }
+
+    /// Write the local table to this `dnsrelay.txt` file after each update.
+    #[cfg(feature = "update")]
+    pub fn persist_updates(mut self, path: PathBuf) -> Server {
+        self.config.update_file = Some(path);
+        self
+    }
+
+    /// How long a stopping server waits for queries in flight to be
+    /// answered (default: 2 seconds).
+    pub fn shutdown_grace(mut self, grace: Duration) -> Server {
+        self.config.shutdown_grace = grace;
+        self
+    }
+
+    /// Number of worker threads used by `run` (default: one per CPU).
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Pin the worker threads used by `run` to these CPUs, round-robin.
+    pub fn cpu_affinity(mut self, cpus: Vec<usize>) -> Server {
+        self.config.cpu_affinity = cpus;
+        self
+    }
+
+    /// Ask the kernel to process the listening sockets' packets on `cpu`.
+    pub fn rx_cpu(mut self, cpu: usize) -> Server {
+        self.config.rx_cpu = Some(cpu);
+        self
+    }
+
+    /// Switch to `account` once the sockets are bound and the DoH key is
+    /// read, before any packet is.
+    pub fn run_as(mut self, account: Account) -> Server {
+        self.config.run_as = Some(account);
+        self
+    }
+
+    /// Bind the sockets and spawn the server onto `executor`.
+    pub fn start(self, executor: &TaskExecutor) -> io::Result<StopHandle> {
+        let (server, handle) = serve(self.config)?;
+        executor.spawn(server);
+        Ok(handle)
+    }
+
+    /// Fork into the background before `run` serves, once it is serving
+    /// (or has failed to start).
+    #[cfg(unix)]
+    pub fn daemon(mut self, daemon: bool) -> Server {
+        self.config.daemon = daemon;
+        self
+    }
+
+    /// Write the process ID to `path` when `run` starts, and remove it when
+    /// it stops.
+    pub fn pid_file(mut self, path: PathBuf) -> Server {
+        self.config.pid_file = Some(path);
+        self
+    }
+
+    /// Bind the sockets and serve on a new runtime, until SIGTERM or
+    /// Ctrl-C.
+    pub fn run(self) -> io::Result<()> {
+        self.run_until(terminations().into_future().then(|_| {
+            info!("Terminating");
+            Ok(())
+        }))
+    }
+
+    /// Bind the sockets and serve on a new runtime, until `stop` completes.
+    pub fn run_until<F>(self, stop: F) -> io::Result<()>
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        // Only the forking thread lives on in the child
+        #[cfg(unix)]
+        let detached = if self.config.daemon {
+            Some(daemon::detach()?)
+        } else {
+            None
+        };
+        // Written before switching users, as where it goes usually needs root
+        let _pid_file = match self.config.pid_file {
+            Some(ref path) => Some(PidFile::create(path.clone())?),
+            None => None,
+        };
+        let mut builder = runtime::Builder::new();
+        if self.config.workers > 0 {
+            builder.core_threads(self.config.workers);
+        }
+        if !self.config.cpu_affinity.is_empty() {
+            let cpus = CpuSet::new(self.config.cpu_affinity.clone());
+            builder.after_start(move || cpus.pin_next());
+        }
+        let mut runtime = builder.build()?;
+
+        let (server, handle) = serve(self.config)?;
+        #[cfg(unix)]
+        {
+            if let Some(detached) = detached {
+                detached.ready()?;
+            }
+        }
+        let (done, finished) = oneshot::channel();
+        runtime.spawn(server.then(|_| done.send(())));
+        runtime.spawn(stop.then(|_| {
+            handle.stop();
+            Ok(())
+        }));
+        let _ = finished.wait();
+        // Connections left open are closed
+        runtime
+            .shutdown_now()
+            .wait()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "runtime failed to shut down"))
+    }
+}
+
+/// Stops a started server.  Dropping it leaves the server running.
+#[derive(Debug)]
+pub struct StopHandle {
+    local_addrs: Vec<SocketAddr>,
+    stop: oneshot::Sender<()>,
+}
+
+impl StopHandle {
+    /// The address the server is actually listening on, or the first of
+    /// them.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// The addresses the server is actually listening on, in the order
+    /// given.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stop accepting queries, and stop once those already received are
+    /// answered, or the grace period is over.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+    }
+}
+
+/// Bind UDP and TCP sockets on `addr`.  An IPv6 address also takes IPv4
+/// clients (as `::ffff:a.b.c.d`) on `[::]`, unless `v6_only`.
+fn bind(addr: &SocketAddr, v6_only: bool) -> io::Result<(UdpSocket, TcpListener)> {
+    let context = |e| bind_error(addr, e);
+    let (udp_builder, tcp_builder) = match addr {
+        SocketAddr::V4(_) => (UdpBuilder::new_v4(), TcpBuilder::new_v4()),
+        SocketAddr::V6(_) => (UdpBuilder::new_v6(), TcpBuilder::new_v6()),
+    };
+    let (udp_builder, tcp_builder) = (udp_builder.map_err(context)?, tcp_builder.map_err(context)?);
+    if addr.is_ipv6() {
+        udp_builder.only_v6(v6_only).map_err(context)?;
+        tcp_builder.only_v6(v6_only).map_err(context)?;
+    }
+    let udp_sock = udp_builder.bind(addr).map_err(context)?;
+    // Make sure an ephemeral port is shared between UDP and TCP
+    let addr = udp_sock.local_addr()?;
+    #[cfg(unix)]
+    tcp_builder.reuse_address(true).map_err(context)?;
+    let tcp_sock = tcp_builder
+        .bind(addr)
+        .and_then(|builder| builder.listen(1024))
+        .map_err(context)?;
+    let handle = Handle::default();
+    Ok((
+        UdpSocket::from_std(udp_sock, &handle)?,
+        TcpListener::from_std(tcp_sock, &handle)?,
+    ))
+}
+
+/// The IPv4 address of an IPv4 client of an IPv6 socket, so that it is
+/// told apart by the same rules as over IPv4.
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                let ip = Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo));
+                SocketAddr::new(IpAddr::V4(ip), v6.port())
+            }
+            _ => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// `addr` as a socket bound to `local` can send to.
+fn mapped_for(local: &SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+fn bind_error(addr: &SocketAddr, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("error binding {}: {}", addr, e))
+}
+
+fn serve(mut config: ServerConfig) -> io::Result<(impl Future<Item = (), Error = ()>, StopHandle)> {
+    let sockets = if config.listen_addrs.is_empty() {
+        // Clients of either family, unless the system has no IPv6
+        vec![bind(&"[::]:53".parse().unwrap(), false)
+            .or_else(|_| bind(&"0.0.0.0:53".parse().unwrap(), false))?]
+    } else {
+        let listen_addrs = &config.listen_addrs;
+        listen_addrs
+            .iter()
+            .map(|addr| {
+                // IPv4 clients go to the IPv4 listener on the same port
+                let v6_only = listen_addrs
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == addr.port());
+                bind(addr, v6_only)
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    // Whether each listener answers ANY queries minimally
+    let minimal_any: Vec<_> = if config.listen_addrs.is_empty() {
+        vec![config.minimal_any]
+    } else {
+        config
+            .listen_addrs
+            .iter()
+            .map(|addr| config.minimal_any_for(addr))
+            .collect()
+    };
+    if let Some(cpu) = config.rx_cpu {
+        for (udp_sock, tcp_sock) in &sockets {
+            if let Err(e) = affinity::set_incoming_cpu(udp_sock, cpu)
+                .and_then(|_| affinity::set_incoming_cpu(tcp_sock, cpu))
+            {
+                warn!("Can't set incoming CPU {}: {}", cpu, e);
+            }
+        }
+    }
+    #[cfg(feature = "doh")]
+    let doh_socket = match config.doh_listen {
+        Some(addr) => {
+            let tls = match config.doh_tls {
+                Some((ref cert, ref key)) => Some(doh::tls_acceptor(
+                    cert,
+                    key,
+                    config.doh_client_ca.as_ref().map(AsRef::as_ref),
+                )?),
+                None => None,
+            };
+            let listener = TcpListener::bind(&addr).map_err(|e| bind_error(&addr, e))?;
+            Some((listener, tls))
+        }
+        None => None,
+    };
+    #[cfg(feature = "admin")]
+    let admin_socket = match config.admin_listen {
+        Some(addr) if !addr.ip().is_loopback() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The admin API can't listen on {}, only on loopback", addr),
+            ))
+        }
+        Some(addr) => {
+            let listener = TcpListener::bind(&addr).map_err(|e| bind_error(&addr, e))?;
+            let addr = listener.local_addr()?;
+            Some((listener, addr))
+        }
+        None => None,
+    };
+    #[cfg(feature = "admin")]
+    let admin_token = match config.admin_token_file {
+        Some(ref path) => Some(admin::read_token(path)?),
+        None => None,
+    };
+    #[cfg(unix)]
+    let control_socket = match config.control_path {
+        Some(ref path) => Some(control::bind(path)?),
+        None => None,
+    };
+    if let Some(account) = config.run_as {
+        account.switch()?;
+    }
+    // Created by the account serving, which SQLite's journal is written as
+    #[cfg(feature = "history")]
+    let history = match config.history {
+        Some((ref path, retention)) => Some(History::open(path, retention)?),
+        None => None,
+    };
+    let local_addrs = sockets
+        .iter()
+        .map(|(udp_sock, _)| udp_sock.local_addr())
+        .collect::<io::Result<Vec<_>>>()?;
+    let addrs = local_addrs.clone();
+    let (stop, stopped) = oneshot::channel();
+    // Dropping the handle leaves the server running
+    let stopped = stopped.or_else(|_| future::empty::<(), ()>()).shared();
+    let stopping = move || stopped.clone().then(|_| Ok::<(), ()>(()));
+    let draining = Arc::new(AtomicBool::new(false));
+    // Queries over TCP and HTTPS being answered
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    let mirror_addr = config.mirror_addr;
+    let mut table = mem::replace(&mut config.local, EntryTable::new());
+    complete_local(&config, &mut table);
+    for table in config.view_tables.iter().chain(Some(&config.canary_table)) {
+        add_reverse_entries(&mut table.write().unwrap());
+    }
+    config.regex = Arc::new(RwLock::new(
+        RegexRules::new(&config.regex_rules)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+    ));
+    // Dynamic updates and reloads change the local table while serving
+    let local = Arc::new(RwLock::new(table));
+    let config = Arc::new(config);
+    let memory = MemoryBudget::new(config.memory_budget);
+    memory.set_local(local_size(&config, &local.read().unwrap()));
+    let views = config.views.iter().map(|view| view.name.clone()).collect();
+    let stats = Arc::new(Stats::new(memory, views));
+    let stats_udp = stats.clone();
+    // Reloads asked for over the control socket, which are told the result
+    let (reloads, reload_requests) = mpsc::unbounded();
+    let reloader = match config.reloader {
+        Some(ref reloader) => {
+            let mut triggers: Box<dyn Stream<Item = Option<ReloadReply>, Error = ()> + Send> =
+                Box::new(reload_requests.map(Some));
+            #[cfg(unix)]
+            {
+                triggers = Box::new(triggers.select(hangups().map(|_| None)));
+            }
+            #[cfg(feature = "watch")]
+            {
+                if !config.watch_paths.is_empty() {
+                    let changes = watch::changes(&config.watch_paths, config.watch_delay)?;
+                    triggers = Box::new(triggers.select(changes.map(|_| None)));
+                }
+            }
+            Either::A(reload_on(
+                triggers,
+                reloader.clone(),
+                config.clone(),
+                local.clone(),
+                stats.clone(),
+            ))
+        }
+        None => Either::B(future::ok(())),
+    };
+    #[cfg(feature = "pipe")]
+    let pipes: Arc<Vec<PipeBackend>> = Arc::new(
+        config
+            .pipes
+            .iter()
+            .cloned()
+            .map(PipeBackend::spawn)
+            .collect(),
+    );
+    #[cfg(feature = "cache")]
+    let cache = Arc::new(ResponseCache::new(config.cache_size, config.cache_memory));
+    #[cfg(feature = "cache")]
+    let cache_udp = cache.clone();
+    if config.dns_addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No upstream servers",
+        ));
+    }
+    #[cfg(feature = "dnstap")]
+    let dnstap = config
+        .dnstap_path
+        .clone()
+        .map(|path| Dnstap::connect(path, config.identity.clone()));
+    let upstreams =
+        Upstreams::new(config.dns_addrs.clone(), config.strategy).with_timeout(config.timeout);
+    #[cfg(feature = "dnstap")]
+    let upstreams = upstreams.with_dnstap(dnstap.clone());
+    let upstreams = Arc::new(upstreams);
+    let prober = if upstreams.probed() {
+        Either::A(upstream::maintain(
+            upstreams.clone(),
+            config.probe_name.clone(),
+        ))
+    } else {
+        Either::B(future::ok(()))
+    };
+    #[cfg(feature = "metrics")]
+    let upstreams_report = upstreams.clone();
+    #[cfg(feature = "secondary")]
+    let secondaries = Arc::new(secondary::Zones::default());
+    #[cfg(feature = "secondary")]
+    let maintainers = config
+        .secondary_zones
+        .iter()
+        .map(|zone| {
+            let key = match zone.key {
+                Some(ref name) => Some(
+                    config
+                        .tsig_keys
+                        .iter()
+                        .find(|key| key.name == *name)
+                        .cloned()
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("Unknown TSIG key {}", name),
+                            )
+                        })?,
+                ),
+                None => None,
+            };
+            Ok(secondary::maintain(zone.clone(), key, secondaries.clone()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    #[cfg(feature = "secondary")]
+    let maintainer = future::join_all(maintainers).map(|_| ());
+    #[cfg(not(feature = "secondary"))]
+    let maintainer = future::ok::<(), ()>(());
+
+    let resolver = Resolver {
+        config: config.clone(),
+        local: local.clone(),
+        stats: stats.clone(),
+        #[cfg(feature = "pipe")]
+        pipes: pipes.clone(),
+        #[cfg(feature = "secondary")]
+        secondaries: secondaries.clone(),
+        #[cfg(feature = "cache")]
+        cache: cache.clone(),
+        upstreams: upstreams.clone(),
+        #[cfg(feature = "dnstap")]
+        dnstap: dnstap.clone(),
+        #[cfg(feature = "history")]
+        history: history.clone(),
+    };
+
+    // Datagrams from all listeners come in along with where to send their
+    // responses and whether their listener answers ANY minimally, and
+    // queries to upstreams go out of the first listener
+    let (udp_socks, tcp_socks): (Vec<_>, Vec<_>) = sockets.into_iter().unzip();
+    let mut udp_senders = vec![];
+    let mut udp_in: Box<
+        dyn Stream<Item = (DnsMessage, SocketAddr, Reply, bool), Error = _> + Send,
+    > = Box::new(stream::empty());
+    let mut replies = vec![];
+    for (udp_sock, minimal_any) in udp_socks.into_iter().zip(minimal_any.clone()) {
+        let local_addr = udp_sock.local_addr()?;
+        let (udp_out, incoming) = UdpFramed::new(
+            udp_sock,
+            UdpCodec(DnsMessageCodec::new(false).limits(config.decode_limits)),
+        )
+        .split();
+        let (reply, outgoing) = mpsc::unbounded::<(DnsMessage, SocketAddr)>();
+        #[cfg(feature = "dnstap")]
+        let dnstap_out = dnstap.clone();
+        udp_senders.push(
+            outgoing
+                .fold(udp_out, move |udp_out, (message, addr)| {
+                    #[cfg(feature = "dnstap")]
+                    if let Some(ref dnstap) = dnstap_out {
+                        if message.is_query() {
+                            dnstap.log(
+                                dnstap::Kind::ForwarderQuery,
+                                dnstap::Protocol::Udp,
+                                Some(local_addr),
+                                Some(addr),
+                                &message,
+                            );
+                        } else {
+                            dnstap.log(
+                                dnstap::Kind::ClientResponse,
+                                dnstap::Protocol::Udp,
+                                Some(addr),
+                                Some(local_addr),
+                                &message,
+                            );
+                        }
+                    }
+                    udp_out
+                        .send((message, mapped_for(&local_addr, addr)))
+                        .map_err(|e| error!("{}", e))
+                })
+                .map_err(|e| error!("error in sender: {:?}", e)),
+        );
+        let incoming = incoming
+            // A malformed datagram only costs itself
+            .then(|result| match result {
+                Ok(frame) => Ok(Some(frame)),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::InvalidData
+                        || e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    warn!("Dropping malformed datagram: {}", e);
+                    Ok(None)
+                }
+                Err(e) => Err(DispatcherError::from(e)),
+            })
+            .filter_map(|frame| frame);
+        let tag = reply.clone();
+        #[cfg(feature = "dnstap")]
+        let dnstap_in = dnstap.clone();
+        udp_in = Box::new(udp_in.select(incoming.map(move |(message, addr)| {
+            let addr = unmapped(addr);
+            #[cfg(feature = "dnstap")]
+            if let Some(ref dnstap) = dnstap_in {
+                if message.is_query() {
+                    dnstap.log(
+                        dnstap::Kind::ClientQuery,
+                        dnstap::Protocol::Udp,
+                        Some(addr),
+                        Some(local_addr),
+                        &message,
+                    );
+                } else {
+                    dnstap.log(
+                        dnstap::Kind::ForwarderResponse,
+                        dnstap::Protocol::Udp,
+                        Some(local_addr),
+                        Some(addr),
+                        &message,
+                    );
+                }
+            }
+            (message, addr, tag.clone(), minimal_any)
+        })));
+        replies.push(reply);
+    }
+    let tx = replies[0].clone();
+    // Responses from upstreams over TCP or HTTPS
+    let (stream_tx, stream_rx) = mpsc::unbounded::<(DnsMessage, SocketAddr)>();
+
+    let clients: Arc<Mutex<ClientTable>> = Arc::new(Mutex::new(ClientTable::new(100000)));
+    let (clients_stop, config_stop, local_stop, draining_stop, in_flight_stop) = (
+        clients.clone(),
+        config.clone(),
+        local.clone(),
+        draining.clone(),
+        in_flight.clone(),
+    );
+    // Pending queries outlive their last retry
+    let ttl = (0..=config.retries)
+        .map(|attempt| backoff(config.timeout, attempt))
+        .sum::<Duration>()
+        + Duration::from_secs(1);
+    let retransmitter = Retransmitter {
+        config: config.clone(),
+        clients: clients.clone(),
+        stats: stats.clone(),
+        upstreams: upstreams.clone(),
+        tx: tx.clone(),
+    };
+
+    let udp_sender = future::join_all(udp_senders).map(|_| ());
+
+    let stream_reply = tx.clone();
+    let draining_udp = draining.clone();
+    let udp_dispatcher = udp_in
+        .select(
+            stream_rx
+                .map(move |(message, addr)| (message, addr, stream_reply.clone(), false))
+                .map_err(|_| unreachable!()),
+        )
+        .for_each(move |(mut message, addr, reply, minimal_any)| {
+            let id = message.header.id;
+
+            if message.is_query() {
+                if draining_udp.load(Ordering::SeqCst) {
+                    debug!("Dropping message {:x} from {} while stopping", id, addr);
+                    return Ok(());
+                }
+                info!("Message {:x} from {} is UDP query", id, addr);
+                debug!("Message is\n{}", message);
+                #[cfg(feature = "history")]
+                {
+                    if let Some(ref history) = history {
+                        history.record(addr.ip(), &message);
+                    }
+                }
+
+                // Duplicate the query, as received, to the shadow upstream
+                #[cfg(feature = "mirror")]
+                {
+                    if let Some(mirror) = mirror_addr {
+                        debug!("Mirroring message {:x} to {}", id, mirror);
+                        let _ = tx.unbounded_send((message.clone(), mirror));
+                    }
+                }
+
+                let variant = config.variant_for(&addr);
+                let counters = stats_udp.variant(variant);
+                Stats::count(&counters.queries);
+                let valid = check_names(config.name_policy, &message);
+                let action = config.query_action(&addr, &message, &stats_udp);
+                if action == Action::Drop {
+                    return Ok(());
+                }
+                if valid && action != Action::Refuse {
+                    let table = local_table(&config, &local, variant).read().unwrap();
+                    if let Some(response) = authority::answer(&table, &config.local_zones, &message)
+                    {
+                        Stats::count(&counters.local);
+                        report_answers(&response);
+                        return reply
+                            .unbounded_send((response, addr))
+                            .map_err(DispatcherError::from);
+                    }
+                }
+
+                // Filter out questions which have local entries of their type
+                let answers_local = local_answers(&config, &local, variant, &mut message.question);
+                debug!("After filtration:\n{}", message);
+
+                // If no question raised, the server won't reply, let's construct a reply
+                if valid && action != Action::Refuse {
+                    let response =
+                        opcode_answer(&config, &local, variant, &stats_udp.memory, &message, &addr)
+                            .or_else(|| chaos_answer(&config, &message))
+                            .or_else(|| any_answer(minimal_any, &message));
+                    #[cfg(feature = "secondary")]
+                    let response = response.or_else(|| secondary::answer(&secondaries, &message));
+                    if let Some(response) = response {
+                        Stats::count(&counters.local);
+                        return reply
+                            .unbounded_send((response, addr))
+                            .map_err(DispatcherError::from);
+                    }
+                    #[cfg(feature = "pipe")]
+                    if let Some(answer) = pipe_answer(&pipes, &message, &addr) {
+                        Stats::count(&counters.local);
+                        tokio::spawn(answer.map(move |response| {
+                            let _ = reply.unbounded_send((response, addr));
+                        }));
+                        return Ok(());
+                    }
+                    #[cfg(feature = "cache")]
+                    if let Some(response) = cache_answer(
+                        &config,
+                        &cache_udp,
+                        variant,
+                        &message,
+                        &addr,
+                        &answers_local,
+                    ) {
+                        Stats::count(&counters.local);
+                        report_answers(&response);
+                        return reply
+                            .unbounded_send((response, addr))
+                            .map_err(DispatcherError::from);
+                    }
+                }
+
+                // A client's retransmission of a pending query waits for its
+                // response, which is being retried already
+                if valid && action != Action::Refuse && !message.question.is_empty() {
+                    let key = query_key(addr, id, &message.question);
+                    if let Some(upstream_id) = clients.lock().unwrap().upstream_id(&key) {
+                        info!(
+                            "Message {:x} from {} is a retransmission of {:x}",
+                            id, addr, upstream_id
+                        );
+                        return Ok(());
+                    }
+                }
+
+                let mut message = if !valid {
+                    from_rcode(id, DnsRcode::FormatError)
+                } else if action == Action::Refuse {
+                    from_rcode(id, DnsRcode::Refused)
+                } else if message.question.is_empty() {
+                    let mut response = from_answer(id, &answers_local);
+                    rewrite::flatten(&config.rewrite_rules, &mut response);
+                    response
+                } else if !charge_pending(&stats_udp.memory, &clients, &answers_local) {
+                    warn!("Memory budget exhausted, failing message {:x}", id);
+                    from_rcode(id, DnsRcode::ServerFailure)
+                } else {
+                    message
+                };
+                let dests = if message.question.is_empty() {
+                    vec![addr]
+                } else {
+                    client_subnet_query(config.ecs, &mut message, addr.ip());
+                    config.destinations(&upstreams, variant)
+                };
+
+                if !message.question.is_empty() {
+                    Stats::count(&counters.forwarded);
+                    // Upstream sees a random ID, which maps back to the client
+                    let mut clients = clients.lock().unwrap();
+                    let upstream_id = clients.new_upstream_id();
+                    debug!("Forwarding message {:x} as {:x}", id, upstream_id);
+                    message.header.id = upstream_id;
+                    let asked: Vec<_> = message.question.iter().map(|q| q.qname.clone()).collect();
+                    if config.randomize_case {
+                        for question in &mut message.question {
+                            question.qname = question.qname.randomize_case();
+                        }
+                    }
+                    let pending = Pending {
+                        client: addr,
+                        reply: reply.clone(),
+                        id,
+                        variant,
+                        upstreams: dests.clone(),
+                        asked,
+                        query: message.clone(),
+                        retried: false,
+                        answers_local,
+                        since: Instant::now(),
+                    };
+                    let since = pending.since;
+                    if let Some(replaced) = clients.insert(upstream_id, pending, ttl) {
+                        stats_udp
+                            .memory
+                            .release_pending(pending_size(&replaced.answers_local));
+                    }
+                    retransmitter.clone().watch(upstream_id, since, 0);
+                } else {
+                    Stats::count(&counters.local);
+                }
+
+                // Send packets
+                debug!("UDP send to {:?}:\n{}", dests, message);
+                let out = if message.question.is_empty() {
+                    &reply
+                } else {
+                    &tx
+                };
+                for &dest in &dests {
+                    if upstreams.url(dest).is_some() {
+                        forward_stream(&upstreams, dest, message.clone(), &stream_tx);
+                    } else {
+                        let _ = out.unbounded_send((message.clone(), dest));
+                    }
+                }
+                Ok(())
+            } else if Some(addr) == mirror_addr {
+                // Shadow upstream answers never reach clients
+                debug!("Discarding mirrored response {:x}", id);
+                Ok(())
+            } else {
+                info!("Message {:x} from {} is UDP response", id, addr);
+                let mut clients = clients.lock().unwrap();
+                let valid = clients.get(id).map(|pending| {
+                    pending.upstreams.contains(&addr)
+                        && echoes_question(&message, &pending.query.question, config.randomize_case)
+                });
+                if valid == Some(false) {
+                    // Keep waiting for the real response
+                    warn!(
+                        "Dropping response {:x} from {}, which doesn't match the query",
+                        id, addr
+                    );
+                    return Ok(());
+                }
+                if message.header.truncated && upstreams.url(addr).is_none() {
+                    if let Some(pending) = clients.get_mut(id) {
+                        // Responses to UDP retransmissions are truncated too
+                        if pending.retried {
+                            debug!(
+                                "Dropping truncated response {:x} from {}, retried over TCP",
+                                id, addr
+                            );
+                            return Ok(());
+                        }
+                        // Ask the same upstream again over TCP, once
+                        info!(
+                            "Response {:x} from {} is truncated, retrying over TCP",
+                            id, addr
+                        );
+                        pending.retried = true;
+                        retry_tcp(&upstreams, addr, pending.query.clone(), &stream_tx);
+                        return Ok(());
+                    }
+                }
+                if let Some(pending) = clients.remove(id) {
+                    let client_addr = pending.client;
+                    message.header.id = pending.id;
+                    restore_case(&mut message, &pending.query.question, &pending.asked);
+                    upstreams.record(addr, pending.since.elapsed());
+                    decay_response(&mut message, pending.since.elapsed());
+                    upstream_response(&config, &mut message);
+                    #[cfg(feature = "cache")]
+                    {
+                        if config.cache_size > 0 {
+                            cache_udp.insert(pending.variant, &message);
+                        }
+                    }
+                    Stats::count(&stats_udp.variant(pending.variant).responses);
+                    stats_udp
+                        .memory
+                        .release_pending(pending_size(&pending.answers_local));
+                    add_local_answers(&mut message, pending.answers_local);
+                    rewrite::flatten(&config.rewrite_rules, &mut message);
+                    client_subnet_response(config.ecs, &mut message);
+                    if config.minimal_responses {
+                        minimize(&mut message);
+                    }
+                    if config.round_robin {
+                        rotate_addresses(&mut message);
+                    }
+                    report_answers(&message);
+                    debug!("Message to {} is\n{}", client_addr, message);
+                    pending
+                        .reply
+                        .unbounded_send((message, client_addr))
+                        .map_err(DispatcherError::from)
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .map_err(|e| error!("error in udp dispatcher: {}", e));
+
+    #[cfg(feature = "doh")]
+    let doh_listener = match doh_socket {
+        Some((listener, tls)) => {
+            let resolver = resolver.clone();
+            let in_flight = in_flight.clone();
+            #[cfg(feature = "dnstap")]
+            let doh_addr = listener.local_addr().ok();
+            let minimal_any = resolver
+                .config
+                .doh_listen
+                .map_or(false, |addr| resolver.config.minimal_any_for(&addr));
+            Either::A(
+                doh::serve(
+                    listener,
+                    tls,
+                    resolver.config.decode_limits,
+                    move |message, client| {
+                        let in_flight = InFlight::enter(&in_flight);
+                        #[cfg(feature = "dnstap")]
+                        let tap = resolver.clone();
+                        #[cfg(feature = "dnstap")]
+                        tap.tap(
+                            dnstap::Kind::ClientQuery,
+                            dnstap::Protocol::Doh,
+                            &message,
+                            client,
+                            doh_addr,
+                        );
+                        resolver
+                            .resolve(message, client, minimal_any)
+                            .map(move |responses| {
+                                drop(in_flight);
+                                let response = responses.into_iter().next();
+                                #[cfg(feature = "dnstap")]
+                                if let Some(ref response) = response {
+                                    tap.tap(
+                                        dnstap::Kind::ClientResponse,
+                                        dnstap::Protocol::Doh,
+                                        response,
+                                        client,
+                                        doh_addr,
+                                    );
+                                }
+                                response
+                            })
+                    },
+                )
+                .select(stopping())
+                .then(|_| Ok(())),
+            )
+        }
+        None => Either::B(future::ok(())),
+    };
+    #[cfg(not(feature = "doh"))]
+    let doh_listener = future::ok(());
+
+    #[cfg(feature = "admin")]
+    let admin_listener = match admin_socket {
+        Some((listener, addr)) => Either::A(
+            admin::serve(
+                listener,
+                addr,
+                admin_token,
+                Managed {
+                    config: resolver.config.clone(),
+                    local: resolver.local.clone(),
+                    stats: resolver.stats.clone(),
+                    #[cfg(feature = "cache")]
+                    cache: resolver.cache.clone(),
+                    upstreams: resolver.upstreams.clone(),
+                },
+            )
+            .select(stopping())
+            .then(|_| Ok(())),
+        ),
+        None => Either::B(future::ok(())),
+    };
+    #[cfg(not(feature = "admin"))]
+    let admin_listener = future::ok(());
+
+    #[cfg(unix)]
+    let control_listener = match (control_socket, resolver.config.control_path.clone()) {
+        (Some(listener), Some(path)) => Either::A(
+            control::serve(
+                listener,
+                Controlled {
+                    stats: resolver.stats.clone(),
+                    #[cfg(feature = "cache")]
+                    cache: resolver.cache.clone(),
+                    upstreams: resolver.upstreams.clone(),
+                    reload: reloads,
+                },
+            )
+            .select(stopping())
+            .then(move |_| {
+                let _ = std::fs::remove_file(path);
+                Ok(())
+            }),
+        ),
+        _ => Either::B(future::ok(())),
+    };
+    #[cfg(not(unix))]
+    let control_listener = {
+        drop(reloads);
+        future::ok(())
+    };
+
+    let tcp_dispatchers = tcp_socks
+        .into_iter()
+        .zip(minimal_any)
+        .map(|(tcp_sock, minimal_any)| {
+            let resolver = resolver.clone();
+            let draining = draining.clone();
+            let in_flight = in_flight.clone();
+            tcp_sock
+                .incoming()
+                .for_each(move |stream| {
+                    let resolver = resolver.clone();
+                    let draining = draining.clone();
+                    let in_flight = in_flight.clone();
+                    let client_addr = stream.peer_addr().expect("peer_addr");
+                    #[cfg(feature = "dnstap")]
+                    let local_addr = stream.local_addr().ok();
+                    let (sink, stream) = DnsMessageCodec::new(true)
+                        .limits(resolver.config.decode_limits)
+                        .framed(stream)
+                        .split();
+
+                    let forwarder = stream
+                        .inspect(move |message| {
+                            info!(
+                                "Message {:x} from {} is TCP query",
+                                message.header.id, client_addr
+                            )
+                        })
+                        .map_err(|e| error!("error in tcp stream {}", e))
+                        .take_while(move |_| Ok(!draining.load(Ordering::SeqCst)))
+                        .fold(sink, move |sink, message| {
+                            let in_flight = InFlight::enter(&in_flight);
+                            #[cfg(feature = "dnstap")]
+                            let tap = resolver.clone();
+                            #[cfg(feature = "dnstap")]
+                            tap.tap(
+                                dnstap::Kind::ClientQuery,
+                                dnstap::Protocol::Tcp,
+                                &message,
+                                client_addr,
+                                local_addr,
+                            );
+                            resolver
+                                .resolve(message, client_addr, minimal_any)
+                                .and_then(move |responses| {
+                                    #[cfg(feature = "dnstap")]
+                                    for response in &responses {
+                                        tap.tap(
+                                            dnstap::Kind::ClientResponse,
+                                            dnstap::Protocol::Tcp,
+                                            response,
+                                            client_addr,
+                                            local_addr,
+                                        );
+                                    }
+                                    // Send to client
+                                    sink.send_all(stream::iter_ok::<_, io::Error>(responses))
+                                        .map(|(sink, _)| sink)
+                                        .map_err(|e| error!("{}", e))
+                                })
+                                .then(move |result| {
+                                    drop(in_flight);
+                                    result
+                                })
+                        })
+                        .map(|_| ());
+                    tokio::spawn(forwarder);
+
+                    future::ok(())
+                })
+                .map_err(|e| error!("error in tcp dispatcher: {:?}", e))
+                .select(stopping())
+                .then(|_| Ok(()))
+        })
+        .collect::<Vec<_>>();
+    let tcp_dispatcher = future::join_all(tcp_dispatchers).map(|_| ());
+
+    #[cfg(feature = "metrics")]
+    let reporter = Interval::new_interval(Duration::from_secs(60))
+        .for_each(move |_| {
+            stats.report();
+            #[cfg(feature = "cache")]
+            info!(
+                "Cache: entries={} bytes={} hits={} misses={} evictions={}",
+                cache.len(),
+                cache.bytes(),
+                cache.hits(),
+                cache.misses(),
+                cache.evictions(),
+            );
+            for (addr, rtt, up) in upstreams_report.status() {
+                let state = if up { "up" } else { "down" };
+                match rtt {
+                    Some(rtt) => info!("Upstream {}: {} rtt={:?}", addr, state, rtt),
+                    None => info!("Upstream {}: {} rtt unknown", addr, state),
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("error in stats reporter: {}", e));
+    #[cfg(not(feature = "metrics"))]
+    let reporter = future::ok(());
+
+    let server = udp_sender
+        .join(udp_dispatcher)
+        .join(tcp_dispatcher)
+        .join(doh_listener)
+        .join(admin_listener)
+        .join(control_listener)
+        .join(reporter)
+        .join(maintainer)
+        .join(prober)
+        .join(reloader)
+        .map(|_| ())
+        .select(stopping().and_then(move |_| {
+            // Answer what was asked before stopping, but nothing more
+            draining_stop.store(true, Ordering::SeqCst);
+            let grace = config_stop.shutdown_grace;
+            info!("Stopping, waiting up to {:?} for queries in flight", grace);
+            drain(clients_stop, in_flight_stop, grace)
+                .map(move |_| flush(&config_stop, &local_stop))
+        }))
+        .map(move |_| info!("Server on {:?} stopped", addrs))
+        .map_err(|_| ());
+    Ok((server, StopHandle { local_addrs, stop }))
+}
+
+/// What queries over a stream, rather than datagrams, are answered with.
+#[derive(Clone)]
+struct Resolver {
+    config: Arc<ServerConfig>,
+    local: Arc<RwLock<EntryTable>>,
+    stats: Arc<Stats>,
+    #[cfg(feature = "pipe")]
+    pipes: Arc<Vec<PipeBackend>>,
+    #[cfg(feature = "secondary")]
+    secondaries: Arc<secondary::Zones>,
+    #[cfg(feature = "cache")]
+    cache: Arc<ResponseCache>,
+    upstreams: Arc<Upstreams>,
+    #[cfg(feature = "dnstap")]
+    dnstap: Option<Dnstap>,
+    #[cfg(feature = "history")]
+    history: Option<History>,
+}
+
+impl Resolver {
+    /// Log a message exchanged with a client over a stream to dnstap.
+    #[cfg(feature = "dnstap")]
+    fn tap(
+        &self,
+        kind: dnstap::Kind,
+        protocol: dnstap::Protocol,
+        message: &DnsMessage,
+        client_addr: SocketAddr,
+        local_addr: Option<SocketAddr>,
+    ) {
+        if let Some(ref dnstap) = self.dnstap {
+            dnstap.log(
+                kind,
+                protocol,
+                Some(unmapped(client_addr)),
+                local_addr,
+                message,
+            );
+        }
+    }
+
+    /// The responses to a query from `client_addr`: several for a zone
+    /// transfer, and none if the query is dropped.  `minimal_any` is
+    /// whether the listener answers ANY queries minimally.
+    fn resolve(
+        &self,
+        mut message: DnsMessage,
+        client_addr: SocketAddr,
+        minimal_any: bool,
+    ) -> impl Future<Item = Vec<DnsMessage>, Error = ()> {
+        let config = self.config.clone();
+        let local = &self.local;
+        let stats = self.stats.clone();
+        #[cfg(feature = "cache")]
+        let cache = self.cache.clone();
+        let upstreams = self.upstreams.clone();
+        let client_addr = unmapped(client_addr);
+        let variant = config.variant_for(&client_addr);
+        Stats::count(&stats.variant(variant).queries);
+        #[cfg(feature = "history")]
+        {
+            if let Some(ref history) = self.history {
+                history.record(client_addr.ip(), &message);
+            }
+        }
+
+        #[cfg(feature = "mirror")]
+        {
+            if let Some(mirror) = config.mirror_addr {
+                mirror_tcp(message.clone(), mirror);
+            }
+        }
+
+        let id = message.header.id;
+        let early = if !check_names(config.name_policy, &message) {
+            Some(vec![from_rcode(id, DnsRcode::FormatError)])
+        } else {
+            match config.query_action(&client_addr, &message, &stats) {
+                Action::Drop => Some(vec![]),
+                Action::Refuse => Some(vec![from_rcode(id, DnsRcode::Refused)]),
+                _ => None,
+            }
+        };
+        let early = match early {
+            Some(responses) => Some(Either::A(future::ok(responses))),
+            None => match self.answer(&message, &client_addr, variant, minimal_any) {
+                Some(responses) => Some(Either::A(future::ok(responses))),
+                None => self
+                    .pipe_answer(&message, &client_addr)
+                    .map(|answer| Either::B(answer.map(|response| vec![response]))),
+            },
+        };
+        if let Some(responses) = early {
+            Stats::count(&stats.variant(variant).local);
+            return Either::A(responses);
+        }
+
+        let minimal_responses = config.minimal_responses;
+        let round_robin = config.round_robin;
+        let ecs = config.ecs;
+        let since = Instant::now();
+        let local_answers = local_answers(&config, local, variant, &mut message.question);
+        let response = if !message.question.is_empty() {
+            Stats::count(&stats.variant(variant).forwarded);
+            client_subnet_query(ecs, &mut message, client_addr.ip());
+            let mut servfail =
+                DnsMessage::response_to(&message).with_rcode(DnsRcode::ServerFailure);
+            unalias(&mut servfail.question, &local_answers);
+            let asked: Vec<_> = message.question.iter().map(|q| q.qname.clone()).collect();
+            let randomize_case = config.randomize_case;
+            if randomize_case {
+                for question in &mut message.question {
+                    question.qname = question.qname.randomize_case();
+                }
+            }
+            let sent = message.question.clone();
+            // Ask the upstreams, and take the first response which echoes
+            // the case of the question
+            let timeout = config.timeout;
+            let exchanges = config
+                .destinations(&upstreams, variant)
+                .into_iter()
+                .map(|upstream| {
+                    let upstreams = upstreams.clone();
+                    let sent = message.question.clone();
+                    upstreams
+                        .exchange(upstream, message.clone())
+                        .and_then(move |response| {
+                            if randomize_case && !echoes_question(&response, &sent, true) {
+                                let e = "response doesn't match the query";
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                            }
+                            Ok(response)
+                        })
+                        .map_err(move |e| {
+                            error!("error asking {}: {}", upstream, e);
+                            upstreams.record(upstream, timeout);
+                        })
+                });
+            Either::A(future::select_ok(exchanges).then(move |result| {
+                let mut response = match result {
+                    Ok((response, _)) => response,
+                    Err(_) => {
+                        warn!("No upstream answered message {:x}", id);
+                        return Ok::<_, ()>(servfail);
+                    }
+                };
+                restore_case(&mut response, &sent, &asked);
+                Stats::count(&stats.variant(variant).responses);
+                info!("Message {:x} is TCP response", response.header.id);
+                debug!("Response is\n{}", response);
+                decay_response(&mut response, since.elapsed());
+                upstream_response(&config, &mut response);
+                #[cfg(feature = "cache")]
+                {
+                    if config.cache_size > 0 {
+                        cache.insert(variant, &response);
+                    }
+                }
+                add_local_answers(&mut response, local_answers);
+                rewrite::flatten(&config.rewrite_rules, &mut response);
+                client_subnet_response(ecs, &mut response);
+                Ok(response)
+            }))
+        } else {
+            Stats::count(&stats.variant(variant).local);
+            let mut response = from_answer(id, &local_answers);
+            rewrite::flatten(&config.rewrite_rules, &mut response);
+            Either::B(future::ok(response))
+        };
+
+        Either::B(response.map(move |mut response| {
+            if minimal_responses {
+                minimize(&mut response);
+            }
+            if round_robin {
+                rotate_addresses(&mut response);
+            }
+            report_answers(&response);
+            vec![response]
+        }))
+    }
+
+    /// The responses to a query which isn't forwarded: a zone transfer, an
+    /// UPDATE, or a local, secondary or cached answer.
+    fn answer(
+        &self,
+        message: &DnsMessage,
+        client_addr: &SocketAddr,
+        variant: Variant,
+        minimal_any: bool,
+    ) -> Option<Vec<DnsMessage>> {
+        let config = &self.config;
+        let local = &self.local;
+        #[cfg(feature = "transfer")]
+        {
+            let table = local_table(config, local, variant);
+            if let Some(responses) = transfer_answer(config, table, message, client_addr) {
+                return Some(responses);
+            }
+        }
+        let memory = &self.stats.memory;
+        let response = opcode_answer(config, local, variant, memory, message, client_addr)
+            .or_else(|| chaos_answer(config, message))
+            .or_else(|| any_answer(minimal_any, message))
+            .or_else(|| {
+                let table = local_table(config, local, variant).read().unwrap();
+                authority::answer(&table, &config.local_zones, message)
+            });
+        #[cfg(feature = "secondary")]
+        let response = response.or_else(|| secondary::answer(&self.secondaries, message));
+        #[cfg(feature = "cache")]
+        let response = response.or_else(|| {
+            let mut query = message.clone();
+            let answers_local = local_answers(config, local, variant, &mut query.question);
+            cache_answer(
+                config,
+                &self.cache,
+                variant,
+                &query,
+                client_addr,
+                &answers_local,
+            )
+        });
+        response.map(|response| vec![response])
+    }
+
+    /// The answer of the pipe backend responsible for the query's name.
+    #[cfg(feature = "pipe")]
+    fn pipe_answer(
+        &self,
+        message: &DnsMessage,
+        client_addr: &SocketAddr,
+    ) -> Option<impl Future<Item = DnsMessage, Error = ()>> {
+        pipe_answer(&self.pipes, message, client_addr)
+    }
+
+    #[cfg(not(feature = "pipe"))]
+    fn pipe_answer(
+        &self,
+        _: &DnsMessage,
+        _: &SocketAddr,
+    ) -> Option<future::FutureResult<DnsMessage, ()>> {
+        None
+    }
+}
+
+/// A step every upstream response goes through before it is relayed.
+type ResponseStage = fn(&ServerConfig, &mut DnsMessage);
+
+/// The steps, in order.  The response cache stores their result, and local
+/// answers and client-specific changes come after that.
+const UPSTREAM_STAGES: &[ResponseStage] = &[scrub_stage, rewrite_stage, family_stage, ttl_stage];
+
+fn upstream_response(config: &ServerConfig, message: &mut DnsMessage) {
+    for stage in UPSTREAM_STAGES {
+        stage(config, message);
+    }
+}
+
+fn scrub_stage(config: &ServerConfig, message: &mut DnsMessage) {
+    if config.scrub {
+        let removed = scrub::scrub(message);
+        if removed > 0 {
+            warn!(
+                "Removed {} records outside the bailiwick from response {:x}",
+                removed, message.header.id
+            );
+        }
+    }
+}
+
+fn rewrite_stage(config: &ServerConfig, message: &mut DnsMessage) {
+    let changed = rewrite::rewrite(&config.rewrite_rules, message);
+    if changed > 0 {
+        info!(
+            "Rewrote {} records of response {:x}",
+            changed, message.header.id
+        );
+    }
+}
+
+fn family_stage(config: &ServerConfig, message: &mut DnsMessage) {
+    let removed = family::strip(&config.family_filters, message);
+    if removed > 0 {
+        debug!(
+            "Removed {} filtered addresses from response {:x}",
+            removed, message.header.id
+        );
+    }
+}
+
+fn ttl_stage(config: &ServerConfig, message: &mut DnsMessage) {
+    let max_ttl = config.max_ttl.unwrap_or(u32::max_value());
+    for rr in message
+        .answer
+        .iter_mut()
+        .chain(&mut message.authority)
+        .chain(&mut message.additional)
+    {
+        rr.ttl = rr.ttl.max(config.min_ttl).min(max_ttl);
+    }
+}
+
+/// Forward a query to an upstream over TCP, or DNS-over-HTTPS for a URL.
+/// Its response comes back through `responses`, as if it came over UDP.
+fn forward_stream(
+    upstreams: &Upstreams,
+    upstream: SocketAddr,
+    message: DnsMessage,
+    responses: &mpsc::UnboundedSender<(DnsMessage, SocketAddr)>,
+) {
+    let responses = responses.clone();
+    tokio::spawn(upstreams.exchange(upstream, message).then(move |result| {
+        match result {
+            Ok(response) => {
+                let _ = responses.unbounded_send((response, upstream));
+            }
+            Err(e) => warn!("error asking {}: {}", upstream, e),
+        }
+        Ok(())
+    }));
+}
+
+/// Ask `upstream` again over TCP, after a truncated response.  Should that
+/// fail, the client gets a SERVFAIL, as truncated responses to the query
+/// are dropped from then on.
+fn retry_tcp(
+    upstreams: &Upstreams,
+    upstream: SocketAddr,
+    query: DnsMessage,
+    responses: &mpsc::UnboundedSender<(DnsMessage, SocketAddr)>,
+) {
+    let responses = responses.clone();
+    let servfail = DnsMessage::response_to(&query).with_rcode(DnsRcode::ServerFailure);
+    tokio::spawn(upstreams.exchange(upstream, query).then(move |result| {
+        let response = result.unwrap_or_else(|e| {
+            warn!("error asking {} over TCP: {}", upstream, e);
+            servfail
+        });
+        let _ = responses.unbounded_send((response, upstream));
+        Ok(())
+    }));
+}
+
+/// Fire-and-forget a copy of a TCP query to the shadow upstream.
+#[cfg(feature = "mirror")]
+fn mirror_tcp(message: DnsMessage, mirror: SocketAddr) {
+    debug!("Mirroring message {:x} to {}", message.header.id, mirror);
+    let fut = TcpStream::connect(&mirror)
+        .map(|conn| DnsMessageCodec::new(true).framed(conn))
+        .and_then(|codec| codec.send(message))
+        .and_then(|codec| codec.into_future().map_err(|(e, _)| e))
+        .timeout(Duration::from_secs(2))
+        .map(|_| ())
+        .map_err(move |_| debug!("mirror {} did not answer", mirror));
+    tokio::spawn(fut);
+}
+
+fn report_answers(message: &DnsMessage) {
+    let report: Vec<_> = message
+        .answer
+        .iter()
+        .filter(|x| match x.data {
+            DnsRRData::A(_) | DnsRRData::AAAA(_) => true,
+            _ => false,
+        })
+        .map(|x| {
+            (
+                &x.name,
+                match x.data {
+                    DnsRRData::A(ip4) => IpAddr::V4(ip4),
+                    DnsRRData::AAAA(ip6) => IpAddr::V6(ip6),
+                    _ => unreachable!(),
+                },
+            )
+        })
+        .collect();
+    for (name, ip) in report {
+        println!("{:x}: {}: {}", message.header.id, name.to_unicode(), ip)
+    }
+}
+
+/// Apply UPDATE messages to the local table of the client's variant, if
+/// updates are enabled, and answer NOTIFY messages with NOTIMP.
+#[cfg_attr(not(feature = "update"), allow(unused_variables))]
+fn opcode_answer(
+    config: &ServerConfig,
+    local: &RwLock<EntryTable>,
+    variant: Variant,
+    memory: &MemoryBudget,
+    message: &DnsMessage,
+    client: &SocketAddr,
+) -> Option<DnsMessage> {
+    let rcode = match message.header.opcode {
+        #[cfg(feature = "update")]
+        DnsOpcode::Update if !config.update_zones.is_empty() => {
+            return Some(update_answer(
+                config, local, variant, memory, message, client,
+            ));
+        }
+        DnsOpcode::Notify | DnsOpcode::Update => DnsRcode::NotImplemented,
+        _ => return None,
+    };
+    let mut response = from_rcode(message.header.id, rcode);
+    response.header.opcode = message.header.opcode;
+    Some(response)
+}
+
+/// Apply an UPDATE message to the local table, answering it signed if it
+/// was.
+#[cfg(feature = "update")]
+fn update_answer(
+    config: &ServerConfig,
+    local: &RwLock<EntryTable>,
+    variant: Variant,
+    memory: &MemoryBudget,
+    message: &DnsMessage,
+    client: &SocketAddr,
+) -> DnsMessage {
+    let id = message.header.id;
+    let key = match tsig::verify(&config.tsig_keys, message, tsig::now()) {
+        Ok(key) => key,
+        Err(error) => {
+            info!(
+                "Update {:x} from {} has a bad TSIG: {:?}",
+                id, client, error
+            );
+            return tsig_error(message, error);
+        }
+    };
+    let key_name = key.map(|key| &key.name);
+    let applied = update::apply(
+        &config.update_zones,
+        &mut local_table(config, local, variant).write().unwrap(),
+        message,
+        &client.ip(),
+        key_name,
+    );
+    let rcode = match applied {
+        Ok(()) => {
+            local_changed(config, memory, local, variant);
+            DnsRcode::NoErrorCondition
+        }
+        Err(rcode) => {
+            info!("Update {:x} from {} failed: {:?}", id, client, rcode);
+            rcode
+        }
+    };
+    let mut response = from_rcode(id, rcode);
+    response.header.opcode = message.header.opcode;
+    if let (Some(key), Some(tsig)) = (key, &message.tsig) {
+        if let Err(e) = key.sign(&mut response, &tsig.mac, tsig::now()) {
+            error!("Can't sign message {:x}: {}", id, e);
+        }
+    }
+    response
+}
+
+/// Answer an AXFR query for a transferable zone with the whole zone, or an
+/// SOA query with its SOA record, each message signed if the query was.
+#[cfg(feature = "transfer")]
+fn transfer_answer(
+    config: &ServerConfig,
+    local: &RwLock<EntryTable>,
+    message: &DnsMessage,
+    client: &SocketAddr,
+) -> Option<Vec<DnsMessage>> {
+    let question = message.question.first()?;
+    let axfr = question.qtype == DnsType::AXFR;
+    if message.question.len() != 1 || !axfr && question.qtype != DnsType::SOA {
+        return None;
+    }
+    let zone = config
+        .transfer_zones
+        .iter()
+        .find(|zone| zone.zone == question.qname)?;
+    let key = match tsig::verify(&config.tsig_keys, message, tsig::now()) {
+        Ok(key) => key,
+        Err(error) => {
+            info!(
+                "Transfer {:x} to {} has a bad TSIG: {:?}",
+                message.header.id, client, error
+            );
+            return Some(vec![tsig_error(message, error)]);
+        }
+    };
+    let messages = if axfr {
+        if !zone.allows(&client.ip(), key.map(|key| &key.name)) {
+            info!("Refusing transfer of {} to {}", zone.zone, client);
+            return Some(vec![from_rcode(message.header.id, DnsRcode::Refused)]);
+        }
+        info!("Transferring {} to {}", zone.zone, client);
+        transfer::axfr(&local.read().unwrap(), message)
+    } else {
+        transfer::soa_answer(&local.read().unwrap(), message).map(|response| vec![response])
+    };
+    let mut messages = match messages {
+        Some(messages) => messages,
+        None => {
+            error!("Zone {} has no SOA record to transfer", zone.zone);
+            return Some(vec![from_rcode(message.header.id, DnsRcode::ServerFailure)]);
+        }
+    };
+    if let (Some(key), Some(tsig)) = (key, &message.tsig) {
+        let mut mac = tsig.mac.clone();
+        for (i, response) in messages.iter_mut().enumerate() {
+            let signed = if i == 0 {
+                key.sign(response, &mac, tsig::now())
+            } else {
+                key.sign_next(response, &mac, tsig::now())
+            };
+            match signed {
+                Ok(()) => mac = response.tsig.as_ref().unwrap().mac.clone(),
+                Err(e) => error!("Can't sign message {:x}: {}", response.header.id, e),
+            }
+        }
+    }
+    Some(messages)
+}
+
+/// Answer a request whose TSIG doesn't check out.  The answer isn't signed,
+/// which is only correct for BADSIG and BADKEY.
+#[cfg(any(feature = "update", feature = "transfer"))]
+fn tsig_error(message: &DnsMessage, error: DnsRcode) -> DnsMessage {
+    let mut response = from_rcode(message.header.id, DnsRcode::NotAuth);
+    response.header.opcode = message.header.opcode;
+    response.tsig = message.tsig.as_ref().map(|tsig| Tsig {
+        key: tsig.key.clone(),
+        algorithm: tsig.algorithm.clone(),
+        time_signed: tsig::now(),
+        fudge: tsig.fudge,
+        original_id: tsig.original_id,
+        error: error as u16,
+        ..Default::default()
+    });
+    response
+}
+
+/// Answer a CHAOS class query about the server itself, refusing any other.
+fn chaos_answer(config: &ServerConfig, message: &DnsMessage) -> Option<DnsMessage> {
+    let question = message.question.first()?;
+    if question.qclass != DnsClass::Chaos {
+        return None;
+    }
+    let id = message.header.id;
+    let name = question.qname.to_string().to_lowercase();
+    let text = match name.as_str() {
+        "version.bind" | "version.server" => config.version.as_ref(),
+        "id.server" | "hostname.bind" => config.identity.as_ref(),
+        _ => None,
+    };
+    let text = match text {
+        Some(text) if question.qtype == DnsType::TXT || question.qtype == DnsType::Any => text,
+        _ => return Some(from_rcode(id, DnsRcode::Refused)),
+    };
+    debug!("Message {:x} asks for {}", id, name);
+    let answer = DnsResourceRecord {
+        name: question.qname.clone(),
+        rtype: DnsType::TXT,
+        rclass: DnsClass::Chaos,
+        ttl: 0,
+        data: DnsRRData::TXT(vec![text.clone().into_bytes()]),
+    };
+    Some(from_answer(id, &[answer]))
+}
+
+/// Answer an ANY query with a synthesized HINFO record, as RFC 8482 allows:
+/// ANY is mostly asked to amplify attacks, or to fingerprint.
+fn any_answer(minimal_any: bool, message: &DnsMessage) -> Option<DnsMessage> {
+    let question = message.question.first().filter(|_| minimal_any)?;
+    if question.qtype != DnsType::Any || question.qclass != DnsClass::Internet {
+        return None;
+    }
+    debug!("Answering message {:x} minimally", message.header.id);
+    let answer = DnsResourceRecord {
+        name: question.qname.clone(),
+        rtype: DnsType::HINFO,
+        rclass: DnsClass::Internet,
+        ttl: 3600,
+        data: DnsRRData::HINFO(b"RFC8482".to_vec(), vec![]),
+    };
+    Some(
+        DnsMessage::response_to(message)
+            .with_recur_available(true)
+            .with_answer(answer),
+    )
+}
+
+/// Answer a query from a pipe backend, if one is responsible for its name.
+#[cfg(feature = "pipe")]
+fn pipe_answer(
+    pipes: &[PipeBackend],
+    message: &DnsMessage,
+    client: &SocketAddr,
+) -> Option<impl Future<Item = DnsMessage, Error = ()>> {
+    let question = message.question.first()?;
+    let pipe = pipes.iter().find(|p| p.config.matches(&question.qname))?;
+    let id = message.header.id;
+    debug!(
+        "Message {:x} goes to pipe backend {}",
+        id, pipe.config.command
+    );
+    let answer = pipe
+        .query(question.clone(), client.ip())
+        .then(move |result| match result {
+            Ok(records) => Ok(from_answer(id, &records)),
+            Err(e) => {
+                warn!("Pipe backend failed message {:x}: {}", id, e);
+                Ok(from_rcode(id, DnsRcode::ServerFailure))
+            }
+        });
+    Some(answer)
+}
+
+/// The UDP codec, except that a message which can't be encoded is answered
+/// with SERVFAIL, so that it doesn't bring down the sender.
+struct UdpCodec(DnsMessageCodec);
+
+impl Decoder for UdpCodec {
+    type Item = DnsMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<DnsMessage>> {
+        self.0.decode(src)
+    }
+}
+
+impl Encoder for UdpCodec {
+    type Item = DnsMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: DnsMessage, buf: &mut BytesMut) -> io::Result<()> {
+        let id = item.header.id;
+        match self.0.encode(item, buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                error!("Can't encode message {:x}: {}", id, e);
+                self.0.encode(from_rcode(id, DnsRcode::ServerFailure), buf)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether all question names pass the validation policy.
+fn check_names(policy: NamePolicy, message: &DnsMessage) -> bool {
+    match message.question.iter().find(|q| !policy.check(&q.qname)) {
+        Some(q) => {
+            warn!(
+                "Message {:x} asks for invalid name {}",
+                message.header.id, q.qname
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+/// Apply the Client Subnet policy to a query about to be forwarded.
+fn client_subnet_query(mode: EcsMode, message: &mut DnsMessage, client: IpAddr) {
+    let edns = match message.edns {
+        Some(ref mut edns) => edns,
+        None => return,
+    };
+    match mode {
+        EcsMode::Pass => (),
+        EcsMode::Strip => edns.options.retain(|opt| opt.code != EDNS_CLIENT_SUBNET),
+        EcsMode::Synthesize(v4, v6) => {
+            edns.options.retain(|opt| opt.code != EDNS_CLIENT_SUBNET);
+            let prefix = if client.is_ipv4() { v4 } else { v6 };
+            edns.options
+                .push(ClientSubnet::new(client, prefix).to_option());
+        }
+    }
+}
+
+/// Hide a synthesized Client Subnet from the client.
+fn client_subnet_response(mode: EcsMode, message: &mut DnsMessage) {
+    if let (EcsMode::Synthesize(..), Some(edns)) = (mode, message.edns.as_mut()) {
+        edns.options.retain(|opt| opt.code != EDNS_CLIENT_SUBNET);
+    }
+}
+
+/// Take the time records have been held, by the cache or while waiting for
+/// upstream, off their TTLs.
+pub(crate) fn decay(records: &mut [DnsResourceRecord], elapsed: Duration) {
+    let elapsed = elapsed.as_secs().min(u64::from(u32::max_value())) as u32;
+    for rr in records {
+        rr.ttl = rr.ttl.saturating_sub(elapsed);
+    }
+}
+
+/// Take the time a response took to arrive off its TTLs, so that none is
+/// advertised for longer than upstream meant.
+fn decay_response(message: &mut DnsMessage, elapsed: Duration) {
+    decay(&mut message.answer, elapsed);
+    decay(&mut message.authority, elapsed);
+    decay(&mut message.additional, elapsed);
+}
+
+/// Answer a query from the response cache, if its one question left
+/// after local answers is cached.  The answer is looked up for the Client
+/// Subnet the query would carry upstream.
+#[cfg(feature = "cache")]
+fn cache_answer(
+    config: &ServerConfig,
+    cache: &ResponseCache,
+    variant: Variant,
+    message: &DnsMessage,
+    client: &SocketAddr,
+    answers_local: &[DnsResourceRecord],
+) -> Option<DnsMessage> {
+    if config.cache_size == 0 {
+        return None;
+    }
+    let answer = match config.ecs {
+        EcsMode::Synthesize(..) => {
+            let mut query = message.clone();
+            client_subnet_query(config.ecs, &mut query, client.ip());
+            cache.get(variant, &query)
+        }
+        _ => cache.get(variant, message),
+    }?;
+    debug!("Answering message {:x} from the cache", message.header.id);
+    let mut response = DnsMessage::response_to(message)
+        .with_recur_available(true)
+        .with_answers(answer);
+    add_local_answers(&mut response, answers_local.to_vec());
+    rewrite::flatten(&config.rewrite_rules, &mut response);
+    if config.minimal_responses {
+        minimize(&mut response);
+    }
+    if config.round_robin {
+        rotate_addresses(&mut response);
+    }
+    Some(response)
+}
+
+/// Strip the authority and additional sections, like BIND's
+/// `minimal-responses`.  The authority section of negative answers is kept,
+/// as clients need its SOA for negative caching.
+fn minimize(message: &mut DnsMessage) {
+    if !message.answer.is_empty() {
+        message.authority.clear();
+    }
+    message.additional.clear();
+}
+
+/// Rotate each run of A or AAAA records of a name in the answer section,
+/// one step further than for the response before.
+fn rotate_addresses(message: &mut DnsMessage) {
+    static TURN: AtomicUsize = AtomicUsize::new(0);
+    let turn = TURN.fetch_add(1, Ordering::Relaxed);
+    let answer = &mut message.answer;
+    let mut start = 0;
+    while start < answer.len() {
+        let first = &answer[start];
+        let len = answer[start..]
+            .iter()
+            .take_while(|rr| rr.rtype == first.rtype && rr.name == first.name)
+            .count();
+        if first.rtype == DnsType::A || first.rtype == DnsType::AAAA {
+            answer[start..start + len].rotate_left(turn % len);
+        }
+        start += len;
+    }
+}
+
+fn from_rcode(id: u16, rcode: DnsRcode) -> DnsMessage {
+    from_answer(id, &[]).with_rcode(rcode)
+}
+
+fn from_answer(id: u16, answer: &[DnsResourceRecord]) -> DnsMessage {
+    let refused = answer.iter().any(is_blocking);
+    let message = DnsMessage {
+        header: DnsHeader {
+            id,
+            recur_desired: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    if refused {
+        message.with_rcode(DnsRcode::Refused)
+    } else {
+        message.with_answers(answer.iter().cloned())
+    }
+}
+
+/// Answer reverse lookups of the addresses in the table with their names,
+/// so that they don't go upstream, unless the table has PTR records for
+/// them already.
+fn add_reverse_entries(table: &mut EntryTable) {
+    let reverse: Vec<_> = table
+        .values()
+        .flatten()
+        .filter(|rr| !is_blocking(rr))
+        .filter_map(|rr| {
+            let addr = match rr.data {
+                DnsRRData::A(addr) => IpAddr::V4(addr),
+                DnsRRData::AAAA(addr) => IpAddr::V6(addr),
+                _ => return None,
+            };
+            Some(DnsResourceRecord {
+                name: hosts::reverse_name(addr),
+                rtype: DnsType::PTR,
+                rclass: DnsClass::Internet,
+                ttl: rr.ttl,
+                data: DnsRRData::PTR(rr.name.clone()),
+            })
+        })
+        .filter(|ptr| match table.get(&ptr.name) {
+            Some(rrs) => !rrs.iter().any(|rr| rr.rtype == DnsType::PTR),
+            None => true,
+        })
+        .collect();
+    for ptr in reverse {
+        let rrs = table.entry(ptr.name.clone()).or_default();
+        if !rrs.contains(&ptr) {
+            rrs.push(ptr);
+        }
+    }
+}
+
+/// Whether a local entry blocks its name: `0.0.0.0` or `::`.
+fn is_blocking(rr: &DnsResourceRecord) -> bool {
+    match rr.data {
+        DnsRRData::A(addr) => addr.is_unspecified(),
+        DnsRRData::AAAA(addr) => addr.is_unspecified(),
+        _ => false,
+    }
+}
+
+/// Take the questions with local answers out of `questions`, and answer them.
+/// Questions for filtered address families are taken out with no answer,
+/// and questions renamed by rewrite rules are asked for their targets, like
+/// those of local CNAME records.
+fn local_answers(
+    config: &ServerConfig,
+    local: &RwLock<EntryTable>,
+    variant: Variant,
+    questions: &mut Vec<DnsQuestion>,
+) -> Vec<DnsResourceRecord> {
+    family::filter_questions(&config.family_filters, questions);
+    let mut answers = rewrite::rename(&config.rewrite_rules, questions);
+    let table = local_table(config, local, variant).read().unwrap();
+    answers.extend(filter_questions(
+        questions,
+        &table,
+        &config.regex.read().unwrap(),
+    ));
+    answers
+}
+
+/// The local table clients of `variant` are answered from: the main table,
+/// or the canary's or a view's.
+fn local_table<'a>(
+    config: &'a ServerConfig,
+    local: &'a RwLock<EntryTable>,
+    variant: Variant,
+) -> &'a RwLock<EntryTable> {
+    match variant {
+        Variant::Canary if config.canary.is_some() => &config.canary_table,
+        Variant::View(i) => &config.view_tables[i],
+        _ => local,
+    }
+}
+
+/// The memory of the local tables, `local` being the main one, locked by
+/// the caller.
+fn local_size(config: &ServerConfig, local: &EntryTable) -> usize {
+    let others = config.view_tables.iter().chain(Some(&config.canary_table));
+    let size = memory::table_size(local)
+        + others
+            .map(|table| memory::table_size(&table.read().unwrap()))
+            .sum::<usize>();
+    #[cfg(feature = "blocklists")]
+    let size = size + config.blocklist.read().unwrap().size();
+    size
+}
+
+/// Account the memory of the local tables after the one of `variant`
+/// changed, and write it back if it is the main one and updates are
+/// persisted.  The tables must not be locked.
+#[cfg(any(feature = "update", feature = "admin"))]
+fn local_changed(
+    config: &ServerConfig,
+    memory: &MemoryBudget,
+    local: &RwLock<EntryTable>,
+    variant: Variant,
+) {
+    let table = local.read().unwrap();
+    memory.set_local(local_size(config, &table));
+    if !ptr::eq(local_table(config, local, variant), local) {
+        return;
+    }
+    #[cfg(feature = "update")]
+    {
+        if let Some(ref path) = config.update_file {
+            let written = update::persist(path, &table);
+            if let Err(ref e) = written {
+                error!("Error writing {}: {}", path.display(), e);
+            }
+            config.unsaved.store(written.is_err(), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Add the records a local table implies: SOA records for the zones which
+/// are transferable or authoritative, and PTR records for its addresses.
+fn complete_local(config: &ServerConfig, table: &mut EntryTable) {
+    let zones = config.local_zones.iter();
+    #[cfg(feature = "transfer")]
+    let zones = zones.chain(config.transfer_zones.iter().map(|zone| &zone.zone));
+    for zone in zones {
+        let rrs = table.entry(zone.clone()).or_default();
+        if !rrs.iter().any(|rr| rr.rtype == DnsType::SOA) {
+            rrs.push(authority::soa(zone));
+        }
+    }
+    add_reverse_entries(table);
+}
+
+/// What the admin API manages of a running server.  Its changes last until
+/// the local files are reloaded.
+#[cfg(feature = "admin")]
+pub(crate) struct Managed {
+    pub config: Arc<ServerConfig>,
+    pub local: Arc<RwLock<EntryTable>>,
+    pub stats: Arc<Stats>,
+    #[cfg(feature = "cache")]
+    pub cache: Arc<ResponseCache>,
+    pub upstreams: Arc<Upstreams>,
+}
+
+#[cfg(feature = "admin")]
+impl Managed {
+    /// The records of the local table, by name.
+    pub fn local_records(&self) -> Vec<DnsResourceRecord> {
+        let table = self.local.read().unwrap();
+        let mut records: Vec<_> = table.values().flatten().cloned().collect();
+        records.sort_by_key(|rr| rr.name.to_string());
+        records
+    }
+
+    /// Answer `name` with `addr` too, like an entry of the local file.
+    pub fn add_local(&self, name: DomainName, addr: IpAddr) {
+        let data = match addr {
+            IpAddr::V4(addr) => DnsRRData::A(addr),
+            IpAddr::V6(addr) => DnsRRData::AAAA(addr),
+        };
+        let mut table = self.local.write().unwrap();
+        let rrs = table.entry(name.clone()).or_default();
+        if !rrs.iter().any(|rr| rr.data == data) {
+            rrs.push(DnsResourceRecord {
+                name,
+                rclass: DnsClass::Internet,
+                rtype: data.rtype(),
+                data,
+                ttl: 10,
+            });
+        }
+        drop(table);
+        local_changed(
+            &self.config,
+            &self.stats.memory,
+            &self.local,
+            Variant::Stable,
+        );
+    }
+
+    /// Remove the records of `name`, or only its address `addr`, returning
+    /// how many there were.  The SOA record of a zone stays, as transfers
+    /// and authoritative answers need it.
+    pub fn remove_local(&self, name: &DomainName, addr: Option<IpAddr>) -> usize {
+        let mut table = self.local.write().unwrap();
+        let removed = match table.get_mut(name) {
+            Some(rrs) => {
+                let before = rrs.len();
+                rrs.retain(|rr| match (addr, &rr.data) {
+                    (_, DnsRRData::SOA(..)) => true,
+                    (None, _) => false,
+                    (Some(IpAddr::V4(addr)), DnsRRData::A(a)) => *a != addr,
+                    (Some(IpAddr::V6(addr)), DnsRRData::AAAA(a)) => *a != addr,
+                    _ => true,
+                });
+                let removed = before - rrs.len();
+                if rrs.is_empty() {
+                    table.remove(name);
+                }
+                removed
+            }
+            None => 0,
+        };
+        drop(table);
+        if removed > 0 {
+            local_changed(
+                &self.config,
+                &self.stats.memory,
+                &self.local,
+                Variant::Stable,
+            );
+        }
+        removed
+    }
+
+    /// The blocked names, sorted.
+    #[cfg(feature = "blocklists")]
+    pub fn blocked(&self) -> Vec<String> {
+        let blocklist = self.config.blocklist.read().unwrap();
+        let mut names: Vec<_> = blocklist.names().map(String::from).collect();
+        names.sort();
+        names
+    }
+
+    /// Block `name` and the names under it, or stop blocking it, returning
+    /// whether that changed anything.
+    #[cfg(feature = "blocklists")]
+    pub fn set_blocked(&self, name: &DomainName, blocked: bool) -> bool {
+        let name = name.to_string();
+        let changed = {
+            let mut blocklist = self.config.blocklist.write().unwrap();
+            if blocked {
+                let before = blocklist.len();
+                blocklist.insert(&name);
+                blocklist.len() > before
+            } else {
+                blocklist.remove(&name)
+            }
+        };
+        let table = self.local.read().unwrap();
+        self.stats
+            .memory
+            .set_local(local_size(&self.config, &table));
+        changed
+    }
+}
+
+/// What [`Server::reload_with`] reloads.
+#[derive(Debug, Default)]
+pub struct LocalConfig {
+    pub local: EntryTable,
+    pub regex_rules: Vec<RegexRule>,
+    #[cfg(feature = "blocklists")]
+    pub blocklist: Blocklist,
+    /// The tables of the views, in the order they were added.  Views left
+    /// out keep theirs.
+    pub views: Vec<EntryTable>,
+    /// The canary's table, or `None` for a copy of `local`.
+    pub canary: Option<EntryTable>,
+}
+
+#[derive(Clone)]
+struct Reloader(Arc<dyn Fn() -> Result<LocalConfig, String> + Send + Sync>);
+
+impl fmt::Debug for Reloader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reloader")
+    }
+}
+
+/// Swap in a reloaded configuration.
+fn reload(
+    config: &ServerConfig,
+    local: &RwLock<EntryTable>,
+    memory: &MemoryBudget,
+    reloaded: LocalConfig,
+) -> Result<(), String> {
+    let regex = RegexRules::new(&reloaded.regex_rules)?;
+    for (view, mut table) in config.view_tables.iter().zip(reloaded.views) {
+        add_reverse_entries(&mut table);
+        *view.write().unwrap() = table;
+    }
+    if config.canary.is_some() {
+        let mut table = match reloaded.canary {
+            Some(table) => table,
+            None => reloaded.local.clone(),
+        };
+        add_reverse_entries(&mut table);
+        *config.canary_table.write().unwrap() = table;
+    }
+    let mut table = reloaded.local;
+    complete_local(config, &mut table);
+    // Local answers are looked up with the table locked, so they don't
+    // see a new table with old rules
+    let mut local = local.write().unwrap();
+    *local = table;
+    *config.regex.write().unwrap() = regex;
+    #[cfg(feature = "blocklists")]
+    {
+        *config.blocklist.write().unwrap() = reloaded.blocklist;
+    }
+    memory.set_local(local_size(config, &local));
+    Ok(())
+}
+
+/// Wait up to `grace` for the pending queries, and those `in_flight` over
+/// streams, to be answered.
+fn drain(
+    clients: Arc<Mutex<ClientTable>>,
+    in_flight: Arc<AtomicUsize>,
+    grace: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    let deadline = Instant::now() + grace;
+    Interval::new_interval(Duration::from_millis(50))
+        .map_err(|e| error!("error draining queries: {}", e))
+        .take_while(move |_| {
+            let left = clients.lock().unwrap().pending_len() + in_flight.load(Ordering::SeqCst);
+            if left > 0 && Instant::now() >= deadline {
+                warn!("Stopping with {} queries unanswered", left);
+            }
+            Ok(left > 0 && Instant::now() < deadline)
+        })
+        .for_each(|_| Ok(()))
+}
+
+/// Save what would otherwise be lost on stopping: updates which couldn't be
+/// written back.
+fn flush(config: &ServerConfig, local: &RwLock<EntryTable>) {
+    #[cfg(feature = "update")]
+    {
+        if let Some(ref path) = config.update_file {
+            if config.unsaved.load(Ordering::SeqCst) {
+                match update::persist(path, &local.read().unwrap()) {
+                    Ok(()) => info!("Wrote updates to {}", path.display()),
+                    Err(e) => error!("Error writing {}, updates are lost: {}", path.display(), e),
+                }
+            }
+        }
+    }
+    #[cfg(not(feature = "update"))]
+    let _ = (config, local);
+}
+
+/// Counts a query in flight for as long as it lives.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn enter(count: &Arc<AtomicUsize>) -> InFlight {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// SIGTERM and Ctrl-C.
+fn terminations() -> impl Stream<Item = (), Error = ()> {
+    let ctrl_c = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .map(|_| ())
+        .map_err(|e| error!("Can't handle Ctrl-C: {}", e));
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGTERM};
+
+        let sigterm = Signal::new(SIGTERM)
+            .flatten_stream()
+            .map(|_| ())
+            .map_err(|e| error!("Can't handle SIGTERM: {}", e));
+        ctrl_c.select(sigterm)
+    }
+    #[cfg(not(unix))]
+    ctrl_c
+}
+
+#[cfg(unix)]
+fn hangups() -> impl Stream<Item = (), Error = ()> {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map(|_| ())
+        .map_err(|e| error!("Can't handle SIGHUP: {}", e))
+}
+
+/// Told the number of local names after reloading, or why it failed.
+type ReloadReply = oneshot::Sender<Result<usize, String>>;
+
+/// Reload the configuration on every item of `triggers`, replying to those
+/// which ask for it.  Loading can take seconds, so it runs on its own
+/// thread, one reload at a time.
+fn reload_on<S>(
+    triggers: S,
+    reloader: Reloader,
+    config: Arc<ServerConfig>,
+    local: Arc<RwLock<EntryTable>>,
+    stats: Arc<Stats>,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: Stream<Item = Option<ReloadReply>, Error = ()>,
+{
+    triggers.for_each(move |reply| {
+        info!("Reloading the configuration");
+        let (reloader, config, local, stats) = (
+            reloader.clone(),
+            config.clone(),
+            local.clone(),
+            stats.clone(),
+        );
+        let (done, reloaded) = oneshot::channel();
+        thread::spawn(move || {
+            let result = (reloader.0)()
+                .and_then(|new| reload(&config, &local, &stats.memory, new))
+                .map(|_| local.read().unwrap().len());
+            match result {
+                Ok(names) => info!("Reloaded {} local names", names),
+                Err(ref e) => error!("Error reloading, keeping the configuration: {}", e),
+            }
+            if let Some(reply) = reply {
+                let _ = reply.send(result);
+            }
+            let _ = done.send(());
+        });
+        reloaded.then(|_| Ok(()))
+    })
+}
+
+/// How many local CNAME records are followed for a question.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Exact matches in the table come first, then regex rules.
+///
+/// A question for a name with a local CNAME record is answered with the
+/// chain of local CNAME records, and becomes a question for its end.  That
+/// is answered locally too if it can be, or else upstream, whose answer is
+/// stitched to the chain by `add_local_answers`.
+///
+/// A name with local addresses has all its addresses local: an A or AAAA
+/// question for it is answered here even without records of its type, so
+/// that a dual-stack client doesn't learn the real address of the other
+/// family.  If the name is blocked, so is the other family.
+fn filter_questions(
+    questions: &mut Vec<DnsQuestion>,
+    local_entries: &EntryTable,
+    rules: &RegexRules,
+) -> Vec<DnsResourceRecord> {
+    let mut answers = vec![];
+    for q in questions.iter_mut() {
+        answers.extend(follow_cnames(q, local_entries));
+    }
+    let is_address = |rtype| rtype == DnsType::A || rtype == DnsType::AAAA;
+    let has_local = |q: &DnsQuestion| match local_entries.get(&q.qname) {
+        Some(records) => records
+            .iter()
+            .any(|rr| rr.code() == q.code() || (is_address(q.qtype) && is_address(rr.rtype))),
+        None => false,
+    };
+    let exact: Vec<_> = questions
+        .drain_filter(|q| has_local(q))
+        .map(|q| {
+            let records = &local_entries[&q.qname];
+            let answer: Vec<_> = records
+                .iter()
+                .filter(|rr| rr.code() == q.code())
+                .cloned()
+                .collect();
+            match records.iter().find(|rr| is_blocking(rr)) {
+                Some(blocking) if answer.is_empty() => vec![blocking.clone()],
+                _ => answer,
+            }
+        })
+        .flatten()
+        .collect();
+    answers.extend(exact);
+    questions.retain(|q| match rules.answer(q) {
+        Some(rr) => {
+            answers.push(rr);
+            false
+        }
+        None => true,
+    });
+    answers
+}
+
+/// Replace the name of `question` with the end of its chain of local CNAME
+/// records, and return the chain.
+fn follow_cnames(question: &mut DnsQuestion, local_entries: &EntryTable) -> Vec<DnsResourceRecord> {
+    let mut chain: Vec<DnsResourceRecord> = vec![];
+    if question.qtype == DnsType::CNAME || question.qtype == DnsType::Any {
+        return chain;
+    }
+    while chain.len() < MAX_CNAME_CHAIN {
+        let target = match local_entries.get(&question.qname).and_then(|records| {
+            records.iter().find_map(|rr| match rr.data {
+                DnsRRData::CNAME(ref target) => Some(target),
+                _ => None,
+            })
+        }) {
+            Some(target) => target,
+            None => break,
+        };
+        if *target == question.qname || chain.iter().any(|rr| rr.name == *target) {
+            warn!("Local CNAME records for {} form a loop", question.qname);
+            break;
+        }
+        chain.push(DnsResourceRecord {
+            name: question.qname.clone(),
+            rtype: DnsType::CNAME,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: DnsRRData::CNAME(target.clone()),
+        });
+        question.qname = target.clone();
+    }
+    chain
+}
+
+/// Put local answers ahead of upstream's in a response, and ask the
+/// questions upstream was asked in place of local CNAME records' names
+/// again.
+fn add_local_answers(response: &mut DnsMessage, answers_local: Vec<DnsResourceRecord>) {
+    unalias(&mut response.question, &answers_local);
+    let upstream = mem::replace(&mut response.answer, answers_local);
+    response.answer.extend(upstream);
+}
+
+/// Rename questions for the targets of local CNAME records back to the
+/// names the client asked for.
+fn unalias(questions: &mut [DnsQuestion], answers_local: &[DnsResourceRecord]) {
+    for q in questions {
+        for _ in 0..MAX_CNAME_CHAIN {
+            match answers_local
+                .iter()
+                .find(|rr| rr.data == DnsRRData::CNAME(q.qname.clone()))
+            {
+                Some(rr) => q.qname = rr.name.clone(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Sends datagrams out of a UDP socket.
+type Reply = mpsc::UnboundedSender<(DnsMessage, SocketAddr)>;
+
+/// A query forwarded upstream.
+struct Pending {
+    client: SocketAddr,
+    /// Where responses to the client go, out of the socket its query came
+    /// in on.
+    reply: Reply,
+    /// The client's ID for the query.
+    id: u16,
+    variant: Variant,
+    /// Where the query went, and so where the response has to come from.
+    upstreams: Vec<SocketAddr>,
+    /// The question names as the client asked them, and the query as sent
+    /// upstream, whose questions differ in case with `randomize_case`.
+    asked: Vec<DomainName>,
+    query: DnsMessage,
+    /// Whether the query was sent again over TCP, after a truncated
+    /// response, so that later truncated ones are dropped.
+    retried: bool,
+    /// Local answers to add to the response.
+    answers_local: Vec<DnsResourceRecord>,
+    /// When the query was forwarded.
+    since: Instant,
+}
+
+impl Pending {
+    fn key(&self) -> QueryKey {
+        let questions: Vec<_> = self
+            .query
+            .question
+            .iter()
+            .zip(&self.asked)
+            .map(|(q, name)| DnsQuestion {
+                qname: name.clone(),
+                ..q.clone()
+            })
+            .collect();
+        query_key(self.client, self.id, &questions)
+    }
+}
+
+/// A query as a client sent it: its address, ID, and the names, types and
+/// classes of the questions.
+type QueryKey = (SocketAddr, u16, Vec<(DomainName, u16, u16)>);
+
+fn query_key(client: SocketAddr, id: u16, questions: &[DnsQuestion]) -> QueryKey {
+    let questions = questions
+        .iter()
+        .map(|q| (q.qname.clone(), q.code(), q.qclass as u16))
+        .collect();
+    (client, id, questions)
+}
+
+/// Pending queries by upstream ID, and their upstream IDs by the queries as
+/// clients sent them.  Clients' IDs collide all the time, but with their
+/// addresses and questions they tell a retransmission from a new query.
+struct ClientTable {
+    pending: TtlCache<u16, Pending>,
+    upstream_ids: TtlCache<QueryKey, u16>,
+}
+
+impl ClientTable {
+    fn new(capacity: usize) -> ClientTable {
+        ClientTable {
+            pending: TtlCache::new(capacity),
+            upstream_ids: TtlCache::new(capacity),
+        }
+    }
+
+    /// Track a query sent upstream as `upstream_id`, returning the one it
+    /// replaces.
+    fn insert(&mut self, upstream_id: u16, pending: Pending, ttl: Duration) -> Option<Pending> {
+        self.upstream_ids.insert(pending.key(), upstream_id, ttl);
+        let replaced = self.pending.insert(upstream_id, pending, ttl)?;
+        self.forget(upstream_id, &replaced);
+        Some(replaced)
+    }
+
+    fn remove(&mut self, upstream_id: u16) -> Option<Pending> {
+        let pending = self.pending.remove(&upstream_id)?;
+        self.forget(upstream_id, &pending);
+        Some(pending)
+    }
+
+    fn forget(&mut self, upstream_id: u16, pending: &Pending) {
+        let key = pending.key();
+        if self.upstream_ids.get(&key) == Some(&upstream_id) {
+            self.upstream_ids.remove(&key);
+        }
+    }
+
+    fn get(&self, upstream_id: u16) -> Option<&Pending> {
+        self.pending.get(&upstream_id)
+    }
+
+    fn get_mut(&mut self, upstream_id: u16) -> Option<&mut Pending> {
+        self.pending.get_mut(&upstream_id)
+    }
+
+    /// How many queries are pending.
+    fn pending_len(&mut self) -> usize {
+        self.pending.iter().count()
+    }
+
+    /// The upstream ID of a client's query, if it is still pending.
+    fn upstream_id(&self, key: &QueryKey) -> Option<u16> {
+        let upstream_id = *self.upstream_ids.get(key)?;
+        self.get(upstream_id)
+            .filter(|pending| pending.key() == *key)
+            .map(|_| upstream_id)
+    }
+
+    /// A random ID for a query to upstream, so that responses are hard to
+    /// spoof.  It avoids pending queries' IDs while that is easy.
+    fn new_upstream_id(&self) -> u16 {
+        let mut id = rand::random();
+        for _ in 0..16 {
+            if !self.pending.contains_key(&id) {
+                break;
+            }
+            id = rand::random();
+        }
+        id
+    }
+}
+
+/// How long to wait for a response to the `attempt`th retry (the first
+/// send being the 0th).
+fn backoff(timeout: Duration, attempt: u32) -> Duration {
+    timeout * (1 << attempt.min(16))
+}
+
+/// Resends UDP queries which get no response, and gives up on them.
+#[derive(Clone)]
+struct Retransmitter {
+    config: Arc<ServerConfig>,
+    clients: Arc<Mutex<ClientTable>>,
+    stats: Arc<Stats>,
+    upstreams: Arc<Upstreams>,
+    tx: mpsc::UnboundedSender<(DnsMessage, SocketAddr)>,
+}
+
+impl Retransmitter {
+    /// Wait for the response to attempt `attempt` at the query pending as
+    /// `upstream_id` since `since`.
+    fn watch(self, upstream_id: u16, since: Instant, attempt: u32) {
+        let timeout = backoff(self.config.timeout, attempt);
+        tokio::spawn(Delay::new(Instant::now() + timeout).then(move |_| {
+            self.expire(upstream_id, since, attempt);
+            Ok(())
+        }));
+    }
+
+    fn expire(self, upstream_id: u16, since: Instant, attempt: u32) {
+        let mut clients = self.clients.lock().unwrap();
+        // Unless it has been answered, or its ID taken over
+        match clients.get(upstream_id) {
+            Some(pending) if pending.since == since => {
+                for &upstream in &pending.upstreams {
+                    self.upstreams
+                        .record(upstream, backoff(self.config.timeout, attempt));
+                }
+            }
+            _ => return,
+        }
+        if attempt < self.config.retries {
+            let pending = clients.get(upstream_id).unwrap();
+            debug!(
+                "Resending message {:x} as {:x}, retry {}",
+                pending.id,
+                upstream_id,
+                attempt + 1
+            );
+            // DNS-over-HTTPS exchanges time out on their own
+            for &dest in &pending.upstreams {
+                if self.upstreams.url(dest).is_none() {
+                    let _ = self.tx.unbounded_send((pending.query.clone(), dest));
+                }
+            }
+            drop(clients);
+            self.watch(upstream_id, since, attempt + 1);
+        } else if let Some(pending) = clients.remove(upstream_id) {
+            warn!(
+                "No response to message {:x} from {:?}, failing it",
+                pending.id, pending.upstreams
+            );
+            self.stats
+                .memory
+                .release_pending(pending_size(&pending.answers_local));
+            let mut response = DnsMessage::response_to(&pending.query)
+                .with_id(pending.id)
+                .with_rcode(DnsRcode::ServerFailure);
+            restore_case(&mut response, &pending.query.question, &pending.asked);
+            unalias(&mut response.question, &pending.answers_local);
+            let _ = pending.reply.unbounded_send((response, pending.client));
+        }
+    }
+}
+
+fn pending_size(answers_local: &[DnsResourceRecord]) -> usize {
+    mem::size_of::<(u16, Pending)>() + memory::records_size(answers_local)
+}
+
+/// Whether the response has the questions sent, in the same case if
+/// `exact_case`.
+fn echoes_question(message: &DnsMessage, sent: &[DnsQuestion], exact_case: bool) -> bool {
+    message.question == sent
+        && (!exact_case
+            || message
+                .question
+                .iter()
+                .zip(sent)
+                .all(|(question, sent)| question.qname.eq_exact(&sent.qname)))
+}
+
+/// Give the names of the questions, and records owned by them, the case the
+/// client asked them in.
+fn restore_case(message: &mut DnsMessage, sent: &[DnsQuestion], asked: &[DomainName]) {
+    let names = message.question.iter_mut().map(|q| &mut q.qname).chain(
+        message
+            .answer
+            .iter_mut()
+            .chain(&mut message.authority)
+            .chain(&mut message.additional)
+            .map(|rr| &mut rr.name),
+    );
+    for name in names {
+        if let Some(i) = sent.iter().position(|sent| sent.qname == *name) {
+            *name = asked[i].clone();
+        }
+    }
+}
+
+/// Charge a new pending query against the memory budget.
+fn charge_pending(
+    memory: &MemoryBudget,
+    clients: &Mutex<ClientTable>,
+    answers_local: &[DnsResourceRecord],
+) -> bool {
+    let size = pending_size(answers_local);
+    if memory.charge_pending(size) {
+        return true;
+    }
+    // Expired entries are never released, recount the live ones
+    let live = clients
+        .lock()
+        .unwrap()
+        .pending
+        .iter()
+        .map(|(_, pending)| pending_size(&pending.answers_local))
+        .sum();
+    memory.set_pending(live);
+    memory.charge_pending(size)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    listen_addrs: Vec<SocketAddr>,
+    #[cfg(feature = "doh")]
+    doh_listen: Option<SocketAddr>,
+    #[cfg(feature = "admin")]
+    admin_listen: Option<SocketAddr>,
+    #[cfg(feature = "admin")]
+    admin_token_file: Option<PathBuf>,
+    #[cfg(unix)]
+    control_path: Option<PathBuf>,
+    #[cfg(feature = "doh")]
+    doh_tls: Option<(PathBuf, PathBuf)>,
+    #[cfg(feature = "doh")]
+    doh_client_ca: Option<PathBuf>,
+    dns_addrs: Vec<UpstreamServer>,
+    strategy: Strategy,
+    probe_name: DomainName,
+    timeout: Duration,
+    retries: u32,
+    mirror_addr: Option<SocketAddr>,
+    local: EntryTable,
+    regex_rules: Vec<RegexRule>,
+    /// `regex_rules`, compiled when serving starts.
+    regex: Arc<RwLock<RegexRules>>,
+    #[cfg(feature = "blocklists")]
+    blocklist: Arc<RwLock<Blocklist>>,
+    reloader: Option<Reloader>,
+    #[cfg(feature = "watch")]
+    watch_paths: Vec<PathBuf>,
+    #[cfg(feature = "watch")]
+    watch_delay: Duration,
+    canary: Option<CanaryConfig>,
+    /// The local table of the canary, taken out of `canary` to be swapped
+    /// on reload.
+    canary_table: Arc<RwLock<EntryTable>>,
+    views: Vec<ViewConfig>,
+    /// The local table of each view, likewise.
+    view_tables: Vec<Arc<RwLock<EntryTable>>>,
+    memory_budget: usize,
+    workers: usize,
+    cpu_affinity: Vec<usize>,
+    rx_cpu: Option<usize>,
+    run_as: Option<Account>,
+    #[cfg(unix)]
+    daemon: bool,
+    pid_file: Option<PathBuf>,
+    name_policy: NamePolicy,
+    qtype_rules: Vec<QtypeRule>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    #[cfg(feature = "pipe")]
+    pipes: Vec<PipeConfig>,
+    decode_limits: DecodeLimits,
+    randomize_case: bool,
+    scrub: bool,
+    #[cfg(feature = "cache")]
+    cache_size: usize,
+    #[cfg(feature = "cache")]
+    cache_memory: usize,
+    min_ttl: u32,
+    max_ttl: Option<u32>,
+    minimal_responses: bool,
+    minimal_any: bool,
+    minimal_any_on: Vec<SocketAddr>,
+    round_robin: bool,
+    rewrite_rules: Vec<RewriteRule>,
+    family_filters: Vec<FamilyFilter>,
+    version: Option<String>,
+    identity: Option<String>,
+    #[cfg(feature = "dnstap")]
+    dnstap_path: Option<PathBuf>,
+    #[cfg(feature = "history")]
+    history: Option<(PathBuf, Duration)>,
+    ecs: EcsMode,
+    #[cfg(feature = "update")]
+    update_zones: Vec<ZoneAccess>,
+    #[cfg(feature = "update")]
+    update_file: Option<PathBuf>,
+    /// Whether the last update failed to be written, to retry on stopping.
+    #[cfg(feature = "update")]
+    unsaved: Arc<AtomicBool>,
+    #[cfg(feature = "transfer")]
+    transfer_zones: Vec<ZoneAccess>,
+    local_zones: Vec<DomainName>,
+    #[cfg(feature = "secondary")]
+    secondary_zones: Vec<SecondaryZone>,
+    #[cfg(feature = "tsig")]
+    tsig_keys: Vec<TsigKey>,
+    shutdown_grace: Duration,
+}
+
+/// How the EDNS Client Subnet option (RFC 7871) of queries is forwarded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EcsMode {
+    /// Remove it, so upstream learns nothing about clients.
+    Strip,
+    /// Forward it unchanged.
+    Pass,
+    /// Replace it with the client's subnet of this many bits, for IPv4 and
+    /// IPv6 clients.  Only queries which use EDNS get one, and it is removed
+    /// from the responses.
+    Synthesize(u8, u8),
+}
+
+impl FromStr for EcsMode {
+    type Err = String;
+
+    /// `strip`, `pass` or `synthesize[/V4[/V6]]`, with default prefix
+    /// lengths of 24 and 56.
+    fn from_str(s: &str) -> Result<EcsMode, String> {
+        let mut parts = s.split('/');
+        let mode = match parts.next().unwrap() {
+            "strip" => EcsMode::Strip,
+            "pass" => EcsMode::Pass,
+            "synthesize" => {
+                let mut prefix = |default, max| match parts.next() {
+                    Some(p) => p
+                        .parse()
+                        .ok()
+                        .filter(|&p| p <= max)
+                        .ok_or(format!("Invalid prefix length {}", p)),
+                    None => Ok(default),
+                };
+                EcsMode::Synthesize(prefix(24, 32)?, prefix(56, 128)?)
+            }
+            _ => return Err(format!("Unknown client subnet mode {}", s)),
+        };
+        match parts.next() {
+            Some(_) => Err(format!("Unknown client subnet mode {}", s)),
+            None => Ok(mode),
+        }
+    }
+}
+
+/// A staged configuration that serves only part of the clients.
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    pub dns_addr: SocketAddr,
+    pub local: EntryTable,
+    /// Share of clients, bucketed by address, served by the canary.
+    pub percent: u8,
+    /// Clients always served by the canary.
+    pub clients: Vec<IpAddr>,
+}
+
+/// Local entries and an upstream server for the clients in some networks,
+/// e.g. internal names for the LAN only.
+#[derive(Debug, Clone)]
+pub struct ViewConfig {
+    pub name: String,
+    pub clients: Vec<Network>,
+    pub local: EntryTable,
+    /// Where the view's queries are forwarded, instead of the upstream
+    /// servers.
+    pub dns_addr: Option<SocketAddr>,
+}
+
+impl ServerConfig {
+    /// Whether ANY queries to the listener at `addr` are answered minimally.
+    fn minimal_any_for(&self, addr: &SocketAddr) -> bool {
+        self.minimal_any || self.minimal_any_on.contains(addr)
+    }
+
+    /// Clients are bucketed by address, so a client always sees the same variant.
+    fn variant_for(&self, client: &SocketAddr) -> Variant {
+        let ip = client.ip();
+        if let Some(i) = self
+            .views
+            .iter()
+            .position(|view| view.clients.iter().any(|net| net.contains(&ip)))
+        {
+            return Variant::View(i);
+        }
+        match self.canary {
+            Some(ref canary) if canary.clients.contains(&client.ip()) => Variant::Canary,
+            Some(ref canary) => {
+                let mut hasher = DefaultHasher::new();
+                client.ip().hash(&mut hasher);
+                if hasher.finish() % 100 < canary.percent as u64 {
+                    Variant::Canary
+                } else {
+                    Variant::Stable
+                }
+            }
+            None => Variant::Stable,
+        }
+    }
+
+    /// Count the question types of a query and apply the rate limit and the
+    /// per-type policies, then refuse it if it asks for a blocked name.
+    fn query_action(&self, client: &SocketAddr, message: &DnsMessage, stats: &Stats) -> Action {
+        if let Some(ref limiter) = self.rate_limiter {
+            match limiter.check(client.ip()) {
+                Verdict::Allowed => {}
+                Verdict::Exceeded => {
+                    info!("Client {} is over the rate limit", client.ip());
+                    return limiter.action();
+                }
+                Verdict::Limited => {
+                    debug!(
+                        "Message {:x} from {} is over the rate limit",
+                        message.header.id, client
+                    );
+                    return limiter.action();
+                }
+            }
+        }
+        for q in &message.question {
+            stats.count_qtype(q.code());
+        }
+        let qtypes: Vec<_> = message.question.iter().map(|q| q.code()).collect();
+        let action = acl::qtype_action(&self.qtype_rules, &qtypes, &client.ip());
+        if action != Action::Allow {
+            let names: Vec<_> = qtypes.iter().map(|&qtype| type_name(qtype)).collect();
+            info!(
+                "Message {:x} from {} asks for {}: {:?}",
+                message.header.id,
+                client,
+                names.join(" "),
+                action
+            );
+        }
+        if action == Action::Refuse || action == Action::Drop {
+            return action;
+        }
+        #[cfg(feature = "blocklists")]
+        match message
+            .question
+            .iter()
+            .find(|q| self.blocklist.read().unwrap().contains(&q.qname))
+        {
+            Some(q) => {
+                info!(
+                    "Message {:x} from {} asks for blocked {}",
+                    message.header.id, client, q.qname
+                );
+                Action::Refuse
+            }
+            None => action,
+        }
+        #[cfg(not(feature = "blocklists"))]
+        action
+    }
+
+    fn destinations(&self, upstreams: &Upstreams, variant: Variant) -> Vec<SocketAddr> {
+        match (variant, &self.canary) {
+            (Variant::Canary, Some(canary)) => vec![canary.dns_addr],
+            (Variant::View(i), _) => match self.views[i].dns_addr {
+                Some(addr) => vec![addr],
+                None => upstreams.pick(),
+            },
+            _ => upstreams.pick(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen_addrs: vec![],
+            #[cfg(feature = "doh")]
+            doh_listen: None,
+            #[cfg(feature = "admin")]
+            admin_listen: None,
+            #[cfg(feature = "admin")]
+            admin_token_file: None,
+            #[cfg(unix)]
+            control_path: None,
+            #[cfg(feature = "doh")]
+            doh_tls: None,
+            #[cfg(feature = "doh")]
+            doh_client_ca: None,
+            dns_addrs: vec!["202.141.178.13:53".parse().unwrap()],
+            strategy: Strategy::default(),
+            probe_name: DomainName::default(),
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            mirror_addr: None,
+            local: HashMap::new(),
+            regex_rules: vec![],
+            regex: Arc::new(RwLock::new(RegexRules::default())),
+            #[cfg(feature = "blocklists")]
+            blocklist: Arc::new(RwLock::new(Blocklist::new())),
+            reloader: None,
+            #[cfg(feature = "watch")]
+            watch_paths: vec![],
+            #[cfg(feature = "watch")]
+            watch_delay: Duration::from_secs(1),
+            canary: None,
+            canary_table: Arc::new(RwLock::new(EntryTable::new())),
+            views: vec![],
+            view_tables: vec![],
+            memory_budget: 0,
+            workers: 0,
+            cpu_affinity: vec![],
+            rx_cpu: None,
+            run_as: None,
+            #[cfg(unix)]
+            daemon: false,
+            pid_file: None,
+            name_policy: NamePolicy::default(),
+            qtype_rules: vec![],
+            rate_limiter: None,
+            #[cfg(feature = "pipe")]
+            pipes: vec![],
+            decode_limits: DecodeLimits::default(),
+            randomize_case: false,
+            scrub: true,
+            #[cfg(feature = "cache")]
+            cache_size: 10000,
+            #[cfg(feature = "cache")]
+            cache_memory: 0,
+            min_ttl: 0,
+            max_ttl: None,
+            minimal_responses: false,
+            minimal_any: false,
+            minimal_any_on: vec![],
+            round_robin: false,
+            rewrite_rules: vec![],
+            family_filters: vec![],
+            version: None,
+            identity: None,
+            #[cfg(feature = "dnstap")]
+            dnstap_path: None,
+            #[cfg(feature = "history")]
+            history: None,
+            ecs: EcsMode::Pass,
+            #[cfg(feature = "update")]
+            update_zones: vec![],
+            #[cfg(feature = "update")]
+            update_file: None,
+            #[cfg(feature = "update")]
+            unsaved: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "transfer")]
+            transfer_zones: vec![],
+            local_zones: vec![],
+            #[cfg(feature = "secondary")]
+            secondary_zones: vec![],
+            #[cfg(feature = "tsig")]
+            tsig_keys: vec![],
+            shutdown_grace: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DispatcherError<T> {
+    ChannelError(mpsc::SendError<T>),
+    NetworkError(std::io::Error),
+}
+
+impl<T> From<mpsc::SendError<T>> for DispatcherError<T> {
+    fn from(e: mpsc::SendError<T>) -> Self {
+        DispatcherError::ChannelError(e)
+    }
+}
+
+impl<T> From<std::io::Error> for DispatcherError<T> {
+    fn from(e: std::io::Error) -> Self {
+        DispatcherError::NetworkError(e)
+    }
+}
+
+impl<T> fmt::Display for DispatcherError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DispatcherError::ChannelError(e) => write!(f, "{}", e),
+            DispatcherError::NetworkError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::codec::Encoder;
+
+    #[test]
+    fn test_chaos_answer() {
+        let config = ServerConfig {
+            version: Some("uind 0.1".to_owned()),
+            ..Default::default()
+        };
+        let query = |name: &str, qtype| DnsMessage {
+            question: vec![DnsQuestion {
+                qname: name.into(),
+                qtype,
+                qcode: 0,
+                qclass: DnsClass::Chaos,
+            }],
+            ..Default::default()
+        };
+        let response = chaos_answer(&config, &query("VERSION.BIND", DnsType::TXT)).unwrap();
+        assert_eq!(
+            response.answer[0].data,
+            DnsRRData::TXT(vec![b"uind 0.1".to_vec()])
+        );
+        let response = chaos_answer(&config, &query("id.server", DnsType::TXT)).unwrap();
+        assert_eq!(response.header.rcode, DnsRcode::Refused);
+        let response = chaos_answer(&config, &query("version.bind", DnsType::A)).unwrap();
+        assert_eq!(response.header.rcode, DnsRcode::Refused);
+        let mut query = query("version.bind", DnsType::TXT);
+        query.question[0].qclass = DnsClass::Internet;
+        assert!(chaos_answer(&config, &query).is_none());
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        assert_eq!("synthesize".parse(), Ok(EcsMode::Synthesize(24, 56)));
+        assert_eq!("synthesize/16".parse(), Ok(EcsMode::Synthesize(16, 56)));
+        assert!("synthesize/33".parse::<EcsMode>().is_err());
+        assert!("strip/8".parse::<EcsMode>().is_err());
+
+        let mut query = DnsMessage {
+            edns: Some(Edns::default()),
+            ..Default::default()
+        };
+        let client = "10.1.2.3".parse().unwrap();
+        client_subnet_query(EcsMode::Synthesize(24, 56), &mut query, client);
+        client_subnet_query(EcsMode::Synthesize(16, 56), &mut query, client);
+        let options = &query.edns.as_ref().unwrap().options;
+        assert_eq!(options.len(), 1);
+        assert_eq!(
+            ClientSubnet::from_option(&options[0]).unwrap().addr,
+            "10.1.0.0".parse::<IpAddr>().unwrap()
+        );
+        client_subnet_query(EcsMode::Strip, &mut query, client);
+        assert!(query.edns.unwrap().options.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "cache")]
+    fn test_cache_client_subnet() {
+        let mut config = ServerConfig {
+            cache_size: 10,
+            ..Default::default()
+        };
+        let cache = ResponseCache::new(10, 0);
+        let rr = |last| DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 300,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, last)),
+        };
+        let with_subnet = |addr: &str, prefix| {
+            let mut query = DnsMessage::query("example.com".into(), DnsType::A);
+            query.edns = Some(Edns {
+                options: vec![ClientSubnet::new(addr.parse().unwrap(), prefix).to_option()],
+                ..Default::default()
+            });
+            query
+        };
+        let client = "10.1.2.3:53".parse().unwrap();
+        let answer = |config: &ServerConfig, query: &DnsMessage| {
+            let response = cache_answer(config, &cache, Variant::Stable, query, &client, &[]);
+            response.map(|response| response.answer)
+        };
+
+        // An answer without a Client Subnet is for everyone
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        cache.insert(
+            Variant::Stable,
+            &DnsMessage::response_to(&query).with_answer(rr(1)),
+        );
+        assert_eq!(answer(&config, &query), Some(vec![rr(1)]));
+        assert_eq!(
+            answer(&config, &with_subnet("10.1.2.3", 24)),
+            Some(vec![rr(1)])
+        );
+
+        // One tailored to 10.1.0.0/16 is only for the subnets in it
+        let mut subnet = ClientSubnet::new("10.1.2.3".parse().unwrap(), 24);
+        subnet.scope_prefix = 16;
+        let mut tailored = DnsMessage::response_to(&query).with_answer(rr(2));
+        tailored.edns = Some(Edns {
+            options: vec![subnet.to_option()],
+            ..Default::default()
+        });
+        cache.insert(Variant::Stable, &tailored);
+        assert_eq!(
+            answer(&config, &with_subnet("10.1.200.0", 24)),
+            Some(vec![rr(2)])
+        );
+        assert_eq!(
+            answer(&config, &with_subnet("10.2.0.0", 24)),
+            Some(vec![rr(1)])
+        );
+        assert_eq!(answer(&config, &query), Some(vec![rr(1)]));
+
+        // Synthesized subnets are looked up as they would be sent
+        config.ecs = EcsMode::Synthesize(24, 56);
+        let mut query = query;
+        query.edns = Some(Edns::default());
+        assert_eq!(answer(&config, &query), Some(vec![rr(2)]));
+        config.ecs = EcsMode::Strip;
+        assert_eq!(answer(&config, &query), Some(vec![rr(1)]));
+    }
+
+    #[test]
+    fn test_ttl_stage() {
+        let config = ServerConfig {
+            min_ttl: 60,
+            max_ttl: Some(86400),
+            ..Default::default()
+        };
+        let rr = |ttl| DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        let mut response = DnsMessage::response_to(&query)
+            .with_answer(rr(5))
+            .with_answer(rr(300))
+            .with_additional(rr(604800));
+        ttl_stage(&config, &mut response);
+        assert_eq!(response.answer, [rr(60), rr(300)]);
+        assert_eq!(response.additional, [rr(86400)]);
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let asked: DomainName = "www.example.com".into();
+        let sent: DomainName = "wWw.ExamPLE.cOm".into();
+        let query = DnsMessage::query(sent.clone(), DnsType::A);
+        let a = DnsResourceRecord {
+            name: sent.clone(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let mut response = DnsMessage::response_to(&query).with_answer(a);
+        assert!(echoes_question(&response, &query.question, true));
+        let lower = DnsMessage::query(asked.clone(), DnsType::A);
+        assert!(!echoes_question(&response, &lower.question, true));
+        assert!(echoes_question(&response, &lower.question, false));
+        let aaaa = DnsMessage::query(asked.clone(), DnsType::AAAA);
+        assert!(!echoes_question(&response, &aaaa.question, false));
+
+        restore_case(&mut response, &query.question, std::slice::from_ref(&asked));
+        assert!(response.question[0].qname.eq_exact(&asked));
+        assert!(response.answer[0].name.eq_exact(&asked));
+    }
+
+    #[test]
+    fn test_filter_questions() {
+        let rr = |name: &str, data| DnsResourceRecord {
+            name: name.into(),
+            rtype: match data {
+                DnsRRData::A(_) => DnsType::A,
+                _ => DnsType::AAAA,
+            },
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data,
+        };
+        let mut local = EntryTable::new();
+        let v4 = rr("v4.lan", DnsRRData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let v6 = rr(
+            "v6.lan",
+            DnsRRData::AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+        );
+        let blocked = rr("ads.example", DnsRRData::A(Ipv4Addr::new(0, 0, 0, 0)));
+        for rr in &[&v4, &v6, &blocked] {
+            local.insert(rr.name.clone(), vec![(*rr).clone()]);
+        }
+        let filter = |name: &str, qtype| {
+            let mut questions = vec![DnsQuestion {
+                qname: name.into(),
+                qtype,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }];
+            let answers = filter_questions(&mut questions, &local, &RegexRules::default());
+            (questions.is_empty(), answers)
+        };
+
+        assert_eq!(filter("v6.lan", DnsType::AAAA), (true, vec![v6.clone()]));
+        // The other family doesn't leak upstream
+        assert_eq!(filter("v4.lan", DnsType::AAAA), (true, vec![]));
+        assert_eq!(filter("v6.lan", DnsType::A), (true, vec![]));
+        assert_eq!(filter("ads.example", DnsType::AAAA), (true, vec![blocked]));
+        assert_eq!(filter("v4.lan", DnsType::MX), (false, vec![]));
+        assert_eq!(filter("other.lan", DnsType::A), (false, vec![]));
+    }
+
+    #[test]
+    fn test_reverse_entries() {
+        let rr = |name: &str, data| DnsResourceRecord {
+            name: name.into(),
+            rtype: match data {
+                DnsRRData::A(_) => DnsType::A,
+                DnsRRData::AAAA(_) => DnsType::AAAA,
+                _ => DnsType::PTR,
+            },
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data,
+        };
+        let mut local = EntryTable::new();
+        let entries = vec![
+            rr("nas.lan", DnsRRData::A(Ipv4Addr::new(10, 0, 0, 2))),
+            rr("nas.lan", DnsRRData::AAAA("fd00::2".parse().unwrap())),
+            rr("router.lan", DnsRRData::A(Ipv4Addr::new(10, 0, 0, 1))),
+            rr("1.0.0.10.in-addr.arpa", DnsRRData::PTR("gw.lan".into())),
+            rr("ads.example", DnsRRData::A(Ipv4Addr::new(0, 0, 0, 0))),
+        ];
+        for rr in entries {
+            local.entry(rr.name.clone()).or_default().push(rr);
+        }
+        add_reverse_entries(&mut local);
+
+        let ptr = |name: &str| {
+            let rrs = local.get(&name.into())?;
+            Some(rrs.iter().map(|rr| rr.data.clone()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            ptr("2.0.0.10.in-addr.arpa"),
+            Some(vec![DnsRRData::PTR("nas.lan".into())])
+        );
+        assert_eq!(
+            ptr("2.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa"),
+            Some(vec![DnsRRData::PTR("nas.lan".into())])
+        );
+        // Explicit PTR records win
+        assert_eq!(
+            ptr("1.0.0.10.in-addr.arpa"),
+            Some(vec![DnsRRData::PTR("gw.lan".into())])
+        );
+        assert_eq!(ptr("0.0.0.0.in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn test_reload() {
+        let server = Server::new().local_zone("lan".into());
+        #[cfg(feature = "regex-rules")]
+        let server = server.regex_rule("/^ad/ 0.0.0.0".parse().unwrap());
+        let config = server.config;
+        let local = RwLock::new(EntryTable::new());
+        let memory = MemoryBudget::new(0);
+
+        let mut reloaded = LocalConfig::default();
+        reloaded.local.insert(
+            "nas.lan".into(),
+            vec![DnsResourceRecord {
+                name: "nas.lan".into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(10, 0, 0, 2)),
+            }],
+        );
+        #[cfg(feature = "blocklists")]
+        reloaded.blocklist.insert("ads.example");
+        reload(&config, &local, &memory, reloaded).unwrap();
+        {
+            let local = local.read().unwrap();
+            assert!(local.contains_key(&"lan".into()));
+            assert!(local.contains_key(&"2.0.0.10.in-addr.arpa".into()));
+        }
+        #[cfg(feature = "blocklists")]
+        assert!(config
+            .blocklist
+            .read()
+            .unwrap()
+            .contains(&"ads.example".into()));
+        let question = DnsQuestion {
+            qname: "ad1.example".into(),
+            qtype: DnsType::A,
+            qcode: 0,
+            qclass: DnsClass::Internet,
+        };
+        assert!(config.regex.read().unwrap().answer(&question).is_none());
+
+        // A failed reload keeps what was loaded
+        let mut reloaded = LocalConfig::default();
+        reloaded.regex_rules.push(RegexRule {
+            pattern: String::from("("),
+            data: DnsRRData::A(Ipv4Addr::new(0, 0, 0, 0)),
+        });
+        assert!(reload(&config, &local, &memory, reloaded).is_err());
+        assert_eq!(local.read().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_local_cname() {
+        let rr = |name: &str, data| DnsResourceRecord {
+            name: name.into(),
+            rtype: match data {
+                DnsRRData::CNAME(_) => DnsType::CNAME,
+                _ => DnsType::A,
+            },
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data,
+        };
+        let mut local = EntryTable::new();
+        let nas = rr("nas.lan", DnsRRData::A(Ipv4Addr::new(10, 0, 0, 2)));
+        let git = rr("git.lan", DnsRRData::CNAME("nas.lan".into()));
+        let www = rr("www.lan", DnsRRData::CNAME("example.com".into()));
+        let loop_a = rr("a.lan", DnsRRData::CNAME("b.lan".into()));
+        let loop_b = rr("b.lan", DnsRRData::CNAME("a.lan".into()));
+        for rr in &[&nas, &git, &www, &loop_a, &loop_b] {
+            local.insert(rr.name.clone(), vec![(*rr).clone()]);
+        }
+        let question = |name: &str| DnsQuestion {
+            qname: name.into(),
+            qtype: DnsType::A,
+            qcode: 0,
+            qclass: DnsClass::Internet,
+        };
+
+        // The target is local
+        let mut questions = vec![question("git.lan")];
+        let answers = filter_questions(&mut questions, &local, &RegexRules::default());
+        assert!(questions.is_empty());
+        assert_eq!(answers, [git.clone(), nas]);
+
+        // The target is upstream's, and its answer follows the chain
+        let mut questions = vec![question("WWW.lan")];
+        let answers = filter_questions(&mut questions, &local, &RegexRules::default());
+        assert_eq!(questions, [question("example.com")]);
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        let upstream = rr("example.com", DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut response = DnsMessage::response_to(&query).with_answer(upstream.clone());
+        add_local_answers(&mut response, answers);
+        assert_eq!(response.question, [question("WWW.lan")]);
+        assert_eq!(response.answer, [rr("WWW.lan", www.data), upstream]);
+
+        let mut questions = vec![question("a.lan")];
+        let answers = filter_questions(&mut questions, &local, &RegexRules::default());
+        assert_eq!(answers, [loop_a]);
+        assert_eq!(questions, [question("b.lan")]);
+    }
+
+    #[test]
+    fn test_rotate_addresses() {
+        let rr = |name: &str, data| DnsResourceRecord {
+            name: name.into(),
+            rtype: match data {
+                DnsRRData::CNAME(_) => DnsType::CNAME,
+                _ => DnsType::A,
+            },
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data,
+        };
+        let cname = rr("www.example", DnsRRData::CNAME("lb.example".into()));
+        let a: Vec<_> = (1..4)
+            .map(|i| rr("lb.example", DnsRRData::A(Ipv4Addr::new(192, 0, 2, i))))
+            .collect();
+        let query = DnsMessage::query("www.example".into(), DnsType::A);
+        let mut response = DnsMessage::response_to(&query).with_answer(cname.clone());
+        for rr in &a {
+            response.answer.push(rr.clone());
+        }
+
+        let mut firsts = vec![];
+        for _ in 0..3 {
+            let mut rotated = response.clone();
+            rotate_addresses(&mut rotated);
+            assert_eq!(rotated.answer[0], cname);
+            let mut addresses = rotated.answer[1..].to_vec();
+            firsts.push(addresses[0].clone());
+            // Rotated, not shuffled
+            let i = a.iter().position(|rr| *rr == addresses[0]).unwrap();
+            addresses.rotate_right(i);
+            assert_eq!(addresses, a);
+        }
+        // Each response starts with another address
+        firsts.dedup();
+        assert_eq!(firsts.len(), 3);
+    }
+
+    #[test]
+    fn test_any_answer() {
+        let query = DnsMessage::query("example.com".into(), DnsType::Any);
+        assert_eq!(any_answer(false, &query), None);
+        let response = any_answer(true, &query).unwrap();
+        assert_eq!(response.question, query.question);
+        assert_eq!(
+            response.answer[0].data,
+            DnsRRData::HINFO(b"RFC8482".to_vec(), vec![])
+        );
+        let query = DnsMessage::query("example.com".into(), DnsType::A);
+        assert_eq!(any_answer(true, &query), None);
+    }
+
+    #[test]
+    fn test_views() {
+        let rr = |name: &str, addr| DnsResourceRecord {
+            name: name.into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: DnsRRData::A(addr),
+        };
+        let mut public = EntryTable::new();
+        public.insert(
+            "www.lan".into(),
+            vec![rr("www.lan", Ipv4Addr::new(192, 0, 2, 1))],
+        );
+        let mut internal = EntryTable::new();
+        internal.insert(
+            "www.lan".into(),
+            vec![rr("www.lan", Ipv4Addr::new(10, 0, 0, 1))],
+        );
+        let internal_dns: SocketAddr = "10.0.0.53:53".parse().unwrap();
+        let config = Server::new()
+            .view(ViewConfig {
+                name: String::from("internal"),
+                clients: vec!["10.0.0.0/8".parse().unwrap()],
+                local: internal,
+                dns_addr: Some(internal_dns),
+            })
+            .config;
+        let local = RwLock::new(public);
+        let upstreams = Upstreams::new(
+            vec!["192.0.2.53:53".parse::<SocketAddr>().unwrap().into()],
+            Strategy::First,
+        );
+
+        let inside = config.variant_for(&"10.1.2.3:5353".parse().unwrap());
+        let outside = config.variant_for(&"192.0.2.9:5353".parse().unwrap());
+        assert_eq!(inside, Variant::View(0));
+        assert_eq!(outside, Variant::Stable);
+        let answer = |variant| {
+            let mut questions = vec![DnsQuestion {
+                qname: "www.lan".into(),
+                qtype: DnsType::A,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }];
+            local_answers(&config, &local, variant, &mut questions)[0]
+                .data
+                .clone()
+        };
+        assert_eq!(answer(inside), DnsRRData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(answer(outside), DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(config.destinations(&upstreams, inside), [internal_dns]);
+        assert_eq!(
+            config.destinations(&upstreams, outside),
+            ["192.0.2.53:53".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_view_query() {
+        let mut internal = EntryTable::new();
+        internal.insert(
+            "nas.lan".into(),
+            vec![DnsResourceRecord {
+                name: "nas.lan".into(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(10, 0, 0, 2)),
+            }],
+        );
+        // The main table has nothing under the zone
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .local_zone("lan".into())
+            .view(ViewConfig {
+                name: String::from("loopback"),
+                clients: vec!["127.0.0.0/8".parse().unwrap()],
+                local: internal,
+                dns_addr: None,
+            });
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut codec = DnsMessageCodec::new(false);
+        let mut ask = |name: &str| {
+            let mut buf = BytesMut::new();
+            let query = DnsMessage::query(name.into(), DnsType::A);
+            codec.encode(query, &mut buf).expect("encode");
+            sock.send_to(&buf, handle.local_addr()).unwrap();
+            let mut resp = [0; 512];
+            let len = sock.recv(&mut resp).expect("response");
+            codec
+                .decode(&mut BytesMut::from(&resp[..len]))
+                .expect("no error")
+                .expect("parse complete")
+        };
+        let response = ask("nas.lan");
+        assert_eq!(response.header.rcode, DnsRcode::NoErrorCondition);
+        assert_eq!(
+            response.answer[0].data,
+            DnsRRData::A(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        assert_eq!(ask("tv.lan").header.rcode, DnsRcode::NameError);
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_view_reload() {
+        let rr = |name: &str, addr| DnsResourceRecord {
+            name: name.into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: DnsRRData::A(addr),
+        };
+        let mut internal = EntryTable::new();
+        internal.insert(
+            "nas.lan".into(),
+            vec![rr("nas.lan", Ipv4Addr::new(10, 0, 0, 2))],
+        );
+        let config = Server::new()
+            .view(ViewConfig {
+                name: String::from("internal"),
+                clients: vec!["10.0.0.0/8".parse().unwrap()],
+                local: internal,
+                dns_addr: None,
+            })
+            .config;
+        let local = RwLock::new(EntryTable::new());
+        let memory = MemoryBudget::new(0);
+
+        let mut reloaded = LocalConfig::default();
+        let mut internal = EntryTable::new();
+        internal.insert(
+            "tv.lan".into(),
+            vec![rr("tv.lan", Ipv4Addr::new(10, 0, 0, 3))],
+        );
+        reloaded.views.push(internal);
+        reload(&config, &local, &memory, reloaded).unwrap();
+        let view = config.view_tables[0].read().unwrap();
+        assert!(!view.contains_key(&"nas.lan".into()));
+        assert!(view.contains_key(&"tv.lan".into()));
+        assert!(view.contains_key(&"3.0.0.10.in-addr.arpa".into()));
+        assert!(memory.local() > 0);
+    }
+
+    #[cfg(feature = "canary")]
+    #[test]
+    fn test_canary_reload() {
+        let rr = |name: &str, addr| DnsResourceRecord {
+            name: name.into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 10,
+            data: DnsRRData::A(addr),
+        };
+        let config = Server::new()
+            .canary(CanaryConfig {
+                dns_addr: "127.0.0.1:5353".parse().unwrap(),
+                local: EntryTable::new(),
+                percent: 10,
+                clients: vec![],
+            })
+            .config;
+        let local = RwLock::new(EntryTable::new());
+        let memory = MemoryBudget::new(0);
+
+        // Without a file of its own, the canary gets the stable entries
+        let mut reloaded = LocalConfig::default();
+        reloaded.local.insert(
+            "nas.lan".into(),
+            vec![rr("nas.lan", Ipv4Addr::new(10, 0, 0, 2))],
+        );
+        reload(&config, &local, &memory, reloaded).unwrap();
+        assert!(config
+            .canary_table
+            .read()
+            .unwrap()
+            .contains_key(&"nas.lan".into()));
+
+        let mut canary = EntryTable::new();
+        canary.insert(
+            "tv.lan".into(),
+            vec![rr("tv.lan", Ipv4Addr::new(10, 0, 0, 3))],
+        );
+        let reloaded = LocalConfig {
+            canary: Some(canary),
+            ..Default::default()
+        };
+        reload(&config, &local, &memory, reloaded).unwrap();
+        let canary = config.canary_table.read().unwrap();
+        assert!(!canary.contains_key(&"nas.lan".into()));
+        assert!(canary.contains_key(&"3.0.0.10.in-addr.arpa".into()));
+    }
+
+    #[test]
+    fn test_embedded_local_answer() {
+        let name: DomainName = vec!["ksqsf", "moe"].into();
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .local_record(DnsResourceRecord {
+                name: name.clone(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(127, 0, 0, 1)),
+            });
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+
+        let query = DnsMessage {
+            header: DnsHeader {
+                id: 4321,
+                query: true,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: name,
+                qtype: DnsType::A,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            ..Default::default()
+        };
+        let mut codec = DnsMessageCodec::new(false);
+        let mut buf = BytesMut::new();
+        codec.encode(query, &mut buf).expect("encode");
+
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        sock.send_to(&buf, handle.local_addr()).unwrap();
+        let mut resp = [0; 512];
+        let len = sock.recv(&mut resp).expect("response");
+        let response = codec
+            .decode(&mut BytesMut::from(&resp[..len]))
+            .expect("no error")
+            .expect("parse complete");
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(
+            response.answer[0].data,
+            DnsRRData::A(Ipv4Addr::new(127, 0, 0, 1))
+        );
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_upstream_timeout() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream(upstream.local_addr().unwrap())
+            .timeout(Duration::from_millis(100))
+            .retries(2);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+
+        let query = DnsMessage::query("example.com".into(), DnsType::A).with_id(4321);
+        let mut codec = DnsMessageCodec::new(false);
+        let mut buf = BytesMut::new();
+        codec.encode(query, &mut buf).expect("encode");
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        sock.send_to(&buf, handle.local_addr()).unwrap();
+
+        // Sent at 0, 100 and 300 ms, and failed at 700 ms
+        let mut resp = [0; 512];
+        let mut ids = vec![];
+        for _ in 0..3 {
+            upstream.recv(&mut resp).expect("query");
+            ids.push(resp[..2].to_vec());
+        }
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        let len = sock.recv(&mut resp).expect("response");
+        let response = codec
+            .decode(&mut BytesMut::from(&resp[..len]))
+            .expect("no error")
+            .expect("parse complete");
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(response.header.rcode, DnsRcode::ServerFailure);
+        assert_eq!(response.question[0].qname, "example.com".into());
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_truncated_retry() {
+        use std::io::{Read, Write};
+
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let upstream_tcp = std::net::TcpListener::bind(upstream.local_addr().unwrap()).unwrap();
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream(upstream.local_addr().unwrap())
+            .timeout(Duration::from_secs(2));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut codec = DnsMessageCodec::new(false);
+
+        // Ask, and answer truncated twice over UDP, as if to a retransmission
+        let mut ask = |id, name: &str| {
+            let mut buf = BytesMut::new();
+            let query = DnsMessage::query(name.into(), DnsType::A).with_id(id);
+            codec.encode(query, &mut buf).expect("encode");
+            sock.send_to(&buf, handle.local_addr()).unwrap();
+            let mut packet = [0; 512];
+            let (len, from) = upstream.recv_from(&mut packet).expect("query");
+            let query = codec
+                .decode(&mut BytesMut::from(&packet[..len]))
+                .unwrap()
+                .unwrap();
+            let mut buf = BytesMut::new();
+            codec
+                .encode(DnsMessage::response_to(&query), &mut buf)
+                .expect("encode");
+            buf[2] |= 0b10; // TC, which the encoder clears when all fits
+            upstream.send_to(&buf, from).unwrap();
+            let (conn, _) = upstream_tcp.accept().expect("TCP retry");
+            upstream.send_to(&buf, from).unwrap();
+            (query, conn)
+        };
+        let answer = DnsResourceRecord {
+            name: "example.com".into(),
+            rtype: DnsType::A,
+            rclass: DnsClass::Internet,
+            ttl: 60,
+            data: DnsRRData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let receive = || {
+            let mut packet = [0; 512];
+            let len = sock.recv(&mut packet).expect("response");
+            DnsMessageCodec::new(false)
+                .decode(&mut BytesMut::from(&packet[..len]))
+                .unwrap()
+                .unwrap()
+        };
+
+        // The client only gets the response over TCP
+        let (query, mut conn) = ask(4321, "example.com");
+        let mut len = [0; 2];
+        conn.read_exact(&mut len).unwrap();
+        conn.read_exact(&mut vec![0; u16::from_be_bytes(len).into()])
+            .unwrap();
+        let mut buf = BytesMut::new();
+        DnsMessageCodec::new(true)
+            .encode(
+                DnsMessage::response_to(&query).with_answer(answer.clone()),
+                &mut buf,
+            )
+            .expect("encode");
+        conn.write_all(&buf).unwrap();
+        let response = receive();
+        assert_eq!(response.header.id, 4321);
+        assert!(!response.header.truncated);
+        assert_eq!(response.answer, vec![answer]);
+
+        // Or a failure, if that fails
+        let (_, conn) = ask(4322, "example.net");
+        drop(conn);
+        let response = receive();
+        assert_eq!(response.header.id, 4322);
+        assert_eq!(response.header.rcode, DnsRcode::ServerFailure);
+        assert_eq!(response.question[0].qname, "example.net".into());
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_listeners() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream(upstream.local_addr().unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+        let (first, second) = (handle.local_addrs()[0], handle.local_addrs()[1]);
+        assert_ne!(first, second);
+
+        // Asked on the second listener, answered out of it, but forwarded
+        // out of the first
+        let query = DnsMessage::query("example.com".into(), DnsType::A).with_id(4321);
+        let mut codec = DnsMessageCodec::new(false);
+        let mut buf = BytesMut::new();
+        codec.encode(query, &mut buf).expect("encode");
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        sock.send_to(&buf, second).unwrap();
+
+        let mut packet = [0; 512];
+        let (len, from) = upstream.recv_from(&mut packet).expect("query");
+        assert_eq!(from, first);
+        let query = codec
+            .decode(&mut BytesMut::from(&packet[..len]))
+            .unwrap()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(DnsMessage::response_to(&query), &mut buf)
+            .expect("encode");
+        upstream.send_to(&buf, from).unwrap();
+
+        let (len, from) = sock.recv_from(&mut packet).expect("response");
+        assert_eq!(from, second);
+        let response = codec
+            .decode(&mut BytesMut::from(&packet[..len]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.header.id, 4321);
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        assert_eq!(
+            unmapped("[::ffff:192.0.2.1]:53".parse().unwrap()),
+            "192.0.2.1:53".parse().unwrap()
+        );
+        assert_eq!(
+            unmapped("[::1]:53".parse().unwrap()),
+            "[::1]:53".parse().unwrap()
+        );
+
+        // An IPv4 client and upstream, through an IPv6 listener
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let server = Server::new()
+            .listen("[::]:0".parse().unwrap())
+            .upstream(upstream.local_addr().unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+        let port = handle.local_addr().port();
+
+        let query = DnsMessage::query("example.com".into(), DnsType::A).with_id(4321);
+        let mut codec = DnsMessageCodec::new(false);
+        let mut buf = BytesMut::new();
+        codec.encode(query, &mut buf).expect("encode");
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        sock.send_to(&buf, ("127.0.0.1", port)).unwrap();
+
+        let mut packet = [0; 512];
+        let (len, from) = upstream.recv_from(&mut packet).expect("query");
+        let query = codec
+            .decode(&mut BytesMut::from(&packet[..len]))
+            .unwrap()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(DnsMessage::response_to(&query), &mut buf)
+            .expect("encode");
+        upstream.send_to(&buf, from).unwrap();
+
+        let (len, _) = sock.recv_from(&mut packet).expect("response");
+        let response = codec
+            .decode(&mut BytesMut::from(&packet[..len]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.header.id, 4321);
+
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_drain() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let server = Server::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream(upstream.local_addr().unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = server.start(&runtime.executor()).expect("start");
+        let addr = handle.local_addr();
+
+        let mut codec = DnsMessageCodec::new(false);
+        let ask = |sock: &std::net::UdpSocket, id| {
+            let query = DnsMessage::query("example.com".into(), DnsType::A).with_id(id);
+            let mut buf = BytesMut::new();
+            DnsMessageCodec::new(false)
+                .encode(query, &mut buf)
+                .expect("encode");
+            sock.send_to(&buf, addr).unwrap();
+        };
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        ask(&sock, 4321);
+        let mut packet = [0; 512];
+        let (len, from) = upstream.recv_from(&mut packet).expect("query");
+        let query = packet[..len].to_vec();
+
+        // New queries aren't taken once stopping
+        handle.stop();
+        thread::sleep(Duration::from_millis(100));
+        ask(&sock, 4322);
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        assert!(upstream.recv_from(&mut packet).is_err());
+
+        // But those taken before are answered, within the grace period
+        let query = codec
+            .decode(&mut BytesMut::from(&query[..]))
+            .unwrap()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(DnsMessage::response_to(&query), &mut buf)
+            .expect("encode");
+        upstream.send_to(&buf, from).unwrap();
+        let (len, _) = sock.recv_from(&mut packet).expect("response");
+        let response = codec
+            .decode(&mut BytesMut::from(&packet[..len]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.header.id, 4321);
+
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_client_table() {
+        let ttl = Duration::from_secs(10);
+        let pending = |client: &str, id| {
+            let query = DnsMessage::query("example.com".into(), DnsType::A);
+            Pending {
+                client: client.parse().unwrap(),
+                reply: mpsc::unbounded().0,
+                id,
+                variant: Variant::Stable,
+                upstreams: vec![],
+                asked: vec!["Example.com".into()],
+                query,
+                retried: false,
+                answers_local: vec![],
+                since: Instant::now(),
+            }
+        };
+        let key = |client: &str, id| {
+            let question = DnsMessage::query("EXAMPLE.com".into(), DnsType::A).question;
+            query_key(client.parse().unwrap(), id, &question)
+        };
+
+        // Two clients with the same ID are different queries
+        let mut clients = ClientTable::new(10);
+        assert!(clients
+            .insert(1, pending("192.0.2.1:1000", 0), ttl)
+            .is_none());
+        assert!(clients
+            .insert(2, pending("192.0.2.2:1000", 0), ttl)
+            .is_none());
+        assert_eq!(clients.upstream_id(&key("192.0.2.1:1000", 0)), Some(1));
+        assert_eq!(clients.upstream_id(&key("192.0.2.2:1000", 0)), Some(2));
+        assert_eq!(clients.upstream_id(&key("192.0.2.1:1000", 1)), None);
+
+        // Answered or replaced queries are forgotten
+        assert_eq!(clients.remove(1).map(|p| p.id), Some(0));
+        assert_eq!(clients.upstream_id(&key("192.0.2.1:1000", 0)), None);
+        assert!(clients
+            .insert(2, pending("192.0.2.3:1000", 5), ttl)
+            .is_some());
+        assert_eq!(clients.upstream_id(&key("192.0.2.2:1000", 0)), None);
+        assert_eq!(clients.upstream_id(&key("192.0.2.3:1000", 5)), Some(2));
+    }
+}
@@ -0,0 +1,195 @@
+//! Running as a Windows service, under the service control manager rather
+//! than in a console window.
+//!
+//! Register uind once, with the arguments to serve with, e.g.
+//!
+//! ```text
+//! sc.exe create uind binPath= "C:\uind\uind.exe service -c C:\uind\uind.toml" start= auto
+//! ```
+//!
+//! and start and stop it like any other service.
+
+use futures::sync::oneshot;
+use futures::Future;
+use std::ffi::OsStr;
+use std::io;
+use std::os::raw::c_void;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::server::Server;
+
+type StatusHandle = *mut c_void;
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *const u16,
+    main: Option<extern "system" fn(u32, *mut *mut u16)>,
+}
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32,
+        context: *mut c_void,
+    ) -> StatusHandle;
+    fn SetServiceStatus(handle: StatusHandle, status: *mut ServiceStatus) -> i32;
+}
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+
+/// The name services of their own process are registered by, which the
+/// service control manager ignores.
+const NAME: &str = "uind";
+
+/// The server, handed from `run` to the service's main function, which the
+/// dispatcher calls on a thread of its own, as a leaked `Box`.
+static SERVER: AtomicUsize = AtomicUsize::new(0);
+
+/// What the control handler needs to stop the service.
+struct Control {
+    handle: AtomicUsize,
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// Serve as a service, until it is stopped.  Only returns once the service
+/// has stopped, or with an error if the process wasn't started by the
+/// service control manager.
+pub fn run(server: Server) -> io::Result<()> {
+    let name = wide(NAME);
+    let table = [
+        ServiceTableEntry {
+            name: name.as_ptr(),
+            main: Some(service_main),
+        },
+        ServiceTableEntry {
+            name: ptr::null(),
+            main: None,
+        },
+    ];
+    SERVER.store(Box::into_raw(Box::new(server)) as usize, Ordering::SeqCst);
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let server = match SERVER.swap(0, Ordering::SeqCst) {
+        0 => return,
+        server => unsafe { Box::from_raw(server as *mut Server) },
+    };
+    let (stop, stopped) = oneshot::channel();
+    // Lives as long as the process, as the handler may be called until then
+    let control: &'static Control = Box::leak(Box::new(Control {
+        handle: AtomicUsize::new(0),
+        stop: Mutex::new(Some(stop)),
+    }));
+    let name = wide(NAME);
+    let handle = unsafe {
+        RegisterServiceCtrlHandlerExW(
+            name.as_ptr(),
+            handler,
+            control as *const Control as *mut c_void,
+        )
+    };
+    if handle.is_null() {
+        error!(
+            "Can't register the service handler: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    control.handle.store(handle as usize, Ordering::SeqCst);
+
+    set_status(handle, SERVICE_RUNNING, NO_ERROR);
+    let result = server.run_until(stopped.then(|_| {
+        info!("Service stopping");
+        Ok(())
+    }));
+    match result {
+        Ok(()) => set_status(handle, SERVICE_STOPPED, NO_ERROR),
+        Err(e) => {
+            error!("{}", e);
+            set_status(handle, SERVICE_STOPPED, ERROR_SERVICE_SPECIFIC_ERROR);
+        }
+    }
+}
+
+extern "system" fn handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    context: *mut c_void,
+) -> u32 {
+    let context = unsafe { &*(context as *const Control) };
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            if let Some(stop) = context.stop.lock().unwrap().take() {
+                let handle = context.handle.load(Ordering::SeqCst) as StatusHandle;
+                set_status(handle, SERVICE_STOP_PENDING, NO_ERROR);
+                let _ = stop.send(());
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+fn set_status(handle: StatusHandle, state: u32, exit_code: u32) {
+    let controls_accepted = match state {
+        SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+        _ => 0,
+    };
+    let mut status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        win32_exit_code: exit_code,
+        service_specific_exit_code: if exit_code == NO_ERROR { 0 } else { 1 },
+        check_point: 0,
+        wait_hint: if state == SERVICE_STOP_PENDING {
+            10000
+        } else {
+            0
+        },
+    };
+    if unsafe { SetServiceStatus(handle, &mut status) } == 0 {
+        error!(
+            "Can't report the service status: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// A NUL-terminated UTF-16 string.
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
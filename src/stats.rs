@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::memory::MemoryBudget;
+use crate::message::DnsType;
+
+/// Which configuration a query was served by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Variant {
+    Stable,
+    Canary,
+    /// The view of this index, for clients in its networks.
+    View(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct VariantStats {
+    pub queries: AtomicUsize,
+    pub local: AtomicUsize,
+    pub forwarded: AtomicUsize,
+    pub responses: AtomicUsize,
+}
+
+/// Counters shared by all dispatchers.
+#[derive(Debug, Default)]
+pub struct Stats {
+    stable: VariantStats,
+    canary: VariantStats,
+    views: Vec<(String, VariantStats)>,
+    qtypes: Mutex<HashMap<u16, usize>>,
+    pub memory: MemoryBudget,
+}
+
+impl Stats {
+    /// Counters for the stable and canary variants, and each view, by name.
+    pub fn new(memory: MemoryBudget, views: Vec<String>) -> Stats {
+        Stats {
+            memory,
+            views: views
+                .into_iter()
+                .map(|name| (name, VariantStats::default()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn variant(&self, variant: Variant) -> &VariantStats {
+        match variant {
+            Variant::Stable => &self.stable,
+            Variant::Canary => &self.canary,
+            Variant::View(i) => &self.views[i].1,
+        }
+    }
+
+    /// The counters of the views, with their names.
+    pub fn views(&self) -> impl Iterator<Item = (&str, &VariantStats)> {
+        self.views
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats))
+    }
+
+    pub fn count(counter: &AtomicUsize) {
+        if cfg!(feature = "metrics") {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a question for the type numbered `qtype`.
+    pub fn count_qtype(&self, qtype: u16) {
+        if cfg!(feature = "metrics") {
+            *self.qtypes.lock().unwrap().entry(qtype).or_insert(0) += 1;
+        }
+    }
+
+    /// How often each query type was asked for, by type code.
+    #[cfg(any(feature = "metrics", feature = "admin", unix))]
+    pub fn qtypes(&self) -> Vec<(u16, usize)> {
+        let mut qtypes: Vec<_> = self.qtypes.lock().unwrap().clone().into_iter().collect();
+        qtypes.sort();
+        qtypes
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn report(&self) {
+        for line in self.summary() {
+            info!("{}", line);
+        }
+    }
+
+    /// The counters, a line for each kind.
+    #[cfg(any(feature = "metrics", unix))]
+    pub fn summary(&self) -> Vec<String> {
+        let variants = vec![
+            (String::from("Stable"), &self.stable),
+            (String::from("Canary"), &self.canary),
+        ];
+        let views = self
+            .views()
+            .map(|(name, stats)| (format!("View {}", name), stats));
+        let mut lines: Vec<_> = variants
+            .into_iter()
+            .chain(views)
+            .map(|(variant, stats)| {
+                format!(
+                    "{}: queries={} local={} forwarded={} responses={}",
+                    variant,
+                    stats.queries.load(Ordering::Relaxed),
+                    stats.local.load(Ordering::Relaxed),
+                    stats.forwarded.load(Ordering::Relaxed),
+                    stats.responses.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        let qtypes: Vec<_> = self
+            .qtypes()
+            .into_iter()
+            .map(|(qtype, count)| match DnsType::try_from(qtype) {
+                Some(qtype) => format!("{:?}={}", qtype, count),
+                None => format!("TYPE{}={}", qtype, count),
+            })
+            .collect();
+        lines.push(format!("Query types: {}", qtypes.join(" ")));
+        lines.push(format!(
+            "Memory: used={} (local={} pending={}) limit={}",
+            self.memory.used(),
+            self.memory.local(),
+            self.memory.pending(),
+            self.memory.limit(),
+        ));
+        lines
+    }
+}
@@ -0,0 +1,114 @@
+//! Zone transfers (AXFR, RFC 5936) of the local table.
+
+use crate::message::*;
+use crate::server::EntryTable;
+
+/// Records per message of a transfer, which keeps even long names well
+/// under the 64 KiB a TCP message can hold.
+const RECORDS_PER_MESSAGE: usize = 64;
+
+/// Answer an SOA query for the zone, as secondaries ask before a transfer,
+/// or `None` if the zone has no SOA record.
+pub fn soa_answer(table: &EntryTable, query: &DnsMessage) -> Option<DnsMessage> {
+    let soa = zone_soa(table, &query.question[0].qname)?;
+    Some(response(query, vec![soa]))
+}
+
+/// Answer an AXFR query: the zone's SOA record, every record under it, and
+/// the SOA record again, in as many messages as it takes, or `None` if the
+/// zone has no SOA record.
+pub fn axfr(table: &EntryTable, query: &DnsMessage) -> Option<Vec<DnsMessage>> {
+    let zone = &query.question[0].qname;
+    let soa = zone_soa(table, zone)?;
+    let mut names: Vec<_> = table.keys().filter(|name| name.ends_with(zone)).collect();
+    names.sort_by_key(|name| name.to_string());
+    let mut records = vec![soa.clone()];
+    for name in names {
+        records.extend(
+            table[name]
+                .iter()
+                .filter(|rr| rr.rtype != DnsType::SOA)
+                .cloned(),
+        );
+    }
+    records.push(soa);
+
+    let messages = records
+        .chunks(RECORDS_PER_MESSAGE)
+        .enumerate()
+        .map(|(i, answer)| {
+            let mut message = response(query, answer.to_vec());
+            // Only the first message repeats the question
+            if i > 0 {
+                message.question.clear();
+            }
+            message
+        })
+        .collect();
+    Some(messages)
+}
+
+fn zone_soa(table: &EntryTable, zone: &DomainName) -> Option<DnsResourceRecord> {
+    table
+        .get(zone)
+        .into_iter()
+        .flatten()
+        .find(|rr| rr.rtype == DnsType::SOA)
+        .cloned()
+}
+
+fn response(query: &DnsMessage, answer: Vec<DnsResourceRecord>) -> DnsMessage {
+    DnsMessage::response_to(query)
+        .with_authoritative(true)
+        .with_answers(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::{bump_serial, soa};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_axfr() {
+        let zone: DomainName = "lan".into();
+        let mut table = EntryTable::new();
+        table.insert(zone.clone(), vec![soa(&zone)]);
+        for i in 0..100 {
+            let name: DomainName = format!("host{}.lan", i).as_str().into();
+            let rr = DnsResourceRecord {
+                name: name.clone(),
+                rtype: DnsType::A,
+                rclass: DnsClass::Internet,
+                ttl: 10,
+                data: DnsRRData::A(Ipv4Addr::new(10, 0, 0, i)),
+            };
+            table.insert(name, vec![rr]);
+        }
+        let other: DomainName = "example.com".into();
+        table.insert(other.clone(), vec![soa(&other)]);
+
+        let serial = |table: &EntryTable| match table[&zone][0].data {
+            DnsRRData::SOA(_, _, serial, _, _, _, _) => serial,
+            _ => unreachable!(),
+        };
+        let before = serial(&table);
+        bump_serial(&mut table, &zone);
+        assert_eq!(serial(&table), before.wrapping_add(1));
+
+        let query = DnsMessage::query(zone.clone(), DnsType::AXFR).with_id(7);
+        let messages = axfr(&table, &query).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].question.len(), 1);
+        assert!(messages[1].question.is_empty());
+        let records: Vec<_> = messages.iter().flat_map(|m| &m.answer).collect();
+        assert_eq!(records.len(), 102);
+        assert_eq!(records[0], &table[&zone][0]);
+        assert_eq!(records[101], &table[&zone][0]);
+        assert!(records[1..101].iter().all(|rr| rr.rtype == DnsType::A));
+
+        let query = DnsMessage::query("host1.lan".into(), DnsType::AXFR);
+        assert!(axfr(&table, &query).is_none());
+        assert!(soa_answer(&table, &query).is_none());
+    }
+}
@@ -0,0 +1,290 @@
+//! Transaction signatures (RFC 8945) with shared keys.
+
+use bytes::BytesMut;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384, Sha512};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::codec::Encoder;
+
+use crate::codec::DnsMessageCodec;
+use crate::message::*;
+
+/// How far apart the clocks of signer and verifier may be, in seconds.
+const FUDGE: u16 = 300;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> DomainName {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256".into(),
+            Algorithm::HmacSha384 => "hmac-sha384".into(),
+            Algorithm::HmacSha512 => "hmac-sha512".into(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s.to_lowercase().as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            "hmac-sha384" => Ok(Algorithm::HmacSha384),
+            "hmac-sha512" => Ok(Algorithm::HmacSha512),
+            _ => Err(format!("Unknown TSIG algorithm {}", s)),
+        }
+    }
+}
+
+/// A shared key.
+///
+/// Written as `[ALGORITHM:]NAME:SECRET`, like BIND's `-y`, with the secret
+/// in base64 and hmac-sha256 as the default algorithm.
+#[derive(Clone, PartialEq)]
+pub struct TsigKey {
+    pub name: DomainName,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TsigKey, String> {
+        let parts: Vec<_> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts.len() {
+            2 => (Algorithm::HmacSha256, parts[0], parts[1]),
+            3 => (parts[0].parse()?, parts[1], parts[2]),
+            _ => return Err(format!("Invalid TSIG key {}", s)),
+        };
+        Ok(TsigKey {
+            name: DomainName::from_idn(name)?,
+            algorithm,
+            secret: base64::decode(secret)
+                .map_err(|_| format!("Invalid base64 in TSIG key {}", name))?,
+        })
+    }
+}
+
+impl TsigKey {
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        macro_rules! hmac {
+            ($digest:ty) => {{
+                let mut mac = Hmac::<$digest>::new_varkey(&self.secret).expect("any key length");
+                mac.input(data);
+                mac.result().code().to_vec()
+            }};
+        }
+        match self.algorithm {
+            Algorithm::HmacSha256 => hmac!(Sha256),
+            Algorithm::HmacSha384 => hmac!(Sha384),
+            Algorithm::HmacSha512 => hmac!(Sha512),
+        }
+    }
+
+    /// Sign a message, which answers a request with `request_mac` if that
+    /// isn't empty.
+    pub fn sign(&self, message: &mut DnsMessage, request_mac: &[u8], now: u64) -> io::Result<()> {
+        self.sign_with(message, request_mac, now, false)
+    }
+
+    /// Sign a message which follows one with `prior_mac` in a zone transfer.
+    pub fn sign_next(
+        &self,
+        message: &mut DnsMessage,
+        prior_mac: &[u8],
+        now: u64,
+    ) -> io::Result<()> {
+        self.sign_with(message, prior_mac, now, true)
+    }
+
+    fn sign_with(
+        &self,
+        message: &mut DnsMessage,
+        request_mac: &[u8],
+        now: u64,
+        timers_only: bool,
+    ) -> io::Result<()> {
+        let mut tsig = Tsig {
+            key: self.name.clone(),
+            algorithm: self.algorithm.name(),
+            time_signed: now,
+            fudge: FUDGE,
+            original_id: message.header.id,
+            ..Default::default()
+        };
+        message.tsig = None;
+        let mut buf = BytesMut::new();
+        DnsMessageCodec::new(true).encode(message.clone(), &mut buf)?;
+        tsig.mac = self.mac(&digest_data(&tsig, request_mac, &buf[2..], timers_only));
+        message.tsig = Some(tsig);
+        Ok(())
+    }
+}
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Check the TSIG of a request.  Returns the key it was signed with, `None`
+/// if it isn't signed, or else the TSIG error.
+pub fn verify<'a>(
+    keys: &'a [TsigKey],
+    message: &DnsMessage,
+    now: u64,
+) -> Result<Option<&'a TsigKey>, DnsRcode> {
+    verify_after(keys, message, &[], false, now)
+}
+
+/// Check the TSIG of a message like `verify`, but one that answers a
+/// request with `prior_mac`, or follows a message with it in a zone
+/// transfer if `next`.
+pub fn verify_after<'a>(
+    keys: &'a [TsigKey],
+    message: &DnsMessage,
+    prior_mac: &[u8],
+    next: bool,
+    now: u64,
+) -> Result<Option<&'a TsigKey>, DnsRcode> {
+    let tsig = match message.tsig {
+        Some(ref tsig) => tsig,
+        None => return Ok(None),
+    };
+    let key = keys
+        .iter()
+        .find(|key| key.name == tsig.key && key.algorithm.name() == tsig.algorithm)
+        .ok_or(DnsRcode::BadKey)?;
+    let mac = key.mac(&digest_data(tsig, prior_mac, &tsig.signed, next));
+    // Compare in constant time, and don't accept truncated MACs
+    let diff = mac
+        .iter()
+        .zip(&tsig.mac)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if mac.len() != tsig.mac.len() || diff != 0 {
+        return Err(DnsRcode::BadVersion); // BADSIG
+    }
+    let skew = if now > tsig.time_signed {
+        now - tsig.time_signed
+    } else {
+        tsig.time_signed - now
+    };
+    if skew > tsig.fudge as u64 {
+        return Err(DnsRcode::BadTime);
+    }
+    Ok(Some(key))
+}
+
+/// What the MAC is computed over: the request MAC, the message and the
+/// TSIG variables, or only the timers for later messages of a transfer.
+fn digest_data(tsig: &Tsig, request_mac: &[u8], message: &[u8], timers_only: bool) -> Vec<u8> {
+    let mut data = vec![];
+    if !request_mac.is_empty() {
+        data.extend(&[(request_mac.len() >> 8) as u8, request_mac.len() as u8]);
+        data.extend(request_mac);
+    }
+    data.extend(message);
+    if !timers_only {
+        canonical_name(&tsig.key, &mut data);
+        data.extend(&[0, DnsClass::Any as u8, 0, 0, 0, 0]);
+        canonical_name(&tsig.algorithm, &mut data);
+    }
+    for i in (0..6).rev() {
+        data.push((tsig.time_signed >> (8 * i)) as u8);
+    }
+    data.extend(&[(tsig.fudge >> 8) as u8, tsig.fudge as u8]);
+    if !timers_only {
+        for &n in &[tsig.error, tsig.other.len() as u16] {
+            data.extend(&[(n >> 8) as u8, n as u8]);
+        }
+        data.extend(&tsig.other);
+    }
+    data
+}
+
+fn canonical_name(name: &DomainName, buf: &mut Vec<u8>) {
+    for label in name {
+        buf.push(label.len() as u8);
+        buf.extend(label.to_lowercase().as_bytes());
+    }
+    buf.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::codec::Decoder;
+
+    #[test]
+    fn test_sign_verify() {
+        let key: TsigKey = "dhcp:c2VjcmV0".parse().unwrap();
+        assert_eq!(key.secret, b"secret");
+        let other: TsigKey = "hmac-sha512:other:c2VjcmV0".parse().unwrap();
+        let keys = vec![other.clone(), key.clone()];
+        let now = 1_600_000_000;
+
+        let roundtrip = |message: DnsMessage| {
+            let mut codec = DnsMessageCodec::new(false);
+            let mut buf = BytesMut::new();
+            codec.encode(message, &mut buf).unwrap();
+            codec.decode(&mut buf).unwrap().unwrap()
+        };
+        let mut request = DnsMessage {
+            header: DnsHeader {
+                id: 4321,
+                opcode: DnsOpcode::Update,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: "lan".into(),
+                qtype: DnsType::SOA,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(verify(&keys, &request, now), Ok(None));
+        key.sign(&mut request, &[], now).unwrap();
+        let decoded = roundtrip(request.clone());
+        assert_eq!(verify(&keys, &decoded, now + 10), Ok(Some(&key)));
+        assert_eq!(verify(&keys, &decoded, now + 1000), Err(DnsRcode::BadTime));
+        assert_eq!(verify(&keys[..1], &decoded, now), Err(DnsRcode::BadKey));
+
+        let mut tampered = decoded.clone();
+        tampered.tsig.as_mut().unwrap().signed[3] ^= 1;
+        assert_eq!(verify(&keys, &tampered, now), Err(DnsRcode::BadVersion));
+
+        // A response is signed over the request's MAC too
+        let request_mac = decoded.tsig.unwrap().mac;
+        let mut response = request.clone();
+        response.header.query = false;
+        key.sign(&mut response, &request_mac, now).unwrap();
+        let response = roundtrip(response);
+        assert_eq!(verify(&keys, &response, now), Err(DnsRcode::BadVersion));
+        assert_eq!(
+            verify_after(&keys, &response, &request_mac, false, now),
+            Ok(Some(&key))
+        );
+    }
+}
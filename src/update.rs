@@ -0,0 +1,265 @@
+//! Dynamic updates (RFC 2136) of the A and AAAA records in the local table.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::acl::ZoneAccess;
+use crate::authority;
+use crate::hosts::{self, Format};
+use crate::message::*;
+use crate::server::EntryTable;
+
+/// Apply an UPDATE message, signed with `key` if any, to `table`.  Either
+/// all of its updates are applied, or none and the reason is returned.
+pub fn apply(
+    zones: &[ZoneAccess],
+    table: &mut EntryTable,
+    message: &DnsMessage,
+    client: &IpAddr,
+    key: Option<&DomainName>,
+) -> Result<(), DnsRcode> {
+    // The question section holds the zone
+    if message.question.len() != 1 || message.question[0].qtype != DnsType::SOA {
+        return Err(DnsRcode::FormatError);
+    }
+    let zone = &message.question[0];
+    let zone = zones
+        .iter()
+        .find(|z| z.zone == zone.qname && zone.qclass == DnsClass::Internet)
+        .ok_or(DnsRcode::NotAuth)?;
+    if !zone.allows(client, key) {
+        return Err(DnsRcode::Refused);
+    }
+
+    // The answer section holds the prerequisites
+    let mut values: Vec<&DnsResourceRecord> = vec![];
+    for rr in &message.answer {
+        if rr.ttl != 0 {
+            return Err(DnsRcode::FormatError);
+        }
+        if !rr.name.ends_with(&zone.zone) {
+            return Err(DnsRcode::NotZone);
+        }
+        let in_use = table.get(&rr.name).map_or(false, |rrs| !rrs.is_empty());
+        let exists = rrset(table, &rr.name, type_code(rr)).next().is_some();
+        match (rr.rclass, rr.rtype) {
+            (DnsClass::Any, _) | (DnsClass::None, _) if !is_empty(rr) => {
+                return Err(DnsRcode::FormatError)
+            }
+            (DnsClass::Any, DnsType::Any) if !in_use => return Err(DnsRcode::NameError),
+            (DnsClass::Any, _) if !exists => return Err(DnsRcode::NXRRSet),
+            (DnsClass::None, DnsType::Any) if in_use => return Err(DnsRcode::YXDomain),
+            (DnsClass::None, _) if exists => return Err(DnsRcode::YXRRSet),
+            (DnsClass::Internet, _) => values.push(rr),
+            (DnsClass::Any, _) | (DnsClass::None, _) => (),
+            _ => return Err(DnsRcode::FormatError),
+        }
+    }
+    // RRsets which must exist with exactly these values
+    for rr in &values {
+        let wanted: Vec<_> = values
+            .iter()
+            .filter(|v| v.name == rr.name && type_code(v) == type_code(rr))
+            .map(|v| &v.data)
+            .collect();
+        let found: Vec<_> = rrset(table, &rr.name, type_code(rr))
+            .map(|v| &v.data)
+            .collect();
+        if wanted.len() != found.len() || !wanted.iter().all(|data| found.contains(data)) {
+            return Err(DnsRcode::NXRRSet);
+        }
+    }
+
+    // The authority section holds the updates
+    for rr in &message.authority {
+        if !rr.name.ends_with(&zone.zone) {
+            return Err(DnsRcode::NotZone);
+        }
+        let supported = rr.rtype == DnsType::A || rr.rtype == DnsType::AAAA;
+        match rr.rclass {
+            DnsClass::Internet if is_empty(rr) => return Err(DnsRcode::FormatError),
+            DnsClass::Any if rr.ttl != 0 || !is_empty(rr) => return Err(DnsRcode::FormatError),
+            DnsClass::None if rr.ttl != 0 || is_empty(rr) => return Err(DnsRcode::FormatError),
+            DnsClass::Any if rr.rtype == DnsType::Any => (),
+            DnsClass::Internet | DnsClass::Any | DnsClass::None if !supported => {
+                return Err(DnsRcode::Refused)
+            }
+            DnsClass::Internet | DnsClass::Any | DnsClass::None => (),
+            _ => return Err(DnsRcode::FormatError),
+        }
+    }
+    for rr in &message.authority {
+        update(table, rr);
+    }
+    if !message.authority.is_empty() {
+        authority::bump_serial(table, &zone.zone);
+    }
+    Ok(())
+}
+
+/// Apply an update which has been checked.
+fn update(table: &mut EntryTable, rr: &DnsResourceRecord) {
+    info!("Update: {} {} {}", rr.name, rr.rclass, type_code(rr));
+    let rrs = table.entry(rr.name.clone()).or_default();
+    match rr.rclass {
+        DnsClass::Internet => {
+            if !rrs.iter().any(|v| v.rtype == rr.rtype && v.data == rr.data) {
+                rrs.push(rr.clone());
+            }
+        }
+        DnsClass::Any if rr.rtype == DnsType::Any => {
+            rrs.retain(|v| v.rtype != DnsType::A && v.rtype != DnsType::AAAA)
+        }
+        DnsClass::Any => rrs.retain(|v| v.rtype != rr.rtype),
+        _ => rrs.retain(|v| v.rtype != rr.rtype || v.data != rr.data),
+    }
+    if rrs.is_empty() {
+        table.remove(&rr.name);
+    }
+}
+
+/// Write the local table back to a `dnsrelay.txt` file.  Only A and PTR
+/// records can be written, and SOA records of transferable zones are left
+/// out quietly.
+pub fn persist(path: &Path, table: &EntryTable) -> io::Result<()> {
+    let mut entries: Vec<_> = table
+        .values()
+        .flatten()
+        .filter(|rr| rr.rtype != DnsType::SOA)
+        .map(|rr| (rr.name.clone(), rr.data.clone()))
+        .collect();
+    entries.sort_by_key(|(name, _)| name.to_string());
+    let tmp = path.with_extension("tmp");
+    hosts::write(Format::Dnsrelay, &entries, fs::File::create(&tmp)?)?;
+    fs::rename(&tmp, path)
+}
+
+fn type_code(rr: &DnsResourceRecord) -> u16 {
+    match rr.data {
+        DnsRRData::Unknown(rtype, _) => rtype,
+        _ => rr.rtype as u16,
+    }
+}
+
+fn is_empty(rr: &DnsResourceRecord) -> bool {
+    match rr.data {
+        DnsRRData::Unknown(_, ref data) => data.is_empty(),
+        _ => false,
+    }
+}
+
+fn rrset<'a>(
+    table: &'a EntryTable,
+    name: &DomainName,
+    rtype: u16,
+) -> impl Iterator<Item = &'a DnsResourceRecord> {
+    table
+        .get(name)
+        .into_iter()
+        .flatten()
+        .filter(move |rr| type_code(rr) == rtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_apply() {
+        let zones = vec!["lan".parse::<ZoneAccess>().unwrap()];
+        let mut table = EntryTable::new();
+        let host: DomainName = "host.lan".into();
+        let record = |rclass, rtype, ttl, data| DnsResourceRecord {
+            name: host.clone(),
+            rtype,
+            rclass,
+            ttl,
+            data,
+        };
+        let a = DnsRRData::A(Ipv4Addr::new(192, 168, 1, 10));
+        let update = |answer: Vec<DnsResourceRecord>, authority| DnsMessage {
+            header: DnsHeader {
+                opcode: DnsOpcode::Update,
+                ..Default::default()
+            },
+            question: vec![DnsQuestion {
+                qname: "lan".into(),
+                qtype: DnsType::SOA,
+                qcode: 0,
+                qclass: DnsClass::Internet,
+            }],
+            answer,
+            authority,
+            ..Default::default()
+        };
+        let localhost = "127.0.0.1".parse().unwrap();
+
+        // Add the address if the name is unused
+        let nxdomain = record(
+            DnsClass::None,
+            DnsType::Any,
+            0,
+            DnsRRData::Unknown(255, vec![]),
+        );
+        let add = update(
+            vec![nxdomain.clone()],
+            vec![record(DnsClass::Internet, DnsType::A, 300, a.clone())],
+        );
+        assert_eq!(apply(&zones, &mut table, &add, &localhost, None), Ok(()));
+        assert_eq!(table[&host][0].data, a);
+        assert_eq!(
+            apply(&zones, &mut table, &add, &localhost, None),
+            Err(DnsRcode::YXDomain)
+        );
+        let remote = "192.168.1.10".parse().unwrap();
+        assert_eq!(
+            apply(&zones, &mut table, &add, &remote, None),
+            Err(DnsRcode::Refused)
+        );
+
+        // Only A and AAAA records may be changed
+        let mx = DnsRRData::MX(10, host.clone());
+        let add_mx = update(
+            vec![],
+            vec![record(DnsClass::Internet, DnsType::MX, 300, mx)],
+        );
+        assert_eq!(
+            apply(&zones, &mut table, &add_mx, &localhost, None),
+            Err(DnsRcode::Refused)
+        );
+
+        // Delete the RRset if it has exactly this value
+        let delete = update(
+            vec![record(DnsClass::Internet, DnsType::A, 0, a.clone())],
+            vec![record(
+                DnsClass::Any,
+                DnsType::A,
+                0,
+                DnsRRData::Unknown(1, vec![]),
+            )],
+        );
+        assert_eq!(apply(&zones, &mut table, &delete, &localhost, None), Ok(()));
+        assert!(table.is_empty());
+        assert_eq!(
+            apply(&zones, &mut table, &delete, &localhost, None),
+            Err(DnsRcode::NXRRSet)
+        );
+
+        // A zone with a key takes updates signed with it from anywhere
+        let zones = vec!["lan,dhcp".parse::<ZoneAccess>().unwrap()];
+        let key: DomainName = "dhcp".into();
+        let other: DomainName = "other".into();
+        assert_eq!(
+            apply(&zones, &mut table, &add, &localhost, None),
+            Err(DnsRcode::Refused)
+        );
+        assert_eq!(
+            apply(&zones, &mut table, &add, &remote, Some(&other)),
+            Err(DnsRcode::Refused)
+        );
+        assert_eq!(apply(&zones, &mut table, &add, &remote, Some(&key)), Ok(()));
+    }
+}
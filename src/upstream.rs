@@ -0,0 +1,618 @@
+//! The upstream servers, and how queries are spread over them.
+
+use bytes::BytesMut;
+use futures::future::{self, Either};
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use crate::codec::DnsMessageCodec;
+#[cfg(feature = "dnstap")]
+use crate::dnstap::{self, Dnstap};
+#[cfg(feature = "doh")]
+use crate::doh;
+use crate::message::*;
+
+/// How often upstreams are probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a probe may take, and by default a query.  Probes without a
+/// response count as this slow.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many probes in a row have to fail for a server to be marked down,
+/// and to succeed for it to be marked up again.
+const FALL: usize = 3;
+const RISE: usize = 2;
+
+/// How queries are spread over the upstream servers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Always ask the first one.
+    First,
+    /// Ask all of them at once, and relay the first valid response.
+    Race,
+    /// Ask the one with the lowest round-trip time.
+    Fastest,
+    /// Take turns, each server in proportion to its weight.
+    Weighted,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "first" => Ok(Strategy::First),
+            "race" => Ok(Strategy::Race),
+            "fastest" => Ok(Strategy::Fastest),
+            "weighted" => Ok(Strategy::Weighted),
+            _ => Err(format!("Unknown upstream strategy {}", s)),
+        }
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::First
+    }
+}
+
+/// An upstream server, and its share of the queries with
+/// `Strategy::Weighted`.
+///
+/// Written as `IP:PORT[/WEIGHT]`, e.g. `192.168.1.2:53/3`, or as
+/// `https://HOST[:PORT]/PATH[/WEIGHT]` for a DNS-over-HTTPS server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamServer {
+    pub addr: SocketAddr,
+    pub weight: u32,
+    /// The URL of a DNS-over-HTTPS server, whose host is at `addr`.
+    pub url: Option<String>,
+}
+
+impl From<SocketAddr> for UpstreamServer {
+    fn from(addr: SocketAddr) -> UpstreamServer {
+        UpstreamServer {
+            addr,
+            weight: 1,
+            url: None,
+        }
+    }
+}
+
+impl FromStr for UpstreamServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UpstreamServer, String> {
+        let mut parts = s.rsplitn(2, '/');
+        let (server, weight) = match (parts.next(), parts.next()) {
+            (Some(weight), Some(server))
+                if weight.bytes().all(|b| b.is_ascii_digit()) && !server.ends_with('/') =>
+            {
+                (
+                    server,
+                    weight
+                        .parse()
+                        .ok()
+                        .filter(|&weight| weight > 0)
+                        .ok_or(format!("Invalid weight in {}", s))?,
+                )
+            }
+            _ => (s, 1),
+        };
+        if server.starts_with("https://") {
+            return doh_server(server, weight);
+        }
+        let addr = server
+            .parse()
+            .map_err(|_| format!("Error parsing DNS server address {}", server))?;
+        Ok(UpstreamServer {
+            addr,
+            weight,
+            url: None,
+        })
+    }
+}
+
+#[cfg(feature = "doh")]
+fn doh_server(url: &str, weight: u32) -> Result<UpstreamServer, String> {
+    Ok(UpstreamServer {
+        addr: doh::locate(url)?,
+        weight,
+        url: Some(url.to_owned()),
+    })
+}
+
+#[cfg(not(feature = "doh"))]
+fn doh_server(url: &str, _weight: u32) -> Result<UpstreamServer, String> {
+    Err(format!(
+        "Can't use {}: DNS-over-HTTPS needs the doh feature",
+        url
+    ))
+}
+
+/// What is known about an upstream server.
+#[derive(Debug)]
+struct State {
+    /// Smoothed round-trip time in microseconds, 0 if not measured yet.
+    srtt: AtomicUsize,
+    up: AtomicBool,
+    /// Probes which failed or succeeded in a row.
+    failures: AtomicUsize,
+    successes: AtomicUsize,
+}
+
+/// The upstream servers of the stable configuration, their smoothed
+/// round-trip times and whether they are up.
+#[derive(Debug)]
+pub struct Upstreams {
+    addrs: Vec<SocketAddr>,
+    weights: Vec<u32>,
+    urls: Vec<Option<String>>,
+    #[cfg(feature = "doh")]
+    doh: doh::Client,
+    strategy: Strategy,
+    states: Vec<State>,
+    /// The servers' current weights in the smooth weighted round-robin of
+    /// nginx: the one with the largest goes next.
+    turns: Mutex<Vec<i64>>,
+    timeout: Duration,
+    #[cfg(feature = "dnstap")]
+    dnstap: Option<Dnstap>,
+}
+
+impl Upstreams {
+    pub fn new(servers: Vec<UpstreamServer>, strategy: Strategy) -> Upstreams {
+        assert!(!servers.is_empty(), "no upstream servers");
+        let addrs: Vec<_> = servers.iter().map(|server| server.addr).collect();
+        let weights = servers.iter().map(|server| server.weight).collect();
+        let urls = servers.iter().map(|server| server.url.clone()).collect();
+        #[cfg(feature = "doh")]
+        let doh = doh::Client::new(
+            &servers
+                .iter()
+                .filter_map(|server| Some((server.url.clone()?, server.addr)))
+                .collect::<Vec<_>>(),
+        );
+        let states = addrs
+            .iter()
+            .map(|_| State {
+                srtt: AtomicUsize::new(0),
+                up: AtomicBool::new(true),
+                failures: AtomicUsize::new(0),
+                successes: AtomicUsize::new(0),
+            })
+            .collect();
+        Upstreams {
+            turns: Mutex::new(vec![0; addrs.len()]),
+            addrs,
+            weights,
+            urls,
+            #[cfg(feature = "doh")]
+            doh,
+            strategy,
+            states,
+            timeout: TIMEOUT,
+            #[cfg(feature = "dnstap")]
+            dnstap: None,
+        }
+    }
+
+    /// How long an exchange over TCP or HTTPS may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Upstreams {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Log exchanges over TCP or HTTPS to dnstap.
+    #[cfg(feature = "dnstap")]
+    pub fn with_dnstap(mut self, dnstap: Option<Dnstap>) -> Upstreams {
+        self.dnstap = dnstap;
+        self
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Whether the servers need probing: to measure them for `Fastest`, or
+    /// to fail over to another.
+    pub fn probed(&self) -> bool {
+        self.strategy == Strategy::Fastest || self.addrs.len() > 1
+    }
+
+    /// The servers to send a query to.  Servers which are down are avoided,
+    /// unless all of them are.
+    pub fn pick(&self) -> Vec<SocketAddr> {
+        let mut candidates: Vec<usize> = (0..self.addrs.len())
+            .filter(|&i| self.states[i].up.load(Ordering::Relaxed))
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..self.addrs.len()).collect();
+        }
+        match self.strategy {
+            Strategy::First => vec![self.addrs[candidates[0]]],
+            Strategy::Race => candidates.iter().map(|&i| self.addrs[i]).collect(),
+            Strategy::Fastest => {
+                let srtts: Vec<_> = self
+                    .states
+                    .iter()
+                    .map(|state| state.srtt.load(Ordering::Relaxed))
+                    .collect();
+                let fastest = candidates
+                    .iter()
+                    .cloned()
+                    .min_by_key(|&i| srtts[i])
+                    .unwrap();
+                // The others look a little faster each time, so that a server
+                // which was slow once is eventually tried again
+                for (i, state) in self.states.iter().enumerate() {
+                    if i != fastest {
+                        state
+                            .srtt
+                            .store(srtts[i] - srtts[i] / 64, Ordering::Relaxed);
+                    }
+                }
+                vec![self.addrs[fastest]]
+            }
+            Strategy::Weighted => {
+                let mut turns = self.turns.lock().unwrap();
+                let mut total = 0;
+                for &i in &candidates {
+                    turns[i] += i64::from(self.weights[i]);
+                    total += i64::from(self.weights[i]);
+                }
+                let next = candidates
+                    .iter()
+                    .cloned()
+                    .min_by_key(|&i| -turns[i])
+                    .unwrap();
+                turns[next] -= total;
+                vec![self.addrs[next]]
+            }
+        }
+    }
+
+    fn state(&self, addr: SocketAddr) -> Option<&State> {
+        let i = self.addrs.iter().position(|&a| a == addr)?;
+        Some(&self.states[i])
+    }
+
+    /// The URL of `addr` if it is a DNS-over-HTTPS server.
+    pub fn url(&self, addr: SocketAddr) -> Option<&str> {
+        let i = self.addrs.iter().position(|&a| a == addr)?;
+        self.urls[i].iter().map(String::as_str).next()
+    }
+
+    /// Ask `addr` over DNS-over-HTTPS if it is such a server, or else over
+    /// a new TCP connection.
+    pub fn exchange(
+        &self,
+        addr: SocketAddr,
+        query: DnsMessage,
+    ) -> Box<dyn Future<Item = DnsMessage, Error = io::Error> + Send> {
+        #[cfg(feature = "dnstap")]
+        let protocol = match self.url(addr) {
+            Some(_) => dnstap::Protocol::Doh,
+            None => dnstap::Protocol::Tcp,
+        };
+        #[cfg(feature = "dnstap")]
+        if let Some(ref dnstap) = self.dnstap {
+            dnstap.log(
+                dnstap::Kind::ForwarderQuery,
+                protocol,
+                None,
+                Some(addr),
+                &query,
+            );
+        }
+        let exchange: Box<dyn Future<Item = DnsMessage, Error = io::Error> + Send> =
+            match self.url(addr) {
+                #[cfg(feature = "doh")]
+                Some(url) => Box::new(self.doh.exchange(url, query)),
+                _ => Box::new(exchange_tcp(addr, query)),
+            };
+        let exchange = exchange
+            .timeout(self.timeout)
+            .map_err(|e| match e.into_inner() {
+                Some(e) => e,
+                None => io::Error::new(io::ErrorKind::TimedOut, "Timed out"),
+            });
+        #[cfg(feature = "dnstap")]
+        let exchange = {
+            let dnstap = self.dnstap.clone();
+            exchange.inspect(move |response| {
+                if let Some(ref dnstap) = dnstap {
+                    dnstap.log(
+                        dnstap::Kind::ForwarderResponse,
+                        protocol,
+                        None,
+                        Some(addr),
+                        response,
+                    );
+                }
+            })
+        };
+        Box::new(exchange)
+    }
+
+    /// Ask `addr` for the NS records of `name`, and measure how long it
+    /// takes to answer.  Failure to resolve counts as an error.
+    pub fn probe(
+        &self,
+        addr: SocketAddr,
+        name: DomainName,
+    ) -> impl Future<Item = Duration, Error = io::Error> {
+        let query = DnsMessage::query(name, DnsType::NS).with_id(rand::random());
+        let id = query.header.id;
+        let start = Instant::now();
+        let response =
+            match self.url(addr) {
+                Some(_) => Either::A(self.exchange(addr, query)),
+                None => Either::B(query_udp(addr, query).timeout(TIMEOUT).map_err(|e| {
+                    match e.into_inner() {
+                        Some(e) => e,
+                        None => io::Error::new(io::ErrorKind::TimedOut, "Probe timed out"),
+                    }
+                })),
+            };
+        response.and_then(move |response| {
+            if response.header.id != id {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unexpected probe response",
+                ));
+            }
+            match response.header.rcode {
+                DnsRcode::ServerFailure | DnsRcode::Refused => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Probe answered {}", response.header.rcode),
+                )),
+                _ => Ok(start.elapsed()),
+            }
+        })
+    }
+
+    /// Account a round trip to `addr`, if it is one of the servers.
+    pub fn record(&self, addr: SocketAddr, rtt: Duration) {
+        let state = match self.state(addr) {
+            Some(state) => state,
+            None => return,
+        };
+        let rtt = (rtt.as_secs() as usize * 1_000_000 + rtt.subsec_micros() as usize).max(1);
+        let srtt = state.srtt.load(Ordering::Relaxed);
+        let srtt = if srtt == 0 {
+            rtt
+        } else {
+            srtt - srtt / 8 + rtt / 8
+        };
+        state.srtt.store(srtt.max(1), Ordering::Relaxed);
+    }
+
+    /// Account the outcome of a probe of `addr`, marking it down or up
+    /// once enough probes in a row failed or succeeded.
+    pub fn record_probe(&self, addr: SocketAddr, success: bool) {
+        let state = match self.state(addr) {
+            Some(state) => state,
+            None => return,
+        };
+        let (count, reset) = if success {
+            (&state.successes, &state.failures)
+        } else {
+            (&state.failures, &state.successes)
+        };
+        reset.store(0, Ordering::Relaxed);
+        let count = count.fetch_add(1, Ordering::Relaxed) + 1;
+        let up = state.up.load(Ordering::Relaxed);
+        if up && !success && count >= FALL {
+            warn!("Upstream {} is down", addr);
+            state.up.store(false, Ordering::Relaxed);
+        } else if !up && success && count >= RISE {
+            warn!("Upstream {} is up again", addr);
+            state.up.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The servers, their smoothed round-trip times if measured, and
+    /// whether they are up.
+    pub fn status(&self) -> Vec<(SocketAddr, Option<Duration>, bool)> {
+        self.addrs
+            .iter()
+            .zip(&self.states)
+            .map(|(&addr, state)| {
+                let rtt = match state.srtt.load(Ordering::Relaxed) {
+                    0 => None,
+                    micros => Some(Duration::from_micros(micros as u64)),
+                };
+                (addr, rtt, state.up.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+}
+
+/// Send `query` to `addr` over UDP, and take the first response from it.
+fn query_udp(
+    addr: SocketAddr,
+    query: DnsMessage,
+) -> impl Future<Item = DnsMessage, Error = io::Error> {
+    let mut buf = BytesMut::new();
+    let local: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    future::result(
+        DnsMessageCodec::new(false)
+            .encode(query, &mut buf)
+            .and_then(|_| UdpSocket::bind(&local)),
+    )
+    .and_then(move |socket| socket.send_dgram(buf, &addr))
+    .and_then(|(socket, _)| socket.recv_dgram(vec![0; 4096]))
+    .and_then(move |(_, buf, len, from)| {
+        match DnsMessageCodec::new(false).decode(&mut BytesMut::from(&buf[..len]))? {
+            Some(response) if from == addr => Ok(response),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected response",
+            )),
+        }
+    })
+}
+
+/// Ask `addr` over a new TCP connection.
+fn exchange_tcp(
+    addr: SocketAddr,
+    query: DnsMessage,
+) -> impl Future<Item = DnsMessage, Error = io::Error> {
+    TcpStream::connect(&addr)
+        .and_then(move |conn| DnsMessageCodec::new(true).framed(conn).send(query))
+        .and_then(|codec| codec.into_future().map_err(|(e, _)| e))
+        .and_then(|(response, _)| {
+            response
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"))
+        })
+}
+
+/// Probe the servers periodically for `name`, account their round-trip
+/// times, and mark them down or up.
+pub fn maintain(upstreams: Arc<Upstreams>, name: DomainName) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), PROBE_INTERVAL)
+        .map_err(|e| error!("error in upstream prober: {}", e))
+        .for_each(move |_| {
+            for &addr in &upstreams.addrs {
+                let upstreams = upstreams.clone();
+                tokio::spawn(upstreams.probe(addr, name.clone()).then(move |result| {
+                    match result {
+                        Ok(rtt) => upstreams.record(addr, rtt),
+                        Err(ref e) => {
+                            debug!("Probe of {} failed: {}", addr, e);
+                            upstreams.record(addr, TIMEOUT);
+                        }
+                    }
+                    upstreams.record_probe(addr, result.is_ok());
+                    Ok(())
+                }));
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        assert_eq!("race".parse(), Ok(Strategy::Race));
+        assert!("fastest-ish".parse::<Strategy>().is_err());
+
+        let addrs: Vec<SocketAddr> = vec![
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+        ];
+        let servers: Vec<_> = addrs.iter().cloned().map(UpstreamServer::from).collect();
+        let first = Upstreams::new(servers.clone(), Strategy::First);
+        assert_eq!(first.pick(), &addrs[..1]);
+        let race = Upstreams::new(servers.clone(), Strategy::Race);
+        assert_eq!(race.pick(), addrs);
+
+        // Unmeasured servers are tried first, and then the faster one
+        let fastest = Upstreams::new(servers.clone(), Strategy::Fastest);
+        assert_eq!(fastest.pick(), &addrs[..1]);
+        fastest.record(addrs[0], Duration::from_millis(80));
+        assert_eq!(fastest.pick(), &addrs[1..]);
+        fastest.record(addrs[1], Duration::from_millis(20));
+        assert_eq!(fastest.pick(), &addrs[1..]);
+        assert_eq!(
+            fastest.status(),
+            [
+                (addrs[0], Some(Duration::from_micros(77520)), true),
+                (addrs[1], Some(Duration::from_millis(20)), true)
+            ]
+        );
+
+        // Until the slower one has decayed below it
+        let tries = (0..100)
+            .position(|_| *fastest.pick() == addrs[..1])
+            .unwrap();
+        assert_eq!(tries, 87);
+    }
+
+    #[test]
+    fn test_weighted() {
+        assert_eq!(
+            "[::1]:53/3".parse(),
+            Ok(UpstreamServer {
+                addr: "[::1]:53".parse().unwrap(),
+                weight: 3,
+                url: None,
+            })
+        );
+        assert!("192.0.2.1:53/0".parse::<UpstreamServer>().is_err());
+        assert!("192.0.2.1/3".parse::<UpstreamServer>().is_err());
+        let doh = "https://192.0.2.1/dns-query/2".parse::<UpstreamServer>();
+        #[cfg(feature = "doh")]
+        {
+            let doh = doh.unwrap();
+            assert_eq!(doh.addr, "192.0.2.1:443".parse().unwrap());
+            assert_eq!(doh.weight, 2);
+            assert_eq!(doh.url.unwrap(), "https://192.0.2.1/dns-query");
+        }
+        #[cfg(not(feature = "doh"))]
+        assert!(doh.is_err());
+
+        let servers: Vec<UpstreamServer> = vec![
+            "192.0.2.1:53/3".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+        ];
+        let upstreams = Upstreams::new(servers.clone(), Strategy::Weighted);
+        let picks: Vec<_> = (0..8).map(|_| upstreams.pick()[0]).collect();
+        let a = servers[0].addr;
+        let b = servers[1].addr;
+        assert_eq!(picks, [a, a, b, a, a, a, b, a]);
+    }
+
+    #[test]
+    fn test_health() {
+        let addrs: Vec<SocketAddr> = vec![
+            "192.0.2.1:53".parse().unwrap(),
+            "192.0.2.2:53".parse().unwrap(),
+        ];
+        let servers: Vec<_> = addrs.iter().cloned().map(UpstreamServer::from).collect();
+        let upstreams = Upstreams::new(servers.clone(), Strategy::First);
+        let up = |upstreams: &Upstreams| -> Vec<bool> {
+            upstreams.status().iter().map(|s| s.2).collect()
+        };
+        for _ in 0..FALL - 1 {
+            upstreams.record_probe(addrs[0], false);
+        }
+        upstreams.record_probe(addrs[0], true);
+        upstreams.record_probe(addrs[0], false);
+        assert_eq!(up(&upstreams), [true, true]);
+        for _ in 0..FALL - 1 {
+            upstreams.record_probe(addrs[0], false);
+        }
+        assert_eq!(up(&upstreams), [false, true]);
+        assert_eq!(upstreams.pick(), &addrs[1..]);
+
+        // With all servers down, they are all tried
+        for _ in 0..FALL {
+            upstreams.record_probe(addrs[1], false);
+        }
+        assert_eq!(upstreams.pick(), &addrs[..1]);
+
+        for _ in 0..RISE {
+            upstreams.record_probe(addrs[1], true);
+        }
+        assert_eq!(up(&upstreams), [false, true]);
+        assert_eq!(upstreams.pick(), &addrs[1..]);
+    }
+}
@@ -0,0 +1,84 @@
+//! Watching files for changes, with inotify on Linux, FSEvents on macOS and
+//! by polling elsewhere, so that tools which rewrite the configuration
+//! needn't signal uind to reload it.
+
+use futures::prelude::*;
+use futures::sync::mpsc;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// A stream with an item `delay` after some of `paths` changed, and no
+/// more changes followed.
+///
+/// The directories of the files are watched rather than the files, as
+/// tools often replace a file by renaming a new one over it.
+pub fn changes(
+    paths: &[PathBuf],
+    delay: Duration,
+) -> io::Result<impl Stream<Item = (), Error = ()>> {
+    let (tx, rx) = std_mpsc::channel();
+    let mut watcher = notify::watcher(tx, delay).map_err(watch_error)?;
+    let mut files = HashSet::new();
+    let mut dirs = HashSet::new();
+    for path in paths {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let dir = dir.canonicalize().map_err(|e| {
+            io::Error::new(e.kind(), format!("Can't watch {}: {}", path.display(), e))
+        })?;
+        if let Some(name) = path.file_name() {
+            files.insert(dir.join(name));
+        }
+        dirs.insert(dir);
+    }
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+    }
+
+    let (changed, changes) = mpsc::unbounded();
+    thread::spawn(move || {
+        // Dropping the watcher stops the watch
+        let _watcher = watcher;
+        for event in rx.iter() {
+            match changed_path(event) {
+                Some(ref path) if files.contains(path) => {
+                    debug!("{} changed", path.display());
+                }
+                _ => continue,
+            }
+            // The events of one burst of changes arrive together
+            while rx.try_recv().is_ok() {}
+            if changed.unbounded_send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(changes)
+}
+
+fn changed_path(event: DebouncedEvent) -> Option<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Remove(path)
+        | DebouncedEvent::Rename(_, path) => Some(path),
+        DebouncedEvent::Error(e, _) => {
+            warn!("Error watching files: {}", e);
+            None
+        }
+        _ => None,
+    }
+}
+
+fn watch_error(e: notify::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Can't watch files: {}", e))
+}
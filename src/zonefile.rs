@@ -0,0 +1,522 @@
+//! Master files (RFC 1035 section 5), the zone files of BIND and most other
+//! servers.
+//!
+//! Supported are `$ORIGIN` and `$TTL`, parentheses, comments, quoted
+//! strings, TTLs with units like `1h30m`, the types which have a
+//! `DnsRRData` variant (SOA, NS, A, AAAA, CNAME, PTR, MX, TXT, HINFO, SRV), and
+//! the generic syntax of RFC 3597 (`TYPE731 \# 6 0001...`) for any other.
+
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::message::*;
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// The tokens of a record or directive, which may span several lines.
+struct Entry {
+    lineno: usize,
+    /// Whether the first line starts with a blank, leaving out the owner.
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Parse a master file into records.  Relative names are completed with
+/// `origin` until `$ORIGIN` changes it, and owner names are checked against
+/// `name_policy`.
+pub fn parse<R: BufRead>(
+    reader: R,
+    origin: DomainName,
+    name_policy: NamePolicy,
+) -> Result<Vec<DnsResourceRecord>, String> {
+    records(reader, origin, None, 1, name_policy)
+}
+
+/// Parse a record like `mail.lan MX 10 relay.lan`, found at line `lineno`
+/// of another file.  Names are relative to the root, and the TTL is `ttl`
+/// unless the record gives one.
+pub fn parse_record(
+    line: &str,
+    lineno: usize,
+    ttl: u32,
+    name_policy: NamePolicy,
+) -> Result<DnsResourceRecord, String> {
+    let mut records = records(
+        line.as_bytes(),
+        DomainName::default(),
+        Some(ttl),
+        lineno,
+        name_policy,
+    )?;
+    match records.len() {
+        1 => Ok(records.remove(0)),
+        _ => Err(format!("Expected one record at line {}", lineno)),
+    }
+}
+
+fn records<R: BufRead>(
+    reader: R,
+    origin: DomainName,
+    default_ttl: Option<u32>,
+    first_lineno: usize,
+    name_policy: NamePolicy,
+) -> Result<Vec<DnsResourceRecord>, String> {
+    let mut origin = origin;
+    let mut default_ttl = default_ttl;
+    let mut last_owner: Option<DomainName> = None;
+    let mut last_ttl = None;
+    let mut records = vec![];
+
+    for entry in entries(reader)? {
+        let err = |e: String| format!("{} at line {}", e, entry.lineno + first_lineno - 1);
+        let mut tokens = entry.tokens.iter().peekable();
+        let first = &entry.tokens[0];
+        if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+            let arg = entry
+                .tokens
+                .get(1)
+                .ok_or_else(|| err(format!("{} needs an argument", first.text)))?;
+            match first.text.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => origin = name(&arg.text, &origin).map_err(err)?,
+                "$TTL" => default_ttl = Some(ttl(&arg.text).map_err(err)?),
+                _ => return Err(err(format!("Unsupported directive {}", first.text))),
+            }
+            continue;
+        }
+
+        let owner = if entry.blank_owner {
+            last_owner
+                .clone()
+                .ok_or_else(|| err(String::from("Missing owner name")))?
+        } else {
+            let owner = name(&tokens.next().unwrap().text, &origin).map_err(err)?;
+            if !name_policy.check(&owner) && !owner.is_empty() {
+                return Err(err(format!("Invalid domain name {}", owner)));
+            }
+            owner
+        };
+        last_owner = Some(owner.clone());
+
+        // The TTL and class come in either order, and may be left out
+        let mut explicit_ttl = None;
+        while let Some(token) = tokens.peek() {
+            if token.text.eq_ignore_ascii_case("IN") {
+                tokens.next();
+            } else if let (None, Ok(value)) = (explicit_ttl, ttl(&token.text)) {
+                explicit_ttl = Some(value);
+                tokens.next();
+            } else {
+                match token.text.to_ascii_uppercase().as_str() {
+                    "CH" | "CS" | "HS" => {
+                        return Err(err(format!("Unsupported class {}", token.text)))
+                    }
+                    _ => break,
+                }
+            }
+        }
+        let rtype = tokens
+            .next()
+            .ok_or_else(|| err(String::from("Missing type")))?;
+        let (rtype, type_code) = parse_type(&rtype.text).map_err(err)?;
+        let rdata: Vec<&Token> = tokens.collect();
+        let data = rdata_from(rtype, type_code, &rdata, &origin).map_err(err)?;
+
+        // Without a TTL, the record takes $TTL or else the last one given
+        let ttl = explicit_ttl
+            .or(default_ttl)
+            .or(last_ttl)
+            .ok_or_else(|| err(String::from("Missing TTL")))?;
+        last_ttl = explicit_ttl.or(last_ttl);
+        records.push(DnsResourceRecord {
+            name: owner,
+            rtype,
+            rclass: DnsClass::Internet,
+            ttl,
+            data,
+        });
+    }
+    Ok(records)
+}
+
+/// Split a master file into entries, joining lines within parentheses.
+fn entries<R: BufRead>(reader: R) -> Result<Vec<Entry>, String> {
+    let mut entries = vec![];
+    let mut depth = 0;
+    let mut current: Option<Entry> = None;
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("Error reading line {}", e))?;
+        let lineno = lineno + 1;
+        if depth == 0 {
+            current = Some(Entry {
+                lineno,
+                blank_owner: line.starts_with(&[' ', '\t'][..]),
+                tokens: vec![],
+            });
+        }
+        let entry = current.as_mut().unwrap();
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' | '\t' | '\r' => (),
+                ';' => break,
+                '(' => depth += 1,
+                ')' if depth == 0 => return Err(format!("Unbalanced ) at line {}", lineno)),
+                ')' => depth -= 1,
+                '"' => {
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => {
+                                text.push('\\');
+                                text.extend(chars.next());
+                            }
+                            Some(c) => text.push(c),
+                            None => return Err(format!("Unterminated string at line {}", lineno)),
+                        }
+                    }
+                    entry.tokens.push(Token { text, quoted: true });
+                }
+                _ => {
+                    let mut text = c.to_string();
+                    if c == '\\' {
+                        text.extend(chars.next());
+                    }
+                    while let Some(&c) = chars.peek() {
+                        match c {
+                            ' ' | '\t' | '\r' | ';' | '(' | ')' | '"' => break,
+                            '\\' => {
+                                chars.next();
+                                text.push(c);
+                                text.extend(chars.next());
+                            }
+                            _ => {
+                                chars.next();
+                                text.push(c);
+                            }
+                        }
+                    }
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+            }
+        }
+        if depth == 0 && !entry.tokens.is_empty() {
+            entries.extend(current.take());
+        }
+    }
+    if depth > 0 {
+        return Err(String::from("Unbalanced ( at end of file"));
+    }
+    Ok(entries)
+}
+
+/// A name, relative to `origin` unless it ends with a dot.  `@` is the
+/// origin itself.
+fn name(s: &str, origin: &DomainName) -> Result<DomainName, String> {
+    if s == "@" {
+        return Ok(origin.clone());
+    }
+    let name = DomainName::from_str(s)?;
+    // A dot escaped by an odd number of backslashes is part of a label
+    let backslashes = s[..s.len() - 1]
+        .chars()
+        .rev()
+        .take_while(|&c| c == '\\')
+        .count();
+    if s == "." || (s.ends_with('.') && backslashes % 2 == 0) {
+        Ok(name)
+    } else {
+        Ok(name.iter().chain(origin.iter()).cloned().collect())
+    }
+}
+
+/// A TTL in seconds, or with BIND's units like `1w2d3h4m5s`.
+fn ttl(s: &str) -> Result<u32, String> {
+    let err = || format!("Invalid TTL {}", s);
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(err());
+    }
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    let mut unit_given = false;
+    for c in s.chars() {
+        let unit = match c.to_ascii_lowercase() {
+            d if d.is_ascii_digit() => {
+                value = value
+                    .checked_mul(10)
+                    .and_then(|v| v.checked_add(d as u32 - '0' as u32))
+                    .ok_or_else(err)?;
+                continue;
+            }
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604_800,
+            _ => return Err(err()),
+        };
+        total = value
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(err)?;
+        value = 0;
+        unit_given = true;
+    }
+    if unit_given && !s.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(err());
+    }
+    total.checked_add(value).ok_or_else(err)
+}
+
+/// A type mnemonic or `TYPEnnn`, and its number.
+fn parse_type(s: &str) -> Result<(DnsType, u16), String> {
+    if s.len() > 4 && s[..4].eq_ignore_ascii_case("TYPE") {
+        let code: u16 = s[4..].parse().map_err(|_| format!("Unknown type {}", s))?;
+        return Ok((DnsType::try_from(code).unwrap_or(DnsType::Unknown), code));
+    }
+    let rtype: DnsType = s.parse()?;
+    Ok((rtype, rtype as u16))
+}
+
+/// Decode the escapes of a character string, `\X` and `\DDD`, into the
+/// at most 255 bytes that go on the wire.
+fn string(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = s.bytes();
+    while let Some(b) = chars.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits = [d, chars.next().unwrap_or(0), chars.next().unwrap_or(0)];
+                let value = std::str::from_utf8(&digits)
+                    .ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .ok_or(format!("Invalid escape in {}", s))?;
+                bytes.push(value);
+            }
+            Some(c) => bytes.push(c),
+            None => return Err(format!("Invalid escape in {}", s)),
+        }
+    }
+    if bytes.len() > 255 {
+        return Err(format!("String of {} bytes is too long", bytes.len()));
+    }
+    Ok(bytes)
+}
+
+fn rdata_from(
+    rtype: DnsType,
+    type_code: u16,
+    rdata: &[&Token],
+    origin: &DomainName,
+) -> Result<DnsRRData, String> {
+    let text: Vec<&str> = rdata.iter().map(|token| token.text.as_str()).collect();
+    let count = |n: usize| {
+        if text.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} fields", rtype, n))
+        }
+    };
+    let number =
+        |s: &str| -> Result<u16, String> { s.parse().map_err(|_| format!("Invalid number {}", s)) };
+
+    // Generic rdata: \# LENGTH HEX...
+    if text.first() == Some(&"\\#") {
+        let len: usize = text
+            .get(1)
+            .and_then(|len| len.parse().ok())
+            .ok_or("Missing length of generic rdata")?;
+        let hex: String = text[2..].concat();
+        if hex.len() != len * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(String::from("Invalid generic rdata"));
+        }
+        let data = (0..len)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect();
+        return Ok(DnsRRData::Unknown(type_code, data));
+    }
+
+    let data = match rtype {
+        DnsType::A => {
+            count(1)?;
+            DnsRRData::A(
+                text[0]
+                    .parse()
+                    .map_err(|_| format!("Invalid IPv4 address {}", text[0]))?,
+            )
+        }
+        DnsType::AAAA => {
+            count(1)?;
+            DnsRRData::AAAA(
+                text[0]
+                    .parse()
+                    .map_err(|_| format!("Invalid IPv6 address {}", text[0]))?,
+            )
+        }
+        DnsType::NS => {
+            count(1)?;
+            DnsRRData::NS(name(text[0], origin)?)
+        }
+        DnsType::CNAME => {
+            count(1)?;
+            DnsRRData::CNAME(name(text[0], origin)?)
+        }
+        DnsType::PTR => {
+            count(1)?;
+            DnsRRData::PTR(name(text[0], origin)?)
+        }
+        DnsType::MX => {
+            count(2)?;
+            DnsRRData::MX(number(text[0])?, name(text[1], origin)?)
+        }
+        DnsType::SRV => {
+            count(4)?;
+            DnsRRData::SRV(
+                number(text[0])?,
+                number(text[1])?,
+                number(text[2])?,
+                name(text[3], origin)?,
+            )
+        }
+        DnsType::TXT => {
+            if text.is_empty() {
+                return Err(String::from("TXT takes at least one string"));
+            }
+            let strings = text.iter().map(|s| string(s));
+            DnsRRData::TXT(strings.collect::<Result<_, _>>()?)
+        }
+        DnsType::HINFO => {
+            count(2)?;
+            DnsRRData::HINFO(string(text[0])?, string(text[1])?)
+        }
+        DnsType::SOA => {
+            count(7)?;
+            DnsRRData::SOA(
+                name(text[0], origin)?,
+                name(text[1], origin)?,
+                text[2]
+                    .parse()
+                    .map_err(|_| format!("Invalid serial {}", text[2]))?,
+                ttl(text[3])?,
+                ttl(text[4])?,
+                ttl(text[5])?,
+                ttl(text[6])?,
+            )
+        }
+        _ => return Err(format!("Unsupported type {}, use the \\# syntax", rtype)),
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let zone = r#"
+$ORIGIN lan.
+$TTL 1h
+@   IN  SOA ns1 hostmaster.lan. (
+            2024010101 ; serial
+            3h 15m 1w 300 )
+    IN  NS  ns1
+ns1     A   192.168.1.1
+nas 300 IN  A   192.168.1.2
+        IN  AAAA fd00::2
+www     CNAME nas
+@       MX  10 mail.example.com.
+txt     TXT "v=spf1 -all" "a\"b; c"
+gen     TYPE731 \# 6 0001 0002 0050
+_sip._tcp SRV 10 60 5060 sip
+$ORIGIN sub.lan.
+host    A   192.168.2.1
+"#;
+        let records = parse(zone.as_bytes(), DomainName::default(), NamePolicy::Standard).unwrap();
+        assert_eq!(records.len(), 11);
+
+        let lan: DomainName = "lan".into();
+        assert_eq!(records[0].name, lan);
+        assert_eq!(
+            records[0].data,
+            DnsRRData::SOA(
+                "ns1.lan".into(),
+                "hostmaster.lan".into(),
+                2024010101,
+                10800,
+                900,
+                604800,
+                300
+            )
+        );
+        assert_eq!(records[0].ttl, 3600);
+        assert_eq!(records[1].name, lan);
+        assert_eq!(records[1].data, DnsRRData::NS("ns1.lan".into()));
+        assert_eq!(records[3].ttl, 300);
+        assert_eq!(records[4].name, "nas.lan".into());
+        assert_eq!(records[4].rtype, DnsType::AAAA);
+        assert_eq!(records[4].ttl, 3600);
+        assert_eq!(records[5].data, DnsRRData::CNAME("nas.lan".into()));
+        assert_eq!(
+            records[6].data,
+            DnsRRData::MX(10, "mail.example.com".into())
+        );
+        assert_eq!(
+            records[7].data,
+            DnsRRData::TXT(vec!["v=spf1 -all".into(), "a\"b; c".into()])
+        );
+        assert_eq!(records[8].rtype, DnsType::Unknown);
+        assert_eq!(
+            records[8].data,
+            DnsRRData::Unknown(731, vec![0, 1, 0, 2, 0, 0x50])
+        );
+        assert_eq!(records[9].name, "_sip._tcp.lan".into());
+        assert_eq!(
+            records[9].data,
+            DnsRRData::SRV(10, 60, 5060, "sip.lan".into())
+        );
+        assert_eq!(records[10].name, "host.sub.lan".into());
+
+        assert_eq!(ttl("1w2d3h4m5s"), Ok(788_645));
+        assert!(ttl("1h30").is_err());
+        assert!(parse("a A 1.2.3.4".as_bytes(), lan.clone(), NamePolicy::Strict).is_err());
+        assert!(parse("a 1 A (1.2.3.4".as_bytes(), lan.clone(), NamePolicy::Strict).is_err());
+        assert!(parse("a_b 1 A 1.2.3.4".as_bytes(), lan, NamePolicy::Strict).is_err());
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(
+            string(r#"caf\195\169 \"\\"#),
+            Ok(b"caf\xc3\xa9 \"\\".to_vec())
+        );
+        assert_eq!(string(r"\255\128"), Ok(vec![255, 128]));
+        assert!(string(r"\256").is_err());
+        assert!(string(r"\2").is_err());
+
+        let records = parse(
+            r#"a 60 TXT "\200" "\000""#.as_bytes(),
+            DomainName::default(),
+            NamePolicy::Standard,
+        )
+        .unwrap();
+        assert_eq!(records[0].data, DnsRRData::TXT(vec![vec![200], vec![0]]));
+
+        assert_eq!(string(&"a".repeat(255)).map(|s| s.len()), Ok(255));
+        assert!(string(&"a".repeat(256)).is_err());
+        assert!(string(&r"\097".repeat(256)).is_err());
+        let long = format!("a 60 TXT {}", "a".repeat(256));
+        assert!(parse(long.as_bytes(), DomainName::default(), NamePolicy::Standard).is_err());
+    }
+}